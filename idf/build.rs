@@ -24,6 +24,9 @@ fn main() {
         .clang_arg("-I".to_owned() + xtensa_toolchain_path.clone().join("lib").join("gcc").join("xtensa-esp32-elf").join("5.2.0").join("include-fixed").to_str().unwrap())
         .clang_arg("-I".to_owned() + idf_components_path.clone().join("newlib").join("platform_include").to_str().unwrap())
         .clang_arg("-I".to_owned() + idf_components_path.clone().join("lwip").join("include").join("apps").to_str().unwrap())
+        .clang_arg("-I".to_owned() + idf_components_path.clone().join("fatfs").join("src").to_str().unwrap())
+        .clang_arg("-I".to_owned() + idf_components_path.clone().join("fatfs").join("diskio").to_str().unwrap())
+        .clang_arg("-I".to_owned() + idf_components_path.clone().join("fatfs").join("vfs").to_str().unwrap())
         .clang_arg("-I".to_owned() + project_build_include_path.clone().to_str().unwrap())
         .use_core()
         .disable_untagged_union()
@@ -37,9 +40,31 @@ fn main() {
         .whitelist_function(r"(spi_|spicommon_).+")
         .whitelist_function(r"(i2c_|I2C_).+")
         .whitelist_function(r"(gpio|GPIO)_.+")
+        .whitelist_function(r"(uart_|UART_).+")
+        .whitelist_function(r"ledc_.+")
+        .whitelist_function(r"(adc1_|adc2_|adc_).+")
+        .whitelist_function(r"dac_.+")
+        .whitelist_function(r"i2s_.+")
+        .whitelist_function(r"rmt_.+")
+        .whitelist_function(r"timer_.+")
+        .whitelist_function(r"pcnt_.+")
+        .whitelist_function(r"touch_pad_.+")
+        .whitelist_function(r"can_.+")
+        .whitelist_function(r"mcpwm_.+")
+        .whitelist_function(r"sdmmc_.+")
+        .whitelist_function(r"(ff_diskio_.+|f_mount|f_mkfs)")
+        .whitelist_function(r"(xRingbufferReceive|vRingbufferReturnItem)")
+        // xQueueReceive is a macro for xQueueGenericReceive in this FreeRTOS.
+        .whitelist_function(r"xQueueGenericReceive")
         .whitelist_function(r"nvs_flash_.+")
+        .whitelist_function(r"ets_delay_us")
         .whitelist_function(r"tcpip_.+")
         .whitelist_function(r"ip(4|6)addr_.+")
+        // Types which are only used through pointers or casts.
+        .whitelist_type(r"spi_transaction_ext_t")
+        // Events received from the queues of the drivers.
+        .whitelist_type(r"uart_event_t")
+        .whitelist_type(r"i2s_event_t")
         // The input header we would like to generate
         // bindings for.
         .header("wrapper.h")
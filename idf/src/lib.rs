@@ -42,9 +42,19 @@ impl AsResult<(), IdfError> for esp_err_t {
 
 pub const portMAX_DELAY: TickType_t = 0xffffffff;
 
+pub const ESP_OK: esp_err_t = 0;
+pub const ESP_FAIL: esp_err_t = -1;
+pub const ESP_ERR_INVALID_ARG: esp_err_t = 0x102;
+pub const ESP_ERR_INVALID_STATE: esp_err_t = 0x103;
+pub const ESP_ERR_TIMEOUT: esp_err_t = 0x107;
+
 #[derive(Copy, Clone, Debug)]
 pub struct IdfError(esp_err_t);
 
+impl IdfError {
+    pub fn code(&self) -> esp_err_t { self.0 }
+}
+
 impl From<esp_err_t> for IdfError {
     fn from(err: esp_err_t) -> Self {
         IdfError(err)
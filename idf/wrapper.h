@@ -5,11 +5,32 @@
 #include <esp_event_loop.h>
 #include <esp_int_wdt.h>
 #include <esp_task_wdt.h>
+#include <esp_timer.h>
+
+#include <rom/ets_sys.h>
 
 #include <driver/gpio.h>
 #include <driver/spi_common.h>
 #include <driver/spi_master.h>
+#include <driver/spi_slave.h>
 #include <driver/i2c.h>
+#include <driver/uart.h>
+#include <driver/ledc.h>
+#include <driver/adc.h>
+#include <driver/dac.h>
+#include <driver/rmt.h>
+#include <driver/timer.h>
+#include <driver/pcnt.h>
+#include <driver/touch_pad.h>
+#include <driver/can.h>
+#include <driver/mcpwm.h>
+#include <driver/sdmmc_host.h>
+#include <esp_sleep.h>
+#include <driver/i2s.h>
+#include <esp_adc_cal.h>
+#include <sdmmc_cmd.h>
+#include <esp_vfs_fat.h>
+#include <diskio_impl.h>
 
 #include <nvs_flash.h>
 
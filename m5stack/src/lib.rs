@@ -2,11 +2,15 @@
 #![feature(alloc)] 
 
 use core::iter::Iterator;
+#[cfg(feature = "esp-idf")]
 use idf;
+#[cfg(feature = "esp-idf")]
 use idf::IdfError;
 
+#[cfg(feature = "esp-idf")]
 use freertos_rs::*;
 use peripheral::*;
+#[cfg(feature = "esp-idf")]
 use embedded_hal::digital::v2::*;
 use embedded_graphics;
 use embedded_graphics::drawable::Pixel;
@@ -15,10 +19,14 @@ use embedded_graphics::pixelcolor::{Rgb565, PixelColor};
 #[derive(Debug)]
 pub enum LcdError {
     Generic,
+    #[cfg(feature = "esp-idf")]
     IdfError(IdfError),
     SpiError(SpiError),
+    #[cfg(feature = "esp-idf")]
+    LedcError(LedcError),
 }
 
+#[cfg(feature = "esp-idf")]
 impl From<IdfError> for LcdError {
     fn from(error: IdfError) -> LcdError {
         LcdError::IdfError(error)
@@ -29,16 +37,201 @@ impl From<SpiError> for LcdError {
         LcdError::SpiError(error)
     }
 }
+#[cfg(feature = "esp-idf")]
+impl From<LedcError> for LcdError {
+    fn from(error: LedcError) -> LcdError {
+        LcdError::LedcError(error)
+    }
+}
 
-pub struct Lcd {
-    spi: SpiDeviceBusLock<bool>,
-    pin_dc: NormalGpio,
-    pin_rst:NormalGpio,
-    pin_bl: NormalGpio,
-    pin_cs: NormalGpio,
+// Open the internal I2C bus (IMU, power management and RTC) on GPIO21 (SDA) and GPIO22 (SCL).
+// Use I2cPort::proxy, or I2cProxy::new with an Arc<I2cPort>, to share it between drivers.
+#[cfg(feature = "esp-idf")]
+pub fn new_internal_i2c(port_number: I2cPortNumber) -> Result<I2cPort, I2cError> {
+    let port = I2cPort::new_master(port_number)?;
+    port.lock(Duration::infinite())?.config(I2cConfig {
+        mode: I2cMode::Master,
+        sda_io: GpioPin21,
+        scl_io: GpioPin22,
+        sda_pullup_en: GpioPullUp::Enable,
+        scl_pullup_en: GpioPullUp::Enable,
+        clk_speed: 400000,
+        addr_10bit_en: false,
+        slave_addr: 0,
+    })?;
+    Ok(port)
+}
+
+// The speaker amplifier is connected to DAC1 on GPIO25.
+#[cfg(feature = "esp-idf")]
+pub const SPEAKER_PIN: GpioPin = GpioPin25;
+
+// Open the speaker for direct output or the cosine generator. Use DacStream with DacStreamChannels::Channel1 to play samples.
+#[cfg(feature = "esp-idf")]
+pub fn new_speaker() -> Result<Dac, DacError> {
+    Dac::new(SPEAKER_PIN)
+}
+
+// Open the speaker on the built-in DAC of I2S0 for 16bit mono samples.
+#[cfg(feature = "esp-idf")]
+pub fn new_speaker_i2s(sample_rate: u32) -> Result<I2s, I2sError> {
+    I2s::new(I2sPort::Port0, I2sConfig {
+        format: I2sFormat::BuiltInDac,
+        sample_rate: sample_rate,
+        channels: I2sChannels::AllRight,
+        ..Default::default()
+    })
+}
+
+// The TF card slot shares the VSPI bus with the LCD; its CS is GPIO4.
+#[cfg(feature = "esp-idf")]
+pub const SD_CARD_CS_PIN: GpioPin = GpioPin4;
+
+// Open the TF card in SPI mode on the bus passed to Lcd::new. Mount it with FatFilesystem::mount.
+#[cfg(feature = "esp-idf")]
+pub fn new_sd_card(bus: &mut SpiBus) -> Result<SdSpiCard<SdSpiDevice>, SdError> {
+    SdSpiCard::new(SdSpiDevice::new(bus, SD_CARD_CS_PIN, 20000000)?)
+}
+
+// Well-known I2C devices on M5Stack cores and units, by 7-bit address.
+pub const M5STACK_I2C_DEVICES: &[(u8, &str)] = &[
+    (0x08, "FACES keyboard"),
+    (0x0c, "AK8963 magnetometer (MPU9250)"),
+    (0x10, "BMM150 magnetometer"),
+    (0x29, "VL53L0X ToF unit"),
+    (0x34, "AXP192 power management"),
+    (0x38, "FT6336U touch panel"),
+    (0x3c, "SSD1306 OLED"),
+    (0x44, "SHT30 (ENV unit)"),
+    (0x51, "BM8563 RTC"),
+    (0x5a, "MLX90614 NCIR unit"),
+    (0x5c, "DHT12 (ENV unit)"),
+    (0x5f, "CardKB unit"),
+    (0x68, "MPU6886/MPU9250 IMU"),
+    (0x70, "QMP6988 (ENV II unit)"),
+    (0x75, "IP5306 power management"),
+    (0x76, "BMP280 (ENV unit)"),
+];
+
+pub fn i2c_device_name(address: u8) -> Option<&'static str> {
+    M5STACK_I2C_DEVICES.iter()
+        .find(|(device_address, _)| *device_address == address)
+        .map(|(_, name)| *name)
+}
+
+// `TSpi` is the SPI device of the LCD. The context of each transaction selects command (false) or data (true).
+// `TControl` drives the reset line and the backlight, see LcdPins.
+pub struct Lcd<TSpi, TControl> {
+    spi: TSpi,
+    control: TControl,
     line_buffer: [u8; 640],
 }
 
+// Reset line, backlight and delays of the LCD, i.e. everything apart from the SPI device.
+pub trait LcdControl {
+    // Drive the reset line. `active` holds the controller in reset.
+    fn set_reset(&mut self, active: bool) -> Result<(), LcdError>;
+    // Set the backlight brightness. 0 turns the backlight off and 255 turns it fully on.
+    fn set_brightness(&mut self, brightness: u8) -> Result<(), LcdError>;
+    fn brightness(&mut self) -> u8;
+    fn fade_brightness(&mut self, brightness: u8, time_ms: u32) -> Result<(), LcdError>;
+    fn delay_ms(&mut self, ms: u32);
+}
+
+// The backlight is driven by a low speed LEDC timer with 8bit brightness.
+#[cfg(feature = "esp-idf")]
+const LCD_BACKLIGHT_FREQUENCY_HZ: u32 = 12000;
+#[cfg(feature = "esp-idf")]
+const LCD_BACKLIGHT_CHANNEL: usize = 0;
+
+// Reset and backlight GPIOs of the M5Stack LCD.
+// DC is switched by the pre-transaction callback of the SPI device and CS by the SPI driver, so they are only configured here.
+#[cfg(feature = "esp-idf")]
+pub struct LcdPins {
+    pin_dc: NormalGpio,
+    pin_rst: NormalGpio,
+    backlight: LedcPwm,
+}
+
+#[cfg(feature = "esp-idf")]
+impl LcdPins {
+    pub fn new(pin_dc: GpioPin, pin_rst: GpioPin, pin_bl: GpioPin) -> Result<LcdPins, LcdError> {
+        let mut dc = pin_dc.normal();
+        let mut rst = pin_rst.normal();
+
+        dc.configure(GpioConfig::output())?;
+        rst.configure(GpioConfig::output())?;
+
+        dc.set_high()?;
+        rst.set_low()?;
+
+        let mut backlight = LedcPwm::new(LedcTimerConfig { speed_mode: LedcSpeedMode::LowSpeed, frequency_hz: LCD_BACKLIGHT_FREQUENCY_HZ, resolution_bits: Some(8) })?;
+        backlight.add_channel(pin_bl)?;
+
+        Ok(LcdPins{pin_dc: dc, pin_rst: rst, backlight: backlight})
+    }
+}
+
+#[cfg(feature = "esp-idf")]
+impl LcdControl for LcdPins {
+    fn set_reset(&mut self, active: bool) -> Result<(), LcdError> {
+        if active {
+            self.pin_rst.set_low()?;
+        }
+        else {
+            self.pin_rst.set_high()?;
+        }
+        Ok(())
+    }
+    // 255 is the full duty of the channel, which keeps the backlight on for the whole period.
+    fn set_brightness(&mut self, brightness: u8) -> Result<(), LcdError> {
+        let channel = self.backlight.channel(LCD_BACKLIGHT_CHANNEL);
+        let duty = brightness as u32 * channel.max_duty() / 255;
+        channel.set_duty(duty)?;
+        Ok(())
+    }
+    fn brightness(&mut self) -> u8 {
+        let channel = self.backlight.channel(LCD_BACKLIGHT_CHANNEL);
+        ((channel.duty() * 255 + channel.max_duty() / 2) / channel.max_duty()) as u8
+    }
+    fn fade_brightness(&mut self, brightness: u8, time_ms: u32) -> Result<(), LcdError> {
+        let channel = self.backlight.channel(LCD_BACKLIGHT_CHANNEL);
+        let duty = brightness as u32 * channel.max_duty() / 255;
+        channel.fade_to(duty, time_ms, false)?;
+        Ok(())
+    }
+    fn delay_ms(&mut self, ms: u32) {
+        TaskDelay::new().delay_until(Duration::ms(ms));
+    }
+}
+
+// Control which only keeps the state and returns from delays immediately.
+// Use it to run the LCD protocol without the hardware, e.g. against a SpiReplay.
+#[derive(Debug, Default)]
+pub struct LcdControlStub {
+    pub reset: bool,
+    pub brightness: u8,
+}
+
+impl LcdControl for LcdControlStub {
+    fn set_reset(&mut self, active: bool) -> Result<(), LcdError> {
+        self.reset = active;
+        Ok(())
+    }
+    fn set_brightness(&mut self, brightness: u8) -> Result<(), LcdError> {
+        self.brightness = brightness;
+        Ok(())
+    }
+    fn brightness(&mut self) -> u8 {
+        self.brightness
+    }
+    fn fade_brightness(&mut self, brightness: u8, _time_ms: u32) -> Result<(), LcdError> {
+        self.brightness = brightness;
+        Ok(())
+    }
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
 const TFT_NOP:u8 = 0x00;
 const TFT_SWRST:u8 = 0x01;
 
@@ -133,44 +326,39 @@ const ILI9341_MADCTL_MH:u8 = 0x04;
 const LCD_WIDTH:u16 = 320;
 const LCD_HEIGHT:u16 = 240;
 
-impl Lcd {
-    pub fn new(bus: &mut SpiBus, pin_cs: GpioPin, pin_dc: GpioPin, pin_rst: GpioPin, pin_bl: GpioPin) -> Result<Lcd, LcdError> {
+#[cfg(feature = "esp-idf")]
+impl Lcd<SpiDeviceBusLock<bool>, LcdPins> {
+    pub fn new(bus: &mut SpiBus, pin_cs: GpioPin, pin_dc: GpioPin, pin_rst: GpioPin, pin_bl: GpioPin) -> Result<Lcd<SpiDeviceBusLock<bool>, LcdPins>, LcdError> {
         let spi_device_config = SpiDeviceInterfaceConfig {
             cs_pin: Some(pin_cs),
             clock_speed_hz: 40000000,
             ..Default::default()
         };
-        let mut dc = pin_dc.normal();
-        let mut rst = pin_rst.normal();
-        let mut bl = pin_bl.normal();
-        let mut cs = pin_cs.normal();
-        
-        dc.configure(GpioConfig::output())?;
-        rst.configure(GpioConfig::output())?;
-        cs.configure(GpioConfig::output())?;
-        bl.configure(GpioConfig::output())?;
-        
-        dc.set_high()?;
-        rst.set_low()?;
-        bl.set_low()?;
-        cs.set_high()?;
+        // Configure the GPIOs first; CS is left to the SPI driver which routes it when the device is added.
+        let control = LcdPins::new(pin_dc, pin_rst, pin_bl)?;
+        let device = bus.add_device(spi_device_config, pin_dc.direct_output(), ())?;
+        Ok(Lcd::with_spi(device, control))
+    }
+}
+
+impl<TSpi, TControl> Lcd<TSpi, TControl> where TSpi: SpiTransactions<bool>, TControl: LcdControl {
+    // Create an LCD on an existing SPI device, e.g. a SpiRecorder or SpiReplay with a LcdControlStub.
+    pub fn with_spi(spi: TSpi, control: TControl) -> Lcd<TSpi, TControl> {
+        Lcd{spi: spi, control: control, line_buffer: [0u8; 640]}
+    }
 
-        let device = bus.add_device(spi_device_config, 
-            move |dc:&bool| {
-                let mut dc_pin = pin_dc.normal();
-                dc_pin.set_level(*dc);
-            }, 
-            |_| {}
-        )?;
-        let lcd = Lcd{spi: device, pin_dc: dc, pin_rst: rst, pin_bl: bl, pin_cs: cs, line_buffer: [0u8; 640]};
-        Ok(lcd)
+    // Replace the SPI device, e.g. `lcd.map_spi(SpiRecorder::new)` to record the transactions.
+    pub fn map_spi<TNewSpi, F>(self, f: F) -> Lcd<TNewSpi, TControl> where F: FnOnce(TSpi) -> TNewSpi {
+        Lcd{spi: f(self.spi), control: self.control, line_buffer: self.line_buffer}
     }
+    pub fn spi(&self) -> &TSpi { &self.spi }
+    pub fn control(&self) -> &TControl { &self.control }
 
     pub fn reset(&mut self) -> Result<(), LcdError> {
-        self.pin_rst.set_low()?;
-        TaskDelay::new().delay_until(Duration::ms(150));
-        self.pin_rst.set_high()?;
-        TaskDelay::new().delay_until(Duration::ms(150));
+        self.control.set_reset(true)?;
+        self.control.delay_ms(150);
+        self.control.set_reset(false)?;
+        self.control.delay_ms(150);
 
         self.write_cmd_data(0xef, &[0x03, 0x80, 0x02])?;
         self.write_cmd_data(0xcf, &[0x00, 0xc1, 0x30])?;
@@ -193,14 +381,26 @@ impl Lcd {
         self.write_cmd_data(ILI9341_GMCTRN1, &[0x00, 0x0E, 0x14, 0x03, 0x11, 0x07, 0x31, 0xC1, 0x48, 0x08, 0x0F, 0x0C, 0x31, 0x36, 0x0F])?;
         self.write_cmd(ILI9341_SLPOUT)?;
         
-        TaskDelay::new().delay_until(Duration::ms(120));
+        self.control.delay_ms(120);
         self.write_cmd(ILI9341_DISPON)?;
         self.write_cmd_data(TFT_MADCTL, &[TFT_MAD_BGR])?;
         
-        self.pin_bl.set_level(true)?;
+        self.set_brightness(255)?;
         Ok(())
     }
 
+    // Set the backlight brightness. 0 turns the backlight off.
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), LcdError> {
+        self.control.set_brightness(brightness)
+    }
+    pub fn brightness(&mut self) -> u8 {
+        self.control.brightness()
+    }
+    // Fade the backlight to the brightness in `time_ms` without blocking.
+    pub fn fade_brightness(&mut self, brightness: u8, time_ms: u32) -> Result<(), LcdError> {
+        self.control.fade_brightness(brightness, time_ms)
+    }
+
     pub fn read_id(&mut self) -> Result<[u8;3], LcdError> {
         let mut buffer = [0, 0, 0]; 
         self.write_cmd(0x04)
@@ -234,27 +434,29 @@ impl Lcd {
     pub fn write_cmd(&mut self, command: u8) -> Result<(), LcdError> {
         let buffer = [command];
         let transaction = SpiTransaction::new_write(&buffer, false);
-        let mut device = self.spi.lock()?;
-        device.transfer(transaction)?;
+        self.spi.transactions(|device| device.transfer(transaction))?;
         Ok(())
     }
     
     pub fn write_data(&self, data: &[u8]) -> Result<(), LcdError> {
         let transaction = SpiTransaction::new_write(data, true);
-        let mut device = self.spi.lock()?;
-        device.transfer(transaction)?;
+        self.spi.transactions(|device| device.transfer(transaction))?;
         Ok(())
     }
 
     pub fn write_cmd_data(&mut self, command: u8, values: &[u8]) -> Result<(), LcdError> {
-        self.write_cmd(command)
-            .and_then(|_| self.write_data(values))
+        // Hold the bus so that no other device can be selected between the command and its parameters.
+        let buffer = [command];
+        self.spi.transactions(|device| {
+            device.transfer(SpiTransaction::new_write(&buffer, false))?;
+            device.transfer(SpiTransaction::new_write(values, true))
+        })?;
+        Ok(())
     }
 
     pub fn read_data(&mut self, data: &mut [u8]) -> Result<(), LcdError> {
         let transaction = SpiTransaction::new_read(data, true);
-        let mut device = self.spi.lock()?;
-        device.transfer(transaction)?;
+        self.spi.transactions(|device| device.transfer(transaction))?;
         Ok(())
     }
 
@@ -383,8 +585,8 @@ impl Into<Rgb565> for GrayScale8 {
     }
 }
 
-impl<TPixelColor> embedded_graphics::Drawing<TPixelColor> for Lcd 
-    where TPixelColor : PixelColor + Into<Rgb565> 
+impl<TSpi, TControl, TPixelColor> embedded_graphics::Drawing<TPixelColor> for Lcd<TSpi, TControl> 
+    where TSpi : SpiTransactions<bool>, TControl : LcdControl, TPixelColor : PixelColor + Into<Rgb565> 
 {
     fn draw<T>(&mut self, item: T)
     where
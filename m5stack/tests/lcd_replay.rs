@@ -0,0 +1,96 @@
+// Replays the SPI traffic of Lcd::reset() and some drawing against a snapshot log.
+// Run with `cargo test --no-default-features` on the host.
+//
+// tests/data/lcd_reset_draw.spilog is not captured from a display. It is recorded on the host through NullSpi, so these
+// tests catch unintended changes of the LCD protocol but do not show that the protocol works on the hardware.
+// Regenerate it with `cargo test --no-default-features --test lcd_replay -- --ignored record_reset_and_draw`
+// after an intended change of the LCD protocol and review the diff of the log before committing it.
+
+use std::cell::Cell;
+use std::fs;
+use std::path::PathBuf;
+
+use embedded_graphics::prelude::*;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::primitives::{Line, Rectangle};
+use m5stack::*;
+use peripheral::*;
+
+const SNAPSHOT_LOG: &[u8] = include_bytes!("data/lcd_reset_draw.spilog");
+
+fn reset_and_draw<TSpi: SpiTransactions<bool>>(lcd: &mut Lcd<TSpi, LcdControlStub>) {
+    lcd.reset().unwrap();
+    lcd.fill(0, 0, 16, 4, Rgb565(0x001f)).unwrap();
+    lcd.draw(Rectangle::new(Coord::new(10, 10), Coord::new(13, 12)).fill(Some(Rgb565(0xf800))));
+    lcd.draw(Line::new(Coord::new(0, 20), Coord::new(5, 22)).stroke(Some(Rgb565(0x07e0))));
+}
+
+// Device which accepts every transaction without any hardware.
+struct NullSpi;
+
+impl SpiTransfer<bool> for NullSpi {
+    fn transfer_received<'t>(&mut self, transaction: SpiTransaction<'t, bool>) -> Result<SpiReceived<'t>, SpiError> {
+        Ok(transaction.into_received([0; 4]))
+    }
+}
+
+impl SpiTransactions<bool> for NullSpi {
+    fn transactions<R, F>(&self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiTransfer<bool>) -> Result<R, SpiError>
+    {
+        f(&mut NullSpi)
+    }
+}
+
+fn record() -> Vec<u8> {
+    // 10us per transaction keeps the log deterministic.
+    let now_us = Cell::new(0u64);
+    let clock = || { now_us.set(now_us.get() + 10); now_us.get() };
+    let mut lcd = Lcd::with_spi(SpiRecorder::with_clock(NullSpi, clock), LcdControlStub::default());
+    reset_and_draw(&mut lcd);
+    lcd.spi().take_log()
+}
+
+#[test]
+fn replay_reset_and_draw() {
+    let mut lcd = Lcd::with_spi(SpiReplay::new(SNAPSHOT_LOG.to_vec()), LcdControlStub::default());
+    reset_and_draw(&mut lcd);
+    assert_eq!(lcd.spi().mismatches(), 0, "first mismatch at {:?}", lcd.spi().first_mismatch());
+    assert!(lcd.spi().is_finished());
+    assert!(!lcd.control().reset);
+    assert_eq!(lcd.control().brightness, 255);
+}
+
+#[test]
+fn replay_detects_changed_pixels() {
+    let mut lcd = Lcd::with_spi(SpiReplay::new(SNAPSHOT_LOG.to_vec()), LcdControlStub::default());
+    lcd.reset().unwrap();
+    let before_fill = lcd.spi().replayed();
+    lcd.fill(0, 0, 16, 4, Rgb565(0x07e0)).unwrap();
+    assert_eq!(lcd.spi().mismatches(), 4);
+    assert_eq!(lcd.spi().first_mismatch(), Some(before_fill + 5));
+}
+
+#[test]
+fn recording_matches_snapshot() {
+    let log = record();
+    let mut reader = SpiLogReader::new(&log);
+    let mut snapshot = SpiLogReader::new(SNAPSHOT_LOG);
+    loop {
+        match (reader.next_record(), snapshot.next_record()) {
+            (Some(record), Some(expected)) => {
+                assert!(record.same_request(&expected), "{:?} != {:?}", record, expected);
+                assert_eq!(record.time_delta_us, expected.time_delta_us);
+            },
+            (None, None) => break,
+            (record, expected) => panic!("{:?} != {:?}", record, expected),
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn record_reset_and_draw() {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "data", "lcd_reset_draw.spilog"].iter().collect();
+    fs::write(path, record()).unwrap();
+}
@@ -0,0 +1,458 @@
+use core::convert::Into;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+use idf::IdfError;
+
+use freertos_rs::*;
+use embedded_hal::adc;
+
+use nb;
+
+use crate::gpio::*;
+use crate::i2s::{I2sPort, claim_i2s_port, release_i2s_port};
+
+#[derive(Copy, Clone, Debug)]
+pub enum AdcError {
+    Generic,
+    IdfError(IdfError),
+    FreeRtosError(FreeRtosError),
+    // The pin is not connected to the ADC unit.
+    InvalidPin,
+    // The ADC unit is already in use by another driver.
+    Busy,
+    // ADC2 is in use by the Wi-Fi driver.
+    Timeout,
+}
+
+impl From<IdfError> for AdcError {
+    fn from(err: IdfError) -> AdcError {
+        AdcError::IdfError(err)
+    }
+}
+impl From<FreeRtosError> for AdcError {
+    fn from(err: FreeRtosError) -> AdcError {
+        AdcError::FreeRtosError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdcUnit {
+    Adc1,
+    Adc2,
+}
+impl Into<idf::adc_unit_t> for AdcUnit {
+    fn into(self) -> idf::adc_unit_t {
+        match self {
+            AdcUnit::Adc1 => idf::adc_unit_t_ADC_UNIT_1,
+            AdcUnit::Adc2 => idf::adc_unit_t_ADC_UNIT_2,
+        }
+    }
+}
+
+// Input range: 0dB up to about 1.1V, 2.5dB 1.5V, 6dB 2.2V, 11dB 3.9V (linear up to about 2.6V).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdcAttenuation {
+    Db0,
+    Db2_5,
+    Db6,
+    Db11,
+}
+impl Default for AdcAttenuation {
+    fn default() -> Self { AdcAttenuation::Db11 }
+}
+impl Into<idf::adc_atten_t> for AdcAttenuation {
+    fn into(self) -> idf::adc_atten_t {
+        match self {
+            AdcAttenuation::Db0 => idf::adc_atten_t_ADC_ATTEN_DB_0,
+            AdcAttenuation::Db2_5 => idf::adc_atten_t_ADC_ATTEN_DB_2_5,
+            AdcAttenuation::Db6 => idf::adc_atten_t_ADC_ATTEN_DB_6,
+            AdcAttenuation::Db11 => idf::adc_atten_t_ADC_ATTEN_DB_11,
+        }
+    }
+}
+impl AdcAttenuation {
+    fn index(&self) -> usize {
+        match self {
+            AdcAttenuation::Db0 => 0,
+            AdcAttenuation::Db2_5 => 1,
+            AdcAttenuation::Db6 => 2,
+            AdcAttenuation::Db11 => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdcWidth {
+    Bits9,
+    Bits10,
+    Bits11,
+    Bits12,
+}
+impl Default for AdcWidth {
+    fn default() -> Self { AdcWidth::Bits12 }
+}
+impl Into<idf::adc_bits_width_t> for AdcWidth {
+    fn into(self) -> idf::adc_bits_width_t {
+        match self {
+            AdcWidth::Bits9 => idf::adc_bits_width_t_ADC_WIDTH_BIT_9,
+            AdcWidth::Bits10 => idf::adc_bits_width_t_ADC_WIDTH_BIT_10,
+            AdcWidth::Bits11 => idf::adc_bits_width_t_ADC_WIDTH_BIT_11,
+            AdcWidth::Bits12 => idf::adc_bits_width_t_ADC_WIDTH_BIT_12,
+        }
+    }
+}
+
+// ADC unit and channel of a pin. GPIO36-39 and GPIO32-35 belong to ADC1; ADC2 is shared with Wi-Fi.
+pub fn adc_channel_of(pin: GpioPin) -> Option<(AdcUnit, u32)> {
+    match pin.number() {
+        36 => Some((AdcUnit::Adc1, 0)),
+        37 => Some((AdcUnit::Adc1, 1)),
+        38 => Some((AdcUnit::Adc1, 2)),
+        39 => Some((AdcUnit::Adc1, 3)),
+        32 => Some((AdcUnit::Adc1, 4)),
+        33 => Some((AdcUnit::Adc1, 5)),
+        34 => Some((AdcUnit::Adc1, 6)),
+        35 => Some((AdcUnit::Adc1, 7)),
+        4 => Some((AdcUnit::Adc2, 0)),
+        0 => Some((AdcUnit::Adc2, 1)),
+        2 => Some((AdcUnit::Adc2, 2)),
+        15 => Some((AdcUnit::Adc2, 3)),
+        13 => Some((AdcUnit::Adc2, 4)),
+        12 => Some((AdcUnit::Adc2, 5)),
+        14 => Some((AdcUnit::Adc2, 6)),
+        27 => Some((AdcUnit::Adc2, 7)),
+        25 => Some((AdcUnit::Adc2, 8)),
+        26 => Some((AdcUnit::Adc2, 9)),
+        _ => None,
+    }
+}
+
+// Source of the calibration data used to convert raw values to millivolts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdcCalibrationSource {
+    // Two point values burned in eFuse.
+    TwoPoint,
+    // Reference voltage burned in eFuse.
+    EfuseVref,
+    // No eFuse data; the default reference voltage (1100mV) is assumed.
+    DefaultVref,
+}
+
+const ADC_DEFAULT_VREF_MV: u32 = 1100;
+
+#[derive(Copy, Clone, Debug)]
+pub struct AdcCalibration {
+    characteristics: idf::esp_adc_cal_characteristics_t,
+    source: AdcCalibrationSource,
+}
+
+impl AdcCalibration {
+    pub fn new(unit: AdcUnit, attenuation: AdcAttenuation, width: AdcWidth) -> AdcCalibration {
+        let mut characteristics = idf::esp_adc_cal_characteristics_t::default();
+        let value = unsafe { idf::esp_adc_cal_characterize(unit.into(), attenuation.into(), width.into(), ADC_DEFAULT_VREF_MV, &mut characteristics) };
+        let source = match value {
+            idf::esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_TP => AdcCalibrationSource::TwoPoint,
+            idf::esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_VREF => AdcCalibrationSource::EfuseVref,
+            _ => AdcCalibrationSource::DefaultVref,
+        };
+        AdcCalibration { characteristics: characteristics, source: source }
+    }
+    pub fn source(&self) -> AdcCalibrationSource { self.source }
+    pub fn raw_to_millivolts(&self, raw: u16) -> u32 {
+        unsafe { idf::esp_adc_cal_raw_to_voltage(raw as u32, &self.characteristics) }
+    }
+}
+
+// Marker types of the ADC units for embedded_hal::adc.
+pub struct Adc1;
+pub struct Adc2;
+
+pub trait AdcUnitId {
+    const UNIT: AdcUnit;
+}
+impl AdcUnitId for Adc1 { const UNIT: AdcUnit = AdcUnit::Adc1; }
+impl AdcUnitId for Adc2 { const UNIT: AdcUnit = AdcUnit::Adc2; }
+
+static ADC_UNITS_IN_USE: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+fn adc_unit_index(unit: AdcUnit) -> usize {
+    match unit {
+        AdcUnit::Adc1 => 0,
+        AdcUnit::Adc2 => 1,
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AdcConfig {
+    pub width: AdcWidth,
+    // Attenuation of channels without set_attenuation.
+    pub attenuation: AdcAttenuation,
+}
+
+pub struct Adc<TUnit: AdcUnitId> {
+    config: AdcConfig,
+    attenuations: [Option<AdcAttenuation>; 10],
+    calibrations: [Option<AdcCalibration>; 4],
+    _unit: PhantomData<TUnit>,
+}
+
+impl<TUnit: AdcUnitId> Adc<TUnit> {
+    pub fn new(config: AdcConfig) -> Result<Adc<TUnit>, AdcError> {
+        if ADC_UNITS_IN_USE[adc_unit_index(TUnit::UNIT)].compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(AdcError::Busy);
+        }
+        let adc = Adc { config: config, attenuations: [None; 10], calibrations: [None; 4], _unit: PhantomData };
+        if TUnit::UNIT == AdcUnit::Adc1 {
+            unsafe { idf::adc1_config_width(config.width.into()).as_result()?; }
+        }
+        Ok(adc)
+    }
+
+    fn channel(pin: GpioPin) -> Result<u32, AdcError> {
+        match adc_channel_of(pin) {
+            Some((unit, channel)) if unit == TUnit::UNIT => Ok(channel),
+            _ => Err(AdcError::InvalidPin),
+        }
+    }
+
+    // Configure the attenuation of a pin, which is applied on its next read.
+    pub fn set_attenuation(&mut self, pin: GpioPin, attenuation: AdcAttenuation) -> Result<(), AdcError> {
+        let channel = Self::channel(pin)?;
+        self.attenuations[channel as usize] = Some(attenuation);
+        Ok(())
+    }
+    pub fn attenuation(&self, pin: GpioPin) -> Result<AdcAttenuation, AdcError> {
+        let channel = Self::channel(pin)?;
+        Ok(self.attenuations[channel as usize].unwrap_or(self.config.attenuation))
+    }
+
+    // Read the raw value of a pin.
+    pub fn read_raw(&mut self, pin: GpioPin) -> Result<u16, AdcError> {
+        let channel = Self::channel(pin)?;
+        let attenuation = self.attenuation(pin)?;
+        match TUnit::UNIT {
+            AdcUnit::Adc1 => {
+                let channel = idf::adc1_channel_t_ADC1_CHANNEL_0 + channel;
+                unsafe { idf::adc1_config_channel_atten(channel, attenuation.into()).as_result()?; }
+                let raw = unsafe { idf::adc1_get_raw(channel) };
+                if raw < 0 { Err(AdcError::Generic) } else { Ok(raw as u16) }
+            },
+            AdcUnit::Adc2 => {
+                let channel = idf::adc2_channel_t_ADC2_CHANNEL_0 + channel;
+                let mut raw: c_int = 0;
+                unsafe { idf::adc2_config_channel_atten(channel, attenuation.into()).as_result()?; }
+                match unsafe { idf::adc2_get_raw(channel, self.config.width.into(), &mut raw).as_result() } {
+                    Ok(_) => Ok(raw as u16),
+                    Err(err) if err.code() == idf::ESP_ERR_TIMEOUT => Err(AdcError::Timeout),
+                    Err(err) => Err(err.into()),
+                }
+            },
+        }
+    }
+
+    // Calibration data for an attenuation, characterized on first use.
+    pub fn calibration(&mut self, attenuation: AdcAttenuation) -> AdcCalibration {
+        let index = attenuation.index();
+        if self.calibrations[index].is_none() {
+            self.calibrations[index] = Some(AdcCalibration::new(TUnit::UNIT, attenuation, self.config.width));
+        }
+        self.calibrations[index].unwrap()
+    }
+
+    // Read the voltage of a pin in millivolts.
+    pub fn read_millivolts(&mut self, pin: GpioPin) -> Result<u32, AdcError> {
+        let raw = self.read_raw(pin)?;
+        let attenuation = self.attenuation(pin)?;
+        Ok(self.calibration(attenuation).raw_to_millivolts(raw))
+    }
+}
+
+impl<TUnit: AdcUnitId> Drop for Adc<TUnit> {
+    fn drop(&mut self) {
+        ADC_UNITS_IN_USE[adc_unit_index(TUnit::UNIT)].store(false, Ordering::SeqCst);
+    }
+}
+
+// embedded_hal::adc on the GpioPin constants. e.g. `adc.read(&mut GpioPin36)`.
+// GpioPin is a single type, so the channel is only known at runtime (see adc_channel_of) and
+// a pin of the other unit fails the read with InvalidPin.
+impl adc::Channel<Adc1> for GpioPin {
+    type ID = ();
+    fn channel() {}
+}
+impl adc::Channel<Adc2> for GpioPin {
+    type ID = ();
+    fn channel() {}
+}
+
+impl<TUnit: AdcUnitId> adc::OneShot<TUnit, u16, GpioPin> for Adc<TUnit> where GpioPin: adc::Channel<TUnit> {
+    type Error = AdcError;
+    fn read(&mut self, pin: &mut GpioPin) -> nb::Result<u16, AdcError> {
+        match self.read_raw(*pin) {
+            Ok(raw) => Ok(raw),
+            Err(AdcError::Timeout) => Err(nb::Error::WouldBlock),
+            Err(err) => Err(nb::Error::Other(err)),
+        }
+    }
+}
+
+pub const ADC_SAMPLE_BLOCK_LEN: usize = 32;
+
+// A block of raw samples from the continuous sampler.
+#[derive(Copy, Clone)]
+pub struct AdcSampleBlock {
+    pub samples: [u16; ADC_SAMPLE_BLOCK_LEN],
+    pub len: usize,
+    // Number of blocks dropped before this one because the queue was full.
+    pub dropped: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AdcContinuousConfig {
+    pub sample_rate: u32,
+    pub width: AdcWidth,
+    pub attenuation: AdcAttenuation,
+    pub dma_buffer_count: usize,
+    // Number of samples per DMA buffer.
+    pub dma_buffer_len: usize,
+    // Number of sample blocks the queue holds.
+    pub queue_size: usize,
+}
+
+impl Default for AdcContinuousConfig {
+    fn default() -> Self {
+        AdcContinuousConfig {
+            sample_rate: 16000,
+            width: Default::default(),
+            attenuation: Default::default(),
+            dma_buffer_count: 4,
+            dma_buffer_len: 256,
+            queue_size: 16,
+        }
+    }
+}
+
+const ADC_I2S_PORT: I2sPort = I2sPort::Port0;
+const ADC_I2S_SAMPLE_MASK: u16 = 0x0fff;
+
+// Continuous sampling of an ADC1 pin through the built-in I2S-ADC DMA. Occupies ADC1 and I2S0.
+// A task reads the DMA buffers and sends the samples to `queue()` in blocks.
+pub struct AdcContinuous {
+    queue: Arc<Queue<AdcSampleBlock>>,
+    running: Arc<AtomicBool>,
+    stopped: Arc<Queue<()>>,
+    calibration: AdcCalibration,
+}
+
+impl AdcContinuous {
+    pub fn new(pin: GpioPin, config: AdcContinuousConfig) -> Result<AdcContinuous, AdcError> {
+        let channel = match adc_channel_of(pin) {
+            Some((AdcUnit::Adc1, channel)) => channel,
+            _ => return Err(AdcError::InvalidPin),
+        };
+        if ADC_UNITS_IN_USE[adc_unit_index(AdcUnit::Adc1)].compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(AdcError::Busy);
+        }
+        if !claim_i2s_port(ADC_I2S_PORT) {
+            ADC_UNITS_IN_USE[adc_unit_index(AdcUnit::Adc1)].store(false, Ordering::SeqCst);
+            return Err(AdcError::Busy);
+        }
+        match AdcContinuous::start(channel, config) {
+            Ok(sampler) => Ok(sampler),
+            Err(err) => {
+                release_i2s_port(ADC_I2S_PORT);
+                ADC_UNITS_IN_USE[adc_unit_index(AdcUnit::Adc1)].store(false, Ordering::SeqCst);
+                Err(err)
+            },
+        }
+    }
+
+    fn start(channel: u32, config: AdcContinuousConfig) -> Result<AdcContinuous, AdcError> {
+        let mut i2s_config = idf::i2s_config_t::default();
+        i2s_config.mode = idf::i2s_mode_t_I2S_MODE_MASTER | idf::i2s_mode_t_I2S_MODE_RX | idf::i2s_mode_t_I2S_MODE_ADC_BUILT_IN;
+        i2s_config.sample_rate = config.sample_rate as i32;
+        i2s_config.bits_per_sample = idf::i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_16BIT;
+        i2s_config.channel_format = idf::i2s_channel_fmt_t_I2S_CHANNEL_FMT_ONLY_LEFT;
+        i2s_config.communication_format = idf::i2s_comm_format_t_I2S_COMM_FORMAT_I2S_MSB;
+        i2s_config.dma_buf_count = config.dma_buffer_count as i32;
+        i2s_config.dma_buf_len = config.dma_buffer_len as i32;
+        let adc_channel = idf::adc1_channel_t_ADC1_CHANNEL_0 + channel;
+        unsafe {
+            idf::i2s_driver_install(ADC_I2S_PORT.into(), &i2s_config, 0, core::ptr::null_mut()).as_result()?;
+            let result = idf::adc1_config_width(config.width.into()).as_result()
+                .and_then(|_| idf::adc1_config_channel_atten(adc_channel, config.attenuation.into()).as_result())
+                .and_then(|_| idf::i2s_set_adc_mode(idf::adc_unit_t_ADC_UNIT_1, adc_channel).as_result())
+                .and_then(|_| idf::i2s_adc_enable(ADC_I2S_PORT.into()).as_result());
+            if let Err(err) = result {
+                idf::i2s_driver_uninstall(ADC_I2S_PORT.into());
+                return Err(err.into());
+            }
+        }
+
+        let queue = Arc::new(Queue::new(config.queue_size)?);
+        let running = Arc::new(AtomicBool::new(true));
+        let stopped = Arc::new(Queue::new(1)?);
+        let task_queue = queue.clone();
+        let task_running = running.clone();
+        let task_stopped = stopped.clone();
+        let task = Task::new().name("adc sampler").stack_size(2048).start(move || {
+            let mut block = AdcSampleBlock { samples: [0u16; ADC_SAMPLE_BLOCK_LEN], len: 0, dropped: 0 };
+            let mut dropped = 0u32;
+            while task_running.load(Ordering::SeqCst) {
+                let mut bytes_read: usize = 0;
+                let result = unsafe {
+                    idf::i2s_read(ADC_I2S_PORT.into(), block.samples.as_mut_ptr() as *mut c_void, ADC_SAMPLE_BLOCK_LEN * 2, &mut bytes_read, Duration::ms(100).to_ticks())
+                };
+                if result != idf::ESP_OK || bytes_read == 0 {
+                    continue;
+                }
+                block.len = bytes_read / 2;
+                for sample in &mut block.samples[..block.len] {
+                    *sample &= ADC_I2S_SAMPLE_MASK;
+                }
+                block.dropped = dropped;
+                match task_queue.send(block, Duration::zero()) {
+                    Ok(_) => dropped = 0,
+                    Err(_) => dropped += 1,
+                }
+            }
+            let _ = task_stopped.send((), Duration::infinite());
+        });
+        if let Err(err) = task {
+            running.store(false, Ordering::SeqCst);
+            unsafe {
+                idf::i2s_adc_disable(ADC_I2S_PORT.into());
+                idf::i2s_driver_uninstall(ADC_I2S_PORT.into());
+            }
+            return Err(err.into());
+        }
+
+        let calibration = AdcCalibration::new(AdcUnit::Adc1, config.attenuation, config.width);
+        Ok(AdcContinuous { queue: queue, running: running, stopped: stopped, calibration: calibration })
+    }
+
+    // The queue receiving the sample blocks.
+    pub fn queue(&self) -> Arc<Queue<AdcSampleBlock>> { self.queue.clone() }
+    // Calibration to convert the samples to millivolts.
+    pub fn calibration(&self) -> &AdcCalibration { &self.calibration }
+}
+
+impl Drop for AdcContinuous {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        let _ = self.stopped.receive(Duration::infinite());
+        unsafe {
+            idf::i2s_adc_disable(ADC_I2S_PORT.into());
+            idf::i2s_driver_uninstall(ADC_I2S_PORT.into());
+        }
+        release_i2s_port(ADC_I2S_PORT);
+        ADC_UNITS_IN_USE[adc_unit_index(AdcUnit::Adc1)].store(false, Ordering::SeqCst);
+    }
+}
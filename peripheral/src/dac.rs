@@ -0,0 +1,356 @@
+use core::convert::Into;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+use idf::IdfError;
+
+use freertos_rs::*;
+
+use crate::gpio::*;
+use crate::i2s::{I2sPort, claim_i2s_port, release_i2s_port};
+
+#[derive(Copy, Clone, Debug)]
+pub enum DacError {
+    Generic,
+    IdfError(IdfError),
+    // Only GPIO25 (DAC1) and GPIO26 (DAC2) have a DAC.
+    InvalidPin,
+    // The DAC channel is already in use by another driver.
+    Busy,
+    // The frequency is out of the range of the cosine generator.
+    InvalidFrequency,
+}
+
+impl From<IdfError> for DacError {
+    fn from(err: IdfError) -> DacError {
+        DacError::IdfError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DacChannel {
+    // GPIO25
+    Channel1,
+    // GPIO26
+    Channel2,
+}
+
+impl Into<idf::dac_channel_t> for DacChannel {
+    fn into(self) -> idf::dac_channel_t {
+        match self {
+            DacChannel::Channel1 => idf::dac_channel_t_DAC_CHANNEL_1,
+            DacChannel::Channel2 => idf::dac_channel_t_DAC_CHANNEL_2,
+        }
+    }
+}
+
+impl DacChannel {
+    pub fn from_pin(pin: GpioPin) -> Option<DacChannel> {
+        match pin.number() {
+            25 => Some(DacChannel::Channel1),
+            26 => Some(DacChannel::Channel2),
+            _ => None,
+        }
+    }
+    pub fn pin(&self) -> GpioPin {
+        match self {
+            DacChannel::Channel1 => GpioPin25,
+            DacChannel::Channel2 => GpioPin26,
+        }
+    }
+    fn mask(&self) -> u8 {
+        match self {
+            DacChannel::Channel1 => 1,
+            DacChannel::Channel2 => 2,
+        }
+    }
+}
+
+static DAC_CHANNELS_IN_USE: AtomicU8 = AtomicU8::new(0);
+
+fn claim_channels(mask: u8) -> Result<(), DacError> {
+    let previous = DAC_CHANNELS_IN_USE.fetch_or(mask, Ordering::SeqCst);
+    if previous & mask != 0 {
+        // Keep the channels which were claimed before.
+        DAC_CHANNELS_IN_USE.fetch_and(!(mask & !previous), Ordering::SeqCst);
+        return Err(DacError::Busy);
+    }
+    Ok(())
+}
+fn release_channels(mask: u8) {
+    DAC_CHANNELS_IN_USE.fetch_and(!mask, Ordering::SeqCst);
+}
+
+// Registers of the cosine generator, which the IDF driver does not cover.
+const SENS_SAR_DAC_CTRL1_REG: usize = 0x3ff48898;
+const SENS_SAR_DAC_CTRL2_REG: usize = 0x3ff4889c;
+const RTC_CNTL_CLK_CONF_REG: usize = 0x3ff48070;
+const SENS_SW_FSTEP_MASK: u32 = 0xffff;
+const SENS_SW_TONE_EN: u32 = 1 << 16;
+const SENS_DAC_DC_SHIFT: [u32; 2] = [0, 8];
+const SENS_DAC_SCALE_SHIFT: [u32; 2] = [16, 18];
+const SENS_DAC_INV_SHIFT: [u32; 2] = [20, 22];
+const SENS_DAC_CW_EN: [u32; 2] = [1 << 24, 1 << 25];
+const RTC_CNTL_CK8M_DIV_SEL_SHIFT: u32 = 12;
+const RTC_CNTL_CK8M_DIV_SEL_MASK: u32 = 0x7 << RTC_CNTL_CK8M_DIV_SEL_SHIFT;
+// Nominal frequency of RTC8M_CLK, which drives the cosine generator.
+const DAC_RTC8M_CLOCK_HZ: u32 = 8_500_000;
+
+unsafe fn modify_register(address: usize, mask: u32, value: u32) {
+    let register = address as *mut u32;
+    ptr::write_volatile(register, (ptr::read_volatile(register) & !mask) | (value & mask));
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DacCosineScale {
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+}
+impl Default for DacCosineScale {
+    fn default() -> Self { DacCosineScale::Full }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DacCosinePhase {
+    Deg0,
+    Deg180,
+}
+impl Default for DacCosinePhase {
+    fn default() -> Self { DacCosinePhase::Deg0 }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DacCosineConfig {
+    pub frequency_hz: u32,
+    pub scale: DacCosineScale,
+    pub phase: DacCosinePhase,
+    // DC offset added to the wave.
+    pub offset: i8,
+}
+
+// Frequency step and RTC8M_CLK divider closest to the frequency. About 16Hz to 130kHz, in steps of about 130Hz / (divider + 1).
+fn cosine_frequency_step(frequency_hz: u32) -> Option<(u32, u32)> {
+    let mut best: Option<(u32, u32, u64)> = None;
+    for divider in 0..8u32 {
+        let clock_hz = (DAC_RTC8M_CLOCK_HZ / (divider + 1)) as u64;
+        let step = ((frequency_hz as u64) * 65536 + clock_hz / 2) / clock_hz;
+        if step == 0 || step > SENS_SW_FSTEP_MASK as u64 {
+            continue;
+        }
+        let actual_hz = clock_hz * step / 65536;
+        let error = if actual_hz > frequency_hz as u64 { actual_hz - frequency_hz as u64 } else { frequency_hz as u64 - actual_hz };
+        if best.map_or(true, |(_, _, best_error)| error < best_error) {
+            best = Some((step as u32, divider, error));
+        }
+    }
+    best.map(|(step, divider, _)| (step, divider))
+}
+
+// A DAC channel driven by software or by the cosine generator.
+pub struct Dac {
+    channel: DacChannel,
+}
+
+impl Dac {
+    pub fn new(pin: GpioPin) -> Result<Dac, DacError> {
+        let channel = DacChannel::from_pin(pin).ok_or(DacError::InvalidPin)?;
+        claim_channels(channel.mask())?;
+        let dac = Dac { channel: channel };
+        unsafe { idf::dac_output_enable(channel.into()).as_result()?; }
+        Ok(dac)
+    }
+
+    pub fn channel(&self) -> DacChannel { self.channel }
+
+    fn index(&self) -> usize {
+        match self.channel {
+            DacChannel::Channel1 => 0,
+            DacChannel::Channel2 => 1,
+        }
+    }
+
+    // Output a voltage of about value / 256 * VDD.
+    pub fn set_output(&mut self, value: u8) -> Result<(), DacError> {
+        unsafe { idf::dac_output_voltage(self.channel.into(), value).as_result()?; }
+        Ok(())
+    }
+
+    // Output a cosine wave from the built-in generator until stop_cosine or set_output.
+    // The generator and its clock divider are shared by both channels; the last frequency set applies to both.
+    pub fn start_cosine(&mut self, config: DacCosineConfig) -> Result<(), DacError> {
+        let (step, divider) = cosine_frequency_step(config.frequency_hz).ok_or(DacError::InvalidFrequency)?;
+        let index = self.index();
+        let scale = match config.scale {
+            DacCosineScale::Full => 0,
+            DacCosineScale::Half => 1,
+            DacCosineScale::Quarter => 2,
+            DacCosineScale::Eighth => 3,
+        };
+        // Inverting the MSB centers the wave; inverting the other bits as well shifts it by 180 degrees.
+        let invert = match config.phase {
+            DacCosinePhase::Deg0 => 2,
+            DacCosinePhase::Deg180 => 3,
+        };
+        unsafe {
+            modify_register(RTC_CNTL_CLK_CONF_REG, RTC_CNTL_CK8M_DIV_SEL_MASK, divider << RTC_CNTL_CK8M_DIV_SEL_SHIFT);
+            modify_register(SENS_SAR_DAC_CTRL1_REG, SENS_SW_FSTEP_MASK | SENS_SW_TONE_EN, step | SENS_SW_TONE_EN);
+            modify_register(SENS_SAR_DAC_CTRL2_REG, 0xff << SENS_DAC_DC_SHIFT[index], (config.offset as u8 as u32) << SENS_DAC_DC_SHIFT[index]);
+            modify_register(SENS_SAR_DAC_CTRL2_REG, 0x3 << SENS_DAC_SCALE_SHIFT[index], scale << SENS_DAC_SCALE_SHIFT[index]);
+            modify_register(SENS_SAR_DAC_CTRL2_REG, 0x3 << SENS_DAC_INV_SHIFT[index], invert << SENS_DAC_INV_SHIFT[index]);
+            modify_register(SENS_SAR_DAC_CTRL2_REG, SENS_DAC_CW_EN[index], SENS_DAC_CW_EN[index]);
+        }
+        Ok(())
+    }
+
+    pub fn stop_cosine(&mut self) {
+        unsafe {
+            modify_register(SENS_SAR_DAC_CTRL2_REG, SENS_DAC_CW_EN[self.index()], 0);
+        }
+    }
+}
+
+impl Drop for Dac {
+    fn drop(&mut self) {
+        self.stop_cosine();
+        unsafe {
+            idf::dac_output_disable(self.channel.into());
+        }
+        release_channels(self.channel.mask());
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DacStreamChannels {
+    Channel1,
+    Channel2,
+    // Samples are interleaved as (DAC1, DAC2) pairs.
+    Both,
+}
+
+impl DacStreamChannels {
+    fn mask(&self) -> u8 {
+        match self {
+            DacStreamChannels::Channel1 => 1,
+            DacStreamChannels::Channel2 => 2,
+            DacStreamChannels::Both => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DacStreamConfig {
+    pub sample_rate: u32,
+    pub channels: DacStreamChannels,
+    pub dma_buffer_count: usize,
+    // Number of frames per DMA buffer.
+    pub dma_buffer_len: usize,
+}
+
+impl Default for DacStreamConfig {
+    fn default() -> Self {
+        DacStreamConfig {
+            sample_rate: 16000,
+            channels: DacStreamChannels::Channel1,
+            dma_buffer_count: 4,
+            dma_buffer_len: 256,
+        }
+    }
+}
+
+const DAC_I2S_PORT: I2sPort = I2sPort::Port0;
+const DAC_STREAM_CHUNK_FRAMES: usize = 64;
+
+// 8bit samples streamed to the DAC by the built-in I2S-DAC DMA. Occupies I2S0.
+pub struct DacStream {
+    channels: DacStreamChannels,
+}
+
+impl DacStream {
+    pub fn new(config: DacStreamConfig) -> Result<DacStream, DacError> {
+        claim_channels(config.channels.mask())?;
+        if !claim_i2s_port(DAC_I2S_PORT) {
+            release_channels(config.channels.mask());
+            return Err(DacError::Busy);
+        }
+        let stream = DacStream { channels: config.channels };
+
+        let mut i2s_config = idf::i2s_config_t::default();
+        i2s_config.mode = idf::i2s_mode_t_I2S_MODE_MASTER | idf::i2s_mode_t_I2S_MODE_TX | idf::i2s_mode_t_I2S_MODE_DAC_BUILT_IN;
+        i2s_config.sample_rate = config.sample_rate as i32;
+        i2s_config.bits_per_sample = idf::i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_16BIT;
+        i2s_config.channel_format = idf::i2s_channel_fmt_t_I2S_CHANNEL_FMT_RIGHT_LEFT;
+        i2s_config.communication_format = idf::i2s_comm_format_t_I2S_COMM_FORMAT_I2S_MSB;
+        i2s_config.dma_buf_count = config.dma_buffer_count as i32;
+        i2s_config.dma_buf_len = config.dma_buffer_len as i32;
+        // Output silence instead of repeating the last buffer on underflow.
+        i2s_config.tx_desc_auto_clear = true;
+        let dac_mode = match config.channels {
+            DacStreamChannels::Channel1 => idf::i2s_dac_mode_t_I2S_DAC_CHANNEL_RIGHT_EN,
+            DacStreamChannels::Channel2 => idf::i2s_dac_mode_t_I2S_DAC_CHANNEL_LEFT_EN,
+            DacStreamChannels::Both => idf::i2s_dac_mode_t_I2S_DAC_CHANNEL_BOTH_EN,
+        };
+        unsafe {
+            if let Err(err) = idf::i2s_driver_install(DAC_I2S_PORT.into(), &i2s_config, 0, ptr::null_mut()).as_result() {
+                release_i2s_port(DAC_I2S_PORT);
+                release_channels(config.channels.mask());
+                return Err(err.into());
+            }
+            // From here, dropping the stream uninstalls the driver.
+            idf::i2s_set_dac_mode(dac_mode).as_result()?;
+        }
+        Ok(stream)
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), DacError> {
+        unsafe { idf::i2s_set_sample_rates(DAC_I2S_PORT.into(), sample_rate).as_result()?; }
+        Ok(())
+    }
+
+    // Queue 8bit samples to the DMA buffers. Returns the number of samples written, which is less than the length on timeout.
+    pub fn write(&mut self, samples: &[u8], wait_ticks: Duration) -> Result<usize, DacError> {
+        let samples_per_frame = if self.channels == DacStreamChannels::Both { 2 } else { 1 };
+        // The DAC takes the upper byte of each 16bit slot. DAC1 (right channel) is the first slot of each frame.
+        let mut frames = [0u16; DAC_STREAM_CHUNK_FRAMES * 2];
+        let mut written = 0;
+        for chunk in samples.chunks(DAC_STREAM_CHUNK_FRAMES * samples_per_frame) {
+            let frame_count = chunk.len() / samples_per_frame;
+            for frame in 0..frame_count {
+                let (dac1, dac2) = if samples_per_frame == 2 { (chunk[frame * 2], chunk[frame * 2 + 1]) } else { (chunk[frame], chunk[frame]) };
+                frames[frame * 2] = (dac1 as u16) << 8;
+                frames[frame * 2 + 1] = (dac2 as u16) << 8;
+            }
+            let bytes = frame_count * 4;
+            let mut bytes_written: usize = 0;
+            unsafe {
+                idf::i2s_write(DAC_I2S_PORT.into(), frames.as_ptr() as *const c_void, bytes, &mut bytes_written, wait_ticks.to_ticks()).as_result()?;
+            }
+            written += bytes_written / 4 * samples_per_frame;
+            if bytes_written < bytes {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    // Discard the queued samples and output silence.
+    pub fn clear(&mut self) -> Result<(), DacError> {
+        unsafe { idf::i2s_zero_dma_buffer(DAC_I2S_PORT.into()).as_result()?; }
+        Ok(())
+    }
+}
+
+impl Drop for DacStream {
+    fn drop(&mut self) {
+        unsafe {
+            idf::i2s_set_dac_mode(idf::i2s_dac_mode_t_I2S_DAC_CHANNEL_DISABLE);
+            idf::i2s_driver_uninstall(DAC_I2S_PORT.into());
+        }
+        release_i2s_port(DAC_I2S_PORT);
+        release_channels(self.channels.mask());
+    }
+}
@@ -120,7 +120,7 @@ impl GpioConfig {
     pub fn inputPullUp() -> GpioConfig { GpioConfig {mode: GpioMode::Input, pullup: GpioPullUp::Enable, ..Default::default() } }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct GpioPin {
     number: u32,
 }
@@ -128,6 +128,7 @@ pub struct GpioPin {
 impl GpioPin {
     pub fn number(&self) -> u32 { self.number }
     pub fn normal(&self) -> NormalGpio { NormalGpio::new(self.number) }
+    pub fn direct_output(&self) -> DirectOutputPin { DirectOutputPin::new(self.number) }
 }
 
 pub const GpioPin0  : GpioPin = GpioPin { number:  0 };
@@ -230,3 +231,49 @@ impl InputPin for NormalGpio {
         self.get_level().map(|v| { !v })
     }
 }
+
+const GPIO_OUT_W1TS_REG: usize  = 0x3ff4_4008;
+const GPIO_OUT_W1TC_REG: usize  = 0x3ff4_400c;
+const GPIO_OUT1_W1TS_REG: usize = 0x3ff4_4014;
+const GPIO_OUT1_W1TC_REG: usize = 0x3ff4_4018;
+
+// Output pin which is driven by writing the GPIO set/clear registers directly.
+// It does not go through the GPIO driver, so it can be used in ISR context (e.g. SPI transaction callbacks).
+// The pin must be configured as an output with NormalGpio::configure beforehand.
+#[derive(Copy, Clone, Debug)]
+pub struct DirectOutputPin {
+    number: u32,
+}
+
+impl DirectOutputPin {
+    pub fn new(number: u32) -> DirectOutputPin {
+        // GPIO34-39 are input only.
+        assert!(number < 34);
+        DirectOutputPin{number: number,}
+    }
+
+    pub fn number(&self) -> u32 { self.number }
+
+    pub fn set_level(&self, level_high: bool) {
+        let (register, bit) = match (self.number < 32, level_high) {
+            (true, true)   => (GPIO_OUT_W1TS_REG, self.number),
+            (true, false)  => (GPIO_OUT_W1TC_REG, self.number),
+            (false, true)  => (GPIO_OUT1_W1TS_REG, self.number - 32),
+            (false, false) => (GPIO_OUT1_W1TC_REG, self.number - 32),
+        };
+        unsafe { core::ptr::write_volatile(register as *mut u32, 1u32 << bit) }
+    }
+}
+
+impl OutputPin for DirectOutputPin {
+    type Error = ();
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.set_level(false);
+        Ok(())
+    }
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.set_level(true);
+        Ok(())
+    }
+}
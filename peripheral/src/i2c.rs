@@ -4,6 +4,12 @@
 use core::convert::Into;
 use core::marker::{Sync, PhantomData};
 use core::mem::zeroed;
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+extern crate alloc;
+use alloc::vec::Vec;
 
 use idf;
 use idf::AsResult;
@@ -19,6 +25,14 @@ pub enum I2cError {
     Generic,
     IdfError(IdfError),
     FreeRtosError(FreeRtosError),
+    // The addressed device (or a data byte) was not acknowledged.
+    Nack,
+    // The transaction did not complete in time.
+    Timeout,
+    // Another controller won the arbitration.
+    ArbitrationLost,
+    // SDA or SCL is held low by a device.
+    BusBusy,
 }
 
 impl From<IdfError> for I2cError {
@@ -47,9 +61,32 @@ impl Into<idf::i2c_port_t> for I2cPortNumber {
     }
 }
 
+// Addresses 0x00-0x07 and 0x78-0x7f are reserved by the I2C specification.
+const I2C_SCAN_FIRST_ADDRESS: u8 = 0x08;
+const I2C_SCAN_LAST_ADDRESS: u8 = 0x77;
+const I2C_PROBE_TIMEOUT_MS: u32 = 10;
+
+// Used to derive transaction timeouts before the clock speed is configured.
+const I2C_DEFAULT_CLOCK_SPEED: u32 = 100000;
+// Half period of the clock generated during bus recovery (about 100kHz).
+const I2C_RECOVERY_HALF_PERIOD_US: u32 = 5;
+
+// The driver clears the interrupt and resets the controller before i2c_master_cmd_begin returns, so lost arbitration
+// is caught by a second handler on the shared interrupt, which runs before the handler of the driver.
+const ESP_INTR_FLAG_SHARED: i32 = 1 << 8;
+const I2C_INTR_SOURCE: [i32; 2] = [49, 50];
+// I2C_INT_STATUS_REG of each controller and its ARBITRATION_LOST bit.
+const I2C_INT_STATUS_REG: [u32; 2] = [0x3ff5_302c, 0x3ff6_702c];
+const I2C_ARBITRATION_LOST_INT_ST: u32 = 1 << 5;
+
+static I2C_ARBITRATION_LOST: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
 pub struct I2cPortImpl {
     port_number: I2cPortNumber,
     config: I2cConfig,
+    timeout: Option<freertos_rs::Duration>,
+    auto_recovery: bool,
+    arbitration_isr: idf::intr_handle_t,
 }
 
 unsafe impl Sync for I2cPort {}
@@ -119,21 +156,35 @@ impl Into<idf::i2c_config_t> for I2cConfig {
     }
 }
 
+// u8 is a 7-bit address and u16 is a 10-bit address.
 pub trait I2cDeviceAddress {
+    // Address bytes sent after START to address the device for writing (SLA+W).
     fn fill_bytes(&self, bytes: &mut [u8]) -> usize;
+    // Address byte sent after a (repeated) START to address the device for reading (SLA+R).
+    // A 10-bit device must have been addressed for writing just before.
+    fn read_byte(&self) -> u8;
+    fn is_ten_bit(&self) -> bool { false }
 }
 impl I2cDeviceAddress for u8 {
     fn fill_bytes(&self, bytes: &mut [u8]) -> usize {
         bytes[0] = *self << 1;
         1
     }
+    fn read_byte(&self) -> u8 {
+        (*self << 1) | 1
+    }
 }
 impl I2cDeviceAddress for u16 {
+    // 11110 A9 A8 R/W, then A7-A0
     fn fill_bytes(&self, bytes: &mut [u8]) -> usize {
-        bytes[0] = (*self >> 7) as u8;
-        bytes[1] = (*self << 1) as u8;
+        bytes[0] = 0xf0 | ((*self >> 7) as u8 & 0x06);
+        bytes[1] = *self as u8;
         2
     }
+    fn read_byte(&self) -> u8 {
+        0xf1 | ((*self >> 7) as u8 & 0x06)
+    }
+    fn is_ten_bit(&self) -> bool { true }
 }
 
 
@@ -153,15 +204,39 @@ impl I2cPort {
         let guard = self.mutex.lock(wait_ticks)?;
         Ok(guard)
     }
+    // Handle which implements the embedded-hal I2C traits by locking this port for each transaction.
+    pub fn proxy<'a>(&'a self) -> I2cProxy<&'a I2cPort> {
+        I2cProxy::new(self)
+    }
+    pub fn probe(&self, address: u8) -> Result<bool, I2cError> {
+        self.lock(freertos_rs::Duration::infinite())?.probe(address)
+    }
+    pub fn scan(&self) -> Result<Vec<u8>, I2cError> {
+        self.lock(freertos_rs::Duration::infinite())?.scan()
+    }
 }
 impl I2cPortImpl {
     fn new_master(port_number: I2cPortNumber) -> Result<I2cPortImpl, I2cError> {
+        let index = port_number as usize;
         unsafe {
-            idf::i2c_driver_install(port_number as idf::i2c_port_t, I2cMode::Master as idf::i2c_mode_t, 0, 0, 0).as_result()?;
-            Ok( I2cPortImpl { port_number: port_number, config: core::mem::zeroed() } )
+            idf::i2c_driver_install(port_number as idf::i2c_port_t, I2cMode::Master as idf::i2c_mode_t, 0, 0, ESP_INTR_FLAG_SHARED).as_result()?;
+            let mut arbitration_isr: idf::intr_handle_t = ptr::null_mut();
+            let result = idf::esp_intr_alloc_intrstatus(I2C_INTR_SOURCE[index], ESP_INTR_FLAG_SHARED,
+                I2C_INT_STATUS_REG[index], I2C_ARBITRATION_LOST_INT_ST,
+                Some(I2cPortImpl::arbitration_lost_handler), index as *mut c_void, &mut arbitration_isr).as_result();
+            if let Err(err) = result {
+                idf::i2c_driver_delete(port_number as idf::i2c_port_t);
+                return Err(err.into());
+            }
+            Ok( I2cPortImpl { port_number: port_number, config: core::mem::zeroed(), timeout: None, auto_recovery: true, arbitration_isr: arbitration_isr } )
         }
     }
 
+    // Only called while ARBITRATION_LOST is pending. `arg` is the port index.
+    unsafe extern "C" fn arbitration_lost_handler(arg: *mut c_void) {
+        I2C_ARBITRATION_LOST[arg as usize].store(true, Ordering::SeqCst);
+    }
+
     pub fn config(&mut self, i2c_config: I2cConfig) -> Result<(), I2cError> {
         let idf_config = i2c_config.into();
         unsafe {
@@ -171,21 +246,148 @@ impl I2cPortImpl {
         }
     }
 
-    pub fn cmd_begin<'a>(&mut self, cmd_link: I2cCommandLink<'a>, wait_ticks: freertos_rs::Duration) -> Result<(), I2cError> {
+    // Timeout of the transactions issued through the embedded-hal traits.
+    // With None, the timeout is derived from the transfer length and the clock speed.
+    pub fn set_transaction_timeout(&mut self, timeout: Option<freertos_rs::Duration>) {
+        self.timeout = timeout;
+    }
+
+    // Hardware timeout of a single bus operation (e.g. clock stretching) in APB clock cycles (up to 0xfffff).
+    pub fn set_hardware_timeout(&mut self, apb_cycles: u32) -> Result<(), I2cError> {
         unsafe {
-            idf::i2c_master_cmd_begin(self.port_number as idf::i2c_port_t, cmd_link.handle, wait_ticks.to_ticks()).as_result()?;
+            idf::i2c_set_timeout(self.port_number as idf::i2c_port_t, apb_cycles as i32).as_result()?;
         }
         Ok(())
     }
 
+    // Recover the bus automatically when a transaction fails with BusBusy, then retry it once. Enabled by default.
+    pub fn set_auto_recovery(&mut self, enable: bool) {
+        self.auto_recovery = enable;
+    }
+
+    pub fn cmd_begin<'a>(&mut self, cmd_link: I2cCommandLink<'a>, wait_ticks: freertos_rs::Duration) -> Result<(), I2cError> {
+        match self.execute(&cmd_link, wait_ticks) {
+            Err(I2cError::BusBusy) if self.auto_recovery => {
+                self.recover_bus()?;
+                self.execute(&cmd_link, wait_ticks)
+            },
+            result => result,
+        }
+    }
+
+    fn execute<'a>(&mut self, cmd_link: &I2cCommandLink<'a>, wait_ticks: freertos_rs::Duration) -> Result<(), I2cError> {
+        I2C_ARBITRATION_LOST[self.port_number as usize].store(false, Ordering::SeqCst);
+        let result = unsafe {
+            idf::i2c_master_cmd_begin(self.port_number as idf::i2c_port_t, cmd_link.handle, wait_ticks.to_ticks()).as_result()
+        };
+        result.map_err(|err| self.classify_error(err))
+    }
+
+    fn classify_error(&self, err: IdfError) -> I2cError {
+        // Depending on the IDF version lost arbitration ends the transaction with ESP_FAIL or ESP_ERR_TIMEOUT.
+        if I2C_ARBITRATION_LOST[self.port_number as usize].load(Ordering::SeqCst) {
+            return I2cError::ArbitrationLost;
+        }
+        match err.code() {
+            idf::ESP_FAIL => I2cError::Nack,
+            idf::ESP_ERR_TIMEOUT => if self.bus_stuck() { I2cError::BusBusy } else { I2cError::Timeout },
+            _ => I2cError::IdfError(err),
+        }
+    }
+
+    fn bus_stuck(&self) -> bool {
+        if self.config.clk_speed == 0 {
+            return false;
+        }
+        unsafe {
+            idf::gpio_get_level(self.config.sda_io.number()) == 0 || idf::gpio_get_level(self.config.scl_io.number()) == 0
+        }
+    }
+
+    // Release a device which holds SDA low (e.g. after a reset in the middle of a read):
+    // clock SCL up to nine times until SDA is released, issue a STOP condition and give the pins back to the controller.
+    pub fn recover_bus(&mut self) -> Result<(), I2cError> {
+        if self.config.clk_speed == 0 {
+            return Err(I2cError::Generic);
+        }
+        let mut sda = self.config.sda_io.normal();
+        let mut scl = self.config.scl_io.normal();
+        let open_drain = GpioConfig { mode: GpioMode::InputOutputOpenDrain, pullup: self.config.sda_pullup_en, ..Default::default() };
+        sda.set_level(true)?;
+        scl.set_level(true)?;
+        sda.configure(open_drain)?;
+        scl.configure(GpioConfig { pullup: self.config.scl_pullup_en, ..open_drain })?;
+
+        let delay = || unsafe { idf::ets_delay_us(I2C_RECOVERY_HALF_PERIOD_US) };
+        for _ in 0..9 {
+            if sda.get_level()? {
+                break;
+            }
+            scl.set_level(false)?;
+            delay();
+            scl.set_level(true)?;
+            delay();
+        }
+        // STOP: SDA rises while SCL is high.
+        scl.set_level(false)?;
+        delay();
+        sda.set_level(false)?;
+        delay();
+        scl.set_level(true)?;
+        delay();
+        sda.set_level(true)?;
+        delay();
+        let released = sda.get_level()? && scl.get_level()?;
+
+        // Route the pins to the controller again.
+        let config = self.config;
+        self.config(config)?;
+        unsafe {
+            idf::i2c_reset_tx_fifo(self.port_number as idf::i2c_port_t).as_result()?;
+            idf::i2c_reset_rx_fifo(self.port_number as idf::i2c_port_t).as_result()?;
+        }
+        if released { Ok(()) } else { Err(I2cError::BusBusy) }
+    }
+
+    // Check whether a device acknowledges its 7-bit address, by sending only SLA+W.
+    pub fn probe(&mut self, address: u8) -> Result<bool, I2cError> {
+        let mut command = I2cCommandLink::new();
+        command.start()?;
+        command.write_byte((address << 1) | 0, true)?;
+        command.stop()?;
+        match self.cmd_begin(command, freertos_rs::Duration::ms(I2C_PROBE_TIMEOUT_MS)) {
+            Ok(_) => Ok(true),
+            Err(I2cError::Nack) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Probe every non-reserved 7-bit address and return the ones which acknowledged.
+    pub fn scan(&mut self) -> Result<Vec<u8>, I2cError> {
+        let mut found = Vec::new();
+        for address in I2C_SCAN_FIRST_ADDRESS..(I2C_SCAN_LAST_ADDRESS + 1) {
+            if self.probe(address)? {
+                found.push(address);
+            }
+        }
+        Ok(found)
+    }
+
     fn wait_ticks_from_len(&self, len: usize) -> freertos_rs::Duration {
-        let wait_ms = ((len as u32) + 8 + 2) * 1000 / self.config.clk_speed + 10;
+        if let Some(timeout) = self.timeout {
+            return timeout;
+        }
+        let clk_speed = if self.config.clk_speed == 0 { I2C_DEFAULT_CLOCK_SPEED } else { self.config.clk_speed };
+        // 9 clocks per byte including ACK, for the data and up to two address bytes, plus margin.
+        let bits = ((len as u64) + 2) * 9;
+        let wait_ms = (bits * 1000 / (clk_speed as u64)) as u32 + 10;
         freertos_rs::Duration::ms(wait_ms)
     }
 }
 impl Drop for I2cPortImpl {
     fn drop(&mut self) {
         unsafe {
+            idf::esp_intr_free(self.arbitration_isr);
             idf::i2c_driver_delete(self.port_number as idf::i2c_port_t);
         }
     }
@@ -240,16 +442,34 @@ impl<'cmdlink> I2cCommandLink<'cmdlink> {
         }
         Ok(self)
     }
-    pub fn write_register<DeviceAddress>(&mut self, device_address: DeviceAddress, register_address: &[u8], data: &'cmdlink [u8]) -> Result<&mut Self, I2cError>
+    // SLA+W
+    pub fn write_address<DeviceAddress>(&mut self, device_address: &DeviceAddress) -> Result<&mut Self, I2cError>
         where DeviceAddress : I2cDeviceAddress 
     {
-        self.start()?;
         let mut device_address_bytes: [u8; 2] = [0; 2];
         let device_address_length = device_address.fill_bytes(&mut device_address_bytes);
-        // SLA+W
         for i in 0..device_address_length {
             self.write_byte(device_address_bytes[i], true)?;
         }
+        Ok(self)
+    }
+    // SLA+R. `addressed` tells whether the device has just been addressed for writing before this (repeated) START.
+    // A 10-bit device which has not been addressed is addressed for writing first.
+    pub fn read_address<DeviceAddress>(&mut self, device_address: &DeviceAddress, addressed: bool) -> Result<&mut Self, I2cError>
+        where DeviceAddress : I2cDeviceAddress 
+    {
+        if device_address.is_ten_bit() && !addressed {
+            self.write_address(device_address)?;
+            self.start()?;
+        }
+        self.write_byte(device_address.read_byte(), true)?;
+        Ok(self)
+    }
+    pub fn write_register<DeviceAddress>(&mut self, device_address: DeviceAddress, register_address: &[u8], data: &'cmdlink [u8]) -> Result<&mut Self, I2cError>
+        where DeviceAddress : I2cDeviceAddress 
+    {
+        self.start()?;
+        self.write_address(&device_address)?;
         for register_address_byte in register_address {
             self.write_byte(*register_address_byte, true)?;
         }
@@ -261,21 +481,12 @@ impl<'cmdlink> I2cCommandLink<'cmdlink> {
         where DeviceAddress : I2cDeviceAddress 
     {
         self.start()?;
-        let mut device_address_bytes: [u8; 2] = [0; 2];
-        let device_address_length = device_address.fill_bytes(&mut device_address_bytes);
-        // SLA+W
-        for i in 0..device_address_length {
-            self.write_byte(device_address_bytes[i], true)?;
-        }
+        self.write_address(&device_address)?;
         for register_address_byte in register_address {
             self.write_byte(*register_address_byte, true)?;
         }
         self.start()?;  // Repeated start
-        // SLA+R
-        for i in 0..device_address_length {
-            let r_bit: u8 = if  i == device_address_length - 1 { 1 } else { 0 };
-            self.write_byte(device_address_bytes[i] | r_bit, true)?;
-        }
+        self.read_address(&device_address, true)?;
         self.read(data, I2cAckType::LastNack)?;
         self.stop()?;
         Ok(self)
@@ -289,105 +500,291 @@ impl<'cmdlink> Drop for I2cCommandLink<'cmdlink> {
     }
 }
 
-// Implement embedded-hal Blocking I2C API
-use embedded_hal::blocking::i2c::*;
+// Single read or write of a transaction. Consecutive operations of the same kind are merged without a repeated START.
+pub enum I2cOperation<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
 
-impl Read for I2cPortImpl {
-    type Error = I2cError;
+impl I2cPortImpl {
+    // Execute operations in one command link: START, then a repeated START with SLA+R/W whenever the direction changes, and STOP.
+    pub fn transaction<'a, A, I>(&mut self, address: A, operations: I) -> Result<(), I2cError>
+        where A: I2cDeviceAddress, I: IntoIterator<Item = I2cOperation<'a>>
+    {
+        let mut command = I2cCommandLink::new();
+        let mut operations = operations.into_iter().peekable();
+        let mut items_count: usize = 0;
+        let mut reading: Option<bool> = None;
+        while let Some(operation) = operations.next() {
+            let is_read = match operation { I2cOperation::Read(_) => true, I2cOperation::Write(_) => false };
+            if reading != Some(is_read) {
+                command.start()?;
+                if is_read {
+                    command.read_address(&address, reading == Some(false))?;
+                }
+                else {
+                    command.write_address(&address)?;
+                }
+                reading = Some(is_read);
+            }
+            match operation {
+                I2cOperation::Write(bytes) => {
+                    if !bytes.is_empty() {
+                        command.write(bytes, true)?;
+                    }
+                    items_count += bytes.len();
+                },
+                I2cOperation::Read(buffer) => {
+                    items_count += buffer.len();
+                    if !buffer.is_empty() {
+                        // Keep acknowledging if the next operation continues reading.
+                        let ack_type = match operations.peek() {
+                            Some(I2cOperation::Read(_)) => I2cAckType::Ack,
+                            _ => I2cAckType::LastNack,
+                        };
+                        command.read(buffer, ack_type)?;
+                    }
+                },
+            }
+        }
+        if reading.is_none() {
+            return Ok(());
+        }
+        command.stop()?;
+        let wait_ticks = self.wait_ticks_from_len(items_count);
+        self.cmd_begin(command, wait_ticks)
+    }
 
-    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
-        let wait_ticks = self.wait_ticks_from_len(buffer.len());
+    fn write_iter_read_inner<A, B>(&mut self, address: A, bytes: B, buffer: Option<&mut [u8]>) -> Result<(), I2cError>
+        where A: I2cDeviceAddress, B: IntoIterator<Item = u8>
+    {
+        let mut items_count: usize = 0;
         let mut command = I2cCommandLink::new();
         command.start()?;
-        command.write_byte((address << 1) | 1, true)?;
-        command.read(buffer, I2cAckType::LastNack)?;
+        command.write_address(&address)?;
+        
+        for byte in bytes {
+            command.write_byte(byte, true)?;
+            items_count += 1;
+        }
+
+        if let Some(buffer) = buffer {
+            items_count += buffer.len();
+            command.start()?;
+            command.read_address(&address, true)?;
+            command.read(buffer, I2cAckType::LastNack)?;
+        }
+
         command.stop()?;
-        self.cmd_begin(command, wait_ticks)?;
-        Ok(())
+        let wait_ticks = self.wait_ticks_from_len(items_count);
+        self.cmd_begin(command, wait_ticks)
     }
 }
 
-impl Write for I2cPortImpl {
+// Implement embedded-hal Blocking I2C API
+use embedded_hal::blocking::i2c::*;
+use core::iter::once;
+
+impl<A> Read<A> for I2cPortImpl where A: AddressMode + I2cDeviceAddress {
     type Error = I2cError;
 
-    fn write(&mut self, address: u8, buffer: &[u8]) -> Result<(), Self::Error> {
-        let wait_ticks = self.wait_ticks_from_len(buffer.len());
-        let mut command = I2cCommandLink::new();
-        command.start()?;
-        command.write_byte((address << 1) | 0, true)?;
-        command.write(buffer, true)?;
-        command.stop()?;
-        self.cmd_begin(command, wait_ticks)?;
-        Ok(())
+    fn read(&mut self, address: A, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.transaction(address, once(I2cOperation::Read(buffer)))
     }
 }
 
+impl<A> Write<A> for I2cPortImpl where A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
 
-impl WriteIter for I2cPortImpl {
+    fn write(&mut self, address: A, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.transaction(address, once(I2cOperation::Write(buffer)))
+    }
+}
+
+impl<A> WriteIter<A> for I2cPortImpl where A: AddressMode + I2cDeviceAddress {
     type Error = I2cError;
 
-    fn write<B>(&mut self, address: u8, bytes: B) -> Result<(), Self::Error>
+    fn write<B>(&mut self, address: A, bytes: B) -> Result<(), Self::Error>
         where B: IntoIterator<Item = u8>
     {
-        let mut items_count: usize = 0;
-        let mut command = I2cCommandLink::new();
-        command.start()?;
-        command.write_byte((address << 1) | 0, true)?;
-        
-        for byte in bytes {
-            command.write_byte(byte, true)?;
-            items_count += 1;
+        self.write_iter_read_inner(address, bytes, None)
+    }
+}
+
+impl<A> WriteRead<A> for I2cPortImpl where A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn write_read(&mut self, address: A, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.transaction(address, once(I2cOperation::Write(bytes)).chain(once(I2cOperation::Read(buffer))))
+    }
+}
+
+impl<A> WriteIterRead<A> for I2cPortImpl where A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn write_iter_read<B>(&mut self, address: A, bytes: B, buffer: &mut [u8]) -> Result<(), Self::Error>
+        where B: IntoIterator<Item = u8>
+    {
+        self.write_iter_read_inner(address, bytes, Some(buffer))
+    }
+}
+
+impl<A> Transactional<A> for I2cPortImpl where A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn exec<'a>(&mut self, address: A, operations: &mut [Operation<'a>]) -> Result<(), Self::Error> {
+        self.transaction(address, operations.iter_mut().map(|operation| match operation {
+            Operation::Read(buffer) => I2cOperation::Read(&mut **buffer),
+            Operation::Write(bytes) => I2cOperation::Write(*bytes),
+        }))
+    }
+}
+
+impl<A> TransactionalIter<A> for I2cPortImpl where A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn exec_iter<'a, O>(&mut self, address: A, operations: O) -> Result<(), Self::Error>
+        where O: IntoIterator<Item = Operation<'a>>
+    {
+        self.transaction(address, operations.into_iter().map(|operation| match operation {
+            Operation::Read(buffer) => I2cOperation::Read(buffer),
+            Operation::Write(bytes) => I2cOperation::Write(bytes),
+        }))
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::i2c::Error for I2cError {
+    fn kind(&self) -> embedded_hal_1::i2c::ErrorKind {
+        use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource};
+        match self {
+            I2cError::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            I2cError::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            I2cError::BusBusy => ErrorKind::Bus,
+            _ => ErrorKind::Other,
         }
+    }
+}
 
-        command.stop()?;
-        self.cmd_begin(command, self.wait_ticks_from_len(items_count))?;
-        Ok(())
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::i2c::ErrorType for I2cPortImpl {
+    type Error = I2cError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<A> embedded_hal_1::i2c::I2c<A> for I2cPortImpl where A: embedded_hal_1::i2c::AddressMode + I2cDeviceAddress {
+    fn transaction(&mut self, address: A, operations: &mut [embedded_hal_1::i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        I2cPortImpl::transaction(self, address, operations.iter_mut().map(|operation| match operation {
+            embedded_hal_1::i2c::Operation::Read(buffer) => I2cOperation::Read(&mut **buffer),
+            embedded_hal_1::i2c::Operation::Write(bytes) => I2cOperation::Write(*bytes),
+        }))
+    }
+}
+
+
+// Shared handle to an I2cPort for drivers which take ownership of their bus.
+// Each embedded-hal call locks the port only for its own transaction, so drivers owning proxies
+// to the same port can run in different tasks. `TPort` is e.g. `&I2cPort` or `Arc<I2cPort>`.
+pub struct I2cProxy<TPort> {
+    port: TPort,
+    wait_ticks: freertos_rs::Duration,
+}
+
+impl<TPort> I2cProxy<TPort> where TPort: Deref<Target = I2cPort> {
+    pub fn new(port: TPort) -> I2cProxy<TPort> {
+        I2cProxy { port: port, wait_ticks: freertos_rs::Duration::infinite() }
+    }
+    // Maximum time to wait for other users of the port.
+    pub fn with_lock_timeout(mut self, wait_ticks: freertos_rs::Duration) -> I2cProxy<TPort> {
+        self.wait_ticks = wait_ticks;
+        self
     }
 }
 
+impl<TPort> Clone for I2cProxy<TPort> where TPort: Deref<Target = I2cPort> + Clone {
+    fn clone(&self) -> Self {
+        I2cProxy { port: self.port.clone(), wait_ticks: self.wait_ticks }
+    }
+}
 
-impl WriteRead for I2cPortImpl {
+impl<TPort, A> Read<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: AddressMode + I2cDeviceAddress {
     type Error = I2cError;
 
-    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
-        let wait_ticks = self.wait_ticks_from_len(buffer.len() + bytes.len());
-        let mut command = I2cCommandLink::new();
-        command.start()?;
-        command.write_byte((address << 1) | 0, true)?;
-        command.write(bytes, true)?;
-        command.start()?;
-        command.write_byte((address << 1) | 1, true)?;
-        command.read(buffer, I2cAckType::LastNack)?;
-        command.stop()?;
-        self.cmd_begin(command, wait_ticks)?;
-        Ok(())
+    fn read(&mut self, address: A, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let mut port = self.port.lock(self.wait_ticks)?;
+        Read::read(&mut *port, address, buffer)
     }
 }
 
+impl<TPort, A> Write<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn write(&mut self, address: A, buffer: &[u8]) -> Result<(), Self::Error> {
+        let mut port = self.port.lock(self.wait_ticks)?;
+        Write::write(&mut *port, address, buffer)
+    }
+}
 
-impl WriteIterRead for I2cPortImpl {
+impl<TPort, A> WriteIter<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: AddressMode + I2cDeviceAddress {
     type Error = I2cError;
 
-    fn write_iter_read<B>(&mut self, address: u8, bytes: B, buffer: &mut [u8]) -> Result<(), Self::Error>
+    fn write<B>(&mut self, address: A, bytes: B) -> Result<(), Self::Error>
         where B: IntoIterator<Item = u8>
     {
-        let mut items_count = buffer.len();
-        let mut command = I2cCommandLink::new();
-        command.start()?;
-        command.write_byte((address << 1) | 0, true)?;
-        
-        for byte in bytes {
-            command.write_byte(byte, true)?;
-            items_count += 1;
-        }
+        let mut port = self.port.lock(self.wait_ticks)?;
+        WriteIter::write(&mut *port, address, bytes)
+    }
+}
 
-        command.start()?;
-        command.write_byte((address << 1) | 1, true)?;
-        command.read(buffer, I2cAckType::LastNack)?;
+impl<TPort, A> WriteRead<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
 
-        command.stop()?;
-        self.cmd_begin(command, self.wait_ticks_from_len(items_count))?;
-        Ok(())
+    fn write_read(&mut self, address: A, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let mut port = self.port.lock(self.wait_ticks)?;
+        WriteRead::write_read(&mut *port, address, bytes, buffer)
+    }
+}
+
+impl<TPort, A> WriteIterRead<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn write_iter_read<B>(&mut self, address: A, bytes: B, buffer: &mut [u8]) -> Result<(), Self::Error>
+        where B: IntoIterator<Item = u8>
+    {
+        let mut port = self.port.lock(self.wait_ticks)?;
+        WriteIterRead::write_iter_read(&mut *port, address, bytes, buffer)
+    }
+}
+
+impl<TPort, A> Transactional<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn exec<'a>(&mut self, address: A, operations: &mut [Operation<'a>]) -> Result<(), Self::Error> {
+        let mut port = self.port.lock(self.wait_ticks)?;
+        Transactional::exec(&mut *port, address, operations)
     }
 }
 
+impl<TPort, A> TransactionalIter<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: AddressMode + I2cDeviceAddress {
+    type Error = I2cError;
+
+    fn exec_iter<'a, O>(&mut self, address: A, operations: O) -> Result<(), Self::Error>
+        where O: IntoIterator<Item = Operation<'a>>
+    {
+        let mut port = self.port.lock(self.wait_ticks)?;
+        TransactionalIter::exec_iter(&mut *port, address, operations)
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<TPort> embedded_hal_1::i2c::ErrorType for I2cProxy<TPort> where TPort: Deref<Target = I2cPort> {
+    type Error = I2cError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<TPort, A> embedded_hal_1::i2c::I2c<A> for I2cProxy<TPort> where TPort: Deref<Target = I2cPort>, A: embedded_hal_1::i2c::AddressMode + I2cDeviceAddress {
+    fn transaction(&mut self, address: A, operations: &mut [embedded_hal_1::i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        let mut port = self.port.lock(self.wait_ticks)?;
+        embedded_hal_1::i2c::I2c::transaction(&mut *port, address, operations)
+    }
+}
@@ -0,0 +1,417 @@
+use core::ops::Range;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum I2cRegisterEndian {
+    Big,
+    Little,
+}
+
+// Bit field within a register value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct I2cBitField {
+    pub shift: u8,
+    pub width: u8,
+}
+
+impl I2cBitField {
+    pub const fn new(shift: u8, width: u8) -> I2cBitField {
+        I2cBitField { shift: shift, width: width }
+    }
+    pub fn mask(&self) -> u32 {
+        let bits = if self.width >= 32 { 0xffff_ffff } else { (1u32 << self.width) - 1 };
+        bits << self.shift
+    }
+    pub fn get(&self, raw: u32) -> u32 {
+        (raw & self.mask()) >> self.shift
+    }
+    pub fn set(&self, raw: u32, value: u32) -> u32 {
+        (raw & !self.mask()) | ((value << self.shift) & self.mask())
+    }
+}
+
+// Register of a device on the I2C bus. Usually declared with the i2c_register! macro.
+pub trait I2cRegister: Sized {
+    const ADDRESS: u8;
+    // Width of the register in bytes (1 to 4).
+    const WIDTH: usize;
+    const ENDIAN: I2cRegisterEndian;
+
+    fn from_raw(raw: u32) -> Self;
+    fn to_raw(&self) -> u32;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let raw = match Self::ENDIAN {
+            I2cRegisterEndian::Big => bytes[..Self::WIDTH].iter().fold(0u32, |raw, byte| (raw << 8) | (*byte as u32)),
+            I2cRegisterEndian::Little => bytes[..Self::WIDTH].iter().rev().fold(0u32, |raw, byte| (raw << 8) | (*byte as u32)),
+        };
+        Self::from_raw(raw)
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let raw = self.to_raw();
+        for index in 0..Self::WIDTH {
+            let shift = match Self::ENDIAN {
+                I2cRegisterEndian::Big => (Self::WIDTH - 1 - index)*8,
+                I2cRegisterEndian::Little => index*8,
+            };
+            bytes[index] = (raw >> shift) as u8;
+        }
+    }
+}
+
+// Declare a register type with bit field constants.
+//
+//     i2c_register!(PowerControl, address: 0x6b, width: 1, endian: Big, {
+//         SLEEP: 6, 1;
+//         CLOCK_SEL: 0, 3;
+//     });
+//     device.modify(|reg: PowerControl| reg.with(PowerControl::SLEEP, 0))?;
+#[macro_export]
+macro_rules! i2c_register {
+    ($name:ident, address: $address:expr, width: $width:expr, endian: $endian:ident, { $($field:ident : $shift:expr, $bits:expr;)* }) => {
+        #[derive(Copy, Clone, Debug, Default, PartialEq)]
+        pub struct $name(pub u32);
+
+        impl $crate::I2cRegister for $name {
+            const ADDRESS: u8 = $address;
+            const WIDTH: usize = $width;
+            const ENDIAN: $crate::I2cRegisterEndian = $crate::I2cRegisterEndian::$endian;
+            fn from_raw(raw: u32) -> Self { $name(raw) }
+            fn to_raw(&self) -> u32 { self.0 }
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            $( pub const $field: $crate::I2cBitField = $crate::I2cBitField::new($shift, $bits); )*
+
+            pub fn get(&self, field: $crate::I2cBitField) -> u32 { field.get(self.0) }
+            pub fn with(self, field: $crate::I2cBitField, value: u32) -> Self { $name(field.set(self.0, value)) }
+        }
+    };
+}
+
+// Maximum number of bytes transferred at once by the burst read and the register write.
+const I2C_REGISTER_BURST_SIZE: usize = 32;
+
+// Typed register access to a device with 8-bit register addresses.
+// Works on any embedded-hal blocking I2C bus such as I2cPortImpl.
+pub struct I2cRegisterDevice<TBus> {
+    bus: TBus,
+    address: u8,
+}
+
+impl<TBus, E> I2cRegisterDevice<TBus> where TBus: Write<Error = E> + WriteRead<Error = E> {
+    pub fn new(bus: TBus, address: u8) -> I2cRegisterDevice<TBus> {
+        I2cRegisterDevice { bus: bus, address: address }
+    }
+    pub fn address(&self) -> u8 { self.address }
+    pub fn bus(&mut self) -> &mut TBus { &mut self.bus }
+    pub fn release(self) -> TBus { self.bus }
+
+    pub fn read<R: I2cRegister>(&mut self) -> Result<R, E> {
+        let mut bytes = [0u8; 4];
+        self.bus.write_read(self.address, &[R::ADDRESS], &mut bytes[..R::WIDTH])?;
+        Ok(R::decode(&bytes))
+    }
+
+    pub fn write<R: I2cRegister>(&mut self, value: R) -> Result<(), E> {
+        let mut bytes = [0u8; 5];
+        bytes[0] = R::ADDRESS;
+        value.encode(&mut bytes[1..]);
+        self.bus.write(self.address, &bytes[..R::WIDTH + 1])
+    }
+
+    // Read-modify-write.
+    pub fn modify<R: I2cRegister, F>(&mut self, f: F) -> Result<(), E> where F: FnOnce(R) -> R {
+        let value = self.read::<R>()?;
+        self.write(f(value))
+    }
+
+    // Read consecutive registers of the same width starting at R::ADDRESS, e.g. the X/Y/Z axes of a sensor.
+    pub fn read_burst<R: I2cRegister>(&mut self, values: &mut [R]) -> Result<(), E> {
+        if R::WIDTH == 0 {
+            // Registers without data are read as 0 without a transfer.
+            for value in values.iter_mut() {
+                *value = R::decode(&[]);
+            }
+            return Ok(());
+        }
+        let mut bytes = [0u8; I2C_REGISTER_BURST_SIZE];
+        let per_chunk = I2C_REGISTER_BURST_SIZE / R::WIDTH;
+        for (chunk_index, chunk) in values.chunks_mut(per_chunk).enumerate() {
+            let register = R::ADDRESS.wrapping_add((chunk_index*per_chunk*R::WIDTH) as u8);
+            let length = chunk.len()*R::WIDTH;
+            self.bus.write_read(self.address, &[register], &mut bytes[..length])?;
+            for (index, value) in chunk.iter_mut().enumerate() {
+                *value = R::decode(&bytes[index*R::WIDTH..]);
+            }
+        }
+        Ok(())
+    }
+
+    // Read raw bytes from consecutive registers.
+    pub fn read_bytes(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), E> {
+        self.bus.write_read(self.address, &[register], buffer)
+    }
+
+    // Write raw bytes to consecutive registers. Longer data is split into writes of I2C_REGISTER_BURST_SIZE bytes.
+    // Without data only the register address is written.
+    pub fn write_bytes(&mut self, register: u8, data: &[u8]) -> Result<(), E> {
+        if data.is_empty() {
+            return self.bus.write(self.address, &[register]);
+        }
+        let mut bytes = [0u8; I2C_REGISTER_BURST_SIZE + 1];
+        for (chunk_index, chunk) in data.chunks(I2C_REGISTER_BURST_SIZE).enumerate() {
+            bytes[0] = register.wrapping_add((chunk_index*I2C_REGISTER_BURST_SIZE) as u8);
+            bytes[1..chunk.len() + 1].copy_from_slice(chunk);
+            self.bus.write(self.address, &bytes[..chunk.len() + 1])?;
+        }
+        Ok(())
+    }
+}
+
+// Emulates a register-based peripheral, e.g. on top of I2cSlave with I2cRegisterFile::process.
+// A write from the controller starts with the register address followed by values stored from that address.
+// After each write, the registers from the current address are queued so that a subsequent read returns them.
+// The register address auto-increments and wraps around at the end of the register file.
+pub struct I2cRegisterFile {
+    registers: Vec<u8>,
+    pointer: usize,
+}
+
+// Registers stored by one write from the controller.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct I2cRegisterWrite {
+    // Register address sent by the controller, modulo the size of the register file.
+    pub start: usize,
+    // Number of values, which may exceed the size of the register file if the controller kept writing.
+    pub count: usize,
+    size: usize,
+}
+
+impl I2cRegisterWrite {
+    // The registers written as up to two ranges of the register file.
+    // The second range is empty unless the write wrapped around to register 0.
+    pub fn ranges(&self) -> (Range<usize>, Range<usize>) {
+        let end = self.start + core::cmp::min(self.count, self.size);
+        if end <= self.size {
+            (self.start..end, 0..0)
+        }
+        else {
+            (self.start..self.size, 0..end - self.size)
+        }
+    }
+    pub fn contains(&self, register: usize) -> bool {
+        let (first, second) = self.ranges();
+        (first.start <= register && register < first.end) || (second.start <= register && register < second.end)
+    }
+}
+
+impl I2cRegisterFile {
+    pub fn new(size: usize) -> I2cRegisterFile {
+        assert!(size > 0 && size <= 256);
+        let mut registers = Vec::with_capacity(size);
+        registers.resize(size, 0);
+        I2cRegisterFile { registers: registers, pointer: 0 }
+    }
+
+    pub fn registers(&self) -> &[u8] { &self.registers }
+    pub fn registers_mut(&mut self) -> &mut [u8] { &mut self.registers }
+    pub fn pointer(&self) -> usize { self.pointer }
+
+    // Apply the bytes of one complete write from the controller.
+    // Returns None if there is no data, and a write with no values if only the address was sent.
+    pub fn apply_write(&mut self, data: &[u8]) -> Option<I2cRegisterWrite> {
+        let mut data = Some(data);
+        let result: Result<_, ()> = self.receive_write(|buffer, _first| {
+            let chunk = data.take().unwrap_or(&[]);
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        });
+        result.unwrap_or(None)
+    }
+
+    // Receive one write in chunks and apply it while it arrives, so that a write of any length can be handled.
+    // `read` fills the buffer and returns the number of bytes. It is called with `true` for the start of the write,
+    // where it may wait for the controller, and then with `false` until it returns 0 at the end of the write.
+    pub fn receive_write<E, F>(&mut self, mut read: F) -> Result<Option<I2cRegisterWrite>, E>
+        where F: FnMut(&mut [u8], bool) -> Result<usize, E>
+    {
+        let size = self.registers.len();
+        let mut buffer = [0u8; 64];
+        let mut written: Option<I2cRegisterWrite> = None;
+        loop {
+            let length = read(&mut buffer, written.is_none())?;
+            if length == 0 {
+                return Ok(written);
+            }
+            let mut values = &buffer[..length];
+            let write = match written {
+                Some(ref mut write) => write,
+                None => {
+                    let start = (values[0] as usize) % size;
+                    values = &values[1..];
+                    self.pointer = start;
+                    written = Some(I2cRegisterWrite { start: start, count: 0, size: size });
+                    written.as_mut().unwrap()
+                },
+            };
+            for value in values {
+                self.registers[self.pointer] = *value;
+                self.pointer = (self.pointer + 1) % size;
+            }
+            write.count += values.len();
+        }
+    }
+
+    // Registers read by the controller from the current address, wrapping around once.
+    pub fn read_data(&self, buffer: &mut [u8]) -> usize {
+        let size = self.registers.len();
+        let length = core::cmp::min(buffer.len(), size);
+        for offset in 0..length {
+            buffer[offset] = self.registers[(self.pointer + offset) % size];
+        }
+        length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn write_within_register_file() {
+        let mut file = I2cRegisterFile::new(8);
+        let write = file.apply_write(&[2, 0xa0, 0xa1, 0xa2]).unwrap();
+        assert_eq!(write.ranges(), (2..5, 0..0));
+        assert_eq!(file.registers(), &[0, 0, 0xa0, 0xa1, 0xa2, 0, 0, 0]);
+        assert_eq!(file.pointer(), 5);
+        assert_eq!(&file.registers()[write.ranges().0], &[0xa0, 0xa1, 0xa2]);
+    }
+
+    #[test]
+    fn write_wraps_around() {
+        let mut file = I2cRegisterFile::new(8);
+        let write = file.apply_write(&[6, 1, 2, 3, 4]).unwrap();
+        assert_eq!((write.start, write.count), (6, 4));
+        assert_eq!(write.ranges(), (6..8, 0..2));
+        assert!(write.contains(7) && write.contains(1) && !write.contains(2) && !write.contains(5));
+        assert_eq!(file.registers(), &[3, 4, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(file.pointer(), 2);
+
+        // The address is taken modulo the size, and a write longer than the file covers every register once.
+        let write = file.apply_write(&[9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19]).unwrap();
+        assert_eq!(write.count, 10);
+        assert_eq!(write.ranges(), (1..8, 0..1));
+        assert_eq!(file.registers(), &[17, 18, 19, 12, 13, 14, 15, 16]);
+        assert_eq!(file.pointer(), 3);
+    }
+
+    #[test]
+    fn address_only_write_moves_the_pointer() {
+        let mut file = I2cRegisterFile::new(4);
+        file.registers_mut().copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(file.apply_write(&[]), None);
+        let write = file.apply_write(&[3]).unwrap();
+        assert_eq!(write.ranges(), (3..3, 0..0));
+        let mut buffer = [0u8; 8];
+        assert_eq!(file.read_data(&mut buffer), 4);
+        assert_eq!(&buffer[..4], &[4, 1, 2, 3]);
+    }
+
+    #[test]
+    fn receive_write_stops_at_the_end_of_the_write() {
+        let mut file = I2cRegisterFile::new(16);
+        // Two writes queued back to back by the driver, each followed by the end of the write.
+        let mut chunks = vec![&[4u8, 1, 2][..], &[3][..], &[][..], &[10, 9][..], &[][..]].into_iter();
+        let mut firsts = Vec::new();
+        let mut read = |buffer: &mut [u8], first: bool| -> Result<usize, ()> {
+            firsts.push(first);
+            let chunk = chunks.next().unwrap();
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        };
+        let write = file.receive_write(&mut read).unwrap().unwrap();
+        assert_eq!(write.ranges(), (4..7, 0..0));
+        let write = file.receive_write(&mut read).unwrap().unwrap();
+        assert_eq!(write.ranges(), (10..11, 0..0));
+        assert_eq!(&file.registers()[4..11], &[1, 2, 3, 0, 0, 0, 9]);
+        assert_eq!(firsts, vec![true, false, false, true, false]);
+    }
+
+    // Bus which keeps 256 registers of one device, like a device with auto-incrementing register addresses.
+    struct RegisterBus {
+        registers: [u8; 256],
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl Write for RegisterBus {
+        type Error = ();
+        fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), ()> {
+            self.writes.push(bytes.to_vec());
+            for (offset, value) in bytes[1..].iter().enumerate() {
+                self.registers[(bytes[0] as usize + offset) % 256] = *value;
+            }
+            Ok(())
+        }
+    }
+
+    impl WriteRead for RegisterBus {
+        type Error = ();
+        fn write_read(&mut self, _address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            for (offset, value) in buffer.iter_mut().enumerate() {
+                *value = self.registers[(bytes[0] as usize + offset) % 256];
+            }
+            Ok(())
+        }
+    }
+
+    fn register_device() -> I2cRegisterDevice<RegisterBus> {
+        I2cRegisterDevice::new(RegisterBus { registers: [0; 256], writes: Vec::new() }, 0x68)
+    }
+
+    i2c_register!(Axis, address: 0x3b, width: 2, endian: Big, {});
+    i2c_register!(Marker, address: 0x10, width: 0, endian: Big, {});
+
+    #[test]
+    fn write_bytes_is_split_into_bursts() {
+        let mut device = register_device();
+        let data: Vec<u8> = (0..70).collect();
+        device.write_bytes(0xf0, &data).unwrap();
+        let lengths: Vec<usize> = device.bus().writes.iter().map(|write| write.len()).collect();
+        assert_eq!(lengths, vec![33, 33, 7]);
+        let registers: Vec<u8> = device.bus().writes.iter().map(|write| write[0]).collect();
+        assert_eq!(registers, vec![0xf0, 0x10, 0x30]);
+        let mut read = vec![0u8; 70];
+        device.read_bytes(0xf0, &mut read).unwrap();
+        assert_eq!(read, data);
+
+        device.write_bytes(0x20, &[]).unwrap();
+        assert_eq!(device.bus().writes.last().unwrap(), &vec![0x20]);
+    }
+
+    #[test]
+    fn read_burst_decodes_consecutive_registers() {
+        let mut device = register_device();
+        device.write_bytes(0x3b, &[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]).unwrap();
+        let mut axes = [Axis::default(); 3];
+        device.read_burst(&mut axes).unwrap();
+        assert_eq!(axes, [Axis(0x1234), Axis(0x5678), Axis(0x9abc)]);
+
+        let mut markers = [Marker(1); 2];
+        device.read_burst(&mut markers).unwrap();
+        assert_eq!(markers, [Marker(0); 2]);
+    }
+
+    #[test]
+    fn receive_write_without_data() {
+        let mut file = I2cRegisterFile::new(4);
+        assert_eq!(file.receive_write(|_, _| -> Result<usize, ()> { Ok(0) }), Ok(None));
+        assert_eq!(file.receive_write(|_, _| -> Result<usize, u8> { Err(7) }), Err(7));
+    }
+}
@@ -0,0 +1,101 @@
+use core::convert::Into;
+
+use idf;
+use idf::AsResult;
+
+use freertos_rs::*;
+
+use crate::i2c::*;
+use crate::i2c_register::*;
+
+// I2C port in slave mode.
+// Data written by the controller is stored in the RX ring buffer, and data to be read by the controller
+// must be queued into the TX ring buffer before it starts reading.
+pub struct I2cSlave {
+    port_number: I2cPortNumber,
+    config: I2cConfig,
+}
+
+unsafe impl Send for I2cSlave {}
+
+impl I2cSlave {
+    // `config.mode` must be I2cMode::Slave. `config.slave_addr` is a 7-bit address, or a 10-bit one if `config.addr_10bit_en` is set.
+    pub fn new(port_number: I2cPortNumber, config: I2cConfig, rx_buffer_len: usize, tx_buffer_len: usize) -> Result<I2cSlave, I2cError> {
+        let max_address = if config.addr_10bit_en { 0x3ff } else { 0x7f };
+        match config.mode {
+            I2cMode::Slave if config.slave_addr <= max_address => {},
+            _ => return Err(I2cError::Generic),
+        }
+        let idf_config: idf::i2c_config_t = config.into();
+        unsafe {
+            idf::i2c_param_config(port_number as idf::i2c_port_t, &idf_config).as_result()?;
+            idf::i2c_driver_install(port_number as idf::i2c_port_t, I2cMode::Slave as idf::i2c_mode_t, rx_buffer_len, tx_buffer_len, 0).as_result()?;
+        }
+        Ok(I2cSlave { port_number: port_number, config: config })
+    }
+
+    pub fn address(&self) -> u16 { self.config.slave_addr }
+
+    // Read the data written by the controller. Returns the number of bytes read, which may be zero on timeout.
+    pub fn read(&mut self, buffer: &mut [u8], wait_ticks: Duration) -> Result<usize, I2cError> {
+        let result = unsafe {
+            idf::i2c_slave_read_buffer(self.port_number as idf::i2c_port_t, buffer.as_mut_ptr(), buffer.len(), wait_ticks.to_ticks())
+        };
+        if result < 0 {
+            Err(I2cError::Generic)
+        }
+        else {
+            Ok(result as usize)
+        }
+    }
+
+    // Queue data to be read by the controller. Returns the number of bytes queued.
+    pub fn write(&mut self, buffer: &[u8], wait_ticks: Duration) -> Result<usize, I2cError> {
+        let result = unsafe {
+            idf::i2c_slave_write_buffer(self.port_number as idf::i2c_port_t, buffer.as_ptr() as *mut u8, buffer.len() as i32, wait_ticks.to_ticks())
+        };
+        if result < 0 {
+            Err(I2cError::Generic)
+        }
+        else {
+            Ok(result as usize)
+        }
+    }
+
+    pub fn reset_tx(&mut self) -> Result<(), I2cError> {
+        unsafe { idf::i2c_reset_tx_fifo(self.port_number as idf::i2c_port_t).as_result()?; }
+        Ok(())
+    }
+    pub fn reset_rx(&mut self) -> Result<(), I2cError> {
+        unsafe { idf::i2c_reset_rx_fifo(self.port_number as idf::i2c_port_t).as_result()?; }
+        Ok(())
+    }
+}
+
+impl Drop for I2cSlave {
+    fn drop(&mut self) {
+        unsafe {
+            idf::i2c_driver_delete(self.port_number as idf::i2c_port_t);
+        }
+    }
+}
+
+// A write ends when no more data arrives for this long, since the driver does not mark the STOP condition in its buffer.
+const I2C_REGISTER_WRITE_GAP_MS: u32 = 10;
+
+impl I2cRegisterFile {
+    // Wait for a write from the controller, apply it and queue the registers to be read next.
+    // Writes which follow each other within I2C_REGISTER_WRITE_GAP_MS are taken as one.
+    pub fn process(&mut self, slave: &mut I2cSlave, wait_ticks: Duration) -> Result<Option<I2cRegisterWrite>, I2cError> {
+        let written = self.receive_write(|buffer, first| {
+            slave.read(buffer, if first { wait_ticks } else { Duration::ms(I2C_REGISTER_WRITE_GAP_MS) })
+        })?;
+        if written.is_some() {
+            let mut tx = [0u8; 256];
+            let tx_length = self.read_data(&mut tx);
+            slave.reset_tx()?;
+            slave.write(&tx[..tx_length], Duration::zero())?;
+        }
+        Ok(written)
+    }
+}
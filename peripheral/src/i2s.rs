@@ -0,0 +1,396 @@
+use core::convert::Into;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+use idf::IdfError;
+
+use freertos_rs::*;
+
+use crate::gpio::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum I2sError {
+    Generic,
+    IdfError(IdfError),
+    FreeRtosError(FreeRtosError),
+    // The configuration is not supported by the port (PDM and the built-in DAC are I2S0 only).
+    InvalidConfig,
+    // The port is used by another driver.
+    Busy,
+}
+
+impl From<IdfError> for I2sError {
+    fn from(err: IdfError) -> I2sError {
+        I2sError::IdfError(err)
+    }
+}
+impl From<FreeRtosError> for I2sError {
+    fn from(err: FreeRtosError) -> I2sError {
+        I2sError::FreeRtosError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum I2sPort {
+    Port0,
+    Port1,
+}
+
+impl Into<idf::i2s_port_t> for I2sPort {
+    fn into(self) -> idf::i2s_port_t {
+        match self {
+            I2sPort::Port0 => idf::i2s_port_t_I2S_NUM_0,
+            I2sPort::Port1 => idf::i2s_port_t_I2S_NUM_1,
+        }
+    }
+}
+
+// Ports with an installed driver. I2s, AdcContinuous and DacStream claim the port before installing it.
+static I2S_PORTS_IN_USE: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+fn i2s_port_index(port: I2sPort) -> usize {
+    match port {
+        I2sPort::Port0 => 0,
+        I2sPort::Port1 => 1,
+    }
+}
+
+// Returns false if the port is already claimed.
+pub(crate) fn claim_i2s_port(port: I2sPort) -> bool {
+    I2S_PORTS_IN_USE[i2s_port_index(port)].compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+}
+pub(crate) fn release_i2s_port(port: I2sPort) {
+    I2S_PORTS_IN_USE[i2s_port_index(port)].store(false, Ordering::SeqCst);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum I2sDirection {
+    Tx,
+    Rx,
+    TxRx,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum I2sFormat {
+    // Philips I2S: data delayed by one BCK after WS changes.
+    Standard,
+    // Left justified: data starts with the WS change.
+    Msb,
+    // PCM with a one BCK wide frame sync.
+    PcmShort,
+    // PCM with a frame sync as long as the channel.
+    PcmLong,
+    // PDM microphone or speaker. The clock is output on the WS pin.
+    Pdm,
+    // Built-in DAC on GPIO25 (right channel) and GPIO26 (left channel). The upper byte of each 16bit sample is output.
+    BuiltInDac,
+}
+
+impl Default for I2sFormat {
+    fn default() -> Self { I2sFormat::Standard }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum I2sBits {
+    Bits8,
+    Bits16,
+    Bits24,
+    Bits32,
+}
+impl Default for I2sBits {
+    fn default() -> Self { I2sBits::Bits16 }
+}
+impl Into<idf::i2s_bits_per_sample_t> for I2sBits {
+    fn into(self) -> idf::i2s_bits_per_sample_t {
+        match self {
+            I2sBits::Bits8 => idf::i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_8BIT,
+            I2sBits::Bits16 => idf::i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_16BIT,
+            I2sBits::Bits24 => idf::i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_24BIT,
+            I2sBits::Bits32 => idf::i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_32BIT,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum I2sChannels {
+    // Both channels, interleaved as (right, left).
+    Stereo,
+    // Only the left channel is transferred.
+    OnlyLeft,
+    // Only the right channel is transferred.
+    OnlyRight,
+    // The same sample is output on both channels.
+    AllLeft,
+    AllRight,
+}
+impl Default for I2sChannels {
+    fn default() -> Self { I2sChannels::Stereo }
+}
+impl Into<idf::i2s_channel_fmt_t> for I2sChannels {
+    fn into(self) -> idf::i2s_channel_fmt_t {
+        match self {
+            I2sChannels::Stereo => idf::i2s_channel_fmt_t_I2S_CHANNEL_FMT_RIGHT_LEFT,
+            I2sChannels::OnlyLeft => idf::i2s_channel_fmt_t_I2S_CHANNEL_FMT_ONLY_LEFT,
+            I2sChannels::OnlyRight => idf::i2s_channel_fmt_t_I2S_CHANNEL_FMT_ONLY_RIGHT,
+            I2sChannels::AllLeft => idf::i2s_channel_fmt_t_I2S_CHANNEL_FMT_ALL_LEFT,
+            I2sChannels::AllRight => idf::i2s_channel_fmt_t_I2S_CHANNEL_FMT_ALL_RIGHT,
+        }
+    }
+}
+
+// Pins left as None are not routed. Not used by I2sFormat::BuiltInDac.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct I2sPins {
+    pub bck: Option<GpioPin>,
+    pub ws: Option<GpioPin>,
+    pub data_out: Option<GpioPin>,
+    pub data_in: Option<GpioPin>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct I2sConfig {
+    pub direction: I2sDirection,
+    pub format: I2sFormat,
+    pub sample_rate: u32,
+    pub bits: I2sBits,
+    pub channels: I2sChannels,
+    pub pins: I2sPins,
+    // DMA buffering: dma_buffer_count buffers of dma_buffer_len frames each.
+    // Larger buffers tolerate longer scheduling delays at the cost of latency.
+    pub dma_buffer_count: usize,
+    pub dma_buffer_len: usize,
+    // Use the audio PLL for accurate sample rates.
+    pub use_apll: bool,
+    // Output silence instead of repeating old data when TX underflows.
+    pub tx_auto_clear: bool,
+    // Length of the event queue. With 0, no events are reported.
+    pub event_queue_size: usize,
+}
+
+impl Default for I2sConfig {
+    fn default() -> Self {
+        I2sConfig {
+            direction: I2sDirection::Tx,
+            format: Default::default(),
+            sample_rate: 44100,
+            bits: Default::default(),
+            channels: Default::default(),
+            pins: Default::default(),
+            dma_buffer_count: 8,
+            dma_buffer_len: 64,
+            use_apll: false,
+            tx_auto_clear: true,
+            event_queue_size: 0,
+        }
+    }
+}
+
+impl Into<idf::i2s_config_t> for I2sConfig {
+    fn into(self) -> idf::i2s_config_t {
+        let mut config = idf::i2s_config_t::default();
+        config.mode = idf::i2s_mode_t_I2S_MODE_MASTER | match self.direction {
+            I2sDirection::Tx => idf::i2s_mode_t_I2S_MODE_TX,
+            I2sDirection::Rx => idf::i2s_mode_t_I2S_MODE_RX,
+            I2sDirection::TxRx => idf::i2s_mode_t_I2S_MODE_TX | idf::i2s_mode_t_I2S_MODE_RX,
+        };
+        config.communication_format = match self.format {
+            I2sFormat::Standard => idf::i2s_comm_format_t_I2S_COMM_FORMAT_I2S | idf::i2s_comm_format_t_I2S_COMM_FORMAT_I2S_MSB,
+            I2sFormat::Msb | I2sFormat::Pdm | I2sFormat::BuiltInDac => idf::i2s_comm_format_t_I2S_COMM_FORMAT_I2S_MSB,
+            I2sFormat::PcmShort => idf::i2s_comm_format_t_I2S_COMM_FORMAT_PCM | idf::i2s_comm_format_t_I2S_COMM_FORMAT_PCM_SHORT,
+            I2sFormat::PcmLong => idf::i2s_comm_format_t_I2S_COMM_FORMAT_PCM | idf::i2s_comm_format_t_I2S_COMM_FORMAT_PCM_LONG,
+        };
+        match self.format {
+            I2sFormat::Pdm => config.mode |= idf::i2s_mode_t_I2S_MODE_PDM,
+            I2sFormat::BuiltInDac => config.mode |= idf::i2s_mode_t_I2S_MODE_DAC_BUILT_IN,
+            _ => {},
+        }
+        config.sample_rate = self.sample_rate as i32;
+        config.bits_per_sample = self.bits.into();
+        config.channel_format = self.channels.into();
+        config.dma_buf_count = self.dma_buffer_count as i32;
+        config.dma_buf_len = self.dma_buffer_len as i32;
+        config.use_apll = self.use_apll;
+        config.tx_desc_auto_clear = self.tx_auto_clear;
+        config
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum I2sEvent {
+    DmaError,
+    // A TX DMA buffer has been sent; the size is in bytes.
+    TxDone(usize),
+    // An RX DMA buffer has been filled; the size is in bytes.
+    RxDone(usize),
+    Other,
+}
+
+impl From<idf::i2s_event_t> for I2sEvent {
+    fn from(event: idf::i2s_event_t) -> I2sEvent {
+        match event.type_ {
+            idf::i2s_event_type_t_I2S_EVENT_DMA_ERROR => I2sEvent::DmaError,
+            idf::i2s_event_type_t_I2S_EVENT_TX_DONE => I2sEvent::TxDone(event.size),
+            idf::i2s_event_type_t_I2S_EVENT_RX_DONE => I2sEvent::RxDone(event.size),
+            _ => I2sEvent::Other,
+        }
+    }
+}
+
+// Plain sample frames which can be transferred to and from the DMA buffers as they are.
+pub unsafe trait I2sFrame: Copy {}
+unsafe impl I2sFrame for u8 {}
+unsafe impl I2sFrame for i16 {}
+unsafe impl I2sFrame for u16 {}
+unsafe impl I2sFrame for i32 {}
+unsafe impl I2sFrame for u32 {}
+unsafe impl I2sFrame for [i16; 2] {}
+unsafe impl I2sFrame for [u16; 2] {}
+unsafe impl I2sFrame for [i32; 2] {}
+
+const I2S_PIN_NO_CHANGE: i32 = -1;
+
+pub struct I2s {
+    port: I2sPort,
+    format: I2sFormat,
+    event_queue: idf::QueueHandle_t,
+}
+
+unsafe impl Send for I2s {}
+
+impl I2s {
+    pub fn new(port: I2sPort, config: I2sConfig) -> Result<I2s, I2sError> {
+        let uses_port0_only = config.format == I2sFormat::Pdm || config.format == I2sFormat::BuiltInDac;
+        if uses_port0_only && port != I2sPort::Port0 {
+            return Err(I2sError::InvalidConfig);
+        }
+        if !claim_i2s_port(port) {
+            return Err(I2sError::Busy);
+        }
+        let idf_config: idf::i2s_config_t = config.into();
+        let mut event_queue: idf::QueueHandle_t = ptr::null_mut();
+        unsafe {
+            let event_queue_ptr = if config.event_queue_size > 0 { &mut event_queue as *mut idf::QueueHandle_t as *mut c_void } else { ptr::null_mut() };
+            if let Err(err) = idf::i2s_driver_install(port.into(), &idf_config, config.event_queue_size as i32, event_queue_ptr).as_result() {
+                release_i2s_port(port);
+                return Err(err.into());
+            }
+        }
+        // From here, dropping the driver uninstalls it and releases the port.
+        let i2s = I2s { port: port, format: config.format, event_queue: event_queue };
+        unsafe {
+            if config.format == I2sFormat::BuiltInDac {
+                idf::i2s_set_pin(port.into(), ptr::null()).as_result()?;
+                idf::i2s_set_dac_mode(idf::i2s_dac_mode_t_I2S_DAC_CHANNEL_BOTH_EN).as_result()?;
+            }
+            else {
+                let pin_number = |pin: Option<GpioPin>| pin.map_or(I2S_PIN_NO_CHANGE, |pin| pin.number() as i32);
+                let pins = idf::i2s_pin_config_t {
+                    bck_io_num: pin_number(config.pins.bck),
+                    ws_io_num: pin_number(config.pins.ws),
+                    data_out_num: pin_number(config.pins.data_out),
+                    data_in_num: pin_number(config.pins.data_in),
+                };
+                idf::i2s_set_pin(port.into(), &pins).as_result()?;
+            }
+        }
+        Ok(i2s)
+    }
+
+    // Change the sample rate, keeping the bit depth and channels.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), I2sError> {
+        unsafe { idf::i2s_set_sample_rates(self.port.into(), sample_rate).as_result()?; }
+        Ok(())
+    }
+    // Change the sample rate, bit depth and number of channels (1 or 2).
+    pub fn set_clock(&mut self, sample_rate: u32, bits: I2sBits, channel_count: u32) -> Result<(), I2sError> {
+        let channel = if channel_count == 1 { idf::i2s_channel_t_I2S_CHANNEL_MONO } else { idf::i2s_channel_t_I2S_CHANNEL_STEREO };
+        unsafe { idf::i2s_set_clk(self.port.into(), sample_rate, bits.into(), channel).as_result()?; }
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), I2sError> {
+        unsafe { idf::i2s_start(self.port.into()).as_result()?; }
+        Ok(())
+    }
+    pub fn stop(&mut self) -> Result<(), I2sError> {
+        unsafe { idf::i2s_stop(self.port.into()).as_result()?; }
+        Ok(())
+    }
+    // Fill the DMA buffers with silence.
+    pub fn clear(&mut self) -> Result<(), I2sError> {
+        unsafe { idf::i2s_zero_dma_buffer(self.port.into()).as_result()?; }
+        Ok(())
+    }
+
+    // Queue frames to the DMA buffers, waiting up to `wait_ticks` for free space.
+    // Returns the number of frames queued. With Duration::zero(), only the frames which fit are queued.
+    pub fn write<TFrame: I2sFrame>(&mut self, frames: &[TFrame], wait_ticks: Duration) -> Result<usize, I2sError> {
+        let frame_size = mem::size_of::<TFrame>();
+        let mut bytes_written: usize = 0;
+        let result = unsafe {
+            idf::i2s_write(self.port.into(), frames.as_ptr() as *const c_void, frames.len() * frame_size, &mut bytes_written, wait_ticks.to_ticks())
+        };
+        if result != idf::ESP_OK && bytes_written == 0 {
+            result.as_result()?;
+        }
+        Ok(bytes_written / frame_size)
+    }
+    // Write all the frames, blocking until they are queued.
+    pub fn write_all<TFrame: I2sFrame>(&mut self, frames: &[TFrame]) -> Result<(), I2sError> {
+        let mut offset = 0;
+        while offset < frames.len() {
+            offset += self.write(&frames[offset..], Duration::infinite())?;
+        }
+        Ok(())
+    }
+
+    // Read received frames, waiting up to `wait_ticks`. Returns the number of frames read.
+    pub fn read<TFrame: I2sFrame>(&mut self, frames: &mut [TFrame], wait_ticks: Duration) -> Result<usize, I2sError> {
+        let frame_size = mem::size_of::<TFrame>();
+        let mut bytes_read: usize = 0;
+        let result = unsafe {
+            idf::i2s_read(self.port.into(), frames.as_mut_ptr() as *mut c_void, frames.len() * frame_size, &mut bytes_read, wait_ticks.to_ticks())
+        };
+        if result != idf::ESP_OK && bytes_read == 0 {
+            result.as_result()?;
+        }
+        Ok(bytes_read / frame_size)
+    }
+    // Fill the buffer, blocking until enough frames are received.
+    pub fn read_exact<TFrame: I2sFrame>(&mut self, frames: &mut [TFrame]) -> Result<(), I2sError> {
+        let mut offset = 0;
+        while offset < frames.len() {
+            offset += self.read(&mut frames[offset..], Duration::infinite())?;
+        }
+        Ok(())
+    }
+
+    // Wait for the next DMA event. Requires I2sConfig::event_queue_size > 0.
+    pub fn receive_event(&mut self, wait_ticks: Duration) -> Result<I2sEvent, I2sError> {
+        if self.event_queue.is_null() {
+            return Err(I2sError::Generic);
+        }
+        let mut event = idf::i2s_event_t::default();
+        let received = unsafe { idf::xQueueGenericReceive(self.event_queue, &mut event as *mut idf::i2s_event_t as *mut c_void, wait_ticks.to_ticks(), 0) };
+        if received == 0 {
+            return Err(I2sError::FreeRtosError(FreeRtosError::QueueReceiveTimeout));
+        }
+        Ok(event.into())
+    }
+}
+
+impl Drop for I2s {
+    fn drop(&mut self) {
+        unsafe {
+            if self.format == I2sFormat::BuiltInDac {
+                idf::i2s_set_dac_mode(idf::i2s_dac_mode_t_I2S_DAC_CHANNEL_DISABLE);
+            }
+            idf::i2s_driver_uninstall(self.port.into());
+        }
+        release_i2s_port(self.port);
+    }
+}
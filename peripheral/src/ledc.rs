@@ -0,0 +1,378 @@
+use core::convert::Into;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use idf;
+use idf::AsResult;
+use idf::IdfError;
+
+use embedded_hal::{Pwm, PwmPin};
+
+use crate::gpio::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum LedcError {
+    Generic,
+    IdfError(IdfError),
+    // All four timers of the speed mode are in use.
+    NoTimerAvailable,
+    // All eight channels of the speed mode are in use.
+    NoChannelAvailable,
+    // The resolution cannot be used with the requested frequency.
+    InvalidResolution,
+    // The pin cannot drive an output (GPIO34-39 are input only).
+    InvalidPin,
+}
+
+impl From<IdfError> for LedcError {
+    fn from(err: IdfError) -> LedcError {
+        LedcError::IdfError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LedcSpeedMode {
+    HighSpeed,
+    LowSpeed,
+}
+impl Default for LedcSpeedMode {
+    fn default() -> Self { LedcSpeedMode::HighSpeed }
+}
+impl Into<idf::ledc_mode_t> for LedcSpeedMode {
+    fn into(self) -> idf::ledc_mode_t {
+        match self {
+            LedcSpeedMode::HighSpeed => idf::ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+            LedcSpeedMode::LowSpeed => idf::ledc_mode_t_LEDC_LOW_SPEED_MODE,
+        }
+    }
+}
+impl LedcSpeedMode {
+    fn index(&self) -> usize {
+        match self {
+            LedcSpeedMode::HighSpeed => 0,
+            LedcSpeedMode::LowSpeed => 1,
+        }
+    }
+}
+
+const LEDC_APB_CLOCK_HZ: u32 = 80_000_000;
+const LEDC_MAX_RESOLUTION_BITS: u32 = 20;
+const LEDC_TIMER_COUNT: u32 = 4;
+const LEDC_CHANNEL_COUNT: u32 = 8;
+
+// Timers and channels in use, one bit each, per speed mode.
+static LEDC_TIMERS_IN_USE: [AtomicU8; 2] = [AtomicU8::new(0), AtomicU8::new(0)];
+static LEDC_CHANNELS_IN_USE: [AtomicU8; 2] = [AtomicU8::new(0), AtomicU8::new(0)];
+static LEDC_FADE_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+fn allocate(in_use: &AtomicU8, count: u32) -> Option<u32> {
+    for index in 0..count {
+        let bit = 1u8 << index;
+        if in_use.fetch_or(bit, Ordering::SeqCst) & bit == 0 {
+            return Some(index);
+        }
+    }
+    None
+}
+fn release(in_use: &AtomicU8, index: u32) {
+    in_use.fetch_and(!(1u8 << index), Ordering::SeqCst);
+}
+
+// Highest duty resolution the APB clock allows at the frequency. e.g. 13 bits at 5kHz, 8 bits at 312.5kHz.
+pub fn ledc_max_resolution_bits(frequency_hz: u32) -> u32 {
+    if frequency_hz == 0 {
+        return LEDC_MAX_RESOLUTION_BITS;
+    }
+    let divider = LEDC_APB_CLOCK_HZ / frequency_hz;
+    if divider < 2 {
+        return 0;
+    }
+    let bits = 31 - divider.leading_zeros();
+    if bits > LEDC_MAX_RESOLUTION_BITS { LEDC_MAX_RESOLUTION_BITS } else { bits }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LedcTimerConfig {
+    pub speed_mode: LedcSpeedMode,
+    pub frequency_hz: u32,
+    // Duty resolution in bits (1-20). None selects the highest resolution for the frequency.
+    pub resolution_bits: Option<u32>,
+}
+
+impl Default for LedcTimerConfig {
+    fn default() -> Self {
+        LedcTimerConfig {
+            speed_mode: Default::default(),
+            frequency_hz: 5000,
+            resolution_bits: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hertz(pub u32);
+
+pub struct LedcTimerDriver {
+    speed_mode: LedcSpeedMode,
+    timer: u32,
+    frequency_hz: u32,
+    resolution_bits: u32,
+}
+
+impl LedcTimerDriver {
+    // Allocate a free timer of the speed mode and configure it.
+    pub fn new(config: LedcTimerConfig) -> Result<LedcTimerDriver, LedcError> {
+        let max_resolution_bits = ledc_max_resolution_bits(config.frequency_hz);
+        let resolution_bits = config.resolution_bits.unwrap_or(max_resolution_bits);
+        if resolution_bits == 0 || resolution_bits > max_resolution_bits {
+            return Err(LedcError::InvalidResolution);
+        }
+        let timer = allocate(&LEDC_TIMERS_IN_USE[config.speed_mode.index()], LEDC_TIMER_COUNT).ok_or(LedcError::NoTimerAvailable)?;
+        let mut driver = LedcTimerDriver { speed_mode: config.speed_mode, timer: timer, frequency_hz: 0, resolution_bits: 0 };
+        // On failure, dropping the driver releases the timer.
+        driver.configure(config.frequency_hz, resolution_bits)?;
+        Ok(driver)
+    }
+
+    fn configure(&mut self, frequency_hz: u32, resolution_bits: u32) -> Result<(), LedcError> {
+        let mut idf_config = idf::ledc_timer_config_t::default();
+        idf_config.speed_mode = self.speed_mode.into();
+        idf_config.timer_num = self.idf_timer();
+        idf_config.freq_hz = frequency_hz;
+        unsafe {
+            *idf_config.__bindgen_anon_1.duty_resolution.as_mut() = idf::ledc_timer_bit_t_LEDC_TIMER_1_BIT + (resolution_bits - 1);
+            idf::ledc_timer_config(&idf_config).as_result()?;
+        }
+        self.frequency_hz = frequency_hz;
+        self.resolution_bits = resolution_bits;
+        Ok(())
+    }
+
+    pub fn speed_mode(&self) -> LedcSpeedMode { self.speed_mode }
+    pub fn frequency_hz(&self) -> u32 { self.frequency_hz }
+    pub fn resolution_bits(&self) -> u32 { self.resolution_bits }
+    // Duty which keeps the output high for the whole period.
+    pub fn max_duty(&self) -> u32 { 1u32 << self.resolution_bits }
+
+    fn idf_timer(&self) -> idf::ledc_timer_t { idf::ledc_timer_t_LEDC_TIMER_0 + self.timer }
+
+    // Change the frequency, keeping the duty resolution.
+    pub fn set_frequency(&mut self, frequency_hz: u32) -> Result<(), LedcError> {
+        if self.resolution_bits > ledc_max_resolution_bits(frequency_hz) {
+            return Err(LedcError::InvalidResolution);
+        }
+        unsafe { idf::ledc_set_freq(self.speed_mode.into(), self.idf_timer(), frequency_hz).as_result()?; }
+        self.frequency_hz = frequency_hz;
+        Ok(())
+    }
+
+    pub fn pause(&mut self) -> Result<(), LedcError> {
+        unsafe { idf::ledc_timer_pause(self.speed_mode.into(), self.idf_timer()).as_result()?; }
+        Ok(())
+    }
+    pub fn resume(&mut self) -> Result<(), LedcError> {
+        unsafe { idf::ledc_timer_resume(self.speed_mode.into(), self.idf_timer()).as_result()?; }
+        Ok(())
+    }
+
+    // Allocate a free channel driven by this timer and route it to the pin. The output starts at duty 0.
+    pub fn channel(&self, pin: GpioPin) -> Result<LedcChannelDriver, LedcError> {
+        if pin.number() >= 34 {
+            return Err(LedcError::InvalidPin);
+        }
+        let channel = allocate(&LEDC_CHANNELS_IN_USE[self.speed_mode.index()], LEDC_CHANNEL_COUNT).ok_or(LedcError::NoChannelAvailable)?;
+        let driver = LedcChannelDriver { speed_mode: self.speed_mode, channel: channel, resolution_bits: self.resolution_bits, duty: 0, enabled: true };
+
+        let mut idf_config = idf::ledc_channel_config_t::default();
+        idf_config.gpio_num = pin.number() as i32;
+        idf_config.speed_mode = self.speed_mode.into();
+        idf_config.channel = driver.idf_channel();
+        idf_config.intr_type = idf::ledc_intr_type_t_LEDC_INTR_DISABLE;
+        idf_config.timer_sel = self.idf_timer();
+        idf_config.duty = 0;
+        idf_config.hpoint = 0;
+        unsafe { idf::ledc_channel_config(&idf_config).as_result()?; }
+        Ok(driver)
+    }
+}
+
+impl Drop for LedcTimerDriver {
+    fn drop(&mut self) {
+        unsafe {
+            idf::ledc_timer_pause(self.speed_mode.into(), self.idf_timer());
+        }
+        release(&LEDC_TIMERS_IN_USE[self.speed_mode.index()], self.timer);
+    }
+}
+
+// A PWM output. The channel keeps running while its timer is alive; dropping the timer stops it.
+pub struct LedcChannelDriver {
+    speed_mode: LedcSpeedMode,
+    channel: u32,
+    resolution_bits: u32,
+    duty: u32,
+    enabled: bool,
+}
+
+impl LedcChannelDriver {
+    fn idf_channel(&self) -> idf::ledc_channel_t { idf::ledc_channel_t_LEDC_CHANNEL_0 + self.channel }
+
+    // Duty which keeps the output high for the whole period.
+    pub fn max_duty(&self) -> u32 { 1u32 << self.resolution_bits }
+    pub fn duty(&self) -> u32 { self.duty }
+    pub fn is_enabled(&self) -> bool { self.enabled }
+
+    // Set the duty (0 to max_duty). A disabled channel keeps the duty until it is enabled.
+    pub fn set_duty(&mut self, duty: u32) -> Result<(), LedcError> {
+        let duty = if duty > self.max_duty() { self.max_duty() } else { duty };
+        self.duty = duty;
+        if self.enabled {
+            unsafe {
+                idf::ledc_set_duty(self.speed_mode.into(), self.idf_channel(), duty).as_result()?;
+                idf::ledc_update_duty(self.speed_mode.into(), self.idf_channel()).as_result()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fade from the current duty to `duty` in `time_ms` using the hardware fade.
+    // With `wait`, returns after the fade completes.
+    // The fade cannot end fully on, so max_duty() fades to max_duty() - 1.
+    pub fn fade_to(&mut self, duty: u32, time_ms: u32, wait: bool) -> Result<(), LedcError> {
+        if !self.enabled {
+            return self.set_duty(duty);
+        }
+        let duty = if duty >= self.max_duty() { self.max_duty() - 1 } else { duty };
+        if LEDC_FADE_INSTALLED.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            if let Err(err) = unsafe { idf::ledc_fade_func_install(0).as_result() } {
+                LEDC_FADE_INSTALLED.store(false, Ordering::SeqCst);
+                return Err(err.into());
+            }
+        }
+        let fade_mode = if wait { idf::ledc_fade_mode_t_LEDC_FADE_WAIT_DONE } else { idf::ledc_fade_mode_t_LEDC_FADE_NO_WAIT };
+        unsafe {
+            idf::ledc_set_fade_with_time(self.speed_mode.into(), self.idf_channel(), duty, time_ms as i32).as_result()?;
+            idf::ledc_fade_start(self.speed_mode.into(), self.idf_channel(), fade_mode).as_result()?;
+        }
+        self.duty = duty;
+        Ok(())
+    }
+
+    // Stop the output at low level.
+    pub fn disable(&mut self) -> Result<(), LedcError> {
+        unsafe { idf::ledc_stop(self.speed_mode.into(), self.idf_channel(), 0).as_result()?; }
+        self.enabled = false;
+        Ok(())
+    }
+    // Restart the output with the last duty.
+    pub fn enable(&mut self) -> Result<(), LedcError> {
+        self.enabled = true;
+        let duty = self.duty;
+        self.set_duty(duty)
+    }
+}
+
+impl Drop for LedcChannelDriver {
+    fn drop(&mut self) {
+        unsafe {
+            idf::ledc_stop(self.speed_mode.into(), self.idf_channel(), 0);
+        }
+        release(&LEDC_CHANNELS_IN_USE[self.speed_mode.index()], self.channel);
+    }
+}
+
+// The embedded-hal traits cannot return errors. The driver only fails on arguments which are checked here,
+// so its errors are ignored; use the inherent methods to handle them.
+impl PwmPin for LedcChannelDriver {
+    type Duty = u32;
+
+    fn disable(&mut self) {
+        let _ = LedcChannelDriver::disable(self);
+    }
+    fn enable(&mut self) {
+        let _ = LedcChannelDriver::enable(self);
+    }
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+    fn get_max_duty(&self) -> u32 {
+        LedcChannelDriver::max_duty(self)
+    }
+    fn set_duty(&mut self, duty: u32) {
+        let _ = LedcChannelDriver::set_duty(self, duty);
+    }
+}
+
+// A timer and the channels driven by it. Channels are addressed by the index returned from add_channel.
+pub struct LedcPwm {
+    channels: Vec<LedcChannelDriver>,
+    timer: LedcTimerDriver,
+}
+
+impl LedcPwm {
+    pub fn new(config: LedcTimerConfig) -> Result<LedcPwm, LedcError> {
+        Ok(LedcPwm { channels: Vec::new(), timer: LedcTimerDriver::new(config)? })
+    }
+    pub fn add_channel(&mut self, pin: GpioPin) -> Result<usize, LedcError> {
+        let channel = self.timer.channel(pin)?;
+        self.channels.push(channel);
+        Ok(self.channels.len() - 1)
+    }
+    pub fn timer(&mut self) -> &mut LedcTimerDriver { &mut self.timer }
+    pub fn channel(&mut self, channel: usize) -> &mut LedcChannelDriver { &mut self.channels[channel] }
+
+    // Change the frequency. If the duty resolution is too high for it, the resolution is lowered and the duties of
+    // the channels are scaled to keep their ratio. Frequencies above the 1-bit limit (40MHz) are clamped.
+    pub fn set_frequency(&mut self, frequency_hz: u32) -> Result<(), LedcError> {
+        if frequency_hz == 0 {
+            return Err(LedcError::Generic);
+        }
+        let frequency_hz = if frequency_hz > LEDC_APB_CLOCK_HZ / 2 { LEDC_APB_CLOCK_HZ / 2 } else { frequency_hz };
+        let old_bits = self.timer.resolution_bits();
+        let max_bits = ledc_max_resolution_bits(frequency_hz);
+        if old_bits <= max_bits {
+            return self.timer.set_frequency(frequency_hz);
+        }
+        self.timer.configure(frequency_hz, max_bits)?;
+        for channel in self.channels.iter_mut() {
+            channel.resolution_bits = max_bits;
+            channel.duty >>= old_bits - max_bits;
+            if channel.enabled {
+                let duty = channel.duty;
+                channel.set_duty(duty)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Pwm for LedcPwm {
+    type Channel = usize;
+    type Time = Hertz;
+    type Duty = u32;
+
+    fn disable(&mut self, channel: usize) {
+        PwmPin::disable(&mut self.channels[channel])
+    }
+    fn enable(&mut self, channel: usize) {
+        PwmPin::enable(&mut self.channels[channel])
+    }
+    fn get_period(&self) -> Hertz {
+        Hertz(self.timer.frequency_hz())
+    }
+    fn get_duty(&self, channel: usize) -> u32 {
+        self.channels[channel].duty()
+    }
+    fn get_max_duty(&self) -> u32 {
+        self.timer.max_duty()
+    }
+    fn set_duty(&mut self, channel: usize, duty: u32) {
+        PwmPin::set_duty(&mut self.channels[channel], duty)
+    }
+    fn set_period<P>(&mut self, period: P) where P: Into<Hertz> {
+        let Hertz(frequency_hz) = period.into();
+        let _ = LedcPwm::set_frequency(self, frequency_hz);
+    }
+}
@@ -1,10 +1,92 @@
 #![no_std]
 #![feature(alloc)] 
 
+// Modules which do not call into ESP-IDF build on any target, so they can be tested on the host
+// with `cargo test --no-default-features`.
+mod spi_transaction;
+mod spi_log;
+mod spi_record;
+mod i2c_register;
+mod rmt_codec;
+mod quadrature;
+mod twai_frame;
+mod sd_spi;
+
+#[cfg(feature = "esp-idf")]
 mod spi;
+#[cfg(feature = "esp-idf")]
+mod spi_slave;
+#[cfg(feature = "esp-idf")]
 mod gpio;
+#[cfg(feature = "esp-idf")]
 mod i2c;
+#[cfg(feature = "esp-idf")]
+mod i2c_slave;
+#[cfg(feature = "esp-idf")]
+mod uart;
+#[cfg(feature = "esp-idf")]
+mod ledc;
+#[cfg(feature = "esp-idf")]
+mod adc;
+#[cfg(feature = "esp-idf")]
+mod dac;
+#[cfg(feature = "esp-idf")]
+mod rmt;
+#[cfg(feature = "esp-idf")]
+mod i2s;
+#[cfg(feature = "esp-idf")]
+mod timer;
+#[cfg(feature = "esp-idf")]
+mod pcnt;
+#[cfg(feature = "esp-idf")]
+mod touch;
+#[cfg(feature = "esp-idf")]
+mod twai;
+#[cfg(feature = "esp-idf")]
+mod mcpwm;
+#[cfg(feature = "esp-idf")]
+mod sd_card;
+
+pub use crate::spi_transaction::*;
+pub use crate::spi_log::*;
+pub use crate::spi_record::*;
+pub use crate::i2c_register::*;
+pub use crate::rmt_codec::*;
+pub use crate::quadrature::*;
+pub use crate::twai_frame::*;
+pub use crate::sd_spi::*;
 
+#[cfg(feature = "esp-idf")]
 pub use crate::spi::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::spi_slave::*;
+#[cfg(feature = "esp-idf")]
 pub use crate::gpio::*;
-pub use crate::i2c::*;
\ No newline at end of file
+#[cfg(feature = "esp-idf")]
+pub use crate::i2c::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::i2c_slave::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::uart::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::ledc::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::adc::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::dac::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::rmt::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::i2s::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::timer::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::pcnt::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::touch::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::twai::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::mcpwm::*;
+#[cfg(feature = "esp-idf")]
+pub use crate::sd_card::*;
\ No newline at end of file
@@ -0,0 +1,417 @@
+use core::convert::Into;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use idf;
+use idf::AsResult;
+use idf::IdfError;
+
+use crate::gpio::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum McpwmError {
+    Generic,
+    IdfError(IdfError),
+    // The timer or capture channel is already in use by another driver.
+    Busy,
+    // Duty must be in 0-100%, speed in -1.0 to 1.0.
+    InvalidValue,
+    // The servo pulse range is reversed or the maximum angle is not positive.
+    InvalidConfig,
+}
+
+impl From<IdfError> for McpwmError {
+    fn from(err: IdfError) -> McpwmError {
+        McpwmError::IdfError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum McpwmUnit {
+    Unit0,
+    Unit1,
+}
+impl Into<idf::mcpwm_unit_t> for McpwmUnit {
+    fn into(self) -> idf::mcpwm_unit_t {
+        match self {
+            McpwmUnit::Unit0 => idf::mcpwm_unit_t_MCPWM_UNIT_0,
+            McpwmUnit::Unit1 => idf::mcpwm_unit_t_MCPWM_UNIT_1,
+        }
+    }
+}
+impl McpwmUnit {
+    fn index(&self) -> u32 {
+        match self {
+            McpwmUnit::Unit0 => 0,
+            McpwmUnit::Unit1 => 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum McpwmTimer {
+    Timer0,
+    Timer1,
+    Timer2,
+}
+impl McpwmTimer {
+    fn index(&self) -> u32 {
+        match self {
+            McpwmTimer::Timer0 => 0,
+            McpwmTimer::Timer1 => 1,
+            McpwmTimer::Timer2 => 2,
+        }
+    }
+}
+impl Into<idf::mcpwm_timer_t> for McpwmTimer {
+    fn into(self) -> idf::mcpwm_timer_t {
+        idf::mcpwm_timer_t_MCPWM_TIMER_0 + self.index()
+    }
+}
+
+// The two outputs of a timer's operator.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum McpwmGenerator {
+    A,
+    B,
+}
+impl Into<idf::mcpwm_operator_t> for McpwmGenerator {
+    fn into(self) -> idf::mcpwm_operator_t {
+        match self {
+            McpwmGenerator::A => idf::mcpwm_operator_t_MCPWM_OPR_A,
+            McpwmGenerator::B => idf::mcpwm_operator_t_MCPWM_OPR_B,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum McpwmCounterMode {
+    Up,
+    Down,
+    // Symmetric (center aligned) PWM at half the frequency of the count.
+    UpDown,
+}
+impl Default for McpwmCounterMode {
+    fn default() -> Self { McpwmCounterMode::Up }
+}
+impl Into<idf::mcpwm_counter_type_t> for McpwmCounterMode {
+    fn into(self) -> idf::mcpwm_counter_type_t {
+        match self {
+            McpwmCounterMode::Up => idf::mcpwm_counter_type_t_MCPWM_UP_COUNTER,
+            McpwmCounterMode::Down => idf::mcpwm_counter_type_t_MCPWM_DOWN_COUNTER,
+            McpwmCounterMode::UpDown => idf::mcpwm_counter_type_t_MCPWM_UP_DOWN_COUNTER,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct McpwmConfig {
+    pub frequency_hz: u32,
+    pub counter_mode: McpwmCounterMode,
+    pub pin_a: Option<GpioPin>,
+    pub pin_b: Option<GpioPin>,
+}
+
+impl McpwmConfig {
+    pub fn new(frequency_hz: u32, pin_a: Option<GpioPin>, pin_b: Option<GpioPin>) -> McpwmConfig {
+        McpwmConfig { frequency_hz: frequency_hz, counter_mode: Default::default(), pin_a: pin_a, pin_b: pin_b }
+    }
+}
+
+// Dead time inserted between the complementary outputs, derived from the duty of generator A.
+#[derive(Copy, Clone, Debug)]
+pub struct McpwmDeadTime {
+    pub rising_edge_ns: u32,
+    pub falling_edge_ns: u32,
+    // A active high and B its inverse; otherwise both active low.
+    pub active_high: bool,
+}
+
+// The dead time generator counts in 100ns.
+const MCPWM_DEAD_TIME_UNIT_NS: u32 = 100;
+
+static MCPWM_TIMERS_IN_USE: AtomicU8 = AtomicU8::new(0);
+static MCPWM_CAPTURES_IN_USE: AtomicU8 = AtomicU8::new(0);
+
+fn claim(in_use: &AtomicU8, bit: u8) -> Result<(), McpwmError> {
+    if in_use.fetch_or(bit, Ordering::SeqCst) & bit != 0 {
+        return Err(McpwmError::Busy);
+    }
+    Ok(())
+}
+
+// A timer of an MCPWM unit with its operator driving up to two outputs.
+pub struct McpwmPwm {
+    unit: McpwmUnit,
+    timer: McpwmTimer,
+}
+
+impl McpwmPwm {
+    // Initialize the timer with both outputs at 0% duty and start it.
+    pub fn new(unit: McpwmUnit, timer: McpwmTimer, config: McpwmConfig) -> Result<McpwmPwm, McpwmError> {
+        claim(&MCPWM_TIMERS_IN_USE, McpwmPwm::bit(unit, timer))?;
+        let pwm = McpwmPwm { unit: unit, timer: timer };
+        // Output signals are ordered MCPWM0A, MCPWM0B, MCPWM1A, ...
+        let signal_a = idf::mcpwm_io_signals_t_MCPWM0A + timer.index() * 2;
+        unsafe {
+            if let Some(pin) = config.pin_a {
+                idf::mcpwm_gpio_init(unit.into(), signal_a, pin.number() as i32).as_result()?;
+            }
+            if let Some(pin) = config.pin_b {
+                idf::mcpwm_gpio_init(unit.into(), signal_a + 1, pin.number() as i32).as_result()?;
+            }
+            let mut idf_config = idf::mcpwm_config_t::default();
+            idf_config.frequency = config.frequency_hz;
+            idf_config.cmpr_a = 0.0;
+            idf_config.cmpr_b = 0.0;
+            idf_config.counter_mode = config.counter_mode.into();
+            idf_config.duty_mode = idf::mcpwm_duty_type_t_MCPWM_DUTY_MODE_0;
+            idf::mcpwm_init(unit.into(), timer.into(), &idf_config).as_result()?;
+        }
+        Ok(pwm)
+    }
+
+    fn bit(unit: McpwmUnit, timer: McpwmTimer) -> u8 {
+        1u8 << (unit.index() * 3 + timer.index())
+    }
+
+    pub fn set_frequency(&mut self, frequency_hz: u32) -> Result<(), McpwmError> {
+        unsafe { idf::mcpwm_set_frequency(self.unit.into(), self.timer.into(), frequency_hz).as_result()?; }
+        Ok(())
+    }
+    pub fn frequency(&self) -> u32 {
+        unsafe { idf::mcpwm_get_frequency(self.unit.into(), self.timer.into()) }
+    }
+
+    // Set the duty of an output in percent (0.0 - 100.0).
+    pub fn set_duty(&mut self, generator: McpwmGenerator, duty_percent: f32) -> Result<(), McpwmError> {
+        if !(0.0 <= duty_percent && duty_percent <= 100.0) {
+            return Err(McpwmError::InvalidValue);
+        }
+        unsafe {
+            idf::mcpwm_set_duty(self.unit.into(), self.timer.into(), generator.into(), duty_percent).as_result()?;
+            // Restore the PWM output after set_level.
+            idf::mcpwm_set_duty_type(self.unit.into(), self.timer.into(), generator.into(), idf::mcpwm_duty_type_t_MCPWM_DUTY_MODE_0).as_result()?;
+        }
+        Ok(())
+    }
+    pub fn duty(&self, generator: McpwmGenerator) -> f32 {
+        unsafe { idf::mcpwm_get_duty(self.unit.into(), self.timer.into(), generator.into()) }
+    }
+    // Set the high time of an output in microseconds.
+    pub fn set_pulse_width_us(&mut self, generator: McpwmGenerator, pulse_width_us: u32) -> Result<(), McpwmError> {
+        unsafe {
+            idf::mcpwm_set_duty_in_us(self.unit.into(), self.timer.into(), generator.into(), pulse_width_us).as_result()?;
+            idf::mcpwm_set_duty_type(self.unit.into(), self.timer.into(), generator.into(), idf::mcpwm_duty_type_t_MCPWM_DUTY_MODE_0).as_result()?;
+        }
+        Ok(())
+    }
+    // Hold an output at a constant level until the next set_duty or set_pulse_width_us.
+    pub fn set_level(&mut self, generator: McpwmGenerator, high: bool) -> Result<(), McpwmError> {
+        unsafe {
+            if high {
+                idf::mcpwm_set_signal_high(self.unit.into(), self.timer.into(), generator.into()).as_result()?;
+            }
+            else {
+                idf::mcpwm_set_signal_low(self.unit.into(), self.timer.into(), generator.into()).as_result()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Drive B as the complement of A with dead time, or restore independent outputs with None.
+    pub fn set_dead_time(&mut self, dead_time: Option<McpwmDeadTime>) -> Result<(), McpwmError> {
+        unsafe {
+            match dead_time {
+                Some(dead_time) => {
+                    let mode = if dead_time.active_high { idf::mcpwm_deadtime_type_t_MCPWM_ACTIVE_HIGH_COMPLIMENT_MODE } else { idf::mcpwm_deadtime_type_t_MCPWM_ACTIVE_LOW_COMPLIMENT_MODE };
+                    let rising = (dead_time.rising_edge_ns + MCPWM_DEAD_TIME_UNIT_NS - 1) / MCPWM_DEAD_TIME_UNIT_NS;
+                    let falling = (dead_time.falling_edge_ns + MCPWM_DEAD_TIME_UNIT_NS - 1) / MCPWM_DEAD_TIME_UNIT_NS;
+                    idf::mcpwm_deadtime_enable(self.unit.into(), self.timer.into(), mode, rising, falling).as_result()?;
+                },
+                None => {
+                    idf::mcpwm_deadtime_disable(self.unit.into(), self.timer.into()).as_result()?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), McpwmError> {
+        unsafe { idf::mcpwm_start(self.unit.into(), self.timer.into()).as_result()?; }
+        Ok(())
+    }
+    pub fn stop(&mut self) -> Result<(), McpwmError> {
+        unsafe { idf::mcpwm_stop(self.unit.into(), self.timer.into()).as_result()?; }
+        Ok(())
+    }
+}
+
+impl Drop for McpwmPwm {
+    fn drop(&mut self) {
+        unsafe {
+            idf::mcpwm_set_signal_low(self.unit.into(), self.timer.into(), idf::mcpwm_operator_t_MCPWM_OPR_A);
+            idf::mcpwm_set_signal_low(self.unit.into(), self.timer.into(), idf::mcpwm_operator_t_MCPWM_OPR_B);
+            idf::mcpwm_stop(self.unit.into(), self.timer.into());
+        }
+        MCPWM_TIMERS_IN_USE.fetch_and(!McpwmPwm::bit(self.unit, self.timer), Ordering::SeqCst);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum McpwmCaptureEdge {
+    Rising,
+    Falling,
+}
+
+// Capture clock: the counter runs on the 80MHz APB clock.
+const MCPWM_CAPTURE_CLOCK_HZ: u32 = 80_000_000;
+
+// Timestamps edges of an input, e.g. to measure the period of an encoder or tachometer signal.
+pub struct McpwmCapture {
+    unit: McpwmUnit,
+    channel: u32,
+    last: Option<u32>,
+}
+
+impl McpwmCapture {
+    // Capture every `prescale`th edge (1-256) on channel 0-2 of the unit.
+    pub fn new(unit: McpwmUnit, channel: u32, pin: GpioPin, edge: McpwmCaptureEdge, prescale: u32) -> Result<McpwmCapture, McpwmError> {
+        if channel > 2 || prescale == 0 || prescale > 256 {
+            return Err(McpwmError::InvalidValue);
+        }
+        claim(&MCPWM_CAPTURES_IN_USE, McpwmCapture::bit(unit, channel))?;
+        let capture = McpwmCapture { unit: unit, channel: channel, last: None };
+        let idf_edge = match edge {
+            McpwmCaptureEdge::Rising => idf::mcpwm_capture_on_edge_t_MCPWM_POS_EDGE,
+            McpwmCaptureEdge::Falling => idf::mcpwm_capture_on_edge_t_MCPWM_NEG_EDGE,
+        };
+        unsafe {
+            idf::mcpwm_gpio_init(unit.into(), idf::mcpwm_io_signals_t_MCPWM_CAP_0 + channel, pin.number() as i32).as_result()?;
+            idf::mcpwm_capture_enable(unit.into(), capture.signal(), idf_edge, prescale).as_result()?;
+        }
+        Ok(capture)
+    }
+
+    fn bit(unit: McpwmUnit, channel: u32) -> u8 {
+        1u8 << (unit.index() * 3 + channel)
+    }
+    fn signal(&self) -> idf::mcpwm_capture_signal_t {
+        idf::mcpwm_capture_signal_t_MCPWM_SELECT_CAP0 + self.channel
+    }
+
+    // Counter value at the last captured edge, in APB clock cycles.
+    pub fn value(&self) -> u32 {
+        unsafe { idf::mcpwm_capture_signal_get_value(self.unit.into(), self.signal()) }
+    }
+
+    // Time between the captured edge and the one seen by the previous call, in microseconds.
+    // Returns None on the first call and while no new edge was captured.
+    pub fn period_us(&mut self) -> Option<u32> {
+        let value = self.value();
+        let period = match self.last {
+            Some(last) if last != value => Some(value.wrapping_sub(last) / (MCPWM_CAPTURE_CLOCK_HZ / 1_000_000)),
+            _ => None,
+        };
+        self.last = Some(value);
+        period
+    }
+}
+
+impl Drop for McpwmCapture {
+    fn drop(&mut self) {
+        unsafe {
+            idf::mcpwm_capture_disable(self.unit.into(), self.signal());
+        }
+        MCPWM_CAPTURES_IN_USE.fetch_and(!McpwmCapture::bit(self.unit, self.channel), Ordering::SeqCst);
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ServoConfig {
+    pub min_pulse_us: u32,
+    pub max_pulse_us: u32,
+    pub max_angle: f32,
+}
+
+impl Default for ServoConfig {
+    // Usual hobby servos: 0.5ms to 2.5ms for 0 to 180 degrees.
+    fn default() -> Self {
+        ServoConfig { min_pulse_us: 500, max_pulse_us: 2500, max_angle: 180.0 }
+    }
+}
+
+impl ServoConfig {
+    fn is_valid(&self) -> bool {
+        self.min_pulse_us <= self.max_pulse_us && self.max_angle > 0.0
+    }
+
+    // Pulse width for the angle, clamped to the range of the servo. An invalid config gives min_pulse_us.
+    pub fn pulse_width_us(&self, angle: f32) -> u32 {
+        if !self.is_valid() {
+            return self.min_pulse_us;
+        }
+        let angle = if angle < 0.0 { 0.0 } else if angle > self.max_angle { self.max_angle } else { angle };
+        self.min_pulse_us + ((self.max_pulse_us - self.min_pulse_us) as f32 * angle / self.max_angle + 0.5) as u32
+    }
+}
+
+const SERVO_FREQUENCY_HZ: u32 = 50;
+
+// Hobby servo driven by output A of an MCPWM timer at 50Hz.
+pub struct Servo {
+    pwm: McpwmPwm,
+    config: ServoConfig,
+}
+
+impl Servo {
+    pub fn new(unit: McpwmUnit, timer: McpwmTimer, pin: GpioPin, config: ServoConfig) -> Result<Servo, McpwmError> {
+        if !config.is_valid() {
+            return Err(McpwmError::InvalidConfig);
+        }
+        let pwm = McpwmPwm::new(unit, timer, McpwmConfig::new(SERVO_FREQUENCY_HZ, Some(pin), None))?;
+        Ok(Servo { pwm: pwm, config: config })
+    }
+    pub fn set_angle(&mut self, angle: f32) -> Result<(), McpwmError> {
+        let pulse_width_us = self.config.pulse_width_us(angle);
+        self.pwm.set_pulse_width_us(McpwmGenerator::A, pulse_width_us)
+    }
+    // Stop driving the servo, letting it move freely.
+    pub fn release(&mut self) -> Result<(), McpwmError> {
+        self.pwm.set_level(McpwmGenerator::A, false)
+    }
+}
+
+// DC motor on an H-bridge whose two inputs are driven by outputs A and B of an MCPWM timer.
+pub struct HBridgeMotor {
+    pwm: McpwmPwm,
+}
+
+impl HBridgeMotor {
+    pub fn new(unit: McpwmUnit, timer: McpwmTimer, pin_a: GpioPin, pin_b: GpioPin, frequency_hz: u32) -> Result<HBridgeMotor, McpwmError> {
+        let mut pwm = McpwmPwm::new(unit, timer, McpwmConfig::new(frequency_hz, Some(pin_a), Some(pin_b)))?;
+        pwm.set_level(McpwmGenerator::A, false)?;
+        pwm.set_level(McpwmGenerator::B, false)?;
+        Ok(HBridgeMotor { pwm: pwm })
+    }
+
+    // Drive at `speed` from -1.0 (full reverse) to 1.0 (full forward). 0.0 coasts.
+    pub fn set_speed(&mut self, speed: f32) -> Result<(), McpwmError> {
+        if !(-1.0 <= speed && speed <= 1.0) {
+            return Err(McpwmError::InvalidValue);
+        }
+        let (driven, held) = if speed >= 0.0 { (McpwmGenerator::A, McpwmGenerator::B) } else { (McpwmGenerator::B, McpwmGenerator::A) };
+        let duty = if speed >= 0.0 { speed * 100.0 } else { -speed * 100.0 };
+        self.pwm.set_level(held, false)?;
+        self.pwm.set_duty(driven, duty)
+    }
+    // Both inputs low: the motor runs freely.
+    pub fn coast(&mut self) -> Result<(), McpwmError> {
+        self.pwm.set_level(McpwmGenerator::A, false)?;
+        self.pwm.set_level(McpwmGenerator::B, false)
+    }
+    // Both inputs high: the motor windings are shorted.
+    pub fn brake(&mut self) -> Result<(), McpwmError> {
+        self.pwm.set_level(McpwmGenerator::A, true)?;
+        self.pwm.set_level(McpwmGenerator::B, true)
+    }
+}
@@ -0,0 +1,408 @@
+use core::convert::Into;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+use idf::IdfError;
+
+use freertos_rs::*;
+
+use crate::gpio::*;
+use crate::quadrature::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum PcntError {
+    Generic,
+    IdfError(IdfError),
+    FreeRtosError(FreeRtosError),
+    // The glitch filter takes up to 1023 APB clock cycles.
+    InvalidFilter,
+    // The limits must satisfy low_limit < 0 < high_limit.
+    InvalidLimit,
+}
+
+impl From<IdfError> for PcntError {
+    fn from(err: IdfError) -> PcntError {
+        PcntError::IdfError(err)
+    }
+}
+impl From<FreeRtosError> for PcntError {
+    fn from(err: FreeRtosError) -> PcntError {
+        PcntError::FreeRtosError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PcntUnitNumber {
+    Unit0,
+    Unit1,
+    Unit2,
+    Unit3,
+    Unit4,
+    Unit5,
+    Unit6,
+    Unit7,
+}
+
+impl PcntUnitNumber {
+    fn index(&self) -> u32 {
+        match self {
+            PcntUnitNumber::Unit0 => 0,
+            PcntUnitNumber::Unit1 => 1,
+            PcntUnitNumber::Unit2 => 2,
+            PcntUnitNumber::Unit3 => 3,
+            PcntUnitNumber::Unit4 => 4,
+            PcntUnitNumber::Unit5 => 5,
+            PcntUnitNumber::Unit6 => 6,
+            PcntUnitNumber::Unit7 => 7,
+        }
+    }
+}
+impl Into<idf::pcnt_unit_t> for PcntUnitNumber {
+    fn into(self) -> idf::pcnt_unit_t {
+        idf::pcnt_unit_t_PCNT_UNIT_0 + self.index()
+    }
+}
+
+// Action on an edge of the pulse input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PcntCountMode {
+    Hold,
+    Increment,
+    Decrement,
+}
+impl Into<idf::pcnt_count_mode_t> for PcntCountMode {
+    fn into(self) -> idf::pcnt_count_mode_t {
+        match self {
+            PcntCountMode::Hold => idf::pcnt_count_mode_t_PCNT_COUNT_DIS,
+            PcntCountMode::Increment => idf::pcnt_count_mode_t_PCNT_COUNT_INC,
+            PcntCountMode::Decrement => idf::pcnt_count_mode_t_PCNT_COUNT_DEC,
+        }
+    }
+}
+
+// Effect of the control input level on the count mode.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PcntControlMode {
+    Keep,
+    Reverse,
+    Disable,
+}
+impl Into<idf::pcnt_ctrl_mode_t> for PcntControlMode {
+    fn into(self) -> idf::pcnt_ctrl_mode_t {
+        match self {
+            PcntControlMode::Keep => idf::pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+            PcntControlMode::Reverse => idf::pcnt_ctrl_mode_t_PCNT_MODE_REVERSE,
+            PcntControlMode::Disable => idf::pcnt_ctrl_mode_t_PCNT_MODE_DISABLE,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PcntChannelConfig {
+    pub pulse_pin: Option<GpioPin>,
+    pub control_pin: Option<GpioPin>,
+    pub rising_edge: PcntCountMode,
+    pub falling_edge: PcntCountMode,
+    pub control_high: PcntControlMode,
+    pub control_low: PcntControlMode,
+}
+
+impl PcntChannelConfig {
+    // Count rising edges of the pin.
+    pub fn rising_edges(pin: GpioPin) -> PcntChannelConfig {
+        PcntChannelConfig {
+            pulse_pin: Some(pin),
+            control_pin: None,
+            rising_edge: PcntCountMode::Increment,
+            falling_edge: PcntCountMode::Hold,
+            control_high: PcntControlMode::Keep,
+            control_low: PcntControlMode::Keep,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PcntConfig {
+    pub channel0: Option<PcntChannelConfig>,
+    pub channel1: Option<PcntChannelConfig>,
+    // The counter is reset to 0 when it reaches a limit. The overflow is accumulated in software.
+    pub high_limit: i16,
+    pub low_limit: i16,
+    // Ignore pulses shorter than this number of APB clock cycles (up to 1023, i.e. 12.8us).
+    pub filter: Option<u16>,
+    pub thresholds: [Option<i16>; 2],
+    // Length of the event queue. With 0, no events are reported, but the overflow is still accumulated.
+    pub event_queue_size: usize,
+}
+
+impl Default for PcntConfig {
+    fn default() -> Self {
+        PcntConfig {
+            channel0: None,
+            channel1: None,
+            high_limit: i16::max_value(),
+            low_limit: i16::min_value(),
+            filter: None,
+            thresholds: [None, None],
+            event_queue_size: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PcntEvent {
+    pub high_limit: bool,
+    pub low_limit: bool,
+    pub threshold0: bool,
+    pub threshold1: bool,
+    pub zero: bool,
+    // Accumulated count at the event.
+    pub count: i32,
+}
+
+const PCNT_PIN_NOT_USED: i32 = -1;
+const PCNT_FILTER_MAX: u16 = 1023;
+// Status and interrupt clear registers, read from the ISR.
+const PCNT_STATUS_UNIT_REG: usize = 0x3ff57090;
+const PCNT_INT_CLR_REG: usize = 0x3ff5708c;
+const PCNT_STATUS_THRES1: u32 = 1 << 2;
+const PCNT_STATUS_THRES0: u32 = 1 << 3;
+const PCNT_STATUS_L_LIM: u32 = 1 << 4;
+const PCNT_STATUS_H_LIM: u32 = 1 << 5;
+const PCNT_STATUS_ZERO: u32 = 1 << 6;
+
+static PCNT_ISR_SERVICE_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+struct PcntIsrState {
+    unit: u32,
+    high_limit: i16,
+    low_limit: i16,
+    overflow: AtomicI32,
+    queue: Option<Arc<Queue<PcntEvent>>>,
+}
+
+pub struct PcntUnit {
+    unit: PcntUnitNumber,
+    state: Box<PcntIsrState>,
+}
+
+unsafe impl Send for PcntUnit {}
+
+impl PcntUnit {
+    pub fn new(unit: PcntUnitNumber, config: PcntConfig) -> Result<PcntUnit, PcntError> {
+        if !(config.low_limit < 0 && 0 < config.high_limit) {
+            return Err(PcntError::InvalidLimit);
+        }
+        if config.filter.map_or(false, |filter| filter > PCNT_FILTER_MAX) {
+            return Err(PcntError::InvalidFilter);
+        }
+        let queue = if config.event_queue_size > 0 { Some(Arc::new(Queue::new(config.event_queue_size)?)) } else { None };
+        let state = Box::new(PcntIsrState { unit: unit.index(), high_limit: config.high_limit, low_limit: config.low_limit, overflow: AtomicI32::new(0), queue: queue });
+
+        let channels = [(idf::pcnt_channel_t_PCNT_CHANNEL_0, config.channel0), (idf::pcnt_channel_t_PCNT_CHANNEL_1, config.channel1)];
+        let pin_number = |pin: Option<GpioPin>| pin.map_or(PCNT_PIN_NOT_USED, |pin| pin.number() as i32);
+        unsafe {
+            for (channel, channel_config) in channels.iter() {
+                let mut idf_config = idf::pcnt_config_t::default();
+                idf_config.unit = unit.into();
+                idf_config.channel = *channel;
+                idf_config.counter_h_lim = config.high_limit;
+                idf_config.counter_l_lim = config.low_limit;
+                match channel_config {
+                    Some(channel_config) => {
+                        idf_config.pulse_gpio_num = pin_number(channel_config.pulse_pin);
+                        idf_config.ctrl_gpio_num = pin_number(channel_config.control_pin);
+                        idf_config.pos_mode = channel_config.rising_edge.into();
+                        idf_config.neg_mode = channel_config.falling_edge.into();
+                        idf_config.hctrl_mode = channel_config.control_high.into();
+                        idf_config.lctrl_mode = channel_config.control_low.into();
+                    },
+                    None => {
+                        idf_config.pulse_gpio_num = PCNT_PIN_NOT_USED;
+                        idf_config.ctrl_gpio_num = PCNT_PIN_NOT_USED;
+                        idf_config.pos_mode = idf::pcnt_count_mode_t_PCNT_COUNT_DIS;
+                        idf_config.neg_mode = idf::pcnt_count_mode_t_PCNT_COUNT_DIS;
+                    },
+                }
+                idf::pcnt_unit_config(&idf_config).as_result()?;
+            }
+            idf::pcnt_counter_pause(unit.into()).as_result()?;
+            idf::pcnt_counter_clear(unit.into()).as_result()?;
+            match config.filter {
+                Some(filter) => {
+                    idf::pcnt_set_filter_value(unit.into(), filter).as_result()?;
+                    idf::pcnt_filter_enable(unit.into()).as_result()?;
+                },
+                None => { idf::pcnt_filter_disable(unit.into()).as_result()?; },
+            }
+            let threshold_events = [idf::pcnt_evt_type_t_PCNT_EVT_THRES_0, idf::pcnt_evt_type_t_PCNT_EVT_THRES_1];
+            for (event, threshold) in threshold_events.iter().zip(config.thresholds.iter()) {
+                if let Some(threshold) = threshold {
+                    idf::pcnt_set_event_value(unit.into(), *event, *threshold).as_result()?;
+                    idf::pcnt_event_enable(unit.into(), *event).as_result()?;
+                }
+                else {
+                    idf::pcnt_event_disable(unit.into(), *event).as_result()?;
+                }
+            }
+            idf::pcnt_event_enable(unit.into(), idf::pcnt_evt_type_t_PCNT_EVT_H_LIM).as_result()?;
+            idf::pcnt_event_enable(unit.into(), idf::pcnt_evt_type_t_PCNT_EVT_L_LIM).as_result()?;
+            if config.event_queue_size > 0 {
+                idf::pcnt_event_enable(unit.into(), idf::pcnt_evt_type_t_PCNT_EVT_ZERO).as_result()?;
+            }
+
+            if PCNT_ISR_SERVICE_INSTALLED.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                match idf::pcnt_isr_service_install(0).as_result() {
+                    // Installed by other code.
+                    Err(err) if err.code() != idf::ESP_ERR_INVALID_STATE => {
+                        PCNT_ISR_SERVICE_INSTALLED.store(false, Ordering::SeqCst);
+                        return Err(err.into());
+                    },
+                    _ => {},
+                }
+            }
+        }
+        let pcnt = PcntUnit { unit: unit, state: state };
+        unsafe {
+            let state_ptr = &*pcnt.state as *const PcntIsrState as *mut c_void;
+            idf::pcnt_isr_handler_add(unit.into(), Some(PcntUnit::isr_handler), state_ptr).as_result()?;
+            idf::pcnt_intr_enable(unit.into()).as_result()?;
+            idf::pcnt_counter_resume(unit.into()).as_result()?;
+        }
+        Ok(pcnt)
+    }
+
+    unsafe extern "C" fn isr_handler(arg: *mut c_void) {
+        let state = &*(arg as *const PcntIsrState);
+        let status = ptr::read_volatile((PCNT_STATUS_UNIT_REG + 4 * state.unit as usize) as *const u32);
+        let mut overflow = state.overflow.load(Ordering::SeqCst);
+        if status & PCNT_STATUS_H_LIM != 0 {
+            overflow = overflow.wrapping_add(state.high_limit as i32);
+        }
+        if status & PCNT_STATUS_L_LIM != 0 {
+            overflow = overflow.wrapping_add(state.low_limit as i32);
+        }
+        state.overflow.store(overflow, Ordering::SeqCst);
+        if let Some(queue) = &state.queue {
+            let event = PcntEvent {
+                high_limit: status & PCNT_STATUS_H_LIM != 0,
+                low_limit: status & PCNT_STATUS_L_LIM != 0,
+                threshold0: status & PCNT_STATUS_THRES0 != 0,
+                threshold1: status & PCNT_STATUS_THRES1 != 0,
+                zero: status & PCNT_STATUS_ZERO != 0,
+                count: overflow,
+            };
+            let mut context = InterruptContext::new();
+            // Events are dropped while the queue is full.
+            let _ = queue.send_from_isr(&mut context, event);
+        }
+        // Clear the interrupt of the unit so that it does not fire again.
+        ptr::write_volatile(PCNT_INT_CLR_REG as *mut u32, 1 << state.unit);
+    }
+
+    // Raw 16bit value of the hardware counter.
+    pub fn counter(&self) -> Result<i16, PcntError> {
+        let mut value: i16 = 0;
+        unsafe { idf::pcnt_get_counter_value(self.unit.into(), &mut value).as_result()?; }
+        Ok(value)
+    }
+
+    // Count including the overflows at the limits.
+    pub fn count(&self) -> Result<i32, PcntError> {
+        loop {
+            let overflow = self.state.overflow.load(Ordering::SeqCst);
+            let counter = self.counter()?;
+            if overflow == self.state.overflow.load(Ordering::SeqCst) {
+                return Ok(overflow.wrapping_add(counter as i32));
+            }
+        }
+    }
+
+    pub fn clear(&mut self) -> Result<(), PcntError> {
+        unsafe { idf::pcnt_counter_clear(self.unit.into()).as_result()?; }
+        self.state.overflow.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+    pub fn pause(&mut self) -> Result<(), PcntError> {
+        unsafe { idf::pcnt_counter_pause(self.unit.into()).as_result()?; }
+        Ok(())
+    }
+    pub fn resume(&mut self) -> Result<(), PcntError> {
+        unsafe { idf::pcnt_counter_resume(self.unit.into()).as_result()?; }
+        Ok(())
+    }
+
+    // The queue receiving the events. None unless PcntConfig::event_queue_size > 0.
+    pub fn events(&self) -> Option<Arc<Queue<PcntEvent>>> { self.state.queue.clone() }
+}
+
+impl Drop for PcntUnit {
+    fn drop(&mut self) {
+        unsafe {
+            idf::pcnt_counter_pause(self.unit.into());
+            idf::pcnt_intr_disable(self.unit.into());
+            idf::pcnt_isr_handler_remove(self.unit.into());
+        }
+    }
+}
+
+// Rotary encoder decoded by a PCNT unit, counting every edge of both signals.
+pub struct QuadratureEncoder {
+    pcnt: PcntUnit,
+    resolution: QuadratureResolution,
+    velocity: QuadratureVelocity,
+}
+
+impl QuadratureEncoder {
+    pub fn new(unit: PcntUnitNumber, pin_a: GpioPin, pin_b: GpioPin, resolution: QuadratureResolution, filter: Option<u16>) -> Result<QuadratureEncoder, PcntError> {
+        // Channel 0 counts the edges of A and channel 1 the edges of B; the other signal selects the direction.
+        // A leading B counts up.
+        let config = PcntConfig {
+            channel0: Some(PcntChannelConfig {
+                pulse_pin: Some(pin_a),
+                control_pin: Some(pin_b),
+                rising_edge: PcntCountMode::Decrement,
+                falling_edge: PcntCountMode::Increment,
+                control_high: PcntControlMode::Keep,
+                control_low: PcntControlMode::Reverse,
+            }),
+            channel1: Some(PcntChannelConfig {
+                pulse_pin: Some(pin_b),
+                control_pin: Some(pin_a),
+                rising_edge: PcntCountMode::Increment,
+                falling_edge: PcntCountMode::Decrement,
+                control_high: PcntControlMode::Keep,
+                control_low: PcntControlMode::Reverse,
+            }),
+            filter: filter,
+            ..Default::default()
+        };
+        Ok(QuadratureEncoder { pcnt: PcntUnit::new(unit, config)?, resolution: resolution, velocity: QuadratureVelocity::new(0.3) })
+    }
+
+    // Signed position in steps of the resolution.
+    pub fn position(&self) -> Result<i32, PcntError> {
+        Ok(quadrature_steps(self.pcnt.count()?, self.resolution))
+    }
+
+    // Velocity in steps per second, smoothed over successive calls. Call it periodically.
+    pub fn velocity(&mut self) -> Result<f32, PcntError> {
+        let count = self.pcnt.count()?;
+        let time_us = unsafe { idf::esp_timer_get_time() } as u64;
+        Ok(self.velocity.update(count, time_us) / self.resolution.edges_per_step() as f32)
+    }
+
+    pub fn reset(&mut self) -> Result<(), PcntError> {
+        self.velocity.reset();
+        self.pcnt.clear()
+    }
+
+    pub fn pcnt(&mut self) -> &mut PcntUnit { &mut self.pcnt }
+}
@@ -0,0 +1,219 @@
+// Quadrature decoding and velocity estimation. QuadratureEncoder in pcnt uses them with the hardware counter,
+// QuadratureDecoder decodes levels sampled in software.
+
+// Steps reported per quadrature cycle: X4 counts every edge of both signals, X1 one step per detent of usual encoders.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QuadratureResolution {
+    X1,
+    X2,
+    X4,
+}
+
+impl QuadratureResolution {
+    // Number of edges per step.
+    pub fn edges_per_step(&self) -> i32 {
+        match self {
+            QuadratureResolution::X1 => 4,
+            QuadratureResolution::X2 => 2,
+            QuadratureResolution::X4 => 1,
+        }
+    }
+}
+
+// Change of the edge count per transition, indexed by (previous state << 2) | state, where state is (A << 1) | B.
+// A leading B counts up: 00 -> 10 -> 11 -> 01 -> 00. None marks an invalid transition (both signals changed).
+const QUADRATURE_TRANSITIONS: [Option<i8>; 16] = [
+    Some(0), Some(-1), Some(1), None,
+    Some(1), Some(0), None, Some(-1),
+    Some(-1), None, Some(0), Some(1),
+    None, Some(1), Some(-1), Some(0),
+];
+
+// Software decoder of the A/B signals, counting every edge.
+#[derive(Copy, Clone, Debug)]
+pub struct QuadratureDecoder {
+    state: u8,
+    count: i32,
+    errors: u32,
+}
+
+impl QuadratureDecoder {
+    pub fn new(a: bool, b: bool) -> QuadratureDecoder {
+        QuadratureDecoder { state: QuadratureDecoder::state_of(a, b), count: 0, errors: 0 }
+    }
+    fn state_of(a: bool, b: bool) -> u8 {
+        ((a as u8) << 1) | (b as u8)
+    }
+
+    // Feed the current levels. Returns the change of the count, or None if an edge was missed.
+    pub fn update(&mut self, a: bool, b: bool) -> Option<i8> {
+        let state = QuadratureDecoder::state_of(a, b);
+        let delta = QUADRATURE_TRANSITIONS[((self.state << 2) | state) as usize];
+        self.state = state;
+        match delta {
+            Some(delta) => self.count = self.count.wrapping_add(delta as i32),
+            None => self.errors += 1,
+        }
+        delta
+    }
+
+    pub fn count(&self) -> i32 { self.count }
+    pub fn steps(&self, resolution: QuadratureResolution) -> i32 { quadrature_steps(self.count, resolution) }
+    // Number of invalid transitions seen.
+    pub fn errors(&self) -> u32 { self.errors }
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.errors = 0;
+    }
+}
+
+// Convert an edge count to steps, rounding toward negative infinity so that steps do not jitter around 0.
+pub fn quadrature_steps(count: i32, resolution: QuadratureResolution) -> i32 {
+    let edges = resolution.edges_per_step();
+    let steps = count / edges;
+    if count % edges < 0 { steps - 1 } else { steps }
+}
+
+// Velocity from successive counts, smoothed by an exponential moving average.
+#[derive(Copy, Clone, Debug)]
+pub struct QuadratureVelocity {
+    // Weight of the new sample (0 < smoothing <= 1). 1 disables smoothing.
+    smoothing: f32,
+    last: Option<(i32, u64)>,
+    velocity: f32,
+}
+
+impl QuadratureVelocity {
+    pub fn new(smoothing: f32) -> QuadratureVelocity {
+        QuadratureVelocity { smoothing: smoothing, last: None, velocity: 0.0 }
+    }
+
+    // Feed a count sampled at `time_us`. Returns the velocity in counts per second.
+    pub fn update(&mut self, count: i32, time_us: u64) -> f32 {
+        if let Some((last_count, last_time_us)) = self.last {
+            if time_us <= last_time_us {
+                return self.velocity;
+            }
+            let sample = count.wrapping_sub(last_count) as f32 * 1_000_000.0 / (time_us - last_time_us) as f32;
+            self.velocity += (sample - self.velocity) * self.smoothing;
+        }
+        self.last = Some((count, time_us));
+        self.velocity
+    }
+
+    pub fn velocity(&self) -> f32 { self.velocity }
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.velocity = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One quadrature cycle with A leading B, as (A, B).
+    const FORWARD: [(bool, bool); 4] = [(true, false), (true, true), (false, true), (false, false)];
+
+    fn feed(decoder: &mut QuadratureDecoder, levels: &[(bool, bool)]) {
+        for &(a, b) in levels {
+            decoder.update(a, b);
+        }
+    }
+
+    #[test]
+    fn forward_sequence_counts_up() {
+        let mut decoder = QuadratureDecoder::new(false, false);
+        for (index, &(a, b)) in FORWARD.iter().cycle().take(12).enumerate() {
+            assert_eq!(decoder.update(a, b), Some(1));
+            assert_eq!(decoder.count(), index as i32 + 1);
+        }
+        assert_eq!(decoder.steps(QuadratureResolution::X4), 12);
+        assert_eq!(decoder.steps(QuadratureResolution::X2), 6);
+        assert_eq!(decoder.steps(QuadratureResolution::X1), 3);
+        assert_eq!(decoder.errors(), 0);
+    }
+
+    #[test]
+    fn reverse_sequence_counts_down() {
+        let mut decoder = QuadratureDecoder::new(false, false);
+        for &(a, b) in FORWARD.iter().rev().skip(1).chain(FORWARD.iter().rev()) {
+            assert_eq!(decoder.update(a, b), Some(-1));
+        }
+        assert_eq!(decoder.count(), -7);
+        assert_eq!(decoder.errors(), 0);
+    }
+
+    #[test]
+    fn direction_change_and_unchanged_levels() {
+        let mut decoder = QuadratureDecoder::new(false, false);
+        feed(&mut decoder, &FORWARD[..3]);
+        assert_eq!(decoder.update(false, true), Some(0));
+        assert_eq!(decoder.update(true, true), Some(-1));
+        assert_eq!(decoder.count(), 2);
+    }
+
+    #[test]
+    fn invalid_transitions_are_counted_as_errors() {
+        let mut decoder = QuadratureDecoder::new(false, false);
+        assert_eq!(decoder.update(true, true), None);
+        assert_eq!(decoder.update(false, false), None);
+        assert_eq!(decoder.update(false, true), Some(-1));
+        assert_eq!(decoder.update(true, false), None);
+        assert_eq!(decoder.count(), -1);
+        assert_eq!(decoder.errors(), 3);
+        // Decoding continues from the new state.
+        assert_eq!(decoder.update(true, true), Some(1));
+
+        decoder.reset();
+        assert_eq!((decoder.count(), decoder.errors()), (0, 0));
+        assert_eq!(decoder.update(false, true), Some(1));
+    }
+
+    #[test]
+    fn negative_counts_round_down() {
+        let steps = |count, resolution| quadrature_steps(count, resolution);
+        assert_eq!(steps(-1, QuadratureResolution::X4), -1);
+        assert_eq!(steps(-1, QuadratureResolution::X2), -1);
+        assert_eq!(steps(-2, QuadratureResolution::X2), -1);
+        assert_eq!(steps(-3, QuadratureResolution::X2), -2);
+        assert_eq!(steps(-1, QuadratureResolution::X1), -1);
+        assert_eq!(steps(-4, QuadratureResolution::X1), -1);
+        assert_eq!(steps(-5, QuadratureResolution::X1), -2);
+        assert_eq!(steps(3, QuadratureResolution::X1), 0);
+        assert_eq!(steps(4, QuadratureResolution::X1), 1);
+        assert_eq!(steps(0, QuadratureResolution::X1), 0);
+    }
+
+    #[test]
+    fn velocity_without_smoothing() {
+        let mut velocity = QuadratureVelocity::new(1.0);
+        assert_eq!(velocity.update(100, 1_000), 0.0);
+        assert_eq!(velocity.update(150, 11_000), 5000.0);
+        assert_eq!(velocity.update(140, 21_000), -1000.0);
+        // A sample without time passing is ignored.
+        assert_eq!(velocity.update(500, 21_000), -1000.0);
+    }
+
+    #[test]
+    fn velocity_is_smoothed() {
+        let mut velocity = QuadratureVelocity::new(0.5);
+        velocity.update(0, 0);
+        assert_eq!(velocity.update(10, 10_000), 500.0);
+        assert_eq!(velocity.update(20, 20_000), 750.0);
+        assert_eq!(velocity.update(30, 30_000), 875.0);
+        assert_eq!(velocity.update(30, 40_000), 437.5);
+        assert_eq!(velocity.velocity(), 437.5);
+
+        velocity.reset();
+        assert_eq!(velocity.update(1000, 50_000), 0.0);
+        assert_eq!(velocity.update(1010, 60_000), 500.0);
+    }
+
+    #[test]
+    fn velocity_across_counter_wrap() {
+        let mut velocity = QuadratureVelocity::new(1.0);
+        velocity.update(i32::max_value() - 4, 0);
+        assert_eq!(velocity.update(i32::min_value() + 5, 1_000_000), 10.0);
+    }
+}
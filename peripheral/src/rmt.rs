@@ -0,0 +1,307 @@
+use core::convert::Into;
+use core::ptr;
+use core::slice;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+use idf::IdfError;
+
+use freertos_rs::*;
+
+use crate::gpio::*;
+use crate::rmt_codec::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum RmtError {
+    Generic,
+    IdfError(IdfError),
+    // Nothing was received in time.
+    Timeout,
+    // The durations of a protocol cannot be represented with the tick length of the channel.
+    InvalidTiming,
+}
+
+impl From<IdfError> for RmtError {
+    fn from(err: IdfError) -> RmtError {
+        RmtError::IdfError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RmtChannel {
+    Channel0,
+    Channel1,
+    Channel2,
+    Channel3,
+    Channel4,
+    Channel5,
+    Channel6,
+    Channel7,
+}
+
+impl Into<idf::rmt_channel_t> for RmtChannel {
+    fn into(self) -> idf::rmt_channel_t {
+        idf::rmt_channel_t_RMT_CHANNEL_0 + match self {
+            RmtChannel::Channel0 => 0,
+            RmtChannel::Channel1 => 1,
+            RmtChannel::Channel2 => 2,
+            RmtChannel::Channel3 => 3,
+            RmtChannel::Channel4 => 4,
+            RmtChannel::Channel5 => 5,
+            RmtChannel::Channel6 => 6,
+            RmtChannel::Channel7 => 7,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RmtCarrier {
+    pub frequency_hz: u32,
+    pub duty_percent: u8,
+    // The level modulated with the carrier.
+    pub level: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RmtTxConfig {
+    pub channel: RmtChannel,
+    pub pin: GpioPin,
+    // Divider of the 80MHz APB clock (1-255).
+    pub clock_divider: u8,
+    // Number of 64 item memory blocks. A channel can take the blocks of the following channels.
+    pub mem_blocks: u8,
+    pub carrier: Option<RmtCarrier>,
+    // Level output while idle. None leaves the output floating.
+    pub idle_level: Option<bool>,
+    // Repeat the items until stopped.
+    pub loop_enabled: bool,
+}
+
+impl RmtTxConfig {
+    pub fn new(channel: RmtChannel, pin: GpioPin) -> RmtTxConfig {
+        RmtTxConfig {
+            channel: channel,
+            pin: pin,
+            clock_divider: 80,
+            mem_blocks: 1,
+            carrier: None,
+            idle_level: Some(false),
+            loop_enabled: false,
+        }
+    }
+}
+
+impl Into<idf::rmt_config_t> for RmtTxConfig {
+    fn into(self) -> idf::rmt_config_t {
+        let mut config = idf::rmt_config_t::default();
+        config.rmt_mode = idf::rmt_mode_t_RMT_MODE_TX;
+        config.channel = self.channel.into();
+        config.clk_div = self.clock_divider;
+        config.gpio_num = self.pin.number() as idf::gpio_num_t;
+        config.mem_block_num = self.mem_blocks;
+        let mut tx_config = idf::rmt_tx_config_t::default();
+        tx_config.loop_en = self.loop_enabled;
+        if let Some(carrier) = self.carrier {
+            tx_config.carrier_en = true;
+            tx_config.carrier_freq_hz = carrier.frequency_hz;
+            tx_config.carrier_duty_percent = carrier.duty_percent;
+            tx_config.carrier_level = if carrier.level { idf::rmt_carrier_level_t_RMT_CARRIER_LEVEL_HIGH } else { idf::rmt_carrier_level_t_RMT_CARRIER_LEVEL_LOW };
+        }
+        if let Some(level) = self.idle_level {
+            tx_config.idle_output_en = true;
+            tx_config.idle_level = if level { idf::rmt_idle_level_t_RMT_IDLE_LEVEL_HIGH } else { idf::rmt_idle_level_t_RMT_IDLE_LEVEL_LOW };
+        }
+        unsafe { *config.__bindgen_anon_1.tx_config.as_mut() = tx_config; }
+        config
+    }
+}
+
+pub struct RmtTx {
+    channel: RmtChannel,
+    tick_ps: u32,
+}
+
+impl RmtTx {
+    pub fn new(config: RmtTxConfig) -> Result<RmtTx, RmtError> {
+        let idf_config: idf::rmt_config_t = config.into();
+        unsafe {
+            idf::rmt_config(&idf_config).as_result()?;
+            idf::rmt_driver_install(config.channel.into(), 0, 0).as_result()?;
+        }
+        Ok(RmtTx { channel: config.channel, tick_ps: rmt_tick_ps(config.clock_divider) })
+    }
+
+    // Length of a tick in picoseconds.
+    pub fn tick_ps(&self) -> u32 { self.tick_ps }
+
+    // Start sending the items. With `wait`, returns after the transmission completes.
+    pub fn write(&mut self, items: &[RmtItem], wait: bool) -> Result<(), RmtError> {
+        unsafe {
+            idf::rmt_write_items(self.channel.into(), items.as_ptr() as *const idf::rmt_item32_t, items.len() as i32, wait).as_result()?;
+        }
+        Ok(())
+    }
+
+    pub fn wait_done(&mut self, wait_ticks: Duration) -> Result<(), RmtError> {
+        match unsafe { idf::rmt_wait_tx_done(self.channel.into(), wait_ticks.to_ticks()).as_result() } {
+            Ok(_) => Ok(()),
+            Err(err) if err.code() == idf::ESP_ERR_TIMEOUT => Err(RmtError::Timeout),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn stop(&mut self) -> Result<(), RmtError> {
+        unsafe { idf::rmt_tx_stop(self.channel.into()).as_result()?; }
+        Ok(())
+    }
+}
+
+impl Drop for RmtTx {
+    fn drop(&mut self) {
+        unsafe {
+            idf::rmt_driver_uninstall(self.channel.into());
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RmtRxConfig {
+    pub channel: RmtChannel,
+    pub pin: GpioPin,
+    // Divider of the 80MHz APB clock (1-255).
+    pub clock_divider: u8,
+    pub mem_blocks: u8,
+    // Ignore pulses shorter than this number of APB clock cycles.
+    pub filter_ticks: Option<u8>,
+    // A level held longer than this number of RMT ticks ends the reception.
+    pub idle_threshold: u16,
+    // Size of the ring buffer holding received frames, in bytes.
+    pub rx_buffer_size: usize,
+}
+
+impl RmtRxConfig {
+    pub fn new(channel: RmtChannel, pin: GpioPin) -> RmtRxConfig {
+        RmtRxConfig {
+            channel: channel,
+            pin: pin,
+            clock_divider: 80,
+            mem_blocks: 1,
+            filter_ticks: Some(100),
+            idle_threshold: 12000,
+            rx_buffer_size: 1024,
+        }
+    }
+}
+
+impl Into<idf::rmt_config_t> for RmtRxConfig {
+    fn into(self) -> idf::rmt_config_t {
+        let mut config = idf::rmt_config_t::default();
+        config.rmt_mode = idf::rmt_mode_t_RMT_MODE_RX;
+        config.channel = self.channel.into();
+        config.clk_div = self.clock_divider;
+        config.gpio_num = self.pin.number() as idf::gpio_num_t;
+        config.mem_block_num = self.mem_blocks;
+        let mut rx_config = idf::rmt_rx_config_t::default();
+        if let Some(filter_ticks) = self.filter_ticks {
+            rx_config.filter_en = true;
+            rx_config.filter_ticks_thresh = filter_ticks;
+        }
+        rx_config.idle_threshold = self.idle_threshold;
+        unsafe { *config.__bindgen_anon_1.rx_config.as_mut() = rx_config; }
+        config
+    }
+}
+
+pub struct RmtRx {
+    channel: RmtChannel,
+    tick_ps: u32,
+    ring_buffer: idf::RingbufHandle_t,
+}
+
+unsafe impl Send for RmtRx {}
+
+impl RmtRx {
+    // Install the receiver. Reception starts with start().
+    pub fn new(config: RmtRxConfig) -> Result<RmtRx, RmtError> {
+        let idf_config: idf::rmt_config_t = config.into();
+        let mut ring_buffer: idf::RingbufHandle_t = ptr::null_mut();
+        unsafe {
+            idf::rmt_config(&idf_config).as_result()?;
+            idf::rmt_driver_install(config.channel.into(), config.rx_buffer_size, 0).as_result()?;
+            if let Err(err) = idf::rmt_get_ringbuf_handle(config.channel.into(), &mut ring_buffer).as_result() {
+                idf::rmt_driver_uninstall(config.channel.into());
+                return Err(err.into());
+            }
+        }
+        Ok(RmtRx { channel: config.channel, tick_ps: rmt_tick_ps(config.clock_divider), ring_buffer: ring_buffer })
+    }
+
+    // Length of a tick in picoseconds.
+    pub fn tick_ps(&self) -> u32 { self.tick_ps }
+
+    pub fn start(&mut self) -> Result<(), RmtError> {
+        unsafe { idf::rmt_rx_start(self.channel.into(), true).as_result()?; }
+        Ok(())
+    }
+    pub fn stop(&mut self) -> Result<(), RmtError> {
+        unsafe { idf::rmt_rx_stop(self.channel.into()).as_result()?; }
+        Ok(())
+    }
+
+    // Wait for the next received frame, i.e. the items up to an idle period.
+    pub fn receive(&mut self, wait_ticks: Duration) -> Result<Vec<RmtItem>, RmtError> {
+        let mut size: usize = 0;
+        unsafe {
+            let data = idf::xRingbufferReceive(self.ring_buffer, &mut size, wait_ticks.to_ticks());
+            if data.is_null() {
+                return Err(RmtError::Timeout);
+            }
+            let items = slice::from_raw_parts(data as *const RmtItem, size / 4).to_vec();
+            idf::vRingbufferReturnItem(self.ring_buffer, data);
+            Ok(items)
+        }
+    }
+}
+
+impl Drop for RmtRx {
+    fn drop(&mut self) {
+        unsafe {
+            idf::rmt_rx_stop(self.channel.into());
+            idf::rmt_driver_uninstall(self.channel.into());
+        }
+    }
+}
+
+// A strip of WS2812/SK6812 LEDs driven by an RMT channel.
+pub struct Ws2812 {
+    tx: RmtTx,
+    encoder: Ws2812Encoder,
+    items: Vec<RmtItem>,
+}
+
+impl Ws2812 {
+    pub fn new(channel: RmtChannel, pin: GpioPin, timing: Ws2812Timing) -> Result<Ws2812, RmtError> {
+        // 25ns ticks resolve the 50ns tolerance of the LEDs.
+        let config = RmtTxConfig { clock_divider: 2, ..RmtTxConfig::new(channel, pin) };
+        let tx = RmtTx::new(config)?;
+        let encoder = Ws2812Encoder::new(timing, tx.tick_ps()).ok_or(RmtError::InvalidTiming)?;
+        Ok(Ws2812 { tx: tx, encoder: encoder, items: Vec::new() })
+    }
+
+    // Send [r, g, b] pixels and wait until they are latched.
+    pub fn write_rgb(&mut self, pixels: &[[u8; 3]]) -> Result<(), RmtError> {
+        self.items.clear();
+        self.encoder.encode_rgb(pixels, &mut self.items);
+        self.tx.write(&self.items, true)
+    }
+    // Send [r, g, b, w] pixels of SK6812 RGBW LEDs and wait until they are latched.
+    pub fn write_rgbw(&mut self, pixels: &[[u8; 4]]) -> Result<(), RmtError> {
+        self.items.clear();
+        self.encoder.encode_rgbw(pixels, &mut self.items);
+        self.tx.write(&self.items, true)
+    }
+}
@@ -0,0 +1,406 @@
+// Encoders and decoders of RMT symbols for WS2812/SK6812 LEDs and the NEC infrared protocol.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+pub const RMT_ITEM_MAX_DURATION: u16 = 0x7fff;
+
+// An RMT symbol: two (level, duration) pairs with durations in RMT ticks.
+// Same layout as rmt_item32_t: duration0 [14:0], level0 [15], duration1 [30:16], level1 [31].
+// A duration of 0 ends the transmission.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct RmtItem(pub u32);
+
+impl RmtItem {
+    pub fn new(level0: bool, duration0: u16, level1: bool, duration1: u16) -> RmtItem {
+        RmtItem(
+            ((duration0 & RMT_ITEM_MAX_DURATION) as u32)
+            | ((level0 as u32) << 15)
+            | (((duration1 & RMT_ITEM_MAX_DURATION) as u32) << 16)
+            | ((level1 as u32) << 31))
+    }
+    pub fn level0(&self) -> bool { self.0 & (1 << 15) != 0 }
+    pub fn duration0(&self) -> u16 { (self.0 & 0x7fff) as u16 }
+    pub fn level1(&self) -> bool { self.0 & (1 << 31) != 0 }
+    pub fn duration1(&self) -> u16 { ((self.0 >> 16) & 0x7fff) as u16 }
+}
+
+const RMT_SOURCE_CLOCK_HZ: u64 = 80_000_000;
+
+// Length of an RMT tick in picoseconds for the clock divider, e.g. 12500 for a divider of 1.
+// Nanoseconds would truncate the 12.5ns of the 80MHz source clock.
+pub fn rmt_tick_ps(clock_divider: u8) -> u32 {
+    (clock_divider as u64 * 1_000_000_000_000 / RMT_SOURCE_CLOCK_HZ) as u32
+}
+
+// Convert nanoseconds to RMT ticks of `tick_ps` picoseconds, rounded to the nearest tick.
+// Returns None if the tick length is 0, if a non-zero duration rounds to 0 ticks (which would end the
+// transmission), or if the duration does not fit into RMT_ITEM_MAX_DURATION ticks.
+pub fn rmt_ticks_from_ns(duration_ns: u32, tick_ps: u32) -> Option<u16> {
+    if tick_ps == 0 {
+        return None;
+    }
+    let ticks = (duration_ns as u64 * 1000 + tick_ps as u64 / 2) / tick_ps as u64;
+    if (ticks == 0 && duration_ns != 0) || ticks > RMT_ITEM_MAX_DURATION as u64 {
+        None
+    }
+    else {
+        Some(ticks as u16)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ws2812Timing {
+    pub t0h_ns: u32,
+    pub t0l_ns: u32,
+    pub t1h_ns: u32,
+    pub t1l_ns: u32,
+    // Low period which latches the data.
+    pub reset_ns: u32,
+}
+
+pub const WS2812_TIMING: Ws2812Timing = Ws2812Timing { t0h_ns: 400, t0l_ns: 850, t1h_ns: 800, t1l_ns: 450, reset_ns: 50_000 };
+pub const SK6812_TIMING: Ws2812Timing = Ws2812Timing { t0h_ns: 300, t0l_ns: 900, t1h_ns: 600, t1l_ns: 600, reset_ns: 80_000 };
+
+// Encode the bytes of WS2812 style LEDs, MSB first. Pixels are sent in GRB (or GRBW) order.
+#[derive(Copy, Clone, Debug)]
+pub struct Ws2812Encoder {
+    bit0: RmtItem,
+    bit1: RmtItem,
+    reset: RmtItem,
+}
+
+impl Ws2812Encoder {
+    // Returns None if a period of the timing cannot be represented with ticks of `tick_ps` picoseconds.
+    pub fn new(timing: Ws2812Timing, tick_ps: u32) -> Option<Ws2812Encoder> {
+        let ticks = |duration_ns| rmt_ticks_from_ns(duration_ns, tick_ps);
+        let reset_ticks = ticks(timing.reset_ns / 2)?;
+        Some(Ws2812Encoder {
+            bit0: RmtItem::new(true, ticks(timing.t0h_ns)?, false, ticks(timing.t0l_ns)?),
+            bit1: RmtItem::new(true, ticks(timing.t1h_ns)?, false, ticks(timing.t1l_ns)?),
+            reset: RmtItem::new(false, reset_ticks, false, reset_ticks),
+        })
+    }
+
+    pub fn encode_byte(&self, value: u8) -> [RmtItem; 8] {
+        let mut items = [self.bit0; 8];
+        for bit in 0..8 {
+            if value & (0x80 >> bit) != 0 {
+                items[bit] = self.bit1;
+            }
+        }
+        items
+    }
+
+    pub fn encode_bytes(&self, bytes: &[u8], items: &mut Vec<RmtItem>) {
+        for byte in bytes {
+            items.extend_from_slice(&self.encode_byte(*byte));
+        }
+    }
+
+    // Encode [r, g, b] pixels followed by the reset period.
+    pub fn encode_rgb(&self, pixels: &[[u8; 3]], items: &mut Vec<RmtItem>) {
+        for pixel in pixels {
+            self.encode_bytes(&[pixel[1], pixel[0], pixel[2]], items);
+        }
+        items.push(self.reset);
+    }
+
+    // Encode [r, g, b, w] pixels of SK6812 RGBW LEDs followed by the reset period.
+    pub fn encode_rgbw(&self, pixels: &[[u8; 4]], items: &mut Vec<RmtItem>) {
+        for pixel in pixels {
+            self.encode_bytes(&[pixel[1], pixel[0], pixel[2], pixel[3]], items);
+        }
+        items.push(self.reset);
+    }
+}
+
+const NEC_LEADER_MARK_US: u32 = 9000;
+const NEC_LEADER_SPACE_US: u32 = 4500;
+const NEC_REPEAT_SPACE_US: u32 = 2250;
+const NEC_BIT_MARK_US: u32 = 560;
+const NEC_BIT0_SPACE_US: u32 = 560;
+const NEC_BIT1_SPACE_US: u32 = 1690;
+// Accepted deviation of the received durations in percent.
+const NEC_TOLERANCE_PERCENT: u32 = 25;
+
+pub const NEC_CARRIER_FREQUENCY_HZ: u32 = 38000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NecCommand {
+    // Address is 8bit unless the address byte is not followed by its complement (extended NEC).
+    Command { address: u16, command: u8 },
+    // The key of the last command is still held.
+    Repeat,
+}
+
+// Encoder and decoder of NEC frames. `mark_level` is the level of a carrier burst:
+// high for the transmitter, low for the usual demodulating receivers.
+#[derive(Copy, Clone, Debug)]
+pub struct NecCodec {
+    tick_ps: u32,
+    mark_level: bool,
+}
+
+impl NecCodec {
+    // Returns None if the durations of the protocol cannot be represented with ticks of `tick_ps` picoseconds,
+    // e.g. the 9ms leader (plus the tolerance) with a clock divider below 28.
+    pub fn new(tick_ps: u32, mark_level: bool) -> Option<NecCodec> {
+        let durations_us = [NEC_LEADER_MARK_US, NEC_LEADER_SPACE_US, NEC_REPEAT_SPACE_US, NEC_BIT_MARK_US, NEC_BIT0_SPACE_US, NEC_BIT1_SPACE_US];
+        for duration_us in durations_us.iter() {
+            // Including the tolerance of received durations.
+            rmt_ticks_from_ns(duration_us * (100 + NEC_TOLERANCE_PERCENT) / 100 * 1000, tick_ps)?;
+        }
+        Some(NecCodec { tick_ps: tick_ps, mark_level: mark_level })
+    }
+
+    // The durations of the protocol are checked by new().
+    fn ticks(&self, duration_us: u32) -> u16 {
+        rmt_ticks_from_ns(duration_us * 1000, self.tick_ps).unwrap_or(RMT_ITEM_MAX_DURATION)
+    }
+    fn item(&self, mark_us: u32, space_us: u32) -> RmtItem {
+        RmtItem::new(self.mark_level, self.ticks(mark_us), !self.mark_level, self.ticks(space_us))
+    }
+    fn matches(&self, ticks: u16, expected_us: u32) -> bool {
+        let expected = self.ticks(expected_us) as u32;
+        let margin = expected * NEC_TOLERANCE_PERCENT / 100;
+        let ticks = ticks as u32;
+        expected - margin <= ticks && ticks <= expected + margin
+    }
+    fn is_mark(&self, item: &RmtItem, mark_us: u32) -> bool {
+        item.level0() == self.mark_level && self.matches(item.duration0(), mark_us)
+    }
+    fn is_space(&self, item: &RmtItem, space_us: u32) -> bool {
+        item.level1() != self.mark_level && self.matches(item.duration1(), space_us)
+    }
+
+    pub fn encode(&self, command: NecCommand, items: &mut Vec<RmtItem>) {
+        match command {
+            NecCommand::Command { address, command } => {
+                let address_bytes = if address <= 0xff { [address as u8, !(address as u8)] } else { [address as u8, (address >> 8) as u8] };
+                items.push(self.item(NEC_LEADER_MARK_US, NEC_LEADER_SPACE_US));
+                for byte in &[address_bytes[0], address_bytes[1], command, !command] {
+                    for bit in 0..8 {
+                        let space_us = if byte & (1 << bit) != 0 { NEC_BIT1_SPACE_US } else { NEC_BIT0_SPACE_US };
+                        items.push(self.item(NEC_BIT_MARK_US, space_us));
+                    }
+                }
+            },
+            NecCommand::Repeat => {
+                items.push(self.item(NEC_LEADER_MARK_US, NEC_REPEAT_SPACE_US));
+            },
+        }
+        // Stop bit, ending the transmission.
+        items.push(RmtItem::new(self.mark_level, self.ticks(NEC_BIT_MARK_US), !self.mark_level, 0));
+    }
+
+    // Decode a received frame. Returns None for anything but a valid frame or repeat code.
+    pub fn decode(&self, items: &[RmtItem]) -> Option<NecCommand> {
+        let leader = items.first()?;
+        if !self.is_mark(leader, NEC_LEADER_MARK_US) {
+            return None;
+        }
+        if self.is_space(leader, NEC_REPEAT_SPACE_US) {
+            return Some(NecCommand::Repeat);
+        }
+        if !self.is_space(leader, NEC_LEADER_SPACE_US) || items.len() < 33 {
+            return None;
+        }
+        let mut value = 0u32;
+        for (bit, item) in items[1..33].iter().enumerate() {
+            if !self.is_mark(item, NEC_BIT_MARK_US) {
+                return None;
+            }
+            if self.is_space(item, NEC_BIT1_SPACE_US) {
+                value |= 1 << bit;
+            }
+            else if !self.is_space(item, NEC_BIT0_SPACE_US) {
+                // The space of the last bit runs into the idle period unless the stop bit follows.
+                if !(bit == 31 && item.duration1() == 0) {
+                    return None;
+                }
+            }
+        }
+        let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+        if bytes[2] != !bytes[3] {
+            return None;
+        }
+        let address = if bytes[0] == !bytes[1] { bytes[0] as u16 } else { (bytes[0] as u16) | ((bytes[1] as u16) << 8) };
+        Some(NecCommand::Command { address: address, command: bytes[2] })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const TICK_25NS: u32 = 25_000;
+    const TICK_1US: u32 = 1_000_000;
+
+    #[test]
+    fn tick_length() {
+        assert_eq!(rmt_tick_ps(1), 12_500);
+        assert_eq!(rmt_tick_ps(2), 25_000);
+        assert_eq!(rmt_tick_ps(80), 1_000_000);
+        assert_eq!(rmt_tick_ps(255), 3_187_500);
+    }
+
+    #[test]
+    fn ticks_are_rounded_and_checked() {
+        assert_eq!(rmt_ticks_from_ns(400, 12_500), Some(32));
+        assert_eq!(rmt_ticks_from_ns(406, 12_500), Some(32));
+        assert_eq!(rmt_ticks_from_ns(407, 12_500), Some(33));
+        assert_eq!(rmt_ticks_from_ns(0, 12_500), Some(0));
+        assert_eq!(rmt_ticks_from_ns(5, TICK_25NS), None);
+        assert_eq!(rmt_ticks_from_ns(13, TICK_25NS), Some(1));
+        assert_eq!(rmt_ticks_from_ns(400, 0), None);
+        assert_eq!(rmt_ticks_from_ns(32_767_000, TICK_1US), Some(RMT_ITEM_MAX_DURATION));
+        assert_eq!(rmt_ticks_from_ns(32_768_000, TICK_1US), None);
+    }
+
+    #[test]
+    fn ws2812_bit_timing() {
+        let encoder = Ws2812Encoder::new(WS2812_TIMING, TICK_25NS).unwrap();
+        let items = encoder.encode_byte(0xa5);
+        let bit0 = RmtItem::new(true, 16, false, 34);
+        let bit1 = RmtItem::new(true, 32, false, 18);
+        assert_eq!(items, [bit1, bit0, bit1, bit0, bit0, bit1, bit0, bit1]);
+    }
+
+    #[test]
+    fn sk6812_bit_timing() {
+        let encoder = Ws2812Encoder::new(SK6812_TIMING, TICK_25NS).unwrap();
+        let items = encoder.encode_byte(0x81);
+        assert_eq!(items[0], RmtItem::new(true, 24, false, 24));
+        assert_eq!(items[1], RmtItem::new(true, 12, false, 36));
+        assert_eq!(items[7], RmtItem::new(true, 24, false, 24));
+    }
+
+    #[test]
+    fn ws2812_pixels_are_sent_grb_with_reset() {
+        let encoder = Ws2812Encoder::new(WS2812_TIMING, TICK_25NS).unwrap();
+        let mut items = Vec::new();
+        encoder.encode_rgb(&[[0x01, 0x02, 0x03]], &mut items);
+        assert_eq!(items.len(), 25);
+        assert_eq!(&items[0..8], &encoder.encode_byte(0x02));
+        assert_eq!(&items[8..16], &encoder.encode_byte(0x01));
+        assert_eq!(&items[16..24], &encoder.encode_byte(0x03));
+        // 50us low, split over both halves of the item.
+        assert_eq!(items[24], RmtItem::new(false, 1000, false, 1000));
+
+        items.clear();
+        encoder.encode_rgbw(&[[0x01, 0x02, 0x03, 0x04]], &mut items);
+        assert_eq!(items.len(), 33);
+        assert_eq!(&items[24..32], &encoder.encode_byte(0x04));
+    }
+
+    #[test]
+    fn ws2812_rejects_coarse_ticks() {
+        // 3.1875us ticks cannot represent the 400ns high period.
+        assert!(Ws2812Encoder::new(WS2812_TIMING, rmt_tick_ps(255)).is_none());
+        assert!(Ws2812Encoder::new(WS2812_TIMING, 0).is_none());
+        // The reset period does not fit into an item with 12.5ns ticks if it is longer than 819us.
+        let timing = Ws2812Timing { reset_ns: 1_000_000, ..WS2812_TIMING };
+        assert!(Ws2812Encoder::new(timing, rmt_tick_ps(1)).is_none());
+    }
+
+    fn encode(codec: &NecCodec, command: NecCommand) -> Vec<RmtItem> {
+        let mut items = Vec::new();
+        codec.encode(command, &mut items);
+        items
+    }
+
+    // Items as seen by a receiver with the other mark level, with durations scaled by `percent`.
+    fn received(items: &[RmtItem], percent: u32) -> Vec<RmtItem> {
+        items.iter().map(|item| RmtItem::new(
+            !item.level0(), (item.duration0() as u32 * percent / 100) as u16,
+            !item.level1(), (item.duration1() as u32 * percent / 100) as u16)).collect()
+    }
+
+    #[test]
+    fn nec_frame_layout() {
+        let codec = NecCodec::new(TICK_1US, true).unwrap();
+        let items = encode(&codec, NecCommand::Command { address: 0x12, command: 0x34 });
+        assert_eq!(items.len(), 34);
+        assert_eq!(items[0], RmtItem::new(true, 9000, false, 4500));
+        // LSB first: address 0x12 starts with 0, 1.
+        assert_eq!(items[1], RmtItem::new(true, 560, false, 560));
+        assert_eq!(items[2], RmtItem::new(true, 560, false, 1690));
+        assert_eq!(items[33], RmtItem::new(true, 560, false, 0));
+    }
+
+    #[test]
+    fn nec_round_trip() {
+        let tx = NecCodec::new(TICK_1US, true).unwrap();
+        let rx = NecCodec::new(TICK_1US, false).unwrap();
+        let commands = [
+            NecCommand::Command { address: 0x12, command: 0x34 },
+            NecCommand::Command { address: 0x00, command: 0xff },
+            NecCommand::Command { address: 0x1234, command: 0x56 },
+            NecCommand::Repeat,
+        ];
+        for command in commands.iter() {
+            let items = encode(&tx, *command);
+            assert_eq!(tx.decode(&items), Some(*command));
+            assert_eq!(rx.decode(&received(&items, 100)), Some(*command));
+            // The receiver with the wrong mark level sees nothing.
+            assert_eq!(rx.decode(&items), None);
+        }
+    }
+
+    #[test]
+    fn nec_repeat_code() {
+        let codec = NecCodec::new(TICK_1US, true).unwrap();
+        let items = encode(&codec, NecCommand::Repeat);
+        assert_eq!(items, vec![RmtItem::new(true, 9000, false, 2250), RmtItem::new(true, 560, false, 0)]);
+        assert_eq!(codec.decode(&items[..1]), Some(NecCommand::Repeat));
+    }
+
+    #[test]
+    fn nec_tolerance() {
+        let codec = NecCodec::new(TICK_1US, false).unwrap();
+        let items = encode(&NecCodec::new(TICK_1US, true).unwrap(), NecCommand::Command { address: 0x5a, command: 0xc3 });
+        let command = Some(NecCommand::Command { address: 0x5a, command: 0xc3 });
+        assert_eq!(codec.decode(&received(&items, 80)), command);
+        assert_eq!(codec.decode(&received(&items, 120)), command);
+        assert_eq!(codec.decode(&received(&items, 70)), None);
+        assert_eq!(codec.decode(&received(&items, 130)), None);
+    }
+
+    #[test]
+    fn nec_last_space_runs_into_idle() {
+        let codec = NecCodec::new(TICK_1US, true).unwrap();
+        // !0x80 = 0x7f, so the last bit is a 0.
+        let mut items = encode(&codec, NecCommand::Command { address: 0x01, command: 0x80 });
+        items.truncate(33);
+        items[32] = RmtItem::new(true, 560, false, 0);
+        assert_eq!(codec.decode(&items), Some(NecCommand::Command { address: 0x01, command: 0x80 }));
+    }
+
+    #[test]
+    fn nec_rejects_invalid_frames() {
+        let codec = NecCodec::new(TICK_1US, true).unwrap();
+        let items = encode(&codec, NecCommand::Command { address: 0x01, command: 0x02 });
+        assert_eq!(codec.decode(&[]), None);
+        assert_eq!(codec.decode(&items[..20]), None);
+        // The command is not followed by its complement.
+        let mut corrupted = items.clone();
+        corrupted[17] = RmtItem::new(true, 560, false, 1690);
+        assert_eq!(codec.decode(&corrupted), None);
+        // A space which is neither a 0 nor a 1.
+        let mut corrupted = items.clone();
+        corrupted[5] = RmtItem::new(true, 560, false, 1100);
+        assert_eq!(codec.decode(&corrupted), None);
+    }
+
+    #[test]
+    fn nec_rejects_coarse_or_fine_ticks() {
+        assert!(NecCodec::new(0, true).is_none());
+        // The 9ms leader does not fit into an item with 12.5ns ticks.
+        assert!(NecCodec::new(rmt_tick_ps(1), true).is_none());
+        assert!(NecCodec::new(rmt_tick_ps(27), true).is_none());
+        assert!(NecCodec::new(rmt_tick_ps(28), true).is_some());
+    }
+}
@@ -0,0 +1,495 @@
+use core::ffi::c_void;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use idf;
+use idf::AsResult;
+use idf::IdfError;
+
+use freertos_rs::*;
+
+use crate::gpio::*;
+use crate::spi::*;
+use crate::spi_transaction::*;
+use crate::sd_spi::*;
+
+impl From<IdfError> for SdError {
+    fn from(err: IdfError) -> SdError {
+        SdError::Driver(err.code())
+    }
+}
+impl From<SpiError> for SdError {
+    fn from(err: SpiError) -> SdError {
+        match err {
+            SpiError::IdfError(err) => SdError::Driver(err.code()),
+            _ => SdError::Bus,
+        }
+    }
+}
+
+// Clock during card identification.
+pub const SD_SPI_INIT_CLOCK_HZ: i32 = 400000;
+// Size of the stack buffer used to split transfers into SPI transactions.
+const SD_SPI_CHUNK_SIZE: usize = 64;
+
+// Card in SPI mode on a shared SPI bus, e.g. the VSPI bus of the M5Stack LCD.
+// CS is driven by this driver so that it stays low from a command until its data is transferred.
+pub struct SdSpiDevice {
+    identification: SpiDeviceBusLock<()>,
+    data: SpiDeviceBusLock<()>,
+    cs: NormalGpio,
+    high_speed: bool,
+}
+
+impl SdSpiDevice {
+    // Add the card to `bus`. `clock_speed_hz` is used after identification, at most 25MHz.
+    pub fn new(bus: &mut SpiBus, pin_cs: GpioPin, clock_speed_hz: i32) -> Result<SdSpiDevice, SdError> {
+        let mut cs = pin_cs.normal();
+        cs.configure(GpioConfig::output())?;
+        cs.set_level(true)?;
+        let identification = bus.add_device(SpiDeviceInterfaceConfig {
+            clock_speed_hz: SD_SPI_INIT_CLOCK_HZ,
+            cs_pin: None,
+            ..Default::default()
+        }, (), ())?;
+        let data = bus.add_device(SpiDeviceInterfaceConfig {
+            clock_speed_hz: clock_speed_hz,
+            cs_pin: None,
+            ..Default::default()
+        }, (), ())?;
+        Ok(SdSpiDevice {
+            identification: identification,
+            data: data,
+            cs: cs,
+            high_speed: false,
+        })
+    }
+}
+
+struct SdSpiSession<'a> {
+    spi: &'a mut (SpiTransfer<()> + 'a),
+}
+
+impl<'a> SdSpiSession<'a> {
+    fn transfer_spi(&mut self, data: &mut [u8]) -> Result<(), SpiError> {
+        let mut tx = [0u8; SD_SPI_CHUNK_SIZE];
+        for chunk in data.chunks_mut(SD_SPI_CHUNK_SIZE) {
+            let tx = &mut tx[..chunk.len()];
+            tx.copy_from_slice(chunk);
+            self.spi.transfer(SpiTransaction::new_both(tx, chunk, ()))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> SdSpiBus for SdSpiSession<'a> {
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), SdError> {
+        self.transfer_spi(data).map_err(SdError::from)
+    }
+}
+
+impl SdSpiTransport for SdSpiDevice {
+    fn selected<R, F>(&mut self, f: F) -> Result<R, SdError>
+        where F: FnOnce(&mut SdSpiBus) -> Result<R, SdError>
+    {
+        let device = if self.high_speed { &self.data } else { &self.identification };
+        let cs = &mut self.cs;
+        device.transactions(|spi| {
+            let mut session = SdSpiSession { spi: spi };
+            cs.set_level(false)?;
+            let result = f(&mut session);
+            cs.set_level(true)?;
+            // The card releases MISO on the next clock after CS goes high.
+            session.transfer_spi(&mut [0xff])?;
+            Ok(result)
+        })?
+    }
+    fn idle_clocks(&mut self, bytes: usize) -> Result<(), SdError> {
+        let device = if self.high_speed { &self.data } else { &self.identification };
+        device.transactions(|spi| {
+            let mut session = SdSpiSession { spi: spi };
+            let mut remaining = bytes;
+            while remaining > 0 {
+                let mut idle = [0xffu8; SD_SPI_CHUNK_SIZE];
+                let length = remaining.min(SD_SPI_CHUNK_SIZE);
+                session.transfer_spi(&mut idle[..length])?;
+                remaining -= length;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+    fn set_high_speed(&mut self, high_speed: bool) -> Result<(), SdError> {
+        self.high_speed = high_speed;
+        Ok(())
+    }
+    fn delay_ms(&mut self, ms: u32) {
+        CurrentTask::delay(Duration::ms(ms));
+    }
+}
+
+const SDMMC_HOST_FLAG_1BIT: u32 = 1 << 0;
+const SDMMC_HOST_FLAG_4BIT: u32 = 1 << 1;
+const SDMMC_HOST_FLAG_8BIT: u32 = 1 << 2;
+const SDMMC_HOST_FLAG_DDR: u32 = 1 << 4;
+const SDMMC_SLOT_FLAG_INTERNAL_PULLUP: u32 = 1 << 0;
+const SDMMC_SLOT_NO_PIN: i32 = -1;
+pub const SDMMC_FREQ_DEFAULT_KHZ: u32 = 20000;
+pub const SDMMC_FREQ_HIGHSPEED_KHZ: u32 = 40000;
+const SD_OCR_SDHC_CAP: u32 = 1 << 30;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SdmmcSlot {
+    // GPIO6-11 and 16-17, usually connected to the flash. 8bit width.
+    Slot0,
+    // CLK GPIO14, CMD GPIO15, D0 GPIO2, D1 GPIO4, D2 GPIO12, D3 GPIO13. 4bit width.
+    Slot1,
+}
+impl SdmmcSlot {
+    fn index(&self) -> i32 {
+        match self {
+            SdmmcSlot::Slot0 => 0,
+            SdmmcSlot::Slot1 => 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SdmmcBusWidth {
+    Width1,
+    Width4,
+    Width8,
+}
+impl SdmmcBusWidth {
+    fn bits(&self) -> u8 {
+        match self {
+            SdmmcBusWidth::Width1 => 1,
+            SdmmcBusWidth::Width4 => 4,
+            SdmmcBusWidth::Width8 => 8,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SdmmcConfig {
+    pub slot: SdmmcSlot,
+    pub width: SdmmcBusWidth,
+    pub max_freq_khz: u32,
+    pub card_detect: Option<GpioPin>,
+    pub write_protect: Option<GpioPin>,
+    // Enable the internal pull-ups of CMD and the data lines. External 10k pull-ups are still recommended.
+    pub internal_pullups: bool,
+}
+
+impl Default for SdmmcConfig {
+    fn default() -> Self {
+        SdmmcConfig {
+            slot: SdmmcSlot::Slot1,
+            width: SdmmcBusWidth::Width4,
+            max_freq_khz: SDMMC_FREQ_DEFAULT_KHZ,
+            card_detect: None,
+            write_protect: None,
+            internal_pullups: false,
+        }
+    }
+}
+
+impl Into<idf::sdmmc_slot_config_t> for SdmmcConfig {
+    fn into(self) -> idf::sdmmc_slot_config_t {
+        idf::sdmmc_slot_config_t {
+            gpio_cd: self.card_detect.map_or(SDMMC_SLOT_NO_PIN, |pin| pin.number() as i32),
+            gpio_wp: self.write_protect.map_or(SDMMC_SLOT_NO_PIN, |pin| pin.number() as i32),
+            width: self.width.bits(),
+            flags: if self.internal_pullups { SDMMC_SLOT_FLAG_INTERNAL_PULLUP } else { 0 },
+        }
+    }
+}
+
+impl SdmmcConfig {
+    // Equivalent of SDMMC_HOST_DEFAULT() for the configured slot and frequency.
+    fn host(&self) -> idf::sdmmc_host_t {
+        let mut host = idf::sdmmc_host_t::default();
+        host.flags = SDMMC_HOST_FLAG_8BIT | SDMMC_HOST_FLAG_4BIT | SDMMC_HOST_FLAG_1BIT | SDMMC_HOST_FLAG_DDR;
+        host.slot = self.slot.index();
+        host.max_freq_khz = self.max_freq_khz as i32;
+        host.io_voltage = 3.3;
+        host.init = Some(idf::sdmmc_host_init);
+        host.set_bus_width = Some(idf::sdmmc_host_set_bus_width);
+        host.get_bus_width = Some(idf::sdmmc_host_get_slot_width);
+        host.set_bus_ddr_mode = Some(idf::sdmmc_host_set_bus_ddr_mode);
+        host.set_card_clk = Some(idf::sdmmc_host_set_card_clk);
+        host.do_transaction = Some(idf::sdmmc_host_do_transaction);
+        host.deinit = Some(idf::sdmmc_host_deinit);
+        host.io_int_enable = Some(idf::sdmmc_host_io_int_enable);
+        host.io_int_wait = Some(idf::sdmmc_host_io_int_wait);
+        host.command_timeout_ms = 0;
+        host
+    }
+}
+
+static SDMMC_HOST_IN_USE: AtomicBool = AtomicBool::new(false);
+
+// Card on the SDMMC host controller. Only one card can be opened at a time.
+pub struct SdmmcCard {
+    card: Box<idf::sdmmc_card_t>,
+    info: SdCardInfo,
+}
+
+impl SdmmcCard {
+    pub fn new(config: SdmmcConfig) -> Result<SdmmcCard, SdError> {
+        if SDMMC_HOST_IN_USE.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(SdError::Driver(idf::ESP_ERR_INVALID_STATE));
+        }
+        let host = config.host();
+        let slot_config: idf::sdmmc_slot_config_t = config.into();
+        let mut card = Box::new(idf::sdmmc_card_t::default());
+        let result = unsafe {
+            idf::sdmmc_host_init().as_result()
+                .and_then(|_| idf::sdmmc_host_init_slot(host.slot, &slot_config).as_result())
+                .and_then(|_| idf::sdmmc_card_init(&host, &mut *card).as_result().map_err(|err| {
+                    idf::sdmmc_host_deinit();
+                    err
+                }))
+        };
+        if let Err(err) = result {
+            SDMMC_HOST_IN_USE.store(false, Ordering::SeqCst);
+            return Err(err.into());
+        }
+        let info = SdmmcCard::card_info(&card);
+        Ok(SdmmcCard { card: card, info: info })
+    }
+
+    fn card_info(card: &idf::sdmmc_card_t) -> SdCardInfo {
+        let cid = unsafe { card.__bindgen_anon_1.cid.as_ref() };
+        let mut product_name = [0u8; 5];
+        for (name, c) in product_name.iter_mut().zip(cid.name.iter()) {
+            *name = *c as u8;
+        }
+        SdCardInfo {
+            version: if card.scr.sd_spec >= 2 { SdCardVersion::V2 } else { SdCardVersion::V1 },
+            high_capacity: (card.ocr & SD_OCR_SDHC_CAP) != 0,
+            block_count: (card.csd.capacity as u64 * card.csd.sector_size as u64 / SD_BLOCK_SIZE as u64) as u32,
+            cid: SdCid {
+                manufacturer_id: cid.mfg_id as u8,
+                oem_id: cid.oem_id as u16,
+                product_name: product_name,
+                revision: cid.revision as u8,
+                serial: cid.serial as u32,
+                manufacture_year: 2000 + (cid.date >> 4) as u16,
+                manufacture_month: (cid.date & 0xf) as u8,
+            },
+            // The SDMMC driver only keeps the decoded CSD.
+            csd: [0u8; 16],
+        }
+    }
+
+    // Maximum clock frequency of the card in kHz.
+    pub fn max_freq_khz(&self) -> u32 { self.card.max_freq_khz as u32 }
+
+    fn check_range(&self, block: u32, length: usize) -> Result<usize, SdError> {
+        if length % SD_BLOCK_SIZE != 0 {
+            return Err(SdError::InvalidBuffer);
+        }
+        let count = length / SD_BLOCK_SIZE;
+        if block as u64 + count as u64 > self.info.block_count as u64 {
+            return Err(SdError::OutOfRange);
+        }
+        Ok(count)
+    }
+}
+
+impl SdBlockDevice for SdmmcCard {
+    fn read_blocks(&mut self, block: u32, buffer: &mut [u8]) -> Result<(), SdError> {
+        let count = self.check_range(block, buffer.len())?;
+        if count == 0 {
+            return Ok(());
+        }
+        unsafe { idf::sdmmc_read_sectors(&mut *self.card, buffer.as_mut_ptr() as *mut c_void, block as usize, count).as_result()? };
+        Ok(())
+    }
+    fn write_blocks(&mut self, block: u32, buffer: &[u8]) -> Result<(), SdError> {
+        let count = self.check_range(block, buffer.len())?;
+        if count == 0 {
+            return Ok(());
+        }
+        unsafe { idf::sdmmc_write_sectors(&mut *self.card, buffer.as_ptr() as *const c_void, block as usize, count).as_result()? };
+        Ok(())
+    }
+    fn block_count(&self) -> u32 { self.info.block_count }
+    fn info(&self) -> &SdCardInfo { &self.info }
+}
+
+impl Drop for SdmmcCard {
+    fn drop(&mut self) {
+        unsafe { idf::sdmmc_host_deinit(); }
+        SDMMC_HOST_IN_USE.store(false, Ordering::SeqCst);
+    }
+}
+
+// Number of FatFs volumes (FF_VOLUMES) in the default configuration.
+const FAT_MAX_VOLUMES: usize = 2;
+const FAT_STA_NOINIT: idf::DSTATUS = 0x01;
+const FAT_CTRL_SYNC: u8 = 0;
+const FAT_GET_SECTOR_COUNT: u8 = 1;
+const FAT_GET_SECTOR_SIZE: u8 = 2;
+const FAT_GET_BLOCK_SIZE: u8 = 3;
+const FAT_FM_ANY: u8 = 0x07;
+// Work area for f_mkfs, at least FF_MAX_SS bytes.
+const FAT_MKFS_WORK_SIZE: usize = 4096;
+
+// Block devices of the mounted volumes, indexed by the FatFs drive number.
+// Only accessed by FatFilesystem and the diskio callbacks, which FatFs serializes per volume.
+static mut FAT_DEVICES: [Option<Box<SdBlockDevice + Send>>; FAT_MAX_VOLUMES] = [None, None];
+
+unsafe fn fat_device(pdrv: u8) -> Option<&'static mut Box<SdBlockDevice + Send>> {
+    FAT_DEVICES.get_mut(pdrv as usize).and_then(|device| device.as_mut())
+}
+
+unsafe extern "C" fn fat_disk_status(pdrv: u8) -> idf::DSTATUS {
+    if fat_device(pdrv).is_some() { 0 } else { FAT_STA_NOINIT }
+}
+unsafe extern "C" fn fat_disk_read(pdrv: u8, buffer: *mut u8, sector: u32, count: u32) -> idf::DRESULT {
+    match fat_device(pdrv) {
+        Some(device) => {
+            let buffer = slice::from_raw_parts_mut(buffer, count as usize * SD_BLOCK_SIZE);
+            match device.read_blocks(sector, buffer) {
+                Ok(_) => idf::DRESULT_RES_OK,
+                Err(SdError::OutOfRange) => idf::DRESULT_RES_PARERR,
+                Err(_) => idf::DRESULT_RES_ERROR,
+            }
+        },
+        None => idf::DRESULT_RES_NOTRDY,
+    }
+}
+unsafe extern "C" fn fat_disk_write(pdrv: u8, buffer: *const u8, sector: u32, count: u32) -> idf::DRESULT {
+    match fat_device(pdrv) {
+        Some(device) => {
+            let buffer = slice::from_raw_parts(buffer, count as usize * SD_BLOCK_SIZE);
+            match device.write_blocks(sector, buffer) {
+                Ok(_) => idf::DRESULT_RES_OK,
+                Err(SdError::OutOfRange) => idf::DRESULT_RES_PARERR,
+                Err(_) => idf::DRESULT_RES_ERROR,
+            }
+        },
+        None => idf::DRESULT_RES_NOTRDY,
+    }
+}
+unsafe extern "C" fn fat_disk_ioctl(pdrv: u8, command: u8, buffer: *mut c_void) -> idf::DRESULT {
+    match fat_device(pdrv) {
+        Some(device) => match command {
+            // Blocks are written through to the card.
+            FAT_CTRL_SYNC => idf::DRESULT_RES_OK,
+            FAT_GET_SECTOR_COUNT => {
+                *(buffer as *mut u32) = device.block_count();
+                idf::DRESULT_RES_OK
+            },
+            FAT_GET_SECTOR_SIZE => {
+                *(buffer as *mut u16) = SD_BLOCK_SIZE as u16;
+                idf::DRESULT_RES_OK
+            },
+            FAT_GET_BLOCK_SIZE => {
+                // Erase block size is unknown.
+                *(buffer as *mut u32) = 1;
+                idf::DRESULT_RES_OK
+            },
+            _ => idf::DRESULT_RES_PARERR,
+        },
+        None => idf::DRESULT_RES_NOTRDY,
+    }
+}
+
+static FAT_DISKIO: idf::ff_diskio_impl_t = idf::ff_diskio_impl_t {
+    init: Some(fat_disk_status),
+    status: Some(fat_disk_status),
+    read: Some(fat_disk_read),
+    write: Some(fat_disk_write),
+    ioctl: Some(fat_disk_ioctl),
+};
+
+#[derive(Copy, Clone, Debug)]
+pub struct FatMountConfig {
+    pub max_files: usize,
+    // Create a new FAT filesystem if the card has none.
+    pub format_if_mount_failed: bool,
+}
+
+impl Default for FatMountConfig {
+    fn default() -> Self {
+        FatMountConfig {
+            max_files: 4,
+            format_if_mount_failed: false,
+        }
+    }
+}
+
+// FAT filesystem on a block device, registered in the VFS at a base path such as "/sdcard".
+// Files are then accessed with the C library, e.g. fopen("/sdcard/log.txt").
+pub struct FatFilesystem {
+    drive: [u8; 3],
+    base_path: Vec<u8>,
+    fs: *mut idf::FATFS,
+}
+
+unsafe impl Send for FatFilesystem {}
+
+impl FatFilesystem {
+    pub fn mount<TDevice>(device: TDevice, base_path: &str, config: FatMountConfig) -> Result<FatFilesystem, SdError>
+        where TDevice: SdBlockDevice + Send + 'static
+    {
+        let mut pdrv: u8 = 0xff;
+        unsafe { idf::ff_diskio_get_drive(&mut pdrv).as_result()? };
+        if pdrv as usize >= FAT_MAX_VOLUMES {
+            return Err(SdError::Driver(idf::ESP_ERR_INVALID_STATE));
+        }
+        let mut base_path_c = Vec::with_capacity(base_path.len() + 1);
+        base_path_c.extend_from_slice(base_path.as_bytes());
+        base_path_c.push(0);
+        let mut filesystem = FatFilesystem {
+            drive: [b'0' + pdrv, b':', 0],
+            base_path: base_path_c,
+            fs: ptr::null_mut(),
+        };
+        unsafe {
+            FAT_DEVICES[pdrv as usize] = Some(Box::new(device));
+            idf::ff_diskio_register(pdrv, &FAT_DISKIO);
+            // From here, Drop releases whatever has been registered.
+            idf::esp_vfs_fat_register(filesystem.base_path.as_ptr() as *const _, filesystem.drive.as_ptr() as *const _, config.max_files, &mut filesystem.fs).as_result()?;
+            let mut result = idf::f_mount(filesystem.fs, filesystem.drive.as_ptr() as *const _, 1);
+            if result == idf::FRESULT_FR_NO_FILESYSTEM && config.format_if_mount_failed {
+                let mut work = Vec::new();
+                work.resize(FAT_MKFS_WORK_SIZE, 0u8);
+                result = idf::f_mkfs(filesystem.drive.as_ptr() as *const _, FAT_FM_ANY, 0, work.as_mut_ptr() as *mut c_void, work.len() as u32);
+                if result == idf::FRESULT_FR_OK {
+                    result = idf::f_mount(filesystem.fs, filesystem.drive.as_ptr() as *const _, 1);
+                }
+            }
+            if result != idf::FRESULT_FR_OK {
+                return Err(SdError::Fat(result as u32));
+            }
+        }
+        Ok(filesystem)
+    }
+
+    pub fn base_path(&self) -> &str {
+        core::str::from_utf8(&self.base_path[..self.base_path.len() - 1]).unwrap()
+    }
+}
+
+impl Drop for FatFilesystem {
+    fn drop(&mut self) {
+        let pdrv = self.drive[0] - b'0';
+        unsafe {
+            if !self.fs.is_null() {
+                idf::f_mount(ptr::null_mut(), self.drive.as_ptr() as *const _, 0);
+                idf::esp_vfs_fat_unregister_path(self.base_path.as_ptr() as *const _);
+            }
+            idf::ff_diskio_register(pdrv, ptr::null());
+            FAT_DEVICES[pdrv as usize] = None;
+        }
+    }
+}
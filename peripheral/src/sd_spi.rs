@@ -0,0 +1,1031 @@
+// SD card protocol in SPI mode, card information and SdCardEmulator, a card without hardware for checking the protocol.
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+pub const SD_BLOCK_SIZE: usize = 512;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SdError {
+    // The underlying SPI bus failed.
+    Bus,
+    // The card did not answer a command.
+    NoResponse,
+    // The card answered a command with the given R1 error bits.
+    Command { command: u8, response: u8 },
+    // The card does not support 3.3V or is not an SD card.
+    UnsupportedCard,
+    // The card did not leave the idle state.
+    InitTimeout,
+    // No data block was received.
+    DataTimeout,
+    // The card sent the given data error token instead of a data block.
+    DataError(u8),
+    // The card rejected a written block with the given data response.
+    WriteRejected(u8),
+    // The card stayed busy after a write.
+    BusyTimeout,
+    // The blocks are beyond the end of the card.
+    OutOfRange,
+    // The buffer length is not a multiple of SD_BLOCK_SIZE.
+    InvalidBuffer,
+    // An error code (esp_err_t) of the IDF SDMMC or VFS driver.
+    Driver(i32),
+    // A result code (FRESULT) of FatFs.
+    Fat(u32),
+}
+
+// Block device on an SD card, implemented by SdSpiCard and SdmmcCard.
+pub trait SdBlockDevice {
+    // Read `buffer.len() / SD_BLOCK_SIZE` blocks starting at `block`.
+    fn read_blocks(&mut self, block: u32, buffer: &mut [u8]) -> Result<(), SdError>;
+    // Write `buffer.len() / SD_BLOCK_SIZE` blocks starting at `block`.
+    fn write_blocks(&mut self, block: u32, buffer: &[u8]) -> Result<(), SdError>;
+    fn block_count(&self) -> u32;
+    fn info(&self) -> &SdCardInfo;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SdCardVersion {
+    // SD version 1.x standard capacity card.
+    V1,
+    // SD version 2.0 or later card, standard (SDSC) or high capacity (SDHC/SDXC).
+    V2,
+}
+
+// Card identification register.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SdCid {
+    pub manufacturer_id: u8,
+    pub oem_id: u16,
+    pub product_name: [u8; 5],
+    pub revision: u8,
+    pub serial: u32,
+    pub manufacture_year: u16,
+    pub manufacture_month: u8,
+}
+
+impl SdCid {
+    pub fn parse(cid: &[u8; 16]) -> SdCid {
+        let mut product_name = [0u8; 5];
+        product_name.copy_from_slice(&cid[3..8]);
+        let date = register_bits(cid, 19, 8);
+        SdCid {
+            manufacturer_id: cid[0],
+            oem_id: register_bits(cid, 119, 104) as u16,
+            product_name: product_name,
+            revision: cid[8],
+            serial: register_bits(cid, 55, 24),
+            manufacture_year: 2000 + (date >> 4) as u16,
+            manufacture_month: (date & 0xf) as u8,
+        }
+    }
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut cid = [0u8; 16];
+        cid[0] = self.manufacturer_id;
+        set_register_bits(&mut cid, 119, 104, self.oem_id as u32);
+        cid[3..8].copy_from_slice(&self.product_name);
+        cid[8] = self.revision;
+        set_register_bits(&mut cid, 55, 24, self.serial);
+        set_register_bits(&mut cid, 19, 8, ((self.manufacture_year.saturating_sub(2000) as u32) << 4) | (self.manufacture_month as u32 & 0xf));
+        cid[15] = (sd_crc7(&cid[..15]) << 1) | 1;
+        cid
+    }
+}
+
+// Number of 512 byte blocks described by a CSD register, None for unknown structure versions.
+pub fn sd_csd_block_count(csd: &[u8; 16]) -> Option<u32> {
+    match register_bits(csd, 127, 126) {
+        0 => {
+            let c_size = register_bits(csd, 73, 62);
+            let c_size_mult = register_bits(csd, 49, 47);
+            let read_bl_len = register_bits(csd, 83, 80);
+            let bytes = ((c_size as u64 + 1) << (c_size_mult + 2)) << read_bl_len;
+            Some((bytes / SD_BLOCK_SIZE as u64) as u32)
+        },
+        1 => Some((register_bits(csd, 69, 48) + 1) * 1024),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SdCardInfo {
+    pub version: SdCardVersion,
+    // Blocks are addressed by block number instead of byte offset.
+    pub high_capacity: bool,
+    pub block_count: u32,
+    pub cid: SdCid,
+    pub csd: [u8; 16],
+}
+
+impl SdCardInfo {
+    pub fn capacity_bytes(&self) -> u64 { self.block_count as u64 * SD_BLOCK_SIZE as u64 }
+}
+
+// Bits [msb:lsb] of a big endian 128bit register (CID/CSD).
+fn register_bits(register: &[u8; 16], msb: usize, lsb: usize) -> u32 {
+    let mut value = 0u32;
+    for bit in (lsb..msb + 1).rev() {
+        let byte = register[15 - bit / 8];
+        value = (value << 1) | ((byte >> (bit % 8)) & 1) as u32;
+    }
+    value
+}
+fn set_register_bits(register: &mut [u8; 16], msb: usize, lsb: usize, value: u32) {
+    for bit in lsb..msb + 1 {
+        let mask = 1u8 << (bit % 8);
+        if (value >> (bit - lsb)) & 1 != 0 {
+            register[15 - bit / 8] |= mask;
+        } else {
+            register[15 - bit / 8] &= !mask;
+        }
+    }
+}
+
+// CRC7 of commands and the CID/CSD registers (x^7 + x^3 + 1).
+pub fn sd_crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            crc <<= 1;
+            if ((byte ^ crc) & 0x80) != 0 {
+                crc ^= 0x09;
+            }
+            byte <<= 1;
+        }
+    }
+    crc & 0x7f
+}
+
+// CRC16-CCITT of data blocks (x^16 + x^12 + x^5 + 1).
+pub fn sd_crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if (crc & 0x8000) != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+const CMD_GO_IDLE_STATE: u8 = 0;
+const CMD_SEND_IF_COND: u8 = 8;
+const CMD_SEND_CSD: u8 = 9;
+const CMD_SEND_CID: u8 = 10;
+const CMD_STOP_TRANSMISSION: u8 = 12;
+const CMD_SEND_STATUS: u8 = 13;
+const CMD_SET_BLOCKLEN: u8 = 16;
+const CMD_READ_SINGLE_BLOCK: u8 = 17;
+const CMD_READ_MULTIPLE_BLOCK: u8 = 18;
+const CMD_WRITE_BLOCK: u8 = 24;
+const CMD_WRITE_MULTIPLE_BLOCK: u8 = 25;
+const CMD_APP_CMD: u8 = 55;
+const CMD_READ_OCR: u8 = 58;
+const CMD_CRC_ON_OFF: u8 = 59;
+const ACMD_SD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_CRC_ERROR: u8 = 0x08;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+const TOKEN_START_BLOCK: u8 = 0xfe;
+const TOKEN_START_MULTIPLE_WRITE: u8 = 0xfc;
+const TOKEN_STOP_TRANSMISSION: u8 = 0xfd;
+const DATA_RESPONSE_ACCEPTED: u8 = 0x05;
+const DATA_RESPONSE_CRC_ERROR: u8 = 0x0b;
+const DATA_RESPONSE_WRITE_ERROR: u8 = 0x0d;
+const DATA_ERROR_OUT_OF_RANGE: u8 = 0x08;
+
+const OCR_CCS: u32 = 1 << 30;
+const OCR_POWER_UP: u32 = 1 << 31;
+const OCR_VOLTAGE_3V3: u32 = 0x0030_0000;
+const IF_COND_CHECK: u32 = 0x1aa;
+
+const COMMAND_RESPONSE_ATTEMPTS: usize = 16;
+const GO_IDLE_ATTEMPTS: usize = 10;
+const OP_COND_ATTEMPTS: usize = 1000;
+const DATA_TOKEN_ATTEMPTS: usize = 100_000;
+const BUSY_ATTEMPTS: usize = 500_000;
+
+// Byte stream to the card while it is selected.
+pub trait SdSpiBus {
+    // Send `data` and replace it with the bytes received at the same time.
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), SdError>;
+}
+
+// Connection to a card in SPI mode.
+pub trait SdSpiTransport {
+    // Run `f` with the card selected. The bus must not be used by other devices until `f` returns.
+    fn selected<R, F>(&mut self, f: F) -> Result<R, SdError>
+        where F: FnOnce(&mut SdSpiBus) -> Result<R, SdError>;
+    // Send `bytes` bytes of 0xff with the card deselected.
+    fn idle_clocks(&mut self, bytes: usize) -> Result<(), SdError>;
+    // Switch between the 400kHz identification clock and the data transfer clock.
+    fn set_high_speed(&mut self, high_speed: bool) -> Result<(), SdError>;
+    fn delay_ms(&mut self, ms: u32);
+}
+
+fn read_byte(bus: &mut SdSpiBus) -> Result<u8, SdError> {
+    let mut byte = [0xffu8];
+    bus.transfer(&mut byte)?;
+    Ok(byte[0])
+}
+
+// Send a command and return its R1 response. The rest of a longer response follows on the bus.
+fn send_command(bus: &mut SdSpiBus, command: u8, argument: u32) -> Result<u8, SdError> {
+    let mut frame = [
+        0x40 | command,
+        (argument >> 24) as u8,
+        (argument >> 16) as u8,
+        (argument >> 8) as u8,
+        argument as u8,
+        0,
+    ];
+    frame[5] = (sd_crc7(&frame[..5]) << 1) | 1;
+    bus.transfer(&mut frame)?;
+    if command == CMD_STOP_TRANSMISSION {
+        // Skip the stuff byte following CMD12.
+        read_byte(bus)?;
+    }
+    for _ in 0..COMMAND_RESPONSE_ATTEMPTS {
+        let response = read_byte(bus)?;
+        if (response & 0x80) == 0 {
+            return Ok(response);
+        }
+    }
+    Err(SdError::NoResponse)
+}
+
+fn check_response(command: u8, response: u8) -> Result<(), SdError> {
+    if (response & !R1_IDLE) != 0 {
+        Err(SdError::Command { command: command, response: response })
+    } else {
+        Ok(())
+    }
+}
+
+fn wait_not_busy(bus: &mut SdSpiBus) -> Result<(), SdError> {
+    for _ in 0..BUSY_ATTEMPTS {
+        if read_byte(bus)? == 0xff {
+            return Ok(());
+        }
+    }
+    Err(SdError::BusyTimeout)
+}
+
+fn read_data(bus: &mut SdSpiBus, buffer: &mut [u8]) -> Result<(), SdError> {
+    let mut token = 0xff;
+    for _ in 0..DATA_TOKEN_ATTEMPTS {
+        token = read_byte(bus)?;
+        if token != 0xff {
+            break;
+        }
+    }
+    match token {
+        TOKEN_START_BLOCK => {
+            for byte in buffer.iter_mut() {
+                *byte = 0xff;
+            }
+            bus.transfer(buffer)?;
+            // The CRC is not checked since CRC checking is disabled in SPI mode by default.
+            let mut crc = [0xffu8; 2];
+            bus.transfer(&mut crc)
+        },
+        0xff => Err(SdError::DataTimeout),
+        token => Err(SdError::DataError(token)),
+    }
+}
+
+fn write_data(bus: &mut SdSpiBus, token: u8, block: &[u8]) -> Result<(), SdError> {
+    let mut header = [0xff, token];
+    bus.transfer(&mut header)?;
+    let mut data = [0u8; SD_BLOCK_SIZE];
+    data.copy_from_slice(block);
+    bus.transfer(&mut data)?;
+    let crc = sd_crc16(block);
+    let mut trailer = [(crc >> 8) as u8, crc as u8];
+    bus.transfer(&mut trailer)?;
+    let response = read_byte(bus)?;
+    if (response & 0x1f) != DATA_RESPONSE_ACCEPTED {
+        return Err(SdError::WriteRejected(response));
+    }
+    wait_not_busy(bus)
+}
+
+// SD card in SPI mode.
+pub struct SdSpiCard<TTransport> {
+    transport: TTransport,
+    info: SdCardInfo,
+}
+
+impl<TTransport> SdSpiCard<TTransport> where TTransport: SdSpiTransport {
+    // Initialize the card and read its registers.
+    pub fn new(mut transport: TTransport) -> Result<SdSpiCard<TTransport>, SdError> {
+        transport.set_high_speed(false)?;
+        // At least 74 clocks with CS high to enter the native mode before CMD0 selects SPI mode.
+        transport.idle_clocks(10)?;
+
+        let mut idle = false;
+        for _ in 0..GO_IDLE_ATTEMPTS {
+            let response = transport.selected(|bus| send_command(bus, CMD_GO_IDLE_STATE, 0));
+            if response == Ok(R1_IDLE) {
+                idle = true;
+                break;
+            }
+        }
+        if !idle {
+            return Err(SdError::NoResponse);
+        }
+
+        let version = transport.selected(|bus| {
+            let response = send_command(bus, CMD_SEND_IF_COND, IF_COND_CHECK)?;
+            if (response & R1_ILLEGAL_COMMAND) != 0 {
+                return Ok(SdCardVersion::V1);
+            }
+            check_response(CMD_SEND_IF_COND, response)?;
+            let mut r7 = [0xffu8; 4];
+            bus.transfer(&mut r7)?;
+            if (r7[2] & 0x0f) != 0x01 || r7[3] != IF_COND_CHECK as u8 {
+                return Err(SdError::UnsupportedCard);
+            }
+            Ok(SdCardVersion::V2)
+        })?;
+
+        let op_cond_argument = if version == SdCardVersion::V2 { OCR_CCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..OP_COND_ATTEMPTS {
+            let response = transport.selected(|bus| {
+                let response = send_command(bus, CMD_APP_CMD, 0)?;
+                check_response(CMD_APP_CMD, response)?;
+                let response = send_command(bus, ACMD_SD_SEND_OP_COND, op_cond_argument)?;
+                check_response(ACMD_SD_SEND_OP_COND, response)?;
+                Ok(response)
+            })?;
+            if response == 0 {
+                ready = true;
+                break;
+            }
+            transport.delay_ms(1);
+        }
+        if !ready {
+            return Err(SdError::InitTimeout);
+        }
+
+        let high_capacity = if version == SdCardVersion::V2 {
+            let ocr = transport.selected(|bus| {
+                let response = send_command(bus, CMD_READ_OCR, 0)?;
+                check_response(CMD_READ_OCR, response)?;
+                let mut ocr = [0xffu8; 4];
+                bus.transfer(&mut ocr)?;
+                Ok(((ocr[0] as u32) << 24) | ((ocr[1] as u32) << 16) | ((ocr[2] as u32) << 8) | ocr[3] as u32)
+            })?;
+            if (ocr & OCR_VOLTAGE_3V3) == 0 {
+                return Err(SdError::UnsupportedCard);
+            }
+            (ocr & OCR_CCS) != 0
+        } else {
+            false
+        };
+        if !high_capacity {
+            transport.selected(|bus| {
+                let response = send_command(bus, CMD_SET_BLOCKLEN, SD_BLOCK_SIZE as u32)?;
+                check_response(CMD_SET_BLOCKLEN, response)
+            })?;
+        }
+
+        transport.set_high_speed(true)?;
+
+        let csd = Self::read_register(&mut transport, CMD_SEND_CSD)?;
+        let cid = Self::read_register(&mut transport, CMD_SEND_CID)?;
+        let block_count = sd_csd_block_count(&csd).ok_or(SdError::UnsupportedCard)?;
+
+        Ok(SdSpiCard {
+            transport: transport,
+            info: SdCardInfo {
+                version: version,
+                high_capacity: high_capacity,
+                block_count: block_count,
+                cid: SdCid::parse(&cid),
+                csd: csd,
+            },
+        })
+    }
+
+    fn read_register(transport: &mut TTransport, command: u8) -> Result<[u8; 16], SdError> {
+        transport.selected(|bus| {
+            let response = send_command(bus, command, 0)?;
+            check_response(command, response)?;
+            let mut register = [0u8; 16];
+            read_data(bus, &mut register)?;
+            Ok(register)
+        })
+    }
+
+    fn check_range(&self, block: u32, length: usize) -> Result<u32, SdError> {
+        if length % SD_BLOCK_SIZE != 0 {
+            return Err(SdError::InvalidBuffer);
+        }
+        let count = (length / SD_BLOCK_SIZE) as u64;
+        if block as u64 + count > self.info.block_count as u64 {
+            return Err(SdError::OutOfRange);
+        }
+        Ok(count as u32)
+    }
+    fn block_address(&self, block: u32) -> u32 {
+        if self.info.high_capacity { block } else { block * SD_BLOCK_SIZE as u32 }
+    }
+
+    // Read the card status register (R2).
+    pub fn status(&mut self) -> Result<u16, SdError> {
+        self.transport.selected(|bus| {
+            let response = send_command(bus, CMD_SEND_STATUS, 0)?;
+            let status = read_byte(bus)?;
+            Ok(((response as u16) << 8) | status as u16)
+        })
+    }
+    // Enable or disable CRC checking of commands and data by the card.
+    pub fn set_crc(&mut self, enable: bool) -> Result<(), SdError> {
+        self.transport.selected(|bus| {
+            let response = send_command(bus, CMD_CRC_ON_OFF, if enable { 1 } else { 0 })?;
+            check_response(CMD_CRC_ON_OFF, response)
+        })
+    }
+
+    pub fn transport(&self) -> &TTransport { &self.transport }
+    pub fn transport_mut(&mut self) -> &mut TTransport { &mut self.transport }
+    pub fn into_transport(self) -> TTransport { self.transport }
+}
+
+impl<TTransport> SdBlockDevice for SdSpiCard<TTransport> where TTransport: SdSpiTransport {
+    fn read_blocks(&mut self, block: u32, buffer: &mut [u8]) -> Result<(), SdError> {
+        let count = self.check_range(block, buffer.len())?;
+        let address = self.block_address(block);
+        match count {
+            0 => Ok(()),
+            1 => self.transport.selected(|bus| {
+                let response = send_command(bus, CMD_READ_SINGLE_BLOCK, address)?;
+                check_response(CMD_READ_SINGLE_BLOCK, response)?;
+                read_data(bus, buffer)
+            }),
+            _ => self.transport.selected(|bus| {
+                let response = send_command(bus, CMD_READ_MULTIPLE_BLOCK, address)?;
+                check_response(CMD_READ_MULTIPLE_BLOCK, response)?;
+                let result = buffer.chunks_mut(SD_BLOCK_SIZE).map(|chunk| read_data(bus, chunk)).collect::<Result<(), SdError>>();
+                // Stop the transmission even if a block failed so that the card returns to the transfer state.
+                let response = send_command(bus, CMD_STOP_TRANSMISSION, 0)?;
+                result?;
+                check_response(CMD_STOP_TRANSMISSION, response)?;
+                wait_not_busy(bus)
+            }),
+        }
+    }
+
+    fn write_blocks(&mut self, block: u32, buffer: &[u8]) -> Result<(), SdError> {
+        let count = self.check_range(block, buffer.len())?;
+        let address = self.block_address(block);
+        match count {
+            0 => Ok(()),
+            1 => self.transport.selected(|bus| {
+                let response = send_command(bus, CMD_WRITE_BLOCK, address)?;
+                check_response(CMD_WRITE_BLOCK, response)?;
+                write_data(bus, TOKEN_START_BLOCK, buffer)
+            }),
+            _ => self.transport.selected(|bus| {
+                let response = send_command(bus, CMD_WRITE_MULTIPLE_BLOCK, address)?;
+                check_response(CMD_WRITE_MULTIPLE_BLOCK, response)?;
+                let result = buffer.chunks(SD_BLOCK_SIZE).map(|chunk| write_data(bus, TOKEN_START_MULTIPLE_WRITE, chunk)).collect::<Result<(), SdError>>();
+                let mut stop = [TOKEN_STOP_TRANSMISSION, 0xff];
+                bus.transfer(&mut stop)?;
+                wait_not_busy(bus)?;
+                result
+            }),
+        }
+    }
+
+    fn block_count(&self) -> u32 { self.info.block_count }
+    fn info(&self) -> &SdCardInfo { &self.info }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum EmulatorRxState {
+    Command,
+    // Waiting for the start token of a written block.
+    WriteToken { block: u32, multiple: bool },
+    // Receiving a written block and its CRC.
+    WriteData { block: u32, multiple: bool },
+}
+
+// SD card in SPI mode backed by an in-memory image, to run SdSpiCard on the host.
+pub struct SdCardEmulator {
+    image: Vec<u8>,
+    high_capacity: bool,
+    cid: SdCid,
+    // Number of ACMD41 answered with the idle state before the card is ready.
+    op_cond_polls: u32,
+    idle: bool,
+    app_command: bool,
+    // CRC checking of all commands and written blocks, switched by CMD59.
+    crc: bool,
+    // Transmission errors to inject, see corrupt_next_command and corrupt_next_block.
+    corrupt_command: bool,
+    corrupt_block: bool,
+    selected: bool,
+    command: Vec<u8>,
+    tx: VecDeque<u8>,
+    rx_state: EmulatorRxState,
+    rx_data: Vec<u8>,
+    read_block: Option<u32>,
+    commands: Vec<u8>,
+}
+
+impl SdCardEmulator {
+    // `image` is truncated to whole blocks. A standard capacity card must be smaller than 1GiB.
+    pub fn new(mut image: Vec<u8>, high_capacity: bool) -> SdCardEmulator {
+        let blocks = image.len() / SD_BLOCK_SIZE;
+        image.truncate(blocks * SD_BLOCK_SIZE);
+        SdCardEmulator {
+            image: image,
+            high_capacity: high_capacity,
+            cid: SdCid {
+                manufacturer_id: 0x03,
+                oem_id: 0x5344,
+                product_name: *b"EMU01",
+                revision: 0x10,
+                serial: 0x1234_5678,
+                manufacture_year: 2019,
+                manufacture_month: 6,
+            },
+            op_cond_polls: 3,
+            idle: true,
+            app_command: false,
+            crc: false,
+            corrupt_command: false,
+            corrupt_block: false,
+            selected: false,
+            command: Vec::new(),
+            tx: VecDeque::new(),
+            rx_state: EmulatorRxState::Command,
+            rx_data: Vec::new(),
+            read_block: None,
+            commands: Vec::new(),
+        }
+    }
+    pub fn with_cid(mut self, cid: SdCid) -> SdCardEmulator {
+        self.cid = cid;
+        self
+    }
+
+    pub fn image(&self) -> &[u8] { &self.image }
+    pub fn into_image(self) -> Vec<u8> { self.image }
+    pub fn block_count(&self) -> u32 { (self.image.len() / SD_BLOCK_SIZE) as u32 }
+    // Indices of the commands received so far, application commands included.
+    pub fn commands(&self) -> &[u8] { &self.commands }
+    pub fn crc_enabled(&self) -> bool { self.crc }
+
+    // Flip a bit in the argument of the next command as if it was disturbed on the bus.
+    pub fn corrupt_next_command(&mut self) {
+        self.corrupt_command = true;
+    }
+    // Flip a bit in the next written block as if it was disturbed on the bus.
+    pub fn corrupt_next_block(&mut self) {
+        self.corrupt_block = true;
+    }
+
+    pub fn csd(&self) -> [u8; 16] {
+        let mut csd = [0u8; 16];
+        let blocks = self.block_count();
+        if self.high_capacity {
+            set_register_bits(&mut csd, 127, 126, 1);
+            set_register_bits(&mut csd, 69, 48, (blocks / 1024).saturating_sub(1));
+        } else {
+            // 512 byte read blocks and a multiplier of 512, i.e. C_SIZE counts 256KiB units.
+            set_register_bits(&mut csd, 83, 80, 9);
+            set_register_bits(&mut csd, 49, 47, 7);
+            set_register_bits(&mut csd, 73, 62, (blocks / 512).saturating_sub(1));
+        }
+        set_register_bits(&mut csd, 103, 96, 0x32);
+        csd[15] = (sd_crc7(&csd[..15]) << 1) | 1;
+        csd
+    }
+
+    fn r1(&self, error: u8) -> u8 {
+        if self.idle { error | R1_IDLE } else { error }
+    }
+    fn respond(&mut self, response: &[u8]) {
+        // One byte of command response time (NCR).
+        self.tx.push_back(0xff);
+        self.tx.extend(response.iter().cloned());
+    }
+    fn queue_block(&mut self, data: &[u8]) {
+        self.tx.push_back(0xff);
+        self.tx.push_back(TOKEN_START_BLOCK);
+        self.tx.extend(data.iter().cloned());
+        let crc = sd_crc16(data);
+        self.tx.push_back((crc >> 8) as u8);
+        self.tx.push_back(crc as u8);
+    }
+    fn block_of(&self, argument: u32) -> u32 {
+        if self.high_capacity { argument } else { argument / SD_BLOCK_SIZE as u32 }
+    }
+    fn block_data(&self, block: u32) -> Option<&[u8]> {
+        let start = block as usize * SD_BLOCK_SIZE;
+        self.image.get(start..start + SD_BLOCK_SIZE)
+    }
+
+    fn execute(&mut self, frame: [u8; 6]) {
+        let index = frame[0] & 0x3f;
+        let argument = ((frame[1] as u32) << 24) | ((frame[2] as u32) << 16) | ((frame[3] as u32) << 8) | frame[4] as u32;
+        self.commands.push(index);
+        // The CRC is always checked for CMD0 and CMD8.
+        let check_crc = self.crc || index == CMD_GO_IDLE_STATE || index == CMD_SEND_IF_COND;
+        if check_crc && frame[5] != (sd_crc7(&frame[..5]) << 1) | 1 {
+            let response = self.r1(R1_CRC_ERROR);
+            self.respond(&[response]);
+            return;
+        }
+        let app_command = self.app_command;
+        self.app_command = false;
+        match (app_command, index) {
+            (true, ACMD_SD_SEND_OP_COND) => {
+                if self.op_cond_polls > 0 {
+                    self.op_cond_polls -= 1;
+                } else if !self.high_capacity || (argument & OCR_CCS) != 0 {
+                    self.idle = false;
+                }
+                let response = self.r1(0);
+                self.respond(&[response]);
+            },
+            (_, CMD_GO_IDLE_STATE) => {
+                self.idle = true;
+                self.crc = false;
+                self.read_block = None;
+                self.respond(&[R1_IDLE]);
+            },
+            (_, CMD_SEND_IF_COND) => {
+                let response = self.r1(0);
+                self.respond(&[response, 0x00, 0x00, ((argument >> 8) & 0x0f) as u8, argument as u8]);
+            },
+            (_, CMD_APP_CMD) => {
+                self.app_command = true;
+                let response = self.r1(0);
+                self.respond(&[response]);
+            },
+            (_, CMD_READ_OCR) => {
+                let mut ocr = 0x00ff_8000;
+                if !self.idle {
+                    ocr |= OCR_POWER_UP;
+                    if self.high_capacity {
+                        ocr |= OCR_CCS;
+                    }
+                }
+                let response = self.r1(0);
+                self.respond(&[response, (ocr >> 24) as u8, (ocr >> 16) as u8, (ocr >> 8) as u8, ocr as u8]);
+            },
+            (_, CMD_SET_BLOCKLEN) => {
+                let error = if argument == SD_BLOCK_SIZE as u32 { 0 } else { R1_PARAMETER_ERROR };
+                let response = self.r1(error);
+                self.respond(&[response]);
+            },
+            (_, CMD_CRC_ON_OFF) => {
+                self.crc = (argument & 1) != 0;
+                let response = self.r1(0);
+                self.respond(&[response]);
+            },
+            (_, CMD_SEND_STATUS) => {
+                let response = self.r1(0);
+                self.respond(&[response, 0x00]);
+            },
+            (_, CMD_SEND_CSD) | (_, CMD_SEND_CID) if !self.idle => {
+                let register = if index == CMD_SEND_CSD { self.csd() } else { self.cid.to_bytes() };
+                self.respond(&[0x00]);
+                self.queue_block(&register);
+            },
+            (_, CMD_STOP_TRANSMISSION) => {
+                self.read_block = None;
+                self.tx.clear();
+                // Stuff byte, R1 and one busy byte.
+                self.tx.push_back(0xff);
+                self.respond(&[0x00, 0x00]);
+            },
+            (_, CMD_READ_SINGLE_BLOCK) | (_, CMD_READ_MULTIPLE_BLOCK) if !self.idle => {
+                let block = self.block_of(argument);
+                if block >= self.block_count() {
+                    self.respond(&[R1_ADDRESS_ERROR]);
+                } else if index == CMD_READ_SINGLE_BLOCK {
+                    self.respond(&[0x00]);
+                    let data = self.block_data(block).unwrap().to_vec();
+                    self.queue_block(&data);
+                } else {
+                    self.respond(&[0x00]);
+                    self.read_block = Some(block);
+                }
+            },
+            (_, CMD_WRITE_BLOCK) | (_, CMD_WRITE_MULTIPLE_BLOCK) if !self.idle => {
+                let block = self.block_of(argument);
+                if block >= self.block_count() {
+                    self.respond(&[R1_ADDRESS_ERROR]);
+                } else {
+                    self.respond(&[0x00]);
+                    self.rx_state = EmulatorRxState::WriteToken { block: block, multiple: index == CMD_WRITE_MULTIPLE_BLOCK };
+                }
+            },
+            _ => {
+                let response = self.r1(R1_ILLEGAL_COMMAND);
+                self.respond(&[response]);
+            },
+        }
+    }
+
+    fn next_tx(&mut self) -> u8 {
+        if self.tx.is_empty() {
+            if let Some(block) = self.read_block {
+                match self.block_data(block).map(|data| data.to_vec()) {
+                    Some(data) => {
+                        self.queue_block(&data);
+                        self.read_block = Some(block + 1);
+                    },
+                    None => {
+                        self.tx.push_back(DATA_ERROR_OUT_OF_RANGE);
+                        self.read_block = None;
+                    },
+                }
+            }
+        }
+        self.tx.pop_front().unwrap_or(0xff)
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match self.rx_state {
+            EmulatorRxState::Command => {
+                if self.command.is_empty() && (byte & 0xc0) != 0x40 {
+                    return;
+                }
+                self.command.push(byte);
+                if self.command.len() == 6 {
+                    let mut frame = [0u8; 6];
+                    frame.copy_from_slice(&self.command);
+                    self.command.clear();
+                    if self.corrupt_command {
+                        self.corrupt_command = false;
+                        frame[4] ^= 0x01;
+                    }
+                    self.execute(frame);
+                }
+            },
+            EmulatorRxState::WriteToken { block, multiple } => {
+                match byte {
+                    TOKEN_START_BLOCK if !multiple => {
+                        self.rx_state = EmulatorRxState::WriteData { block: block, multiple: false };
+                    },
+                    TOKEN_START_MULTIPLE_WRITE if multiple => {
+                        self.rx_state = EmulatorRxState::WriteData { block: block, multiple: true };
+                    },
+                    TOKEN_STOP_TRANSMISSION if multiple => {
+                        self.rx_state = EmulatorRxState::Command;
+                        self.tx.push_back(0x00);
+                    },
+                    _ => {},
+                }
+            },
+            EmulatorRxState::WriteData { block, multiple } => {
+                self.rx_data.push(byte);
+                if self.rx_data.len() < SD_BLOCK_SIZE + 2 {
+                    return;
+                }
+                if self.corrupt_block {
+                    self.corrupt_block = false;
+                    self.rx_data[0] ^= 0x01;
+                }
+                let crc = ((self.rx_data[SD_BLOCK_SIZE] as u16) << 8) | self.rx_data[SD_BLOCK_SIZE + 1] as u16;
+                if self.crc && crc != sd_crc16(&self.rx_data[..SD_BLOCK_SIZE]) {
+                    self.rx_data.clear();
+                    self.tx.push_back(DATA_RESPONSE_CRC_ERROR);
+                    self.rx_state = if multiple {
+                        EmulatorRxState::WriteToken { block: block, multiple: true }
+                    } else {
+                        EmulatorRxState::Command
+                    };
+                    return;
+                }
+                let start = block as usize * SD_BLOCK_SIZE;
+                let accepted = match self.image.get_mut(start..start + SD_BLOCK_SIZE) {
+                    Some(target) => {
+                        target.copy_from_slice(&self.rx_data[..SD_BLOCK_SIZE]);
+                        true
+                    },
+                    None => false,
+                };
+                self.rx_data.clear();
+                if accepted {
+                    // Data accepted, then busy while programming.
+                    self.tx.extend([DATA_RESPONSE_ACCEPTED, 0x00, 0x00].iter().cloned());
+                    self.rx_state = if multiple {
+                        EmulatorRxState::WriteToken { block: block + 1, multiple: true }
+                    } else {
+                        EmulatorRxState::Command
+                    };
+                } else {
+                    self.tx.push_back(DATA_RESPONSE_WRITE_ERROR);
+                    self.rx_state = if multiple {
+                        EmulatorRxState::WriteToken { block: block, multiple: true }
+                    } else {
+                        EmulatorRxState::Command
+                    };
+                }
+            },
+        }
+    }
+}
+
+impl SdSpiBus for SdCardEmulator {
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), SdError> {
+        for byte in data.iter_mut() {
+            if !self.selected {
+                *byte = 0xff;
+                continue;
+            }
+            let received = *byte;
+            *byte = self.next_tx();
+            self.receive(received);
+        }
+        Ok(())
+    }
+}
+
+impl SdSpiTransport for SdCardEmulator {
+    fn selected<R, F>(&mut self, f: F) -> Result<R, SdError>
+        where F: FnOnce(&mut SdSpiBus) -> Result<R, SdError>
+    {
+        self.selected = true;
+        let result = f(self);
+        // The card releases the data output and drops a partially received command when deselected.
+        self.selected = false;
+        self.command.clear();
+        self.tx.clear();
+        result
+    }
+    fn idle_clocks(&mut self, _bytes: usize) -> Result<(), SdError> { Ok(()) }
+    fn set_high_speed(&mut self, _high_speed: bool) -> Result<(), SdError> { Ok(()) }
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const BLOCKS: usize = 1024;
+
+    // Every byte of a block holds the block index.
+    fn image() -> Vec<u8> {
+        (0..BLOCKS * SD_BLOCK_SIZE).map(|i| (i / SD_BLOCK_SIZE) as u8).collect()
+    }
+
+    fn card(high_capacity: bool) -> SdSpiCard<SdCardEmulator> {
+        SdSpiCard::new(SdCardEmulator::new(image(), high_capacity)).unwrap()
+    }
+
+    // Commands received since `start`.
+    fn commands_since(card: &SdSpiCard<SdCardEmulator>, start: usize) -> Vec<u8> {
+        card.transport().commands()[start..].to_vec()
+    }
+
+    #[test]
+    fn init_high_capacity() {
+        let card = card(true);
+        assert_eq!(card.info().version, SdCardVersion::V2);
+        assert!(card.info().high_capacity);
+        assert_eq!(card.block_count(), BLOCKS as u32);
+        assert_eq!(card.info().capacity_bytes(), (BLOCKS * SD_BLOCK_SIZE) as u64);
+        assert_eq!(&card.info().cid.product_name, b"EMU01");
+        // ACMD41 is repeated until the card leaves the idle state.
+        assert_eq!(card.transport().commands(), &[0, 8, 55, 41, 55, 41, 55, 41, 55, 41, 58, 9, 10]);
+    }
+
+    #[test]
+    fn init_standard_capacity() {
+        let mut card = card(false);
+        assert_eq!(card.info().version, SdCardVersion::V2);
+        assert!(!card.info().high_capacity);
+        assert_eq!(card.block_count(), BLOCKS as u32);
+        // The block length is set after READ_OCR.
+        assert_eq!(card.transport().commands(), &[0, 8, 55, 41, 55, 41, 55, 41, 55, 41, 58, 16, 9, 10]);
+
+        // Blocks are addressed in bytes.
+        let mut block = vec![0u8; SD_BLOCK_SIZE];
+        card.read_blocks(3, &mut block).unwrap();
+        assert!(block.iter().all(|&byte| byte == 3));
+    }
+
+    #[test]
+    fn read_single_block() {
+        let mut card = card(true);
+        let start = card.transport().commands().len();
+        let mut block = vec![0u8; SD_BLOCK_SIZE];
+        card.read_blocks(5, &mut block).unwrap();
+        assert!(block.iter().all(|&byte| byte == 5));
+        assert_eq!(commands_since(&card, start), &[17]);
+    }
+
+    #[test]
+    fn read_multiple_blocks() {
+        let mut card = card(true);
+        let start = card.transport().commands().len();
+        let mut blocks = vec![0u8; 3 * SD_BLOCK_SIZE];
+        card.read_blocks(7, &mut blocks).unwrap();
+        for (index, block) in blocks.chunks(SD_BLOCK_SIZE).enumerate() {
+            assert!(block.iter().all(|&byte| byte == 7 + index as u8));
+        }
+        // The transmission is ended with CMD12 and the card accepts commands again.
+        assert_eq!(commands_since(&card, start), &[18, 12]);
+        assert_eq!(card.status(), Ok(0));
+    }
+
+    #[test]
+    fn write_single_block() {
+        let mut card = card(true);
+        let start = card.transport().commands().len();
+        card.write_blocks(3, &[0xa5; SD_BLOCK_SIZE]).unwrap();
+        assert_eq!(commands_since(&card, start), &[24]);
+        let image = card.transport().image();
+        assert!(image[3 * SD_BLOCK_SIZE..4 * SD_BLOCK_SIZE].iter().all(|&byte| byte == 0xa5));
+        assert!(image[2 * SD_BLOCK_SIZE..3 * SD_BLOCK_SIZE].iter().all(|&byte| byte == 2));
+        assert!(image[4 * SD_BLOCK_SIZE..5 * SD_BLOCK_SIZE].iter().all(|&byte| byte == 4));
+    }
+
+    #[test]
+    fn write_multiple_blocks() {
+        let mut card = card(false);
+        let data: Vec<u8> = (0..4 * SD_BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        let start = card.transport().commands().len();
+        card.write_blocks(10, &data).unwrap();
+        // The stop token ends the write without a command.
+        assert_eq!(commands_since(&card, start), &[25]);
+        assert_eq!(&card.transport().image()[10 * SD_BLOCK_SIZE..14 * SD_BLOCK_SIZE], &data[..]);
+        assert!(card.transport().image()[14 * SD_BLOCK_SIZE..15 * SD_BLOCK_SIZE].iter().all(|&byte| byte == 14));
+
+        let mut read = vec![0u8; data.len()];
+        card.read_blocks(10, &mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn crc_errors() {
+        let mut card = card(true);
+        card.set_crc(true).unwrap();
+        assert!(card.transport().crc_enabled());
+
+        let mut block = vec![0u8; SD_BLOCK_SIZE];
+        card.transport_mut().corrupt_next_command();
+        assert_eq!(card.read_blocks(4, &mut block), Err(SdError::Command { command: 17, response: R1_CRC_ERROR }));
+
+        card.transport_mut().corrupt_next_block();
+        assert_eq!(card.write_blocks(4, &[0xa5; SD_BLOCK_SIZE]), Err(SdError::WriteRejected(DATA_RESPONSE_CRC_ERROR)));
+        assert!(card.transport().image()[4 * SD_BLOCK_SIZE..5 * SD_BLOCK_SIZE].iter().all(|&byte| byte == 4));
+
+        // A multiple block write is stopped and the card stays usable.
+        card.transport_mut().corrupt_next_block();
+        assert_eq!(card.write_blocks(4, &[0xa5; 2 * SD_BLOCK_SIZE]), Err(SdError::WriteRejected(DATA_RESPONSE_CRC_ERROR)));
+        card.read_blocks(4, &mut block).unwrap();
+        assert!(block.iter().all(|&byte| byte == 4));
+    }
+
+    #[test]
+    fn corrupted_block_without_crc() {
+        let mut card = card(true);
+        assert!(!card.transport().crc_enabled());
+        card.transport_mut().corrupt_next_block();
+        card.write_blocks(4, &[0xa4; SD_BLOCK_SIZE]).unwrap();
+        assert_eq!(card.transport().image()[4 * SD_BLOCK_SIZE], 0xa5);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut card = card(true);
+        let start = card.transport().commands().len();
+        let mut blocks = vec![0u8; 2 * SD_BLOCK_SIZE];
+        assert_eq!(card.read_blocks(BLOCKS as u32 - 1, &mut blocks), Err(SdError::OutOfRange));
+        assert_eq!(card.write_blocks(BLOCKS as u32, &blocks[..SD_BLOCK_SIZE]), Err(SdError::OutOfRange));
+        assert_eq!(card.read_blocks(0, &mut blocks[..100]), Err(SdError::InvalidBuffer));
+        // Nothing is sent to the card.
+        assert_eq!(commands_since(&card, start), &[] as &[u8]);
+
+        // The emulated card rejects the address or ends the data at the last block.
+        let transport = card.transport_mut();
+        let response = transport.selected(|bus| send_command(bus, CMD_READ_SINGLE_BLOCK, BLOCKS as u32));
+        assert_eq!(response, Ok(R1_ADDRESS_ERROR));
+        let result = transport.selected(|bus| {
+            let response = send_command(bus, CMD_READ_MULTIPLE_BLOCK, BLOCKS as u32 - 1)?;
+            check_response(CMD_READ_MULTIPLE_BLOCK, response)?;
+            read_data(bus, &mut blocks[..SD_BLOCK_SIZE])?;
+            read_data(bus, &mut blocks[SD_BLOCK_SIZE..])
+        });
+        assert_eq!(result, Err(SdError::DataError(DATA_ERROR_OUT_OF_RANGE)));
+        assert!(blocks[..SD_BLOCK_SIZE].iter().all(|&byte| byte == (BLOCKS - 1) as u8));
+    }
+}
@@ -4,11 +4,11 @@
 use core::ptr;
 use core::mem;
 use core::ops::{Deref, DerefMut};
-use core::cell::{UnsafeCell};
 use core::convert::Into;
 
 extern crate alloc;
 use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 
 use idf;
 use idf::AsResult;
@@ -18,11 +18,11 @@ use idf::IdfError;
 use freertos_rs::*;
 use embedded_hal::blocking::spi::*;
 use embedded_hal::spi::FullDuplex;
-use embedded_hal::blocking::spi::transfer::Default as TransferDefault;
 
 use nb;
 
 use crate::gpio::*;
+use crate::spi_transaction::*;
 
 pub struct SpiBus {
     host_device: idf::spi_host_device_t,
@@ -48,24 +48,6 @@ impl Into<idf::spi_host_device_t> for SpiHostDevice {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum SpiError {
-    Generic,
-    IdfError(IdfError),
-    FreeRtosError(FreeRtosError),
-}
-
-impl From<IdfError> for SpiError {
-    fn from(err: IdfError) -> SpiError {
-        SpiError::IdfError(err)
-    }
-}
-impl From<FreeRtosError> for SpiError {
-    fn from(err: FreeRtosError) -> SpiError {
-        SpiError::FreeRtosError(err)
-    }
-}
-
 #[derive(Copy, Clone, Debug)]
 pub struct SpiBusConfig {
     pub mosi_pin: GpioPin,
@@ -113,6 +95,19 @@ impl Into<u8> for SpiMode {
     }
 }
 
+// Maximum number of transactions which can be queued to a device at once.
+pub const SPI_DEVICE_QUEUE_SIZE: usize = 8;
+
+// Device flags (SPI_DEVICE_* in spi_master.h)
+pub const SPI_DEVICE_TXBIT_LSBFIRST: u32 = 1 << 0;
+pub const SPI_DEVICE_RXBIT_LSBFIRST: u32 = 1 << 1;
+pub const SPI_DEVICE_BIT_LSBFIRST: u32   = SPI_DEVICE_TXBIT_LSBFIRST | SPI_DEVICE_RXBIT_LSBFIRST;
+pub const SPI_DEVICE_3WIRE: u32          = 1 << 2;
+pub const SPI_DEVICE_POSITIVE_CS: u32    = 1 << 3;
+pub const SPI_DEVICE_HALFDUPLEX: u32     = 1 << 4;
+pub const SPI_DEVICE_CLK_AS_CS: u32      = 1 << 5;
+pub const SPI_DEVICE_NO_DUMMY: u32       = 1 << 6;
+
 #[derive(Copy, Clone, Default)]
 pub struct SpiDeviceInterfaceConfig {
     pub command_bits: u8,
@@ -125,6 +120,7 @@ pub struct SpiDeviceInterfaceConfig {
     pub clock_speed_hz: i32,
     pub input_delay_ns: i32,
     pub cs_pin: Option<GpioPin>,
+    pub flags: u32,
 }
 
 impl Into<idf::spi_device_interface_config_t> for SpiDeviceInterfaceConfig {
@@ -140,8 +136,8 @@ impl Into<idf::spi_device_interface_config_t> for SpiDeviceInterfaceConfig {
             clock_speed_hz: self.clock_speed_hz,
             input_delay_ns: self.input_delay_ns,
             spics_io_num: self.cs_pin.map_or(-1, |pin| { pin.number() as i32 }),
-            flags: 0,
-            queue_size: 8,
+            flags: self.flags,
+            queue_size: SPI_DEVICE_QUEUE_SIZE as i32,
             pre_cb: None,
             post_cb: None,
         }
@@ -162,7 +158,7 @@ impl SpiBus {
     }
 
     pub fn add_device<TTransactionContext, FPre, FPost>(&mut self, config: SpiDeviceInterfaceConfig, pre_callback: FPre, post_callback: FPost ) -> Result<SpiDeviceBusLock<TTransactionContext>, SpiError> 
-        where FPre : FnMut(&TTransactionContext) + 'static, FPost : FnMut(&TTransactionContext) + 'static {
+        where FPre : SpiIsrCallback<TTransactionContext> + 'static, FPost : SpiIsrCallback<TTransactionContext> + 'static {
         let mut handle: idf::spi_device_handle_t = ptr::null_mut();
         //let guard = self.lock.lock(Duration::infinite()).unwrap();
         unsafe {
@@ -186,143 +182,307 @@ impl Drop for SpiBus {
 }
 
 
-pub struct SpiTransaction<'a, T> {
-    flags: u32,
-    cmd: u16,
-    addr: u64,
-    length: usize,
-    rxlength: Option<usize>,
-    tx_buffer: Option<&'a [u8]>,
-    rx_buffer: Option<&'a mut [u8]>,
-    user: T,
+// Callback invoked by the SPI driver just before (pre) or after (post) a transaction, in ISR context.
+// Implementations must not block, allocate or call driver APIs which take locks.
+// Safe implementations are provided for `()` (no-op) and DirectOutputPin (D/C pin).
+// Use SpiIsrFn to register other functions.
+pub unsafe trait SpiIsrCallback<TTransactionContext>: Send + Sync {
+    fn call(&self, context: &TTransactionContext);
 }
 
-impl<'a, T> SpiTransaction<'a, T> {
-    pub fn new_write(tx_buffer: &'a [u8], user: T) -> Self {
-        Self {
-            flags: 0,
-            cmd: 0,
-            addr: 0,
-            length: tx_buffer.len()*8 as usize,
-            rxlength: None,
-            tx_buffer: Some(tx_buffer),
-            rx_buffer: None,
-            user: user,
-        }
-    }
-    pub fn new_read(rx_buffer: &'a mut [u8], user: T) -> Self {
-        Self {
-            flags: 0,
-            cmd: 0,
-            addr: 0,
-            length: rx_buffer.len()*8 as usize,
-            rxlength: Some(0),
-            tx_buffer: None,
-            rx_buffer: Some(rx_buffer),
-            user: user,
-        }
+unsafe impl<TTransactionContext> SpiIsrCallback<TTransactionContext> for () {
+    fn call(&self, _context: &TTransactionContext) {}
+}
+
+// Drives the D/C pin of command/data displays from the transaction context:
+// `false` selects command, `true` selects data.
+unsafe impl SpiIsrCallback<bool> for DirectOutputPin {
+    fn call(&self, context: &bool) {
+        self.set_level(*context);
     }
-    pub fn new_both(tx_buffer: &'a [u8], rx_buffer: &'a mut [u8], user: T) -> Self {
-        Self {
-            flags: 0,
-            cmd: 0,
-            addr: 0,
-            length: tx_buffer.len()*8 as usize,
-            rxlength: Some(rx_buffer.len()*8 as usize),
-            tx_buffer: Some(tx_buffer),
-            rx_buffer: Some(rx_buffer),
-            user: user,
-        }
+}
+
+// Plain function used as an SPI callback. It has no captured state, so calling it never allocates.
+pub struct SpiIsrFn<TTransactionContext>(fn(&TTransactionContext));
+
+impl<TTransactionContext> SpiIsrFn<TTransactionContext> {
+    // The caller must ensure that `f` only performs operations which are allowed in ISR context.
+    pub unsafe fn new(f: fn(&TTransactionContext)) -> Self {
+        SpiIsrFn(f)
     }
+}
 
+unsafe impl<TTransactionContext> SpiIsrCallback<TTransactionContext> for SpiIsrFn<TTransactionContext> {
+    fn call(&self, context: &TTransactionContext) {
+        (self.0)(context)
+    }
 }
 
 pub struct SpiDevice<TTransactionContext> {
     handle: idf::spi_device_handle_t,
     config: idf::spi_device_interface_config_t,
-    pre_callback: Box<FnMut(&TTransactionContext)>,
-    post_callback:  Box<FnMut(&TTransactionContext)>,
+    pre_callback: Box<SpiIsrCallback<TTransactionContext>>,
+    post_callback:  Box<SpiIsrCallback<TTransactionContext>>,
 
-    last_word: u8,
+    last_word: Option<u8>,
 }
 
-struct SpiTransactionContext<'a, TTransactionContext> {
-    device: &'a mut SpiDevice<TTransactionContext>,
+// Passed to the pre/post callbacks through spi_transaction_t::user.
+// The device is held by a raw pointer because several queued transactions may refer to it at once.
+struct SpiTransactionContext<TTransactionContext> {
+    device: *mut SpiDevice<TTransactionContext>,
     context: TTransactionContext,
 }
 
 impl<TTransactionContext> SpiDevice<TTransactionContext> {
     fn new<FPre, FPost>(handle: idf::spi_device_handle_t, config: idf::spi_device_interface_config_t, pre_callback: FPre, post_callback: FPost) -> Result<SpiDeviceBusLock<TTransactionContext>, SpiError> 
-        where FPre : FnMut(&TTransactionContext) + 'static, FPost : FnMut(&TTransactionContext) + 'static {
-        Ok( SpiDeviceBusLock::new(SpiDevice{handle: handle, config: config, pre_callback: Box::new(pre_callback), post_callback: Box::new(post_callback), last_word: 0}))
+        where FPre : SpiIsrCallback<TTransactionContext> + 'static, FPost : SpiIsrCallback<TTransactionContext> + 'static {
+        SpiDeviceBusLock::new(SpiDevice{handle: handle, config: config, pre_callback: Box::new(pre_callback), post_callback: Box::new(post_callback), last_word: None})
     }
 
     unsafe extern "C" fn pre_callback_handler(idf_transaction: *mut idf::spi_transaction_t) {
         let context_ptr = (*idf_transaction).user as *mut SpiTransactionContext<TTransactionContext>;
-        let device = &mut (*context_ptr).device;
+        let device = &*(*context_ptr).device;
         let user_context = &(*context_ptr).context;
-        (*device.pre_callback)(user_context);
+        device.pre_callback.call(user_context);
     } 
 
     unsafe extern "C" fn post_callback_handler(idf_transaction: *mut idf::spi_transaction_t) {
         let context_ptr = (*idf_transaction).user as *mut SpiTransactionContext<TTransactionContext>;
-        let device = &mut (*context_ptr).device;
+        let device = &*(*context_ptr).device;
         let user_context = &(*context_ptr).context;
-        (*device.post_callback)(user_context);
+        device.post_callback.call(user_context);
     } 
 
     pub fn transfer<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<(), SpiError> {
+        self.transfer_received(transaction)?;
+        Ok(())
+    }
+
+    // Transfer and return the data received with SpiTransactionBuilder::read_inline.
+    pub fn transfer_inline<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<[u8; 4], SpiError> {
+        self.transfer_received(transaction).map(|received| received.inline())
+    }
+
+    // Transfer and hand back the receive buffer together with the inline data.
+    pub fn transfer_received<'t>(&mut self, mut transaction: SpiTransaction<'t, TTransactionContext>) -> Result<SpiReceived<'t>, SpiError> {
+        let rx_len = transaction.rx_len();
         let mut context = SpiTransactionContext::<TTransactionContext> {
-            device: self,
+            device: self as *mut SpiDevice<TTransactionContext>,
             context: transaction.user,
         };
         
         unsafe {
-            let mut idf_transaction = idf::spi_transaction_t::default();
+            // spi_transaction_ext_t starts with spi_transaction_t, and the extra fields are only used with SPI_TRANS_VARIABLE_* flags.
+            let mut idf_transaction_ext = idf::spi_transaction_ext_t::default();
+            idf_transaction_ext.command_bits = transaction.command_bits;
+            idf_transaction_ext.address_bits = transaction.address_bits;
+            idf_transaction_ext.dummy_bits   = transaction.dummy_bits;
+
+            let idf_transaction = &mut idf_transaction_ext.base;
+            idf_transaction.flags    = transaction.flags;
             idf_transaction.length   = transaction.length as usize;
             idf_transaction.rxlength = transaction.rxlength.map_or(0, |v| v);
             idf_transaction.cmd      = transaction.cmd;
             idf_transaction.addr     = transaction.addr;
 
-            let mut tx_buffer_ptr = idf_transaction.__bindgen_anon_1.tx_buffer.as_mut();
-            *tx_buffer_ptr = transaction.tx_buffer.map_or(ptr::null(), |tx_buffer| tx_buffer.as_ptr() as *const c_void);
-            
-            let mut rx_buffer_ptr = idf_transaction.__bindgen_anon_2.rx_buffer.as_mut();
-            *rx_buffer_ptr = transaction.rx_buffer.map_or(ptr::null_mut(), |rx_buffer| rx_buffer.as_ptr() as *mut c_void);
+            if (transaction.flags & SPI_TRANS_USE_TXDATA) != 0 {
+                *idf_transaction.__bindgen_anon_1.tx_data.as_mut() = transaction.tx_data;
+            }
+            else {
+                let mut tx_buffer_ptr = idf_transaction.__bindgen_anon_1.tx_buffer.as_mut();
+                *tx_buffer_ptr = transaction.tx_buffer.map_or(ptr::null(), |tx_buffer| tx_buffer.as_ptr() as *const c_void);
+            }
+            if (transaction.flags & SPI_TRANS_USE_RXDATA) == 0 {
+                let mut rx_buffer_ptr = idf_transaction.__bindgen_anon_2.rx_buffer.as_mut();
+                *rx_buffer_ptr = transaction.rx_buffer.as_mut().map_or(ptr::null_mut(), |rx_buffer| rx_buffer.as_mut_ptr() as *mut c_void);
+            }
         
             idf_transaction.user = (&mut context as *mut SpiTransactionContext<TTransactionContext>) as *mut c_void;
-            idf::spi_device_polling_transmit(self.handle, &mut idf_transaction).as_result()?;
-            Ok(())
+            idf::spi_device_polling_transmit(self.handle, idf_transaction).as_result()?;
+            if (transaction.flags & SPI_TRANS_USE_RXDATA) != 0 {
+                Ok(SpiReceived::new(None, *idf_transaction.__bindgen_anon_2.rx_data.as_ref(), rx_len))
+            }
+            else {
+                Ok(SpiReceived::new(transaction.rx_buffer, [0; 4], rx_len))
+            }
+        }
+    }
+
+    // Start queued (interrupt driven) transfers on this device.
+    // The returned queue borrows the device, so polling transfers cannot be mixed with in-flight queued ones.
+    pub fn queue<'d, TBuffer>(&'d mut self) -> SpiTransactionQueue<'d, TBuffer, TTransactionContext> {
+        SpiTransactionQueue {
+            device: self,
+            in_flight: VecDeque::with_capacity(SPI_DEVICE_QUEUE_SIZE),
+        }
+    }
+}
+
+struct SpiQueuedEntry<TBuffer, TTransactionContext> {
+    idf_transaction: idf::spi_transaction_t,
+    context: SpiTransactionContext<TTransactionContext>,
+    buffer: TBuffer,
+}
+
+// Queue of in-flight transactions on a device.
+// Each queued transaction owns its buffer until it is handed back by `wait`, so the buffer cannot be freed or modified during DMA.
+// The buffer must be DMA capable memory (e.g. Vec/Box allocated from internal RAM, or a 'static array).
+pub struct SpiTransactionQueue<'d, TBuffer, TTransactionContext> {
+    device: &'d mut SpiDevice<TTransactionContext>,
+    in_flight: VecDeque<Box<SpiQueuedEntry<TBuffer, TTransactionContext>>>,
+}
+
+impl<'d, TBuffer, TTransactionContext> SpiTransactionQueue<'d, TBuffer, TTransactionContext>
+    where TBuffer: 'static, TTransactionContext: 'static
+{
+    pub fn pending(&self) -> usize { self.in_flight.len() }
+    pub fn is_full(&self) -> bool { self.in_flight.len() >= SPI_DEVICE_QUEUE_SIZE }
+
+    pub fn enqueue_write(&mut self, buffer: TBuffer, user: TTransactionContext, wait_ticks: Duration) -> Result<(), SpiError>
+        where TBuffer: AsRef<[u8]>
+    {
+        let mut entry = self.new_entry(buffer, user);
+        unsafe {
+            let length = entry.buffer.as_ref().len();
+            entry.idf_transaction.length = length*8;
+            let tx_buffer_ptr = entry.idf_transaction.__bindgen_anon_1.tx_buffer.as_mut();
+            *tx_buffer_ptr = entry.buffer.as_ref().as_ptr() as *const c_void;
+        }
+        self.submit(entry, wait_ticks)
+    }
+
+    pub fn enqueue_read(&mut self, buffer: TBuffer, user: TTransactionContext, wait_ticks: Duration) -> Result<(), SpiError>
+        where TBuffer: AsMut<[u8]>
+    {
+        let mut entry = self.new_entry(buffer, user);
+        unsafe {
+            let length = entry.buffer.as_mut().len();
+            entry.idf_transaction.length = length*8;
+            let rx_buffer_ptr = entry.idf_transaction.__bindgen_anon_2.rx_buffer.as_mut();
+            *rx_buffer_ptr = entry.buffer.as_mut().as_mut_ptr() as *mut c_void;
+        }
+        self.submit(entry, wait_ticks)
+    }
+
+    // Wait for the oldest queued transaction to complete and hand its buffer and context back.
+    pub fn wait(&mut self, wait_ticks: Duration) -> Result<(TBuffer, TTransactionContext), SpiError> {
+        if self.in_flight.is_empty() {
+            return Err(SpiError::Generic);
+        }
+        let mut completed: *mut idf::spi_transaction_t = ptr::null_mut();
+        unsafe {
+            idf::spi_device_get_trans_result(self.device.handle, &mut completed, wait_ticks.to_ticks()).as_result()?;
+        }
+        let index = self.in_flight.iter()
+            .position(|entry| &entry.idf_transaction as *const idf::spi_transaction_t == completed as *const idf::spi_transaction_t)
+            .ok_or(SpiError::Generic)?;
+        let entry = *self.in_flight.remove(index).unwrap();
+        Ok((entry.buffer, entry.context.context))
+    }
+
+    // Wait for all queued transactions and drop their buffers.
+    pub fn flush(&mut self) -> Result<(), SpiError> {
+        while !self.in_flight.is_empty() {
+            self.wait(Duration::infinite())?;
+        }
+        Ok(())
+    }
+
+    fn new_entry(&mut self, buffer: TBuffer, user: TTransactionContext) -> Box<SpiQueuedEntry<TBuffer, TTransactionContext>> {
+        Box::new(SpiQueuedEntry {
+            idf_transaction: idf::spi_transaction_t::default(),
+            context: SpiTransactionContext {
+                device: self.device as *mut SpiDevice<TTransactionContext>,
+                context: user,
+            },
+            buffer: buffer,
+        })
+    }
+
+    fn submit(&mut self, mut entry: Box<SpiQueuedEntry<TBuffer, TTransactionContext>>, wait_ticks: Duration) -> Result<(), SpiError> {
+        if self.is_full() {
+            return Err(SpiError::FreeRtosError(FreeRtosError::QueueFull));
+        }
+        unsafe {
+            // The entry is boxed, so the context address stays valid while the box is moved into in_flight.
+            entry.idf_transaction.user = (&mut entry.context as *mut SpiTransactionContext<TTransactionContext>) as *mut c_void;
+            idf::spi_device_queue_trans(self.device.handle, &mut entry.idf_transaction, wait_ticks.to_ticks()).as_result()?;
+        }
+        self.in_flight.push_back(entry);
+        Ok(())
+    }
+}
+
+impl<'d, TBuffer, TTransactionContext> Drop for SpiTransactionQueue<'d, TBuffer, TTransactionContext> {
+    fn drop(&mut self) {
+        // Buffers must not be released while the DMA is still accessing them.
+        while !self.in_flight.is_empty() {
+            let mut completed: *mut idf::spi_transaction_t = ptr::null_mut();
+            let result = unsafe { idf::spi_device_get_trans_result(self.device.handle, &mut completed, idf::portMAX_DELAY).as_result() };
+            if result.is_err() {
+                // Leak the remaining entries rather than freeing buffers which may still be in flight.
+                while let Some(entry) = self.in_flight.pop_front() {
+                    mem::forget(entry);
+                }
+                break;
+            }
+            let index = self.in_flight.iter()
+                .position(|entry| &entry.idf_transaction as *const idf::spi_transaction_t == completed as *const idf::spi_transaction_t);
+            if let Some(index) = index {
+                self.in_flight.remove(index);
+            }
+        }
+    }
+}
+
+// The device handle may be used from any task as long as only one task uses it at a time,
+// which SpiDeviceBusLock guarantees.
+unsafe impl<TTransactionContext: Send> Send for SpiDevice<TTransactionContext> {}
+
+impl<TTransactionContext> Drop for SpiDevice<TTransactionContext> {
+    fn drop(&mut self) {
+        unsafe {
+            idf::spi_bus_remove_device(self.handle);
         }
     }
 }
 
+// SPI device shared between tasks.
+// lock() takes the per-device mutex and then acquires the bus, so other devices on the same bus
+// cannot interleave transactions until the returned guard is dropped.
 pub struct SpiDeviceBusLock<TTransactionContext> {
-    device: UnsafeCell<SpiDevice<TTransactionContext>>,
+    mutex: freertos_rs::Mutex<SpiDevice<TTransactionContext>>,
 }
-unsafe impl<TTransactionContext> Sync for SpiDeviceBusLock<TTransactionContext> {}
+
 impl<TTransactionContext> SpiDeviceBusLock<TTransactionContext> {
-    fn new(device: SpiDevice<TTransactionContext>) -> Self {
-        SpiDeviceBusLock {
-            device: UnsafeCell::new(device),
-        }
+    fn new(device: SpiDevice<TTransactionContext>) -> Result<Self, SpiError> {
+        Ok(SpiDeviceBusLock {
+            mutex: freertos_rs::Mutex::new(device)?,
+        })
     }
     pub fn lock<'b>(&'b self) -> Result<SpiBusGuard<'b, TTransactionContext>, SpiError> {
+        self.lock_timeout(Duration::infinite())
+    }
+    // Wait for the device mutex at most `wait_ticks`. Acquiring the bus itself always waits until it is released.
+    pub fn lock_timeout<'b>(&'b self, wait_ticks: Duration) -> Result<SpiBusGuard<'b, TTransactionContext>, SpiError> {
+        let guard = self.mutex.lock(wait_ticks)?;
         unsafe {
-            idf::spi_device_acquire_bus((*self.device.get()).handle, idf::portMAX_DELAY).as_result()?;
-            Ok(SpiBusGuard::<'b, TTransactionContext> { device: &self.device })
+            idf::spi_device_acquire_bus(guard.handle, idf::portMAX_DELAY).as_result()?;
         }
+        Ok(SpiBusGuard::<'b, TTransactionContext> { device: guard })
     }
 }
 
+// Holds the device and the bus. Keep it alive to issue several transactions (e.g. command + data) back to back.
 pub struct SpiBusGuard<'a, TTransactionContext> {
-    device: &'a UnsafeCell<SpiDevice<TTransactionContext>>,
+    device: freertos_rs::MutexGuard<'a, SpiDevice<TTransactionContext>, MutexNormal>,
 }
 
 impl<'a, TTransactionContext> Drop for SpiBusGuard<'a, TTransactionContext> {
     fn drop(&mut self) {
         unsafe {
-            idf::spi_device_release_bus((*self.device.get()).handle)
+            idf::spi_device_release_bus(self.device.handle)
         }
     }
 }
@@ -330,54 +490,270 @@ impl<'a, TTransactionContext> Deref for SpiBusGuard<'a, TTransactionContext> {
     type Target = SpiDevice<TTransactionContext>;
 
     fn deref<'b>(&'b self) -> &'b Self::Target {
-        unsafe{ & *self.device.get() }
+        &*self.device
     }
 }
 impl<'a, TTransactionContext> DerefMut for SpiBusGuard<'a, TTransactionContext> {
     fn deref_mut<'b>(&'b mut self) -> &'b mut Self::Target {
-        unsafe{ &mut *self.device.get() }
+        &mut *self.device
     }
 }
 
-impl Write<u8> for SpiDevice<()> 
-{
-    type Error = SpiError;
-    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
-        let transaction = SpiTransaction::<()>::new_write(words, ());
-        self.transfer(transaction)
+impl<TTransactionContext> SpiTransfer<TTransactionContext> for SpiDevice<TTransactionContext> {
+    fn transfer_received<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<SpiReceived<'t>, SpiError> {
+        SpiDevice::transfer_received(self, transaction)
     }
 }
 
-impl Write<u8> for SpiDeviceBusLock<()>
-{
-    type Error = SpiError;
-    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
-        let transaction = SpiTransaction::<()>::new_write(words, ());
+impl<TTransactionContext> SpiTransactions<TTransactionContext> for SpiDeviceBusLock<TTransactionContext> {
+    fn transactions<R, F>(&self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiTransfer<TTransactionContext>) -> Result<R, SpiError>
+    {
         let mut device = self.lock()?;
-        device.transfer(transaction);
-        Ok(())
+        f(&mut *device)
     }
 }
 
-impl TransferDefault<u8> for SpiDeviceBusLock<()> {}
-impl FullDuplex<u8> for SpiDeviceBusLock<()>
-{
-    type Error = SpiError;
+// Binds a fixed transaction context to a shared device so that it can be used through the embedded-hal traits.
+// e.g. `device.with_context(true)` for a display whose pre-transaction callback selects data by `true`.
+pub struct SpiContextDevice<'a, TTransactionContext> {
+    device: &'a SpiDeviceBusLock<TTransactionContext>,
+    context: TTransactionContext,
+}
 
-    fn read(&mut self) -> nb::Result<u8, SpiError> {
-        Ok(self.lock().unwrap().last_word)
+impl<TTransactionContext> SpiDeviceBusLock<TTransactionContext> {
+    pub fn with_context<'a>(&'a self, context: TTransactionContext) -> SpiContextDevice<'a, TTransactionContext> {
+        SpiContextDevice { device: self, context: context }
     }
-    fn send(&mut self, word: u8) -> nb::Result<(), SpiError> {
+}
+
+// Size of the stack buffer used to split embedded-hal transfers into SPI transactions.
+const SPI_HAL_CHUNK_SIZE: usize = 64;
+
+impl<TTransactionContext: Clone> SpiDevice<TTransactionContext> {
+    fn write_words(&mut self, words: &[u8], context: TTransactionContext) -> Result<(), SpiError> {
+        if words.is_empty() {
+            return Ok(());
+        }
+        self.transfer(SpiTransaction::new_write(words, context))
+    }
+    fn read_words(&mut self, words: &mut [u8], context: TTransactionContext) -> Result<(), SpiError> {
+        if words.is_empty() {
+            return Ok(());
+        }
+        self.transfer(SpiTransaction::new_read(words, context))
+    }
+    fn transfer_words(&mut self, words: &mut [u8], context: TTransactionContext) -> Result<(), SpiError> {
+        // The driver cannot transmit from and receive into the same buffer, so copy the words to be sent.
+        let mut tx_buffer = [0u8; SPI_HAL_CHUNK_SIZE];
+        for chunk in words.chunks_mut(SPI_HAL_CHUNK_SIZE) {
+            let tx = &mut tx_buffer[..chunk.len()];
+            tx.copy_from_slice(chunk);
+            self.transfer(SpiTransaction::new_both(tx, chunk, context.clone()))?;
+        }
+        Ok(())
+    }
+    fn transfer_split(&mut self, read: &mut [u8], write: &[u8], context: TTransactionContext) -> Result<(), SpiError> {
+        // In full-duplex mode the receive length must not exceed the transmit length.
+        let common = core::cmp::min(read.len(), write.len());
+        let (read_common, read_rest) = read.split_at_mut(common);
+        if common > 0 {
+            self.transfer(SpiTransaction::new_both(&write[..common], read_common, context.clone()))?;
+        }
+        self.write_words(&write[common..], context.clone())?;
+        self.read_words(read_rest, context)
+    }
+    fn write_iter_words<WI>(&mut self, words: WI, context: TTransactionContext) -> Result<(), SpiError>
+        where WI: IntoIterator<Item = u8>
+    {
+        let mut buffer = [0u8; SPI_HAL_CHUNK_SIZE];
+        let mut count = 0;
+        for word in words {
+            buffer[count] = word;
+            count += 1;
+            if count == SPI_HAL_CHUNK_SIZE {
+                self.write_words(&buffer, context.clone())?;
+                count = 0;
+            }
+        }
+        self.write_words(&buffer[..count], context)
+    }
+    fn send_word(&mut self, word: u8, context: TTransactionContext) -> Result<(), SpiError> {
         let tx : [u8;1] = [word];
         let mut rx : [u8;1] = [0];
-        let transaction = SpiTransaction::<()>::new_both(&tx, &mut rx, ());
-        let mut device = self.lock().unwrap();
-        match device.transfer(transaction) {
-            Ok(_) => {
-                device.last_word = rx[0];
+        self.transfer(SpiTransaction::new_both(&tx, &mut rx, context))?;
+        self.last_word = Some(rx[0]);
+        Ok(())
+    }
+    fn read_word(&mut self) -> nb::Result<u8, SpiError> {
+        // Each send is completed synchronously, so the word is either available or nothing has been sent.
+        self.last_word.take().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+// Gives the embedded-hal implementations access to the device and the context used for each transaction.
+trait SpiHalTarget {
+    type Context: Clone;
+    fn with_device<R, F>(&mut self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiDevice<Self::Context>, Self::Context) -> Result<R, SpiError>;
+}
+
+impl SpiHalTarget for SpiDevice<()> {
+    type Context = ();
+    fn with_device<R, F>(&mut self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiDevice<()>, ()) -> Result<R, SpiError>
+    {
+        f(self, ())
+    }
+}
+
+impl SpiHalTarget for SpiDeviceBusLock<()> {
+    type Context = ();
+    fn with_device<R, F>(&mut self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiDevice<()>, ()) -> Result<R, SpiError>
+    {
+        let mut device = self.lock()?;
+        f(&mut *device, ())
+    }
+}
+
+impl<'a, TTransactionContext: Clone> SpiHalTarget for SpiContextDevice<'a, TTransactionContext> {
+    type Context = TTransactionContext;
+    fn with_device<R, F>(&mut self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiDevice<TTransactionContext>, TTransactionContext) -> Result<R, SpiError>
+    {
+        let mut device = self.device.lock()?;
+        f(&mut *device, self.context.clone())
+    }
+}
+
+macro_rules! impl_spi_hal {
+    ([$($generics:tt)*] $target:ty) => {
+        impl<$($generics)*> Write<u8> for $target {
+            type Error = SpiError;
+            fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+                self.with_device(|device, context| device.write_words(words, context))
+            }
+        }
+
+        impl<$($generics)*> WriteIter<u8> for $target {
+            type Error = SpiError;
+            fn write_iter<WI>(&mut self, words: WI) -> Result<(), SpiError>
+                where WI: IntoIterator<Item = u8>
+            {
+                self.with_device(|device, context| device.write_iter_words(words, context))
+            }
+        }
+
+        impl<$($generics)*> Transfer<u8> for $target {
+            type Error = SpiError;
+            fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+                self.with_device(|device, context| device.transfer_words(words, context))?;
+                Ok(words)
+            }
+        }
+
+        impl<$($generics)*> FullDuplex<u8> for $target {
+            type Error = SpiError;
+            fn read(&mut self) -> nb::Result<u8, SpiError> {
+                match self.with_device(|device, _| Ok(device.read_word())) {
+                    Ok(result) => result,
+                    Err(err) => Err(nb::Error::Other(err)),
+                }
+            }
+            fn send(&mut self, word: u8) -> nb::Result<(), SpiError> {
+                self.with_device(|device, context| device.send_word(word, context))
+                    .map_err(nb::Error::Other)
+            }
+        }
+
+        #[cfg(feature = "embedded-hal-1")]
+        impl<$($generics)*> embedded_hal_1::spi::ErrorType for $target {
+            type Error = SpiError;
+        }
+
+        // The bus is held for the whole call, but CS is not: the hardware CS (SpiDeviceInterfaceConfig::cs_pin)
+        // is asserted per operation. Use SpiSoftCsDevice for drivers which need an embedded-hal SpiDevice.
+        #[cfg(feature = "embedded-hal-1")]
+        impl<$($generics)*> embedded_hal_1::spi::SpiBus<u8> for $target {
+            fn read(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+                self.with_device(|device, context| device.read_words(words, context))
+            }
+            fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+                self.with_device(|device, context| device.write_words(words, context))
+            }
+            fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), SpiError> {
+                self.with_device(|device, context| device.transfer_split(read, write, context))
+            }
+            fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+                self.with_device(|device, context| device.transfer_words(words, context))
+            }
+            fn flush(&mut self) -> Result<(), SpiError> {
+                // Polling transfers are complete when they return.
                 Ok(())
-            },
-            Err(err) => Err(nb::Error::Other(err)),
+            }
+        }
+    };
+}
+
+impl_spi_hal!([] SpiDevice<()>);
+impl_spi_hal!([] SpiDeviceBusLock<()>);
+impl_spi_hal!(['a, TTransactionContext: Clone] SpiContextDevice<'a, TTransactionContext>);
+
+// Device whose CS is a GPIO held low for a whole embedded-hal 1.0 transaction, as SpiDevice requires.
+// Add the device with `cs_pin: None`; the bus is held from the first operation until CS is released.
+#[cfg(feature = "embedded-hal-1")]
+pub struct SpiSoftCsDevice<TTransactionContext> {
+    device: SpiDeviceBusLock<TTransactionContext>,
+    cs: NormalGpio,
+    context: TTransactionContext,
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<TTransactionContext: Clone> SpiSoftCsDevice<TTransactionContext> {
+    pub fn new(device: SpiDeviceBusLock<TTransactionContext>, pin_cs: GpioPin, context: TTransactionContext) -> Result<Self, SpiError> {
+        let mut cs = pin_cs.normal();
+        cs.configure(GpioConfig::output())?;
+        cs.set_level(true)?;
+        Ok(SpiSoftCsDevice { device: device, cs: cs, context: context })
+    }
+    pub fn into_inner(self) -> SpiDeviceBusLock<TTransactionContext> { self.device }
+
+    fn run_operations(device: &mut SpiDevice<TTransactionContext>, operations: &mut [embedded_hal_1::spi::Operation<'_, u8>], context: &TTransactionContext) -> Result<(), SpiError> {
+        use embedded_hal_1::spi::Operation;
+        for operation in operations.iter_mut() {
+            match operation {
+                Operation::Read(words) => device.read_words(words, context.clone())?,
+                Operation::Write(words) => device.write_words(words, context.clone())?,
+                Operation::Transfer(read, write) => device.transfer_split(read, write, context.clone())?,
+                Operation::TransferInPlace(words) => device.transfer_words(words, context.clone())?,
+                Operation::DelayNs(ns) => unsafe { idf::ets_delay_us((*ns + 999) / 1000) },
+            }
         }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<TTransactionContext> embedded_hal_1::spi::ErrorType for SpiSoftCsDevice<TTransactionContext> {
+    type Error = SpiError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<TTransactionContext: Clone> embedded_hal_1::spi::SpiDevice<u8> for SpiSoftCsDevice<TTransactionContext> {
+    fn transaction(&mut self, operations: &mut [embedded_hal_1::spi::Operation<'_, u8>]) -> Result<(), SpiError> {
+        let mut device = self.device.lock()?;
+        self.cs.set_level(false)?;
+        let result = Self::run_operations(&mut *device, operations, &self.context);
+        // Release CS even if an operation failed, so that the next transaction starts cleanly.
+        self.cs.set_level(true)?;
+        result
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::spi::Error for SpiError {
+    fn kind(&self) -> embedded_hal_1::spi::ErrorKind {
+        embedded_hal_1::spi::ErrorKind::Other
     }
 }
@@ -0,0 +1,159 @@
+// Compact binary log of SPI transactions, written by SpiRecorder and played back by SpiReplay.
+//
+// Record layout (all integers are unsigned LEB128 varints):
+//   context, flags, cmd, addr, command bits, address bits, dummy bits, time delta [us], tx length, tx bytes, rx length, rx bytes
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+// Conversion of a transaction context to and from its representation in the log.
+pub trait SpiLogContext: Sized {
+    fn to_log(&self) -> u8;
+    fn from_log(value: u8) -> Self;
+}
+impl SpiLogContext for () {
+    fn to_log(&self) -> u8 { 0 }
+    fn from_log(_value: u8) -> Self {}
+}
+impl SpiLogContext for bool {
+    fn to_log(&self) -> u8 { if *self { 1 } else { 0 } }
+    fn from_log(value: u8) -> Self { value != 0 }
+}
+impl SpiLogContext for u8 {
+    fn to_log(&self) -> u8 { *self }
+    fn from_log(value: u8) -> Self { value }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpiLogRecord<'a> {
+    pub context: u8,
+    pub flags: u32,
+    pub cmd: u16,
+    pub addr: u64,
+    // Lengths of the command, address and dummy phases of transactions with SPI_TRANS_VARIABLE_* flags.
+    pub phase_bits: [u8; 3],
+    // Time since the previous record in microseconds.
+    pub time_delta_us: u64,
+    pub tx: &'a [u8],
+    pub rx: &'a [u8],
+}
+
+impl<'a> SpiLogRecord<'a> {
+    // Compare everything the device under test controls, i.e. all but the timing and the contents of the received data.
+    pub fn same_request(&self, other: &SpiLogRecord) -> bool {
+        self.context == other.context && self.flags == other.flags && self.cmd == other.cmd && self.addr == other.addr
+            && self.phase_bits == other.phase_bits && self.tx == other.tx && self.rx.len() == other.rx.len()
+    }
+}
+
+pub struct SpiLogWriter {
+    data: Vec<u8>,
+}
+
+impl Default for SpiLogWriter {
+    fn default() -> Self { SpiLogWriter::new() }
+}
+
+impl SpiLogWriter {
+    pub fn new() -> SpiLogWriter {
+        SpiLogWriter { data: Vec::new() }
+    }
+    pub fn push(&mut self, record: &SpiLogRecord) {
+        self.write_varint(record.context as u64);
+        self.write_varint(record.flags as u64);
+        self.write_varint(record.cmd as u64);
+        self.write_varint(record.addr);
+        for bits in record.phase_bits.iter() {
+            self.write_varint(*bits as u64);
+        }
+        self.write_varint(record.time_delta_us);
+        self.write_varint(record.tx.len() as u64);
+        self.data.extend_from_slice(record.tx);
+        self.write_varint(record.rx.len() as u64);
+        self.data.extend_from_slice(record.rx);
+    }
+    pub fn as_bytes(&self) -> &[u8] { &self.data }
+    pub fn into_bytes(self) -> Vec<u8> { self.data }
+    pub fn clear(&mut self) { self.data.clear() }
+
+    fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.data.push(byte);
+                return;
+            }
+            self.data.push(byte | 0x80);
+        }
+    }
+}
+
+pub struct SpiLogReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SpiLogReader<'a> {
+    pub fn new(data: &'a [u8]) -> SpiLogReader<'a> {
+        SpiLogReader { data: data, position: 0 }
+    }
+    pub fn is_end(&self) -> bool { self.position >= self.data.len() }
+    pub fn position(&self) -> usize { self.position }
+
+    // Returns None at the end of the log or if the remaining data is not a complete record.
+    pub fn next_record(&mut self) -> Option<SpiLogRecord<'a>> {
+        let context = self.read_varint()?;
+        let flags = self.read_varint()?;
+        let cmd = self.read_varint()?;
+        let addr = self.read_varint()?;
+        let mut phase_bits = [0u8; 3];
+        for bits in phase_bits.iter_mut() {
+            *bits = self.read_varint()? as u8;
+        }
+        let time_delta_us = self.read_varint()?;
+        let tx = self.read_bytes()?;
+        let rx = self.read_bytes()?;
+        Some(SpiLogRecord {
+            context: context as u8,
+            flags: flags as u32,
+            cmd: cmd as u16,
+            addr: addr,
+            phase_bits: phase_bits,
+            time_delta_us: time_delta_us,
+            tx: tx,
+            rx: rx,
+        })
+    }
+
+    fn read_varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = *self.data.get(self.position)?;
+            self.position += 1;
+            if shift >= 64 {
+                return None;
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if (byte & 0x80) == 0 {
+                return Some(value);
+            }
+            shift += 7;
+        }
+    }
+    fn read_bytes(&mut self) -> Option<&'a [u8]> {
+        let length = self.read_varint()? as usize;
+        let end = self.position.checked_add(length)?;
+        let bytes = self.data.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+}
+
+impl<'a> Iterator for SpiLogReader<'a> {
+    type Item = SpiLogRecord<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record()
+    }
+}
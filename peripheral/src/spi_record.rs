@@ -0,0 +1,328 @@
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::vec;
+
+#[cfg(feature = "esp-idf")]
+use idf;
+
+use crate::spi_transaction::*;
+use crate::spi_log::*;
+
+// Source of the timestamps in the log, in microseconds.
+pub trait SpiClock {
+    fn now_us(&self) -> u64;
+}
+impl<F: Fn() -> u64> SpiClock for F {
+    fn now_us(&self) -> u64 { self() }
+}
+
+#[cfg(feature = "esp-idf")]
+fn esp_timer_now_us() -> u64 {
+    unsafe { idf::esp_timer_get_time() as u64 }
+}
+
+// Records every transaction executed through the wrapped device.
+// e.g. wrap the device of an Lcd, run reset() and some drawing, then save take_log() as a reference log.
+pub struct SpiRecorder<TDevice, TClock> {
+    device: TDevice,
+    clock: TClock,
+    log: RefCell<SpiLogWriter>,
+    last_time_us: Cell<Option<u64>>,
+}
+
+#[cfg(feature = "esp-idf")]
+impl<TDevice> SpiRecorder<TDevice, fn() -> u64> {
+    // Recorder which takes the timestamps from esp_timer.
+    pub fn new(device: TDevice) -> SpiRecorder<TDevice, fn() -> u64> {
+        SpiRecorder::with_clock(device, esp_timer_now_us as fn() -> u64)
+    }
+}
+
+impl<TDevice, TClock: SpiClock> SpiRecorder<TDevice, TClock> {
+    pub fn with_clock(device: TDevice, clock: TClock) -> SpiRecorder<TDevice, TClock> {
+        SpiRecorder {
+            device: device,
+            clock: clock,
+            log: RefCell::new(SpiLogWriter::new()),
+            last_time_us: Cell::new(None),
+        }
+    }
+    pub fn device(&self) -> &TDevice { &self.device }
+    pub fn into_inner(self) -> TDevice { self.device }
+
+    // Take the recorded log and start a new one.
+    pub fn take_log(&self) -> Vec<u8> {
+        self.last_time_us.set(None);
+        let mut log = self.log.borrow_mut();
+        let bytes = log.as_bytes().to_vec();
+        log.clear();
+        bytes
+    }
+}
+
+impl<TTransactionContext, TDevice, TClock> SpiTransactions<TTransactionContext> for SpiRecorder<TDevice, TClock>
+    where TTransactionContext: SpiLogContext, TDevice: SpiTransactions<TTransactionContext>, TClock: SpiClock
+{
+    fn transactions<R, F>(&self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiTransfer<TTransactionContext>) -> Result<R, SpiError>
+    {
+        self.device.transactions(|device| {
+            let mut recording = SpiRecordingTransfer {
+                device: device,
+                clock: &self.clock,
+                log: &self.log,
+                last_time_us: &self.last_time_us,
+            };
+            f(&mut recording)
+        })
+    }
+}
+
+struct SpiRecordingTransfer<'r, TTransactionContext> {
+    device: &'r mut SpiTransfer<TTransactionContext>,
+    clock: &'r SpiClock,
+    log: &'r RefCell<SpiLogWriter>,
+    last_time_us: &'r Cell<Option<u64>>,
+}
+
+impl<'r, TTransactionContext: SpiLogContext> SpiTransfer<TTransactionContext> for SpiRecordingTransfer<'r, TTransactionContext> {
+    fn transfer_received<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<SpiReceived<'t>, SpiError> {
+        let context = transaction.user().to_log();
+        let flags = transaction.flags();
+        let cmd = transaction.cmd();
+        let addr = transaction.addr();
+        let phase_bits = transaction.phase_bits();
+        let tx = transaction.tx_bytes().to_vec();
+
+        let now_us = self.clock.now_us();
+        let time_delta_us = self.last_time_us.get().map_or(0, |last| now_us.wrapping_sub(last));
+        self.last_time_us.set(Some(now_us));
+
+        let received = self.device.transfer_received(transaction)?;
+        self.log.borrow_mut().push(&SpiLogRecord {
+            context: context,
+            flags: flags,
+            cmd: cmd,
+            addr: addr,
+            phase_bits: phase_bits,
+            time_delta_us: time_delta_us,
+            tx: &tx,
+            rx: received.data(),
+        });
+        Ok(received)
+    }
+}
+
+// Stand-in device which plays back a recorded log.
+// Received data is taken from the record at the same position, and requests which differ from the log are counted
+// so that a driver can be checked against a reference session. Wrap it with SpiRecorder to get the actual stream for diffing.
+pub struct SpiReplay<TTransactionContext> {
+    state: RefCell<SpiReplayState>,
+    phantom: PhantomData<TTransactionContext>,
+}
+
+struct SpiReplayState {
+    log: Vec<u8>,
+    position: usize,
+    index: usize,
+    mismatches: usize,
+    first_mismatch: Option<usize>,
+}
+
+impl<TTransactionContext> SpiReplay<TTransactionContext> {
+    pub fn new(log: Vec<u8>) -> SpiReplay<TTransactionContext> {
+        SpiReplay {
+            state: RefCell::new(SpiReplayState {
+                log: log,
+                position: 0,
+                index: 0,
+                mismatches: 0,
+                first_mismatch: None,
+            }),
+            phantom: PhantomData,
+        }
+    }
+    // Number of transactions replayed so far.
+    pub fn replayed(&self) -> usize { self.state.borrow().index }
+    pub fn mismatches(&self) -> usize { self.state.borrow().mismatches }
+    // Index of the first transaction which differs from the log.
+    pub fn first_mismatch(&self) -> Option<usize> { self.state.borrow().first_mismatch }
+    // True if every record in the log has been replayed.
+    pub fn is_finished(&self) -> bool {
+        let state = self.state.borrow();
+        state.position >= state.log.len()
+    }
+}
+
+impl<TTransactionContext: SpiLogContext> SpiTransfer<TTransactionContext> for SpiReplay<TTransactionContext> {
+    fn transfer_received<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<SpiReceived<'t>, SpiError> {
+        replay_transfer(&self.state, transaction)
+    }
+}
+
+impl<TTransactionContext: SpiLogContext> SpiTransactions<TTransactionContext> for SpiReplay<TTransactionContext> {
+    fn transactions<R, F>(&self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiTransfer<TTransactionContext>) -> Result<R, SpiError>
+    {
+        let mut transfer = SpiReplayTransfer { state: &self.state, phantom: PhantomData };
+        f(&mut transfer)
+    }
+}
+
+struct SpiReplayTransfer<'r, TTransactionContext> {
+    state: &'r RefCell<SpiReplayState>,
+    phantom: PhantomData<TTransactionContext>,
+}
+
+impl<'r, TTransactionContext: SpiLogContext> SpiTransfer<TTransactionContext> for SpiReplayTransfer<'r, TTransactionContext> {
+    fn transfer_received<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<SpiReceived<'t>, SpiError> {
+        replay_transfer(self.state, transaction)
+    }
+}
+
+fn replay_transfer<'t, TTransactionContext: SpiLogContext>(state: &RefCell<SpiReplayState>, mut transaction: SpiTransaction<'t, TTransactionContext>) -> Result<SpiReceived<'t>, SpiError> {
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let index = state.index;
+    let (record, consumed) = {
+        let mut reader = SpiLogReader::new(&state.log[state.position..]);
+        match reader.next_record() {
+            Some(record) => (record, reader.position()),
+            None => return Err(SpiError::ReplayEnd(index)),
+        }
+    };
+    // Only the length of the received data is part of the request.
+    let expected_rx = vec![0; transaction.rx_len()];
+    let actual = SpiLogRecord {
+        context: transaction.user().to_log(),
+        flags: transaction.flags(),
+        cmd: transaction.cmd(),
+        addr: transaction.addr(),
+        phase_bits: transaction.phase_bits(),
+        time_delta_us: 0,
+        tx: transaction.tx_bytes(),
+        rx: &expected_rx,
+    };
+    if !record.same_request(&actual) {
+        state.mismatches += 1;
+        if state.first_mismatch.is_none() {
+            state.first_mismatch = Some(index);
+        }
+    }
+    let mut rx_data = [0u8; 4];
+    {
+        let rx_buffer = if transaction.is_rx_inline() { Some(&mut rx_data[..]) } else { transaction.rx_buffer_mut() };
+        if let Some(rx_buffer) = rx_buffer {
+            let length = core::cmp::min(rx_buffer.len(), record.rx.len());
+            rx_buffer[..length].copy_from_slice(&record.rx[..length]);
+        }
+    }
+    state.position += consumed;
+    state.index += 1;
+    Ok(transaction.into_received(rx_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Device which receives a fixed pattern.
+    struct PatternDevice;
+
+    impl SpiTransfer<u8> for PatternDevice {
+        fn transfer_received<'t>(&mut self, mut transaction: SpiTransaction<'t, u8>) -> Result<SpiReceived<'t>, SpiError> {
+            if let Some(rx_buffer) = transaction.rx_buffer_mut() {
+                for (i, byte) in rx_buffer.iter_mut().enumerate() {
+                    *byte = 0x10 + i as u8;
+                }
+            }
+            Ok(transaction.into_received([0xa1, 0xa2, 0xa3, 0xa4]))
+        }
+    }
+
+    impl SpiTransactions<u8> for PatternDevice {
+        fn transactions<R, F>(&self, f: F) -> Result<R, SpiError>
+            where F: FnOnce(&mut SpiTransfer<u8>) -> Result<R, SpiError>
+        {
+            f(&mut PatternDevice)
+        }
+    }
+
+    fn record_session() -> Vec<u8> {
+        let now_us = Cell::new(100u64);
+        let recorder = SpiRecorder::with_clock(PatternDevice, || { now_us.set(now_us.get() + 25); now_us.get() });
+        recorder.transactions(|device| {
+            let inline = device.transfer_inline(SpiTransaction::builder(1).write_inline(&[0x9f]).read_inline(16).build())?;
+            assert_eq!(inline, [0xa1, 0xa2, 0xa3, 0xa4]);
+            let mut rx = [0u8; 3];
+            device.transfer(SpiTransaction::new_both(&[1, 2, 3, 4], &mut rx[..3], 2))?;
+            device.transfer(SpiTransaction::builder(3).write_bits(&[0xff, 0xff], 12).build())
+        }).unwrap();
+        recorder.take_log()
+    }
+
+    #[test]
+    fn recorder_logs_received_and_sent_data() {
+        let log = record_session();
+        let mut reader = SpiLogReader::new(&log);
+
+        let record = reader.next_record().unwrap();
+        assert_eq!((record.context, record.time_delta_us), (1, 0));
+        assert_eq!(record.tx, &[0x9f]);
+        assert_eq!(record.rx, &[0xa1, 0xa2]);
+
+        let record = reader.next_record().unwrap();
+        assert_eq!((record.context, record.time_delta_us), (2, 25));
+        assert_eq!(record.rx, &[0x10, 0x11, 0x12]);
+
+        // Only the bits of the transaction are logged.
+        let record = reader.next_record().unwrap();
+        assert_eq!(record.tx, &[0xff, 0xff]);
+        assert_eq!(record.rx, &[] as &[u8]);
+        assert!(reader.next_record().is_none());
+    }
+
+    #[test]
+    fn replay_returns_recorded_data() {
+        let mut replay = SpiReplay::<u8>::new(record_session());
+        let inline = replay.transfer_inline(SpiTransaction::builder(1).write_inline(&[0x9f]).read_inline(16).build()).unwrap();
+        assert_eq!(&inline[..2], &[0xa1, 0xa2]);
+        let mut rx = [0u8; 3];
+        replay.transfer(SpiTransaction::new_both(&[1, 2, 3, 4], &mut rx[..3], 2)).unwrap();
+        assert_eq!(rx, [0x10, 0x11, 0x12]);
+        replay.transfer(SpiTransaction::builder(3).write_bits(&[0xff, 0xff], 12).build()).unwrap();
+        assert_eq!(replay.mismatches(), 0);
+        assert!(replay.is_finished());
+        assert!(replay.transfer(SpiTransaction::new_write(&[0], 3)).is_err());
+    }
+
+    #[test]
+    fn replay_counts_different_requests() {
+        let mut replay = SpiReplay::<u8>::new(record_session());
+        // Reads one byte less than recorded.
+        replay.transfer_inline(SpiTransaction::builder(1).write_inline(&[0x9f]).read_inline(8).build()).unwrap();
+        let mut rx = [0u8; 3];
+        replay.transfer(SpiTransaction::new_both(&[1, 2, 3, 5], &mut rx[..3], 2)).unwrap();
+        replay.transfer(SpiTransaction::builder(3).write_bits(&[0xff, 0xff], 12).build()).unwrap();
+        assert_eq!(replay.mismatches(), 2);
+        assert_eq!(replay.first_mismatch(), Some(0));
+    }
+
+    #[test]
+    fn replay_compares_phase_lengths() {
+        let recorder = SpiRecorder::with_clock(PatternDevice, || 0);
+        recorder.transactions(|device| {
+            device.transfer(SpiTransaction::builder(1).command_bits(0x9f, 8).address_bits(0x12, 24).dummy_bits(8).write(&[0]).build())
+        }).unwrap();
+        let log = recorder.take_log();
+        let record = SpiLogReader::new(&log).next_record().unwrap();
+        assert_eq!(record.phase_bits, [8, 24, 8]);
+
+        let mut replay = SpiReplay::<u8>::new(log);
+        replay.transfer(SpiTransaction::builder(1).command_bits(0x9f, 8).address_bits(0x12, 16).dummy_bits(8).write(&[0]).build()).unwrap();
+        assert_eq!(replay.mismatches(), 1);
+    }
+}
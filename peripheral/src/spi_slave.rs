@@ -0,0 +1,182 @@
+use core::ptr;
+use core::convert::Into;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+
+use freertos_rs::*;
+
+use crate::gpio::*;
+use crate::spi::*;
+use crate::spi_transaction::*;
+
+#[derive(Copy, Clone)]
+pub struct SpiSlaveInterfaceConfig {
+    pub cs_pin: GpioPin,
+    pub mode: SpiMode,
+    pub queue_size: usize,
+    pub flags: u32,
+}
+
+// State referred from the transaction callbacks through spi_slave_transaction_t::user.
+struct SpiSlaveShared {
+    ready_callback: Box<SpiIsrCallback<bool>>,
+}
+
+struct SpiSlaveEntry<TBuffer> {
+    idf_transaction: idf::spi_slave_transaction_t,
+    tx_buffer: Option<TBuffer>,
+    rx_buffer: Option<TBuffer>,
+}
+
+// Completed slave transaction. The buffers passed to SpiSlave::enqueue are handed back here.
+pub struct SpiSlaveTransfer<TBuffer> {
+    pub tx_buffer: Option<TBuffer>,
+    pub rx_buffer: Option<TBuffer>,
+    pub received_bits: usize,
+}
+
+// SPI slave on HSPI or VSPI.
+// Transactions are queued with their buffers and complete when the host clocks them out.
+// With DMA, buffers must be word aligned and their length must be a multiple of 4 bytes.
+pub struct SpiSlave<TBuffer> {
+    host_device: idf::spi_host_device_t,
+    dma_channel: i32,
+    shared: Box<SpiSlaveShared>,
+    queue_size: usize,
+    in_flight: VecDeque<Box<SpiSlaveEntry<TBuffer>>>,
+}
+
+impl<TBuffer> SpiSlave<TBuffer> where TBuffer: AsRef<[u8]> + AsMut<[u8]> + 'static {
+    // `ready_callback` is called in ISR context with `true` when a transaction has been loaded and the host may start clocking,
+    // and with `false` when it has finished. Passing a DirectOutputPin drives a handshake line for the host.
+    pub fn new<FReady>(host_device: SpiHostDevice, bus_config: SpiBusConfig, config: SpiSlaveInterfaceConfig, dma_channel: i32, ready_callback: FReady) -> Result<SpiSlave<TBuffer>, SpiError>
+        where FReady : SpiIsrCallback<bool> + 'static
+    {
+        let host_device: idf::spi_host_device_t = host_device.into();
+        let idf_bus_config: idf::spi_bus_config_t = bus_config.into();
+        let mut idf_config = idf::spi_slave_interface_config_t::default();
+        idf_config.spics_io_num = config.cs_pin.number() as i32;
+        idf_config.flags = config.flags;
+        idf_config.queue_size = config.queue_size as i32;
+        idf_config.mode = config.mode.into();
+        idf_config.post_setup_cb = Some(SpiSlave::<TBuffer>::post_setup_handler);
+        idf_config.post_trans_cb = Some(SpiSlave::<TBuffer>::post_trans_handler);
+        unsafe {
+            idf::spi_slave_initialize(host_device, &idf_bus_config, &idf_config, dma_channel).as_result()?;
+        }
+        Ok(SpiSlave {
+            host_device: host_device,
+            dma_channel: dma_channel,
+            shared: Box::new(SpiSlaveShared { ready_callback: Box::new(ready_callback) }),
+            queue_size: config.queue_size,
+            in_flight: VecDeque::with_capacity(config.queue_size),
+        })
+    }
+
+    unsafe extern "C" fn post_setup_handler(idf_transaction: *mut idf::spi_slave_transaction_t) {
+        let shared = &*((*idf_transaction).user as *const SpiSlaveShared);
+        shared.ready_callback.call(&true);
+    }
+
+    unsafe extern "C" fn post_trans_handler(idf_transaction: *mut idf::spi_slave_transaction_t) {
+        let shared = &*((*idf_transaction).user as *const SpiSlaveShared);
+        shared.ready_callback.call(&false);
+    }
+
+    // Length of a transaction in bits. The tx and rx buffers must have the same length, since the driver
+    // transfers `length` bits from and to both of them.
+    fn transaction_length(&self, tx_buffer: Option<&[u8]>, rx_buffer: Option<&[u8]>) -> Result<usize, SpiError> {
+        let length = match (tx_buffer, rx_buffer) {
+            (Some(tx), Some(rx)) if tx.len() != rx.len() => return Err(SpiError::InvalidBuffer),
+            (Some(buffer), _) | (None, Some(buffer)) => buffer.len(),
+            (None, None) => return Err(SpiError::InvalidBuffer),
+        };
+        if self.dma_channel != 0 {
+            let dma_capable = |buffer: Option<&[u8]>| buffer.map_or(true, |buffer| (buffer.as_ptr() as usize) % 4 == 0 && buffer.len() % 4 == 0);
+            if !dma_capable(tx_buffer) || !dma_capable(rx_buffer) {
+                return Err(SpiError::InvalidBuffer);
+            }
+        }
+        Ok(length*8)
+    }
+
+    pub fn pending(&self) -> usize { self.in_flight.len() }
+    pub fn is_full(&self) -> bool { self.in_flight.len() >= self.queue_size }
+
+    // Queue a transaction. If both buffers are given, they must have the same length.
+    pub fn enqueue(&mut self, tx_buffer: Option<TBuffer>, rx_buffer: Option<TBuffer>, wait_ticks: Duration) -> Result<(), SpiError> {
+        if self.is_full() {
+            return Err(SpiError::FreeRtosError(FreeRtosError::QueueFull));
+        }
+        let mut entry = Box::new(SpiSlaveEntry {
+            idf_transaction: idf::spi_slave_transaction_t::default(),
+            tx_buffer: tx_buffer,
+            rx_buffer: rx_buffer,
+        });
+        // Checked on the boxed entry, since that is where inline buffers (arrays) are accessed by DMA.
+        entry.idf_transaction.length = self.transaction_length(entry.tx_buffer.as_ref().map(|buffer| buffer.as_ref()), entry.rx_buffer.as_ref().map(|buffer| buffer.as_ref()))?;
+        entry.idf_transaction.tx_buffer = entry.tx_buffer.as_ref().map_or(ptr::null(), |buffer| buffer.as_ref().as_ptr() as *const c_void);
+        entry.idf_transaction.rx_buffer = entry.rx_buffer.as_mut().map_or(ptr::null_mut(), |buffer| buffer.as_mut().as_mut_ptr() as *mut c_void);
+        entry.idf_transaction.user = (&*self.shared as *const SpiSlaveShared) as *mut c_void;
+        unsafe {
+            idf::spi_slave_queue_trans(self.host_device, &entry.idf_transaction, wait_ticks.to_ticks()).as_result()?;
+        }
+        self.in_flight.push_back(entry);
+        Ok(())
+    }
+
+    // Wait for the oldest queued transaction to be clocked by the host.
+    pub fn wait(&mut self, wait_ticks: Duration) -> Result<SpiSlaveTransfer<TBuffer>, SpiError> {
+        if self.in_flight.is_empty() {
+            return Err(SpiError::Generic);
+        }
+        let mut completed: *mut idf::spi_slave_transaction_t = ptr::null_mut();
+        unsafe {
+            idf::spi_slave_get_trans_result(self.host_device, &mut completed, wait_ticks.to_ticks()).as_result()?;
+        }
+        let index = self.in_flight.iter()
+            .position(|entry| &entry.idf_transaction as *const idf::spi_slave_transaction_t == completed as *const idf::spi_slave_transaction_t)
+            .ok_or(SpiError::Generic)?;
+        let entry = *self.in_flight.remove(index).unwrap();
+        Ok(SpiSlaveTransfer {
+            tx_buffer: entry.tx_buffer,
+            rx_buffer: entry.rx_buffer,
+            received_bits: entry.idf_transaction.trans_len,
+        })
+    }
+
+    // Transfer with borrowed buffers. Waits until the host clocks the transaction, because the driver
+    // keeps a timed out transaction in its queue and the buffers must stay valid until it completes.
+    // If both buffers are given, they must have the same length. Returns the number of bits actually transferred.
+    pub fn transmit(&mut self, tx_buffer: Option<&[u8]>, rx_buffer: Option<&mut [u8]>) -> Result<usize, SpiError> {
+        if !self.in_flight.is_empty() {
+            return Err(SpiError::Generic);
+        }
+        let mut idf_transaction = idf::spi_slave_transaction_t::default();
+        idf_transaction.length = self.transaction_length(tx_buffer, rx_buffer.as_ref().map(|buffer| &**buffer))?;
+        idf_transaction.tx_buffer = tx_buffer.map_or(ptr::null(), |buffer| buffer.as_ptr() as *const c_void);
+        idf_transaction.rx_buffer = rx_buffer.map_or(ptr::null_mut(), |buffer| buffer.as_mut_ptr() as *mut c_void);
+        idf_transaction.user = (&*self.shared as *const SpiSlaveShared) as *mut c_void;
+        unsafe {
+            idf::spi_slave_transmit(self.host_device, &mut idf_transaction, idf::portMAX_DELAY).as_result()?;
+        }
+        Ok(idf_transaction.trans_len)
+    }
+}
+
+unsafe impl<TBuffer: Send> Send for SpiSlave<TBuffer> {}
+
+impl<TBuffer> Drop for SpiSlave<TBuffer> {
+    fn drop(&mut self) {
+        unsafe {
+            // Freeing the slave resets the peripheral and its DMA, so the queued buffers can be released afterwards.
+            idf::spi_slave_free(self.host_device);
+        }
+    }
+}
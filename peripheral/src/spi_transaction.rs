@@ -0,0 +1,304 @@
+// SPI transactions and the device traits, independent of the ESP-IDF SPI driver.
+
+#[cfg(feature = "esp-idf")]
+use idf::IdfError;
+#[cfg(feature = "esp-idf")]
+use freertos_rs::FreeRtosError;
+
+#[derive(Copy, Clone, Debug)]
+pub enum SpiError {
+    Generic,
+    #[cfg(feature = "esp-idf")]
+    IdfError(IdfError),
+    #[cfg(feature = "esp-idf")]
+    FreeRtosError(FreeRtosError),
+    // The replayed log has no more transactions or is corrupted at the given record.
+    ReplayEnd(usize),
+    // The transmit and receive buffers differ in length, or a DMA buffer is not word aligned or not a multiple of 4 bytes.
+    InvalidBuffer,
+}
+
+#[cfg(feature = "esp-idf")]
+impl From<IdfError> for SpiError {
+    fn from(err: IdfError) -> SpiError {
+        SpiError::IdfError(err)
+    }
+}
+#[cfg(feature = "esp-idf")]
+impl From<FreeRtosError> for SpiError {
+    fn from(err: FreeRtosError) -> SpiError {
+        SpiError::FreeRtosError(err)
+    }
+}
+
+// Transaction flags (SPI_TRANS_* in spi_master.h)
+pub(crate) const SPI_TRANS_MODE_DIO: u32         = 1 << 0;
+pub(crate) const SPI_TRANS_MODE_QIO: u32         = 1 << 1;
+pub(crate) const SPI_TRANS_USE_RXDATA: u32       = 1 << 2;
+pub(crate) const SPI_TRANS_USE_TXDATA: u32       = 1 << 3;
+pub(crate) const SPI_TRANS_MODE_DIOQIO_ADDR: u32 = 1 << 4;
+pub(crate) const SPI_TRANS_VARIABLE_CMD: u32     = 1 << 5;
+pub(crate) const SPI_TRANS_VARIABLE_ADDR: u32    = 1 << 6;
+pub(crate) const SPI_TRANS_VARIABLE_DUMMY: u32   = 1 << 7;
+
+pub struct SpiTransaction<'a, T> {
+    pub(crate) flags: u32,
+    pub(crate) cmd: u16,
+    pub(crate) addr: u64,
+    pub(crate) command_bits: u8,
+    pub(crate) address_bits: u8,
+    pub(crate) dummy_bits: u8,
+    pub(crate) length: usize,
+    pub(crate) rxlength: Option<usize>,
+    pub(crate) tx_buffer: Option<&'a [u8]>,
+    pub(crate) rx_buffer: Option<&'a mut [u8]>,
+    pub(crate) tx_data: [u8; 4],
+    pub(crate) user: T,
+}
+
+impl<'a, T> SpiTransaction<'a, T> {
+    pub fn new_write(tx_buffer: &'a [u8], user: T) -> Self {
+        Self::builder(user).write(tx_buffer).build()
+    }
+    pub fn new_read(rx_buffer: &'a mut [u8], user: T) -> Self {
+        Self::builder(user).read(rx_buffer).build()
+    }
+    pub fn new_both(tx_buffer: &'a [u8], rx_buffer: &'a mut [u8], user: T) -> Self {
+        Self::builder(user).write(tx_buffer).read(rx_buffer).build()
+    }
+    pub fn builder(user: T) -> SpiTransactionBuilder<'a, T> {
+        SpiTransactionBuilder::new(user)
+    }
+
+    // Finish the transaction with the inline data received by it, for implementations of SpiTransfer::transfer_received.
+    pub fn into_received(self, rx_data: [u8; 4]) -> SpiReceived<'a> {
+        let length = self.rx_len();
+        let rx_buffer = if self.is_rx_inline() { None } else { self.rx_buffer };
+        SpiReceived::new(rx_buffer, rx_data, length)
+    }
+}
+
+// Data received by a transaction, with the receive buffer handed back.
+pub struct SpiReceived<'a> {
+    rx_buffer: Option<&'a mut [u8]>,
+    rx_data: [u8; 4],
+    length: usize,
+}
+
+impl<'a> SpiReceived<'a> {
+    // `rx_buffer` is None for transactions receiving inline.
+    pub(crate) fn new(rx_buffer: Option<&'a mut [u8]>, rx_data: [u8; 4], length: usize) -> SpiReceived<'a> {
+        SpiReceived { rx_buffer: rx_buffer, rx_data: rx_data, length: length }
+    }
+    // The data received with SpiTransactionBuilder::read_inline.
+    pub fn inline(&self) -> [u8; 4] { self.rx_data }
+    // The received bytes, either in the receive buffer or inline.
+    pub fn data(&self) -> &[u8] {
+        match self.rx_buffer {
+            Some(ref rx_buffer) => &rx_buffer[..core::cmp::min(self.length, rx_buffer.len())],
+            None => &self.rx_data[..core::cmp::min(self.length, self.rx_data.len())],
+        }
+    }
+    pub fn into_buffer(self) -> Option<&'a mut [u8]> { self.rx_buffer }
+}
+
+// Accessors used by the transaction recorder.
+impl<'a, T> SpiTransaction<'a, T> {
+    pub(crate) fn flags(&self) -> u32 { self.flags }
+    pub(crate) fn cmd(&self) -> u16 { self.cmd }
+    pub(crate) fn addr(&self) -> u64 { self.addr }
+    // Lengths of the command, address and dummy phases set with SPI_TRANS_VARIABLE_* flags.
+    pub(crate) fn phase_bits(&self) -> [u8; 3] { [self.command_bits, self.address_bits, self.dummy_bits] }
+    pub(crate) fn user(&self) -> &T { &self.user }
+    pub(crate) fn tx_bytes(&self) -> &[u8] {
+        if (self.flags & SPI_TRANS_USE_TXDATA) != 0 {
+            &self.tx_data[..(self.length + 7)/8]
+        }
+        else {
+            self.tx_buffer.map_or(&[], |tx_buffer| &tx_buffer[..(self.length + 7)/8])
+        }
+    }
+    // Number of bytes the transaction receives, either into the buffer or inline.
+    pub(crate) fn rx_len(&self) -> usize {
+        self.rxlength.map_or(0, |bits| (bits + 7)/8)
+    }
+    pub(crate) fn is_rx_inline(&self) -> bool {
+        (self.flags & SPI_TRANS_USE_RXDATA) != 0
+    }
+    pub(crate) fn rx_buffer_mut(&mut self) -> Option<&mut [u8]> {
+        match self.rx_buffer {
+            Some(ref mut rx_buffer) => Some(&mut **rx_buffer),
+            None => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SpiDataLines {
+    Single,
+    // Data phase on 2 lines. With `address` set, the address phase also uses 2 lines.
+    Dual { address: bool },
+    // Data phase on 4 lines. With `address` set, the address phase also uses 4 lines.
+    Quad { address: bool },
+}
+
+pub struct SpiTransactionBuilder<'a, T> {
+    flags: u32,
+    cmd: u16,
+    addr: u64,
+    command_bits: u8,
+    address_bits: u8,
+    dummy_bits: u8,
+    tx_bits: usize,
+    rx_bits: usize,
+    tx_buffer: Option<&'a [u8]>,
+    rx_buffer: Option<&'a mut [u8]>,
+    tx_data: [u8; 4],
+    user: T,
+}
+
+impl<'a, T> SpiTransactionBuilder<'a, T> {
+    pub fn new(user: T) -> Self {
+        Self {
+            flags: 0,
+            cmd: 0,
+            addr: 0,
+            command_bits: 0,
+            address_bits: 0,
+            dummy_bits: 0,
+            tx_bits: 0,
+            rx_bits: 0,
+            tx_buffer: None,
+            rx_buffer: None,
+            tx_data: [0; 4],
+            user: user,
+        }
+    }
+
+    // Command phase value. The length of the phase is SpiDeviceInterfaceConfig::command_bits.
+    pub fn command(mut self, cmd: u16) -> Self {
+        self.cmd = cmd;
+        self
+    }
+    // Command phase with a length which overrides the device configuration.
+    pub fn command_bits(mut self, cmd: u16, bits: u8) -> Self {
+        assert!(bits <= 16);
+        self.cmd = cmd;
+        self.command_bits = bits;
+        self.flags |= SPI_TRANS_VARIABLE_CMD;
+        self
+    }
+    // Address phase value. The length of the phase is SpiDeviceInterfaceConfig::address_bits.
+    pub fn address(mut self, addr: u64) -> Self {
+        self.addr = addr;
+        self
+    }
+    // Address phase with a length which overrides the device configuration.
+    pub fn address_bits(mut self, addr: u64, bits: u8) -> Self {
+        assert!(bits <= 64);
+        self.addr = addr;
+        self.address_bits = bits;
+        self.flags |= SPI_TRANS_VARIABLE_ADDR;
+        self
+    }
+    // Dummy phase length which overrides the device configuration.
+    pub fn dummy_bits(mut self, bits: u8) -> Self {
+        self.dummy_bits = bits;
+        self.flags |= SPI_TRANS_VARIABLE_DUMMY;
+        self
+    }
+
+    pub fn write(self, tx_buffer: &'a [u8]) -> Self {
+        let bits = tx_buffer.len()*8;
+        self.write_bits(tx_buffer, bits)
+    }
+    pub fn write_bits(mut self, tx_buffer: &'a [u8], bits: usize) -> Self {
+        assert!(bits <= tx_buffer.len()*8);
+        self.flags &= !SPI_TRANS_USE_TXDATA;
+        self.tx_buffer = Some(tx_buffer);
+        self.tx_bits = bits;
+        self
+    }
+    // Send up to 32 bits from the transaction itself instead of a DMA buffer.
+    pub fn write_inline(mut self, data: &[u8]) -> Self {
+        assert!(data.len() <= 4);
+        self.tx_data = [0; 4];
+        self.tx_data[..data.len()].copy_from_slice(data);
+        self.flags |= SPI_TRANS_USE_TXDATA;
+        self.tx_buffer = None;
+        self.tx_bits = data.len()*8;
+        self
+    }
+
+    pub fn read(self, rx_buffer: &'a mut [u8]) -> Self {
+        let bits = rx_buffer.len()*8;
+        self.read_bits(rx_buffer, bits)
+    }
+    pub fn read_bits(mut self, rx_buffer: &'a mut [u8], bits: usize) -> Self {
+        assert!(bits <= rx_buffer.len()*8);
+        self.flags &= !SPI_TRANS_USE_RXDATA;
+        self.rx_buffer = Some(rx_buffer);
+        self.rx_bits = bits;
+        self
+    }
+    // Receive up to 32 bits into the transaction itself. Use SpiDevice::transfer_inline to get the data.
+    pub fn read_inline(mut self, bits: usize) -> Self {
+        assert!(bits <= 32);
+        self.flags |= SPI_TRANS_USE_RXDATA;
+        self.rx_buffer = None;
+        self.rx_bits = bits;
+        self
+    }
+
+    pub fn data_lines(mut self, lines: SpiDataLines) -> Self {
+        self.flags &= !(SPI_TRANS_MODE_DIO | SPI_TRANS_MODE_QIO | SPI_TRANS_MODE_DIOQIO_ADDR);
+        self.flags |= match lines {
+            SpiDataLines::Single => 0,
+            SpiDataLines::Dual { address: false } => SPI_TRANS_MODE_DIO,
+            SpiDataLines::Dual { address: true } => SPI_TRANS_MODE_DIO | SPI_TRANS_MODE_DIOQIO_ADDR,
+            SpiDataLines::Quad { address: false } => SPI_TRANS_MODE_QIO,
+            SpiDataLines::Quad { address: true } => SPI_TRANS_MODE_QIO | SPI_TRANS_MODE_DIOQIO_ADDR,
+        };
+        self
+    }
+
+    pub fn build(self) -> SpiTransaction<'a, T> {
+        let has_rx = self.rx_buffer.is_some() || (self.flags & SPI_TRANS_USE_RXDATA) != 0;
+        let has_tx = self.tx_buffer.is_some() || (self.flags & SPI_TRANS_USE_TXDATA) != 0;
+        SpiTransaction {
+            flags: self.flags,
+            cmd: self.cmd,
+            addr: self.addr,
+            command_bits: self.command_bits,
+            address_bits: self.address_bits,
+            dummy_bits: self.dummy_bits,
+            // In full-duplex mode `length` is the total length and rxlength = 0 means the same length.
+            // In half-duplex mode they are the lengths of the write and read phases.
+            length: if has_tx { self.tx_bits } else { self.rx_bits },
+            rxlength: if has_rx { Some(self.rx_bits) } else { None },
+            tx_buffer: self.tx_buffer,
+            rx_buffer: self.rx_buffer,
+            tx_data: self.tx_data,
+            user: self.user,
+        }
+    }
+}
+
+// Device which executes transactions. Implemented by SpiDevice and the recorder/replay devices.
+pub trait SpiTransfer<TTransactionContext> {
+    fn transfer<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<(), SpiError> {
+        self.transfer_received(transaction).map(|_| ())
+    }
+    // Transfer and return the data received with SpiTransactionBuilder::read_inline.
+    fn transfer_inline<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<[u8; 4], SpiError> {
+        self.transfer_received(transaction).map(|received| received.inline())
+    }
+    // Transfer and hand back the receive buffer together with the inline data.
+    fn transfer_received<'t>(&mut self, transaction: SpiTransaction<'t, TTransactionContext>) -> Result<SpiReceived<'t>, SpiError>;
+}
+
+// Shared device which runs a sequence of transactions while holding the bus.
+pub trait SpiTransactions<TTransactionContext> {
+    fn transactions<R, F>(&self, f: F) -> Result<R, SpiError>
+        where F: FnOnce(&mut SpiTransfer<TTransactionContext>) -> Result<R, SpiError>;
+}
@@ -0,0 +1,379 @@
+use core::convert::Into;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+use idf::IdfError;
+
+use embedded_hal::timer::{CountDown, Periodic, Cancel};
+use embedded_hal::blocking::delay::{DelayUs, DelayMs};
+
+use nb;
+use void::Void;
+
+#[derive(Copy, Clone, Debug)]
+pub enum TimerError {
+    Generic,
+    IdfError(IdfError),
+    // The timer is already in use by another driver.
+    Busy,
+    // The divider must be in 2-65536.
+    InvalidDivider,
+    // The timer is not running.
+    NotStarted,
+    // An alarm period must be at least one tick.
+    InvalidPeriod,
+}
+
+impl From<IdfError> for TimerError {
+    fn from(err: IdfError) -> TimerError {
+        TimerError::IdfError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimerGroup {
+    Group0,
+    Group1,
+}
+impl Into<idf::timer_group_t> for TimerGroup {
+    fn into(self) -> idf::timer_group_t {
+        match self {
+            TimerGroup::Group0 => idf::timer_group_t_TIMER_GROUP_0,
+            TimerGroup::Group1 => idf::timer_group_t_TIMER_GROUP_1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimerIndex {
+    Timer0,
+    Timer1,
+}
+impl Into<idf::timer_idx_t> for TimerIndex {
+    fn into(self) -> idf::timer_idx_t {
+        match self {
+            TimerIndex::Timer0 => idf::timer_idx_t_TIMER_0,
+            TimerIndex::Timer1 => idf::timer_idx_t_TIMER_1,
+        }
+    }
+}
+
+const TIMER_APB_CLOCK_HZ: u64 = 80_000_000;
+// Registers used from the ISR, where the driver functions cannot be called.
+const TIMG_BASE: [usize; 2] = [0x3ff5f000, 0x3ff60000];
+const TIMG_T_CONFIG_OFFSET: usize = 0x24;
+const TIMG_INT_CLR_TIMERS_OFFSET: usize = 0xa4;
+const TIMG_T_ALARM_EN: u32 = 1 << 10;
+
+static TIMERS_IN_USE: AtomicU8 = AtomicU8::new(0);
+
+// Callback invoked on every alarm, in ISR context.
+// Implementations must not block, allocate or call driver APIs which take locks.
+// Use TimerIsrFn to register a plain function.
+pub unsafe trait TimerIsrCallback: Send + Sync {
+    fn call(&self);
+}
+
+unsafe impl TimerIsrCallback for () {
+    fn call(&self) {}
+}
+
+pub struct TimerIsrFn(fn());
+
+impl TimerIsrFn {
+    // The caller must ensure that `f` only performs operations which are allowed in ISR context.
+    pub unsafe fn new(f: fn()) -> Self {
+        TimerIsrFn(f)
+    }
+}
+
+unsafe impl TimerIsrCallback for TimerIsrFn {
+    fn call(&self) {
+        (self.0)()
+    }
+}
+
+// Shared with the ISR through the registered argument.
+struct TimerIsrState {
+    group: usize,
+    index: usize,
+    periodic: bool,
+    alarms: AtomicU32,
+    callback: Box<TimerIsrCallback>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TimerConfig {
+    // Divider of the 80MHz APB clock (2-65536). The default 80 counts microseconds.
+    pub divider: u32,
+    // Reload the counter and re-arm the alarm on every alarm.
+    pub auto_reload: bool,
+}
+
+impl Default for TimerConfig {
+    fn default() -> Self {
+        TimerConfig { divider: 80, auto_reload: true }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Microseconds(pub u64);
+
+// One of the four 64bit general purpose timers.
+pub struct HardwareTimer {
+    group: TimerGroup,
+    index: TimerIndex,
+    divider: u32,
+    state: Box<TimerIsrState>,
+    isr_handle: idf::timer_isr_handle_t,
+    // Alarm count when the timer was last started or waited.
+    alarms_seen: u32,
+    running: bool,
+    // Alarm value and period of the count down, used to re-arm the alarm when auto_reload is not set.
+    alarm_ticks: u64,
+    period_ticks: u64,
+    // Error of the last CountDown::start or re-arm, which the traits cannot return.
+    count_down_error: Option<TimerError>,
+}
+
+unsafe impl Send for HardwareTimer {}
+
+impl HardwareTimer {
+    pub fn new<F>(group: TimerGroup, index: TimerIndex, config: TimerConfig, callback: F) -> Result<HardwareTimer, TimerError>
+        where F: TimerIsrCallback + 'static {
+        if config.divider < 2 || config.divider > 65536 {
+            return Err(TimerError::InvalidDivider);
+        }
+        let group_number = if group == TimerGroup::Group0 { 0 } else { 1 };
+        let index_number = if index == TimerIndex::Timer0 { 0 } else { 1 };
+        let bit = 1u8 << (group_number * 2 + index_number);
+        if TIMERS_IN_USE.fetch_or(bit, Ordering::SeqCst) & bit != 0 {
+            return Err(TimerError::Busy);
+        }
+        let state = Box::new(TimerIsrState { group: group_number, index: index_number, periodic: config.auto_reload, alarms: AtomicU32::new(0), callback: Box::new(callback) });
+        let mut timer = HardwareTimer { group: group, index: index, divider: config.divider, state: state, isr_handle: ptr::null_mut(), alarms_seen: 0, running: false, alarm_ticks: 0, period_ticks: 0, count_down_error: None };
+
+        let mut idf_config = idf::timer_config_t::default();
+        idf_config.alarm_en = idf::timer_alarm_t_TIMER_ALARM_DIS;
+        idf_config.counter_en = idf::timer_start_t_TIMER_PAUSE;
+        idf_config.intr_type = idf::timer_intr_mode_t_TIMER_INTR_LEVEL;
+        idf_config.counter_dir = idf::timer_count_dir_t_TIMER_COUNT_UP;
+        idf_config.auto_reload = if config.auto_reload { idf::timer_autoreload_t_TIMER_AUTORELOAD_EN } else { idf::timer_autoreload_t_TIMER_AUTORELOAD_DIS };
+        // The divider register takes 0 for 65536.
+        idf_config.divider = config.divider & 0xffff;
+        unsafe {
+            idf::timer_init(group.into(), index.into(), &idf_config).as_result()?;
+            idf::timer_set_counter_value(group.into(), index.into(), 0).as_result()?;
+            let state_ptr = &*timer.state as *const TimerIsrState as *mut c_void;
+            idf::timer_isr_register(group.into(), index.into(), Some(HardwareTimer::isr_handler), state_ptr, 0, &mut timer.isr_handle).as_result()?;
+            idf::timer_enable_intr(group.into(), index.into()).as_result()?;
+        }
+        Ok(timer)
+    }
+
+    unsafe extern "C" fn isr_handler(arg: *mut c_void) {
+        let state = &*(arg as *const TimerIsrState);
+        let base = TIMG_BASE[state.group];
+        ptr::write_volatile((base + TIMG_INT_CLR_TIMERS_OFFSET) as *mut u32, 1 << state.index);
+        if state.periodic {
+            // The alarm is disabled by hardware when it fires.
+            let config = (base + TIMG_T_CONFIG_OFFSET * state.index) as *mut u32;
+            ptr::write_volatile(config, ptr::read_volatile(config) | TIMG_T_ALARM_EN);
+        }
+        state.alarms.fetch_add(1, Ordering::SeqCst);
+        state.callback.call();
+    }
+
+    pub fn ticks_per_second(&self) -> u64 { TIMER_APB_CLOCK_HZ / self.divider as u64 }
+    // Saturates at u64::MAX ticks.
+    pub fn ticks_from_us(&self, us: u64) -> u64 {
+        let ticks = us as u128 * self.ticks_per_second() as u128 / 1_000_000;
+        core::cmp::min(ticks, u64::MAX as u128) as u64
+    }
+
+    pub fn counter(&self) -> Result<u64, TimerError> {
+        let mut value: u64 = 0;
+        unsafe { idf::timer_get_counter_value(self.group.into(), self.index.into(), &mut value).as_result()?; }
+        Ok(value)
+    }
+    pub fn set_counter(&mut self, value: u64) -> Result<(), TimerError> {
+        unsafe { idf::timer_set_counter_value(self.group.into(), self.index.into(), value).as_result()?; }
+        Ok(())
+    }
+
+    // Fire the alarm when the counter reaches `ticks`.
+    pub fn set_alarm(&mut self, ticks: u64) -> Result<(), TimerError> {
+        unsafe {
+            idf::timer_set_alarm_value(self.group.into(), self.index.into(), ticks).as_result()?;
+            idf::timer_set_alarm(self.group.into(), self.index.into(), idf::timer_alarm_t_TIMER_ALARM_EN).as_result()?;
+        }
+        Ok(())
+    }
+    pub fn disable_alarm(&mut self) -> Result<(), TimerError> {
+        unsafe { idf::timer_set_alarm(self.group.into(), self.index.into(), idf::timer_alarm_t_TIMER_ALARM_DIS).as_result()?; }
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), TimerError> {
+        unsafe { idf::timer_start(self.group.into(), self.index.into()).as_result()?; }
+        self.running = true;
+        Ok(())
+    }
+    pub fn pause(&mut self) -> Result<(), TimerError> {
+        unsafe { idf::timer_pause(self.group.into(), self.index.into()).as_result()?; }
+        self.running = false;
+        Ok(())
+    }
+
+    // Number of alarms since the timer was created.
+    pub fn alarm_count(&self) -> u32 { self.state.alarms.load(Ordering::SeqCst) }
+
+    // Restart counting from 0 with an alarm after `ticks`.
+    pub fn start_alarm(&mut self, ticks: u64) -> Result<(), TimerError> {
+        if ticks == 0 {
+            return Err(TimerError::InvalidPeriod);
+        }
+        self.pause()?;
+        self.set_counter(0)?;
+        self.alarms_seen = self.alarm_count();
+        self.set_alarm(ticks)?;
+        self.alarm_ticks = ticks;
+        self.period_ticks = ticks;
+        self.start()
+    }
+
+    // Without auto_reload the counter keeps running after the alarm, so the next alarm of the count down is
+    // one period after the last one. Periods which have already passed are skipped, as the alarm only fires
+    // when the counter reaches the alarm value.
+    fn rearm_alarm(&mut self) -> Result<(), TimerError> {
+        let counter = self.counter()?;
+        let mut next = self.alarm_ticks.saturating_add(self.period_ticks);
+        if next <= counter {
+            next += (counter - next) / self.period_ticks * self.period_ticks + self.period_ticks;
+        }
+        self.set_alarm(next)?;
+        self.alarm_ticks = next;
+        Ok(())
+    }
+
+    // Error of the last CountDown::start, or of re-arming the alarm in CountDown::wait.
+    pub fn count_down_error(&self) -> Option<TimerError> { self.count_down_error }
+
+    // Check whether an alarm fired since the last call or start_alarm.
+    pub fn poll_alarm(&mut self) -> bool {
+        let alarms = self.alarm_count();
+        if alarms != self.alarms_seen {
+            self.alarms_seen = self.alarms_seen.wrapping_add(1);
+            true
+        }
+        else {
+            false
+        }
+    }
+}
+
+impl Drop for HardwareTimer {
+    fn drop(&mut self) {
+        unsafe {
+            idf::timer_pause(self.group.into(), self.index.into());
+            idf::timer_disable_intr(self.group.into(), self.index.into());
+            if !self.isr_handle.is_null() {
+                idf::esp_intr_free(self.isr_handle);
+            }
+        }
+        TIMERS_IN_USE.fetch_and(!(1u8 << (self.state.group * 2 + self.state.index)), Ordering::SeqCst);
+    }
+}
+
+// The count down restarts after each period: the hardware reloads the counter when TimerConfig::auto_reload
+// is set, and wait() re-arms the alarm otherwise.
+// The traits cannot return errors. When starting or re-arming the alarm fails, wait() returns immediately instead of
+// blocking forever and count_down_error() returns the error; use start_alarm and poll_alarm to handle them.
+impl CountDown for HardwareTimer {
+    type Time = Microseconds;
+
+    // Counts shorter than a tick are rounded up to one tick.
+    fn start<T>(&mut self, count: T) where T: Into<Microseconds> {
+        let Microseconds(us) = count.into();
+        let ticks = core::cmp::max(self.ticks_from_us(us), 1);
+        self.count_down_error = self.start_alarm(ticks).err();
+    }
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        if self.count_down_error.is_some() {
+            return Ok(());
+        }
+        if !self.poll_alarm() {
+            return Err(nb::Error::WouldBlock);
+        }
+        if !self.state.periodic && self.running {
+            self.count_down_error = self.rearm_alarm().err();
+        }
+        Ok(())
+    }
+}
+
+impl Periodic for HardwareTimer {}
+
+impl Cancel for HardwareTimer {
+    type Error = TimerError;
+
+    fn cancel(&mut self) -> Result<(), TimerError> {
+        if !self.running {
+            return Err(TimerError::NotStarted);
+        }
+        self.disable_alarm()?;
+        self.pause()
+    }
+}
+
+// Blocking delay which busy-waits on a hardware timer with microsecond resolution.
+// It does not yield to other tasks; use CurrentTask::delay for delays of several ticks or more.
+pub struct TimerDelay {
+    timer: HardwareTimer,
+}
+
+impl TimerDelay {
+    pub fn new(group: TimerGroup, index: TimerIndex) -> Result<TimerDelay, TimerError> {
+        let timer = HardwareTimer::new(group, index, TimerConfig { divider: 80, auto_reload: false }, ())?;
+        Ok(TimerDelay { timer: timer })
+    }
+    pub fn free(self) -> HardwareTimer { self.timer }
+}
+
+impl DelayUs<u32> for TimerDelay {
+    fn delay_us(&mut self, us: u32) {
+        if us == 0 {
+            return;
+        }
+        if self.timer.start_alarm(self.timer.ticks_from_us(us as u64)).is_err() {
+            return;
+        }
+        while !self.timer.poll_alarm() {}
+        let _ = self.timer.pause();
+    }
+}
+impl DelayUs<u16> for TimerDelay {
+    fn delay_us(&mut self, us: u16) { DelayUs::<u32>::delay_us(self, us as u32) }
+}
+impl DelayUs<u8> for TimerDelay {
+    fn delay_us(&mut self, us: u8) { DelayUs::<u32>::delay_us(self, us as u32) }
+}
+impl DelayMs<u32> for TimerDelay {
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            DelayUs::<u32>::delay_us(self, 1000);
+        }
+    }
+}
+impl DelayMs<u16> for TimerDelay {
+    fn delay_ms(&mut self, ms: u16) { DelayMs::<u32>::delay_ms(self, ms as u32) }
+}
+impl DelayMs<u8> for TimerDelay {
+    fn delay_ms(&mut self, ms: u8) { DelayMs::<u32>::delay_ms(self, ms as u32) }
+}
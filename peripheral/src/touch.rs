@@ -0,0 +1,274 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use idf;
+use idf::AsResult;
+use idf::IdfError;
+
+use freertos_rs::*;
+
+use crate::gpio::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum TouchError {
+    Generic,
+    IdfError(IdfError),
+    FreeRtosError(FreeRtosError),
+    // The pin has no touch channel, or is not sensed by this driver.
+    InvalidPin,
+    // The touch sensor is already in use by another driver.
+    Busy,
+}
+
+impl From<IdfError> for TouchError {
+    fn from(err: IdfError) -> TouchError {
+        TouchError::IdfError(err)
+    }
+}
+impl From<FreeRtosError> for TouchError {
+    fn from(err: FreeRtosError) -> TouchError {
+        TouchError::FreeRtosError(err)
+    }
+}
+
+const TOUCH_PAD_PINS: [GpioPin; 10] = [GpioPin4, GpioPin0, GpioPin2, GpioPin15, GpioPin13, GpioPin12, GpioPin14, GpioPin27, GpioPin33, GpioPin32];
+
+// Touch channel of a pin: T0 (GPIO4) to T9 (GPIO32).
+pub fn touch_pad_of(pin: GpioPin) -> Option<u32> {
+    TOUCH_PAD_PINS.iter().position(|touch_pin| *touch_pin == pin).map(|pad| pad as u32)
+}
+
+// Tracks the untouched level of a pad and detects touches with hysteresis.
+// The measured value drops when the pad is touched.
+#[derive(Copy, Clone, Debug)]
+pub struct TouchBaseline {
+    // Baseline in 1/16 units.
+    baseline: u32,
+    touched: bool,
+    touch_percent: u32,
+    release_percent: u32,
+    shift: u32,
+}
+
+impl TouchBaseline {
+    // Touched below `touch_percent` of the baseline, released above `release_percent`.
+    // The baseline follows the untouched value with a weight of 1 / 2^shift per sample.
+    pub fn new(initial: u16, touch_percent: u32, release_percent: u32, shift: u32) -> TouchBaseline {
+        TouchBaseline { baseline: (initial as u32) << 4, touched: false, touch_percent: touch_percent, release_percent: release_percent, shift: shift }
+    }
+
+    pub fn baseline(&self) -> u16 { (self.baseline >> 4) as u16 }
+    pub fn is_touched(&self) -> bool { self.touched }
+    pub fn touch_threshold(&self) -> u16 { (self.baseline() as u32 * self.touch_percent / 100) as u16 }
+    pub fn release_threshold(&self) -> u16 { (self.baseline() as u32 * self.release_percent / 100) as u16 }
+
+    // Feed a sample. Returns the new state if it changed.
+    pub fn update(&mut self, value: u16) -> Option<bool> {
+        if self.touched {
+            if value > self.release_threshold() {
+                self.touched = false;
+                return Some(false);
+            }
+        }
+        else if value < self.touch_threshold() {
+            self.touched = true;
+            return Some(true);
+        }
+        else {
+            // Follow slow drift (temperature, humidity) only while released.
+            let target = (value as u32) << 4;
+            if target > self.baseline {
+                self.baseline += (target - self.baseline) >> self.shift;
+            }
+            else {
+                self.baseline -= (self.baseline - target) >> self.shift;
+            }
+        }
+        None
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TouchEvent {
+    pub pin: GpioPin,
+    pub touched: bool,
+    pub value: u16,
+    pub baseline: u16,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TouchConfig {
+    // Period of the IIR filter of the driver.
+    pub filter_period_ms: u32,
+    // Period of the baseline tracking and event detection.
+    pub sample_period_ms: u32,
+    pub touch_percent: u32,
+    pub release_percent: u32,
+    pub baseline_shift: u32,
+    pub event_queue_size: usize,
+}
+
+impl Default for TouchConfig {
+    fn default() -> Self {
+        TouchConfig {
+            filter_period_ms: 10,
+            sample_period_ms: 20,
+            touch_percent: 80,
+            release_percent: 90,
+            baseline_shift: 6,
+            event_queue_size: 16,
+        }
+    }
+}
+
+struct TouchPadState {
+    pin: GpioPin,
+    pad: u32,
+    baseline: TouchBaseline,
+}
+
+static TOUCH_IN_USE: AtomicBool = AtomicBool::new(false);
+
+// Touch buttons on a set of pins. A task samples the filtered values, tracks their baselines and sends touch/release events.
+pub struct Touch {
+    pads: Arc<Mutex<Vec<TouchPadState>>>,
+    queue: Arc<Queue<TouchEvent>>,
+    running: Arc<AtomicBool>,
+    stopped: Arc<Queue<()>>,
+}
+
+fn read_filtered(pad: u32) -> Result<u16, TouchError> {
+    let mut value: u16 = 0;
+    unsafe { idf::touch_pad_read_filtered(idf::touch_pad_t_TOUCH_PAD_NUM0 + pad, &mut value).as_result()?; }
+    Ok(value)
+}
+
+impl Touch {
+    pub fn new(pins: &[GpioPin], config: TouchConfig) -> Result<Touch, TouchError> {
+        let mut pads = Vec::new();
+        for pin in pins {
+            let pad = touch_pad_of(*pin).ok_or(TouchError::InvalidPin)?;
+            pads.push((*pin, pad));
+        }
+        if TOUCH_IN_USE.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(TouchError::Busy);
+        }
+        match Touch::start(&pads, config) {
+            Ok(touch) => Ok(touch),
+            Err(err) => {
+                unsafe {
+                    idf::touch_pad_filter_delete();
+                    idf::touch_pad_deinit();
+                }
+                TOUCH_IN_USE.store(false, Ordering::SeqCst);
+                Err(err)
+            },
+        }
+    }
+
+    fn start(pins: &[(GpioPin, u32)], config: TouchConfig) -> Result<Touch, TouchError> {
+        unsafe {
+            idf::touch_pad_init().as_result()?;
+            // Measure continuously so that the pads also work as a wake-up source.
+            idf::touch_pad_set_fsm_mode(idf::touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER).as_result()?;
+            for (_, pad) in pins {
+                idf::touch_pad_config(idf::touch_pad_t_TOUCH_PAD_NUM0 + *pad, 0).as_result()?;
+            }
+            idf::touch_pad_filter_start(config.filter_period_ms).as_result()?;
+        }
+        // Let the filter settle before taking the initial baselines.
+        CurrentTask::delay(Duration::ms(config.filter_period_ms * 10));
+        let mut pads = Vec::new();
+        for (pin, pad) in pins {
+            let baseline = TouchBaseline::new(read_filtered(*pad)?, config.touch_percent, config.release_percent, config.baseline_shift);
+            pads.push(TouchPadState { pin: *pin, pad: *pad, baseline: baseline });
+        }
+
+        let pads = Arc::new(Mutex::new(pads)?);
+        let queue = Arc::new(Queue::new(config.event_queue_size)?);
+        let running = Arc::new(AtomicBool::new(true));
+        let stopped = Arc::new(Queue::new(1)?);
+        let task_pads = pads.clone();
+        let task_queue = queue.clone();
+        let task_running = running.clone();
+        let task_stopped = stopped.clone();
+        Task::new().name("touch").stack_size(2048).start(move || {
+            while task_running.load(Ordering::SeqCst) {
+                if let Ok(mut pads) = task_pads.lock(Duration::infinite()) {
+                    for state in pads.iter_mut() {
+                        let value = match read_filtered(state.pad) {
+                            Ok(value) => value,
+                            Err(_) => continue,
+                        };
+                        if let Some(touched) = state.baseline.update(value) {
+                            let event = TouchEvent { pin: state.pin, touched: touched, value: value, baseline: state.baseline.baseline() };
+                            // Events are dropped while the queue is full.
+                            let _ = task_queue.send(event, Duration::zero());
+                        }
+                    }
+                }
+                CurrentTask::delay(Duration::ms(config.sample_period_ms));
+            }
+            let _ = task_stopped.send((), Duration::infinite());
+        })?;
+        Ok(Touch { pads: pads, queue: queue, running: running, stopped: stopped })
+    }
+
+    // The queue receiving touch and release events.
+    pub fn events(&self) -> Arc<Queue<TouchEvent>> { self.queue.clone() }
+
+    // Filtered value of a pin.
+    pub fn read(&self, pin: GpioPin) -> Result<u16, TouchError> {
+        read_filtered(touch_pad_of(pin).ok_or(TouchError::InvalidPin)?)
+    }
+
+    fn with_pad<R, F>(&self, pin: GpioPin, f: F) -> Result<R, TouchError> where F: FnOnce(&TouchPadState) -> R {
+        let pads = self.pads.lock(Duration::infinite())?;
+        let state = pads.iter().find(|state| state.pin == pin).ok_or(TouchError::InvalidPin)?;
+        Ok(f(state))
+    }
+
+    pub fn baseline(&self, pin: GpioPin) -> Result<u16, TouchError> {
+        self.with_pad(pin, |state| state.baseline.baseline())
+    }
+    pub fn is_touched(&self, pin: GpioPin) -> Result<bool, TouchError> {
+        self.with_pad(pin, |state| state.baseline.is_touched())
+    }
+
+    // Wake up from deep sleep when the pin is touched, using the current touch threshold of its baseline.
+    pub fn enable_wakeup(&mut self, pin: GpioPin) -> Result<(), TouchError> {
+        let (pad, threshold) = self.with_pad(pin, |state| (state.pad, state.baseline.touch_threshold()))?;
+        unsafe {
+            idf::touch_pad_set_thresh(idf::touch_pad_t_TOUCH_PAD_NUM0 + pad, threshold).as_result()?;
+            idf::touch_pad_set_trigger_mode(idf::touch_trigger_mode_t_TOUCH_TRIGGER_BELOW).as_result()?;
+            idf::esp_sleep_enable_touchpad_wakeup().as_result()?;
+        }
+        Ok(())
+    }
+}
+
+// The pin which woke up the chip from deep sleep, if it was woken by a touch.
+pub fn touch_wakeup_pin() -> Option<GpioPin> {
+    unsafe {
+        if idf::esp_sleep_get_wakeup_cause() != idf::esp_sleep_wakeup_cause_t_ESP_SLEEP_WAKEUP_TOUCHPAD {
+            return None;
+        }
+        let pad = idf::esp_sleep_get_touchpad_wakeup_status() - idf::touch_pad_t_TOUCH_PAD_NUM0;
+        TOUCH_PAD_PINS.get(pad as usize).cloned()
+    }
+}
+
+impl Drop for Touch {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        let _ = self.stopped.receive(Duration::infinite());
+        unsafe {
+            idf::touch_pad_filter_delete();
+            idf::touch_pad_deinit();
+        }
+        TOUCH_IN_USE.store(false, Ordering::SeqCst);
+    }
+}
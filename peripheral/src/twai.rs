@@ -0,0 +1,319 @@
+use core::convert::Into;
+
+use idf;
+use idf::AsResult;
+use idf::IdfError;
+
+use freertos_rs::*;
+
+use nb;
+
+use crate::gpio::*;
+use crate::twai_frame::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum TwaiError {
+    Generic,
+    IdfError(IdfError),
+    // The TX queue is full or nothing was received in time.
+    Timeout,
+    // The controller is not running, e.g. it is stopped or in bus-off.
+    InvalidState,
+}
+
+impl From<IdfError> for TwaiError {
+    fn from(err: IdfError) -> TwaiError {
+        match err.code() {
+            idf::ESP_ERR_TIMEOUT => TwaiError::Timeout,
+            idf::ESP_ERR_INVALID_STATE => TwaiError::InvalidState,
+            _ => TwaiError::IdfError(err),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TwaiMode {
+    Normal,
+    // Transmit without requiring an acknowledgement, e.g. for self tests.
+    NoAck,
+    // Receive only; the controller never drives the bus, not even for acknowledgements.
+    ListenOnly,
+}
+impl Into<idf::can_mode_t> for TwaiMode {
+    fn into(self) -> idf::can_mode_t {
+        match self {
+            TwaiMode::Normal => idf::can_mode_t_CAN_MODE_NORMAL,
+            TwaiMode::NoAck => idf::can_mode_t_CAN_MODE_NO_ACK,
+            TwaiMode::ListenOnly => idf::can_mode_t_CAN_MODE_LISTEN_ONLY,
+        }
+    }
+}
+
+// Bit timing presets for the 80MHz APB clock, sampling at 80%.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TwaiBitrate {
+    Kbps125,
+    Kbps250,
+    Kbps500,
+    Kbps800,
+    Mbps1,
+}
+impl Into<idf::can_timing_config_t> for TwaiBitrate {
+    fn into(self) -> idf::can_timing_config_t {
+        let (brp, tseg_1, tseg_2) = match self {
+            TwaiBitrate::Kbps125 => (32, 15, 4),
+            TwaiBitrate::Kbps250 => (16, 15, 4),
+            TwaiBitrate::Kbps500 => (8, 15, 4),
+            TwaiBitrate::Kbps800 => (4, 16, 8),
+            TwaiBitrate::Mbps1 => (4, 15, 4),
+        };
+        let mut config = idf::can_timing_config_t::default();
+        config.brp = brp;
+        config.tseg_1 = tseg_1;
+        config.tseg_2 = tseg_2;
+        config.sjw = 3;
+        config.triple_sampling = false;
+        config
+    }
+}
+
+// Alert flags reported by read_alerts.
+pub const TWAI_ALERT_TX_IDLE: u32 = 0x0001;
+pub const TWAI_ALERT_TX_SUCCESS: u32 = 0x0002;
+pub const TWAI_ALERT_BELOW_ERR_WARN: u32 = 0x0004;
+pub const TWAI_ALERT_ERR_ACTIVE: u32 = 0x0008;
+pub const TWAI_ALERT_RECOVERY_IN_PROGRESS: u32 = 0x0010;
+pub const TWAI_ALERT_BUS_RECOVERED: u32 = 0x0020;
+pub const TWAI_ALERT_ARB_LOST: u32 = 0x0040;
+pub const TWAI_ALERT_ABOVE_ERR_WARN: u32 = 0x0080;
+pub const TWAI_ALERT_BUS_ERROR: u32 = 0x0100;
+pub const TWAI_ALERT_TX_FAILED: u32 = 0x0200;
+pub const TWAI_ALERT_RX_QUEUE_FULL: u32 = 0x0400;
+pub const TWAI_ALERT_ERR_PASS: u32 = 0x0800;
+pub const TWAI_ALERT_BUS_OFF: u32 = 0x1000;
+pub const TWAI_ALERT_ALL: u32 = 0x1fff;
+
+const CAN_MSG_FLAG_EXTD: u32 = 0x01;
+const CAN_MSG_FLAG_RTR: u32 = 0x02;
+const CAN_IO_UNUSED: i32 = -1;
+
+#[derive(Copy, Clone, Debug)]
+pub struct TwaiConfig {
+    pub mode: TwaiMode,
+    pub tx_pin: GpioPin,
+    pub rx_pin: GpioPin,
+    pub bitrate: TwaiBitrate,
+    pub filter: TwaiFilter,
+    pub tx_queue_len: usize,
+    pub rx_queue_len: usize,
+    // Alerts to report by read_alerts (TWAI_ALERT_*).
+    pub alerts: u32,
+}
+
+impl TwaiConfig {
+    pub fn new(tx_pin: GpioPin, rx_pin: GpioPin, bitrate: TwaiBitrate) -> TwaiConfig {
+        TwaiConfig {
+            mode: TwaiMode::Normal,
+            tx_pin: tx_pin,
+            rx_pin: rx_pin,
+            bitrate: bitrate,
+            filter: TwaiFilter::accept_all(),
+            tx_queue_len: 5,
+            rx_queue_len: 5,
+            alerts: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TwaiState {
+    Stopped,
+    Running,
+    // Too many errors; the controller does not take part in the bus until recovered.
+    BusOff,
+    Recovering,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TwaiStatus {
+    pub state: TwaiState,
+    pub tx_pending: u32,
+    pub rx_pending: u32,
+    pub tx_error_counter: u32,
+    pub rx_error_counter: u32,
+    pub tx_failed_count: u32,
+    pub rx_missed_count: u32,
+    pub arbitration_lost_count: u32,
+    pub bus_error_count: u32,
+}
+
+impl From<idf::can_status_info_t> for TwaiStatus {
+    fn from(status: idf::can_status_info_t) -> TwaiStatus {
+        TwaiStatus {
+            state: match status.state {
+                idf::can_state_t_CAN_STATE_RUNNING => TwaiState::Running,
+                idf::can_state_t_CAN_STATE_BUS_OFF => TwaiState::BusOff,
+                idf::can_state_t_CAN_STATE_RECOVERING => TwaiState::Recovering,
+                _ => TwaiState::Stopped,
+            },
+            tx_pending: status.msgs_to_tx,
+            rx_pending: status.msgs_to_rx,
+            tx_error_counter: status.tx_error_counter,
+            rx_error_counter: status.rx_error_counter,
+            tx_failed_count: status.tx_failed_count,
+            rx_missed_count: status.rx_missed_count,
+            arbitration_lost_count: status.arb_lost_count,
+            bus_error_count: status.bus_error_count,
+        }
+    }
+}
+
+fn to_message(frame: &TwaiFrame) -> idf::can_message_t {
+    let mut message = idf::can_message_t::default();
+    match frame.id() {
+        TwaiId::Standard(id) => message.identifier = id as u32,
+        TwaiId::Extended(id) => {
+            message.identifier = id;
+            message.flags |= CAN_MSG_FLAG_EXTD;
+        },
+    }
+    if frame.is_remote() {
+        message.flags |= CAN_MSG_FLAG_RTR;
+    }
+    message.data_length_code = frame.dlc() as u8;
+    message.data[..frame.data().len()].copy_from_slice(frame.data());
+    message
+}
+
+fn from_message(message: &idf::can_message_t) -> Result<TwaiFrame, TwaiError> {
+    let id = if message.flags & CAN_MSG_FLAG_EXTD != 0 { TwaiId::Extended(message.identifier) } else { TwaiId::Standard(message.identifier as u16) };
+    // A data length code above 8 still carries 8 bytes.
+    let len = if message.data_length_code as usize > TWAI_MAX_DATA_LEN { TWAI_MAX_DATA_LEN } else { message.data_length_code as usize };
+    let frame = if message.flags & CAN_MSG_FLAG_RTR != 0 { TwaiFrame::new_remote(id, len) } else { TwaiFrame::new(id, &message.data[..len]) };
+    frame.ok_or(TwaiError::Generic)
+}
+
+// The TWAI (CAN 2.0) controller. It needs an external transceiver, e.g. the M5Stack CAN unit.
+pub struct Twai {
+    running: bool,
+}
+
+impl Twai {
+    // Install the driver. The controller does not take part in the bus until start().
+    pub fn new(config: TwaiConfig) -> Result<Twai, TwaiError> {
+        let mut general = idf::can_general_config_t::default();
+        general.mode = config.mode.into();
+        general.tx_io = config.tx_pin.number() as idf::gpio_num_t;
+        general.rx_io = config.rx_pin.number() as idf::gpio_num_t;
+        general.clkout_io = CAN_IO_UNUSED;
+        general.bus_off_io = CAN_IO_UNUSED;
+        general.tx_queue_len = config.tx_queue_len as u32;
+        general.rx_queue_len = config.rx_queue_len as u32;
+        general.alerts_enabled = config.alerts;
+        let timing: idf::can_timing_config_t = config.bitrate.into();
+        let mut filter = idf::can_filter_config_t::default();
+        filter.acceptance_code = config.filter.code;
+        filter.acceptance_mask = config.filter.mask;
+        filter.single_filter = config.filter.single;
+        unsafe { idf::can_driver_install(&general, &timing, &filter).as_result()?; }
+        Ok(Twai { running: false })
+    }
+
+    pub fn start(&mut self) -> Result<(), TwaiError> {
+        unsafe { idf::can_start().as_result()?; }
+        self.running = true;
+        Ok(())
+    }
+    pub fn stop(&mut self) -> Result<(), TwaiError> {
+        unsafe { idf::can_stop().as_result()?; }
+        self.running = false;
+        Ok(())
+    }
+
+    // Queue a frame, waiting up to `wait_ticks` for space in the TX queue.
+    pub fn transmit(&mut self, frame: &TwaiFrame, wait_ticks: Duration) -> Result<(), TwaiError> {
+        let message = to_message(frame);
+        unsafe { idf::can_transmit(&message, wait_ticks.to_ticks()).as_result()?; }
+        Ok(())
+    }
+    // Wait up to `wait_ticks` for a received frame.
+    pub fn receive(&mut self, wait_ticks: Duration) -> Result<TwaiFrame, TwaiError> {
+        let mut message = idf::can_message_t::default();
+        unsafe { idf::can_receive(&mut message, wait_ticks.to_ticks()).as_result()?; }
+        from_message(&message)
+    }
+
+    pub fn clear_transmit_queue(&mut self) -> Result<(), TwaiError> {
+        unsafe { idf::can_clear_transmit_queue().as_result()?; }
+        Ok(())
+    }
+    pub fn clear_receive_queue(&mut self) -> Result<(), TwaiError> {
+        unsafe { idf::can_clear_receive_queue().as_result()?; }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Result<TwaiStatus, TwaiError> {
+        let mut status = idf::can_status_info_t::default();
+        unsafe { idf::can_get_status_info(&mut status).as_result()?; }
+        Ok(status.into())
+    }
+
+    // Wait for alerts enabled by TwaiConfig::alerts or set_alerts. Returns the TWAI_ALERT_* flags raised.
+    pub fn read_alerts(&mut self, wait_ticks: Duration) -> Result<u32, TwaiError> {
+        let mut alerts: u32 = 0;
+        unsafe { idf::can_read_alerts(&mut alerts, wait_ticks.to_ticks()).as_result()?; }
+        Ok(alerts)
+    }
+    pub fn set_alerts(&mut self, alerts: u32) -> Result<(), TwaiError> {
+        unsafe { idf::can_reconfigure_alerts(alerts, core::ptr::null_mut()).as_result()?; }
+        Ok(())
+    }
+
+    // Recover from bus-off and restart. The controller has to see 128 occurrences of 11 recessive bits;
+    // this waits up to `wait_ticks` for them.
+    pub fn recover(&mut self, wait_ticks: Duration) -> Result<(), TwaiError> {
+        self.running = false;
+        let mut previous_alerts: u32 = 0;
+        unsafe {
+            idf::can_reconfigure_alerts(TWAI_ALERT_BUS_RECOVERED, &mut previous_alerts).as_result()?;
+            let result = idf::can_initiate_recovery().as_result()
+                .and_then(|_| {
+                    let mut alerts: u32 = 0;
+                    idf::can_read_alerts(&mut alerts, wait_ticks.to_ticks()).as_result()
+                });
+            idf::can_reconfigure_alerts(previous_alerts, core::ptr::null_mut());
+            result?;
+        }
+        self.start()
+    }
+}
+
+impl Drop for Twai {
+    fn drop(&mut self) {
+        unsafe {
+            if self.running {
+                idf::can_stop();
+            }
+            idf::can_driver_uninstall();
+        }
+    }
+}
+
+impl TwaiBus for Twai {
+    type Error = TwaiError;
+
+    fn transmit(&mut self, frame: &TwaiFrame) -> nb::Result<(), TwaiError> {
+        match Twai::transmit(self, frame, Duration::zero()) {
+            Ok(_) => Ok(()),
+            Err(TwaiError::Timeout) => Err(nb::Error::WouldBlock),
+            Err(err) => Err(nb::Error::Other(err)),
+        }
+    }
+    fn receive(&mut self) -> nb::Result<TwaiFrame, TwaiError> {
+        match Twai::receive(self, Duration::zero()) {
+            Ok(frame) => Ok(frame),
+            Err(TwaiError::Timeout) => Err(nb::Error::WouldBlock),
+            Err(err) => Err(nb::Error::Other(err)),
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// TWAI (CAN) frames, acceptance filters and TwaiLoopback, a bus without a controller for checking protocols.
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+
+use nb;
+
+pub const TWAI_STANDARD_ID_MAX: u32 = 0x7ff;
+pub const TWAI_EXTENDED_ID_MAX: u32 = 0x1fff_ffff;
+pub const TWAI_MAX_DATA_LEN: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TwaiId {
+    // 11bit identifier.
+    Standard(u16),
+    // 29bit identifier.
+    Extended(u32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TwaiFrame {
+    id: TwaiId,
+    remote: bool,
+    len: u8,
+    data: [u8; TWAI_MAX_DATA_LEN],
+}
+
+impl TwaiFrame {
+    // A data frame. Returns None if the identifier is out of range or the data is longer than 8 bytes.
+    pub fn new(id: TwaiId, data: &[u8]) -> Option<TwaiFrame> {
+        if !TwaiFrame::valid_id(id) || data.len() > TWAI_MAX_DATA_LEN {
+            return None;
+        }
+        let mut frame = TwaiFrame { id: id, remote: false, len: data.len() as u8, data: [0u8; TWAI_MAX_DATA_LEN] };
+        frame.data[..data.len()].copy_from_slice(data);
+        Some(frame)
+    }
+    // A remote frame requesting `len` bytes.
+    pub fn new_remote(id: TwaiId, len: usize) -> Option<TwaiFrame> {
+        if !TwaiFrame::valid_id(id) || len > TWAI_MAX_DATA_LEN {
+            return None;
+        }
+        Some(TwaiFrame { id: id, remote: true, len: len as u8, data: [0u8; TWAI_MAX_DATA_LEN] })
+    }
+    fn valid_id(id: TwaiId) -> bool {
+        match id {
+            TwaiId::Standard(id) => id as u32 <= TWAI_STANDARD_ID_MAX,
+            TwaiId::Extended(id) => id <= TWAI_EXTENDED_ID_MAX,
+        }
+    }
+
+    pub fn id(&self) -> TwaiId { self.id }
+    pub fn is_extended(&self) -> bool {
+        match self.id {
+            TwaiId::Standard(_) => false,
+            TwaiId::Extended(_) => true,
+        }
+    }
+    pub fn is_remote(&self) -> bool { self.remote }
+    // Data length code, i.e. the number of data bytes (or requested bytes of a remote frame).
+    pub fn dlc(&self) -> usize { self.len as usize }
+    pub fn data(&self) -> &[u8] {
+        if self.remote { &[] } else { &self.data[..self.len as usize] }
+    }
+}
+
+// Acceptance filter in the layout of the controller. A mask bit of 1 ignores the bit of the code.
+//   single, standard frame: [31:21] ID, [20] RTR, [15:8] data byte 0, [7:0] data byte 1
+//   single, extended frame: [31:3] ID, [2] RTR
+//   dual, standard frame:   [31:21]/[15:5] ID, [20]/[4] RTR,
+//                           data byte 0 in [19:16] (upper half) and [3:0] (lower half) for the first filter only
+//   dual, extended frame:   [31:16]/[15:0] ID[28:13]
+// Data bytes which the frame does not carry (remote frames, short frames) are not compared.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TwaiFilter {
+    pub code: u32,
+    pub mask: u32,
+    pub single: bool,
+}
+
+impl TwaiFilter {
+    pub fn accept_all() -> TwaiFilter {
+        TwaiFilter { code: 0, mask: 0xffff_ffff, single: true }
+    }
+    // Accept standard frames whose ID matches `id` in the bits set in `id_mask`.
+    pub fn standard(id: u16, id_mask: u16) -> TwaiFilter {
+        let id = (id as u32) & TWAI_STANDARD_ID_MAX;
+        let id_mask = (id_mask as u32) & TWAI_STANDARD_ID_MAX;
+        TwaiFilter { code: id << 21, mask: !(id_mask << 21), single: true }
+    }
+    // Accept extended frames whose ID matches `id` in the bits set in `id_mask`.
+    pub fn extended(id: u32, id_mask: u32) -> TwaiFilter {
+        let id = id & TWAI_EXTENDED_ID_MAX;
+        let id_mask = id_mask & TWAI_EXTENDED_ID_MAX;
+        TwaiFilter { code: id << 3, mask: !(id_mask << 3), single: true }
+    }
+
+    // Two filters on standard frames, each accepting IDs which match `id` in the bits set in `id_mask`.
+    pub fn dual_standard(first_id: u16, first_id_mask: u16, second_id: u16, second_id_mask: u16) -> TwaiFilter {
+        let half = |id: u16, id_mask: u16| {
+            let id = (id as u32) & TWAI_STANDARD_ID_MAX;
+            let id_mask = (id_mask as u32) & TWAI_STANDARD_ID_MAX;
+            (id << 5, !(id_mask << 5) & 0xffff)
+        };
+        let (first_code, first_mask) = half(first_id, first_id_mask);
+        let (second_code, second_mask) = half(second_id, second_id_mask);
+        // RTR and the data bits of the first filter ([19:16] and [3:0]) are ignored.
+        TwaiFilter { code: (first_code << 16) | second_code, mask: (first_mask << 16) | second_mask, single: false }
+    }
+    // Two filters on extended frames. Only ID[28:13] can be compared, lower bits of `id_mask` are ignored.
+    pub fn dual_extended(first_id: u32, first_id_mask: u32, second_id: u32, second_id_mask: u32) -> TwaiFilter {
+        let half = |id: u32, id_mask: u32| ((id & TWAI_EXTENDED_ID_MAX) >> 13, !((id_mask & TWAI_EXTENDED_ID_MAX) >> 13) & 0xffff);
+        let (first_code, first_mask) = half(first_id, first_id_mask);
+        let (second_code, second_mask) = half(second_id, second_id_mask);
+        TwaiFilter { code: (first_code << 16) | second_code, mask: (first_mask << 16) | second_mask, single: false }
+    }
+
+    fn matches_bits(code: u32, mask: u32, bits: u32) -> bool {
+        (bits ^ code) & !mask == 0
+    }
+
+    pub fn matches(&self, frame: &TwaiFrame) -> bool {
+        let rtr = frame.remote as u32;
+        let present = |index: usize| !frame.remote && index < frame.dlc();
+        let byte = |index: usize| if present(index) { frame.data[index] as u32 } else { 0 };
+        // Mask bits which exclude a data byte the frame does not carry from the comparison.
+        let absent = |index: usize| if present(index) { 0 } else { 0xff };
+        match (self.single, frame.id) {
+            (true, TwaiId::Standard(id)) => {
+                let bits = ((id as u32) << 21) | (rtr << 20) | (byte(0) << 8) | byte(1);
+                // Bits [19:16] are not compared.
+                TwaiFilter::matches_bits(self.code, self.mask | 0x000f_0000 | (absent(0) << 8) | absent(1), bits)
+            },
+            (true, TwaiId::Extended(id)) => {
+                let bits = (id << 3) | (rtr << 2);
+                TwaiFilter::matches_bits(self.code, self.mask | 0x3, bits)
+            },
+            (false, TwaiId::Standard(id)) => {
+                let first = ((id as u32) << 21) | (rtr << 20) | ((byte(0) >> 4) << 16) | (byte(0) & 0xf);
+                let first_mask = self.mask | 0x0000_fff0 | ((absent(0) >> 4) << 16) | (absent(0) & 0xf);
+                let second = ((id as u32) << 5) | (rtr << 4);
+                TwaiFilter::matches_bits(self.code, first_mask, first)
+                    || TwaiFilter::matches_bits(self.code & 0xffff, (self.mask & 0xffff) | 0xffff_000f, second)
+            },
+            (false, TwaiId::Extended(id)) => {
+                let upper = id >> 13;
+                TwaiFilter::matches_bits(self.code >> 16, (self.mask >> 16) | 0xffff_0000, upper)
+                    || TwaiFilter::matches_bits(self.code & 0xffff, (self.mask & 0xffff) | 0xffff_0000, upper)
+            },
+        }
+    }
+}
+
+// Non-blocking frame transfer, implemented by the Twai driver and by TwaiLoopback.
+pub trait TwaiBus {
+    type Error;
+    // Queue a frame for transmission. WouldBlock while the TX queue is full.
+    fn transmit(&mut self, frame: &TwaiFrame) -> nb::Result<(), Self::Error>;
+    // Take a received frame. WouldBlock while none is available.
+    fn receive(&mut self) -> nb::Result<TwaiFrame, Self::Error>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TwaiLoopbackError {
+    // Frames are lost because the receive queue was full.
+    Overrun,
+}
+
+// Host stand-in for a bus with one peer: transmitted frames which pass the filter are received back.
+// Peer frames are injected with push_received and frames sent to the peer are taken with pop_transmitted.
+pub struct TwaiLoopback {
+    filter: TwaiFilter,
+    self_reception: bool,
+    queue_len: usize,
+    received: VecDeque<TwaiFrame>,
+    transmitted: VecDeque<TwaiFrame>,
+    overrun: bool,
+}
+
+impl TwaiLoopback {
+    pub fn new(filter: TwaiFilter, queue_len: usize) -> TwaiLoopback {
+        TwaiLoopback { filter: filter, self_reception: true, queue_len: queue_len, received: VecDeque::new(), transmitted: VecDeque::new(), overrun: false }
+    }
+    // Receive own frames (like the self test mode of the controller). Enabled by default.
+    pub fn set_self_reception(&mut self, enabled: bool) {
+        self.self_reception = enabled;
+    }
+    // Deliver a frame from the peer through the acceptance filter. Returns whether it was accepted.
+    pub fn push_received(&mut self, frame: TwaiFrame) -> bool {
+        if !self.filter.matches(&frame) {
+            return false;
+        }
+        if self.received.len() >= self.queue_len {
+            self.overrun = true;
+            return false;
+        }
+        self.received.push_back(frame);
+        true
+    }
+    // Take the oldest frame sent to the peer.
+    pub fn pop_transmitted(&mut self) -> Option<TwaiFrame> {
+        self.transmitted.pop_front()
+    }
+}
+
+impl TwaiBus for TwaiLoopback {
+    type Error = TwaiLoopbackError;
+
+    fn transmit(&mut self, frame: &TwaiFrame) -> nb::Result<(), TwaiLoopbackError> {
+        if self.transmitted.len() >= self.queue_len {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.transmitted.push_back(*frame);
+        if self.self_reception {
+            self.push_received(*frame);
+        }
+        Ok(())
+    }
+    fn receive(&mut self) -> nb::Result<TwaiFrame, TwaiLoopbackError> {
+        if self.overrun {
+            self.overrun = false;
+            return Err(nb::Error::Other(TwaiLoopbackError::Overrun));
+        }
+        self.received.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(id: TwaiId, data: &[u8]) -> TwaiFrame {
+        TwaiFrame::new(id, data).unwrap()
+    }
+
+    #[test]
+    fn frame_construction() {
+        assert!(TwaiFrame::new(TwaiId::Standard(0x800), &[]).is_none());
+        assert!(TwaiFrame::new(TwaiId::Extended(0x2000_0000), &[]).is_none());
+        assert!(TwaiFrame::new(TwaiId::Standard(0x7ff), &[0; 9]).is_none());
+        assert!(TwaiFrame::new_remote(TwaiId::Standard(0x123), 9).is_none());
+
+        let frame = data(TwaiId::Extended(0x1fff_ffff), &[1, 2, 3]);
+        assert!(frame.is_extended());
+        assert_eq!(frame.dlc(), 3);
+        assert_eq!(frame.data(), &[1, 2, 3]);
+
+        let remote = TwaiFrame::new_remote(TwaiId::Standard(0x123), 4).unwrap();
+        assert!(remote.is_remote());
+        assert_eq!(remote.dlc(), 4);
+        assert_eq!(remote.data(), &[] as &[u8]);
+    }
+
+    #[test]
+    fn single_standard_filter() {
+        let filter = TwaiFilter::standard(0x120, 0x7f0);
+        assert!(filter.matches(&data(TwaiId::Standard(0x120), &[])));
+        assert!(filter.matches(&data(TwaiId::Standard(0x12f), &[0xff])));
+        assert!(filter.matches(&TwaiFrame::new_remote(TwaiId::Standard(0x125), 0).unwrap()));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x130), &[])));
+        assert!(!filter.matches(&data(TwaiId::Extended(0x120), &[])));
+        assert!(TwaiFilter::accept_all().matches(&data(TwaiId::Extended(0x1234_5678), &[])));
+    }
+
+    #[test]
+    fn single_standard_filter_on_data_and_rtr() {
+        // ID 0x123, data frames only, data byte 0 = 0xa5, data byte 1 ignored.
+        let filter = TwaiFilter { code: (0x123 << 21) | (0xa5 << 8), mask: 0x000f_00ff, single: true };
+        assert!(filter.matches(&data(TwaiId::Standard(0x123), &[0xa5, 0x01])));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x123), &[0xa4, 0x01])));
+        assert!(!filter.matches(&TwaiFrame::new_remote(TwaiId::Standard(0x123), 2).unwrap()));
+        // Missing data bytes are not compared.
+        assert!(filter.matches(&data(TwaiId::Standard(0x123), &[])));
+
+        // Remote frames only.
+        let filter = TwaiFilter { code: (0x123 << 21) | (1 << 20), mask: 0x000f_ffff, single: true };
+        assert!(filter.matches(&TwaiFrame::new_remote(TwaiId::Standard(0x123), 8).unwrap()));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x123), &[])));
+    }
+
+    #[test]
+    fn single_extended_filter() {
+        let filter = TwaiFilter::extended(0x1234_5600, 0x1fff_ff00);
+        assert!(filter.matches(&data(TwaiId::Extended(0x1234_56ff), &[1])));
+        assert!(filter.matches(&TwaiFrame::new_remote(TwaiId::Extended(0x1234_5600), 1).unwrap()));
+        assert!(!filter.matches(&data(TwaiId::Extended(0x1234_5700), &[])));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x600), &[])));
+
+        let filter = TwaiFilter { code: (0x1234_5678 << 3) | (1 << 2), mask: 0x3, single: true };
+        assert!(filter.matches(&TwaiFrame::new_remote(TwaiId::Extended(0x1234_5678), 0).unwrap()));
+        assert!(!filter.matches(&data(TwaiId::Extended(0x1234_5678), &[])));
+    }
+
+    #[test]
+    fn dual_standard_filter() {
+        let filter = TwaiFilter::dual_standard(0x100, 0x7ff, 0x200, 0x7f0);
+        assert_eq!(filter.code, (0x100 << 21) | (0x200 << 5));
+        assert!(filter.matches(&data(TwaiId::Standard(0x100), &[0x12])));
+        assert!(filter.matches(&data(TwaiId::Standard(0x20f), &[0x12])));
+        assert!(filter.matches(&TwaiFrame::new_remote(TwaiId::Standard(0x205), 1).unwrap()));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x101), &[])));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x210), &[])));
+        assert!(!filter.matches(&data(TwaiId::Extended(0x100), &[])));
+    }
+
+    #[test]
+    fn dual_standard_filter_on_data_byte() {
+        // First filter: ID 0x100 with data byte 0 = 0xa5, upper half in [19:16], lower half in [3:0].
+        // Second filter: remote frames with ID 0x300.
+        let filter = TwaiFilter {
+            code: (0x100 << 21) | (0xa << 16) | (0x300 << 5) | (1 << 4) | 0x5,
+            mask: 0,
+            single: false,
+        };
+        assert!(filter.matches(&data(TwaiId::Standard(0x100), &[0xa5, 0xff])));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x100), &[0xa4])));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x100), &[0x55])));
+        // Without data byte 0 only the ID and RTR are compared.
+        assert!(filter.matches(&data(TwaiId::Standard(0x100), &[])));
+        assert!(!filter.matches(&TwaiFrame::new_remote(TwaiId::Standard(0x100), 1).unwrap()));
+        // The lower half in [3:0] does not belong to the second filter.
+        assert!(filter.matches(&TwaiFrame::new_remote(TwaiId::Standard(0x300), 1).unwrap()));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x300), &[0x05])));
+    }
+
+    #[test]
+    fn dual_extended_filter() {
+        let filter = TwaiFilter::dual_extended(0x1000_0000, 0x1fff_e000, 0x0002_0000, 0x1ffe_0000);
+        assert!(filter.matches(&data(TwaiId::Extended(0x1000_1fff), &[])));
+        assert!(!filter.matches(&data(TwaiId::Extended(0x1000_2000), &[])));
+        assert!(filter.matches(&data(TwaiId::Extended(0x0003_ffff), &[])));
+        assert!(!filter.matches(&data(TwaiId::Extended(0x0004_0000), &[])));
+        assert!(!filter.matches(&data(TwaiId::Standard(0x000), &[])));
+    }
+
+    #[test]
+    fn loopback_self_reception() {
+        let mut bus = TwaiLoopback::new(TwaiFilter::standard(0x100, 0x7ff), 4);
+        let frame = data(TwaiId::Standard(0x100), &[1, 2]);
+        bus.transmit(&frame).unwrap();
+        bus.transmit(&data(TwaiId::Standard(0x101), &[])).unwrap();
+        assert_eq!(bus.receive(), Ok(frame));
+        assert_eq!(bus.receive(), Err(nb::Error::WouldBlock));
+        assert_eq!(bus.pop_transmitted(), Some(frame));
+        assert_eq!(bus.pop_transmitted().map(|frame| frame.id()), Some(TwaiId::Standard(0x101)));
+        assert_eq!(bus.pop_transmitted(), None);
+
+        bus.set_self_reception(false);
+        bus.transmit(&frame).unwrap();
+        assert_eq!(bus.receive(), Err(nb::Error::WouldBlock));
+        assert_eq!(bus.pop_transmitted(), Some(frame));
+    }
+
+    #[test]
+    fn loopback_transmit_queue_full() {
+        let mut bus = TwaiLoopback::new(TwaiFilter::accept_all(), 2);
+        bus.set_self_reception(false);
+        let frame = data(TwaiId::Standard(0x100), &[]);
+        bus.transmit(&frame).unwrap();
+        bus.transmit(&frame).unwrap();
+        assert_eq!(bus.transmit(&frame), Err(nb::Error::WouldBlock));
+        bus.pop_transmitted();
+        assert_eq!(bus.transmit(&frame), Ok(()));
+    }
+
+    #[test]
+    fn loopback_overrun() {
+        let mut bus = TwaiLoopback::new(TwaiFilter::accept_all(), 2);
+        let frames: alloc::vec::Vec<TwaiFrame> = (0..3u8).map(|i| data(TwaiId::Standard(0x100), &[i])).collect();
+        assert!(bus.push_received(frames[0]));
+        assert!(bus.push_received(frames[1]));
+        assert!(!bus.push_received(frames[2]));
+        // The overrun is reported once, before the queued frames.
+        assert_eq!(bus.receive(), Err(nb::Error::Other(TwaiLoopbackError::Overrun)));
+        assert_eq!(bus.receive(), Ok(frames[0]));
+        assert_eq!(bus.receive(), Ok(frames[1]));
+        assert_eq!(bus.receive(), Err(nb::Error::WouldBlock));
+        // Rejected frames do not overrun the queue.
+        let mut bus = TwaiLoopback::new(TwaiFilter::standard(0x100, 0x7ff), 1);
+        assert!(!bus.push_received(data(TwaiId::Standard(0x200), &[])));
+        assert!(!bus.push_received(data(TwaiId::Standard(0x200), &[])));
+        assert_eq!(bus.receive(), Err(nb::Error::WouldBlock));
+    }
+}
@@ -0,0 +1,380 @@
+use core::convert::Into;
+use core::fmt;
+use core::ptr;
+
+use idf;
+use idf::AsResult;
+use idf::std::os::raw::*;
+use idf::IdfError;
+
+use freertos_rs::*;
+use embedded_hal::serial;
+use embedded_hal::blocking;
+
+use nb;
+
+use crate::gpio::*;
+
+#[derive(Copy, Clone, Debug)]
+pub enum UartError {
+    Generic,
+    IdfError(IdfError),
+    FreeRtosError(FreeRtosError),
+}
+
+impl From<IdfError> for UartError {
+    fn from(err: IdfError) -> UartError {
+        UartError::IdfError(err)
+    }
+}
+impl From<FreeRtosError> for UartError {
+    fn from(err: FreeRtosError) -> UartError {
+        UartError::FreeRtosError(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum UartPortNumber {
+    Port0,
+    Port1,
+    Port2,
+}
+
+impl Into<idf::uart_port_t> for UartPortNumber {
+    fn into(self) -> idf::uart_port_t {
+        match self {
+            UartPortNumber::Port0 => 0,
+            UartPortNumber::Port1 => 1,
+            UartPortNumber::Port2 => 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum UartDataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+impl Default for UartDataBits {
+    fn default() -> Self { UartDataBits::Eight }
+}
+impl Into<idf::uart_word_length_t> for UartDataBits {
+    fn into(self) -> idf::uart_word_length_t {
+        match self {
+            UartDataBits::Five => idf::uart_word_length_t_UART_DATA_5_BITS,
+            UartDataBits::Six => idf::uart_word_length_t_UART_DATA_6_BITS,
+            UartDataBits::Seven => idf::uart_word_length_t_UART_DATA_7_BITS,
+            UartDataBits::Eight => idf::uart_word_length_t_UART_DATA_8_BITS,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum UartParity {
+    Disable,
+    Even,
+    Odd,
+}
+impl Default for UartParity {
+    fn default() -> Self { UartParity::Disable }
+}
+impl Into<idf::uart_parity_t> for UartParity {
+    fn into(self) -> idf::uart_parity_t {
+        match self {
+            UartParity::Disable => idf::uart_parity_t_UART_PARITY_DISABLE,
+            UartParity::Even => idf::uart_parity_t_UART_PARITY_EVEN,
+            UartParity::Odd => idf::uart_parity_t_UART_PARITY_ODD,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum UartStopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+impl Default for UartStopBits {
+    fn default() -> Self { UartStopBits::One }
+}
+impl Into<idf::uart_stop_bits_t> for UartStopBits {
+    fn into(self) -> idf::uart_stop_bits_t {
+        match self {
+            UartStopBits::One => idf::uart_stop_bits_t_UART_STOP_BITS_1,
+            UartStopBits::OneAndHalf => idf::uart_stop_bits_t_UART_STOP_BITS_1_5,
+            UartStopBits::Two => idf::uart_stop_bits_t_UART_STOP_BITS_2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum UartFlowControl {
+    Disable,
+    Rts,
+    Cts,
+    CtsRts,
+}
+impl Default for UartFlowControl {
+    fn default() -> Self { UartFlowControl::Disable }
+}
+impl Into<idf::uart_hw_flowcontrol_t> for UartFlowControl {
+    fn into(self) -> idf::uart_hw_flowcontrol_t {
+        match self {
+            UartFlowControl::Disable => idf::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE,
+            UartFlowControl::Rts => idf::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_RTS,
+            UartFlowControl::Cts => idf::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_CTS,
+            UartFlowControl::CtsRts => idf::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_CTS_RTS,
+        }
+    }
+}
+
+// Pins left as None keep their current routing (e.g. the default console pins of UART0).
+#[derive(Copy, Clone, Debug)]
+pub struct UartConfig {
+    pub baud_rate: u32,
+    pub data_bits: UartDataBits,
+    pub parity: UartParity,
+    pub stop_bits: UartStopBits,
+    pub flow_control: UartFlowControl,
+    pub rx_flow_control_threshold: u8,
+    pub tx_pin: Option<GpioPin>,
+    pub rx_pin: Option<GpioPin>,
+    pub rts_pin: Option<GpioPin>,
+    pub cts_pin: Option<GpioPin>,
+    // Size of the RX ring buffer. Must be larger than the hardware FIFO (128 bytes).
+    pub rx_buffer_size: usize,
+    // Size of the TX ring buffer. With 0, writes block until the data is in the hardware FIFO.
+    pub tx_buffer_size: usize,
+    // Length of the event queue. With 0, no events are reported.
+    pub event_queue_size: usize,
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        UartConfig {
+            baud_rate: 115200,
+            data_bits: Default::default(),
+            parity: Default::default(),
+            stop_bits: Default::default(),
+            flow_control: Default::default(),
+            rx_flow_control_threshold: 122,
+            tx_pin: None,
+            rx_pin: None,
+            rts_pin: None,
+            cts_pin: None,
+            rx_buffer_size: 256,
+            tx_buffer_size: 0,
+            event_queue_size: 0,
+        }
+    }
+}
+
+impl Into<idf::uart_config_t> for UartConfig {
+    fn into(self) -> idf::uart_config_t {
+        let mut config = idf::uart_config_t::default();
+        config.baud_rate = self.baud_rate as i32;
+        config.data_bits = self.data_bits.into();
+        config.parity = self.parity.into();
+        config.stop_bits = self.stop_bits.into();
+        config.flow_ctrl = self.flow_control.into();
+        config.rx_flow_ctrl_thresh = self.rx_flow_control_threshold;
+        config
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UartEvent {
+    // Number of bytes received.
+    Data(usize),
+    Break,
+    BufferFull,
+    FifoOverflow,
+    FrameError,
+    ParityError,
+    DataBreak,
+    // The pattern set by enable_pattern_detection was received.
+    PatternDetected,
+    Other,
+}
+
+impl From<idf::uart_event_t> for UartEvent {
+    fn from(event: idf::uart_event_t) -> UartEvent {
+        match event.type_ {
+            idf::uart_event_type_t_UART_DATA => UartEvent::Data(event.size),
+            idf::uart_event_type_t_UART_BREAK => UartEvent::Break,
+            idf::uart_event_type_t_UART_BUFFER_FULL => UartEvent::BufferFull,
+            idf::uart_event_type_t_UART_FIFO_OVF => UartEvent::FifoOverflow,
+            idf::uart_event_type_t_UART_FRAME_ERR => UartEvent::FrameError,
+            idf::uart_event_type_t_UART_PARITY_ERR => UartEvent::ParityError,
+            idf::uart_event_type_t_UART_DATA_BREAK => UartEvent::DataBreak,
+            idf::uart_event_type_t_UART_PATTERN_DET => UartEvent::PatternDetected,
+            _ => UartEvent::Other,
+        }
+    }
+}
+
+const UART_PIN_NO_CHANGE: i32 = -1;
+
+pub struct Uart {
+    port_number: UartPortNumber,
+    event_queue: idf::QueueHandle_t,
+}
+
+unsafe impl Send for Uart {}
+
+impl Uart {
+    pub fn new(port_number: UartPortNumber, config: UartConfig) -> Result<Uart, UartError> {
+        let port: idf::uart_port_t = port_number.into();
+        let idf_config: idf::uart_config_t = config.into();
+        let pin_number = |pin: Option<GpioPin>| pin.map_or(UART_PIN_NO_CHANGE, |pin| pin.number() as i32);
+        let mut event_queue: idf::QueueHandle_t = ptr::null_mut();
+        unsafe {
+            idf::uart_param_config(port, &idf_config).as_result()?;
+            idf::uart_set_pin(port, pin_number(config.tx_pin), pin_number(config.rx_pin), pin_number(config.rts_pin), pin_number(config.cts_pin)).as_result()?;
+            let event_queue_ptr = if config.event_queue_size > 0 { &mut event_queue as *mut idf::QueueHandle_t } else { ptr::null_mut() };
+            idf::uart_driver_install(port, config.rx_buffer_size as i32, config.tx_buffer_size as i32, config.event_queue_size as i32, event_queue_ptr, 0).as_result()?;
+        }
+        Ok(Uart { port_number: port_number, event_queue: event_queue })
+    }
+
+    fn port(&self) -> idf::uart_port_t { self.port_number.into() }
+
+    pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), UartError> {
+        unsafe { idf::uart_set_baudrate(self.port(), baud_rate).as_result()?; }
+        Ok(())
+    }
+
+    // Copy data into the TX FIFO without waiting. Returns the number of bytes which fitted, which may be 0.
+    // The data bypasses the TX ring buffer, so do not mix it with write() while data written by it is pending.
+    pub fn try_write(&mut self, data: &[u8]) -> Result<usize, UartError> {
+        let result = unsafe { idf::uart_tx_chars(self.port(), data.as_ptr() as *const c_char, data.len() as u32) };
+        if result < 0 { Err(UartError::Generic) } else { Ok(result as usize) }
+    }
+
+    // Queue data to be sent, waiting for space in the TX ring buffer. Returns the number of bytes written.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, UartError> {
+        let result = unsafe { idf::uart_write_bytes(self.port(), data.as_ptr() as *const c_char, data.len()) };
+        if result < 0 { Err(UartError::Generic) } else { Ok(result as usize) }
+    }
+
+    // Read received data. Returns the number of bytes read, which is less than the buffer size on timeout.
+    pub fn read(&mut self, buffer: &mut [u8], wait_ticks: Duration) -> Result<usize, UartError> {
+        let result = unsafe { idf::uart_read_bytes(self.port(), buffer.as_mut_ptr(), buffer.len() as u32, wait_ticks.to_ticks()) };
+        if result < 0 { Err(UartError::Generic) } else { Ok(result as usize) }
+    }
+
+    // Number of received bytes in the RX ring buffer.
+    pub fn buffered_len(&self) -> Result<usize, UartError> {
+        let mut length: usize = 0;
+        unsafe { idf::uart_get_buffered_data_len(self.port(), &mut length).as_result()?; }
+        Ok(length)
+    }
+
+    pub fn wait_tx_done(&mut self, wait_ticks: Duration) -> Result<(), UartError> {
+        unsafe { idf::uart_wait_tx_done(self.port(), wait_ticks.to_ticks()).as_result()?; }
+        Ok(())
+    }
+
+    pub fn flush_input(&mut self) -> Result<(), UartError> {
+        unsafe { idf::uart_flush_input(self.port()).as_result()?; }
+        Ok(())
+    }
+
+    // Detect `count` consecutive `pattern` characters (e.g. "+++" or a line terminator).
+    // The positions of detected patterns in the RX buffer are recorded in a queue of `queue_length` entries.
+    pub fn enable_pattern_detection(&mut self, pattern: u8, count: u8, queue_length: usize) -> Result<(), UartError> {
+        unsafe {
+            // Gap limits in baud-rate cycles: the characters of the pattern must be sent back to back.
+            idf::uart_enable_pattern_det_baud_intr(self.port(), pattern as c_char, count, 9, 0, 0).as_result()?;
+            idf::uart_pattern_queue_reset(self.port(), queue_length as i32).as_result()?;
+        }
+        Ok(())
+    }
+
+    pub fn disable_pattern_detection(&mut self) -> Result<(), UartError> {
+        unsafe { idf::uart_disable_pattern_det_intr(self.port()).as_result()?; }
+        Ok(())
+    }
+
+    // Take the position of the oldest detected pattern in the RX buffer.
+    pub fn pop_pattern_position(&mut self) -> Option<usize> {
+        let position = unsafe { idf::uart_pattern_pop_pos(self.port()) };
+        if position < 0 { None } else { Some(position as usize) }
+    }
+
+    // Wait for the next driver event. Requires UartConfig::event_queue_size > 0.
+    pub fn receive_event(&mut self, wait_ticks: Duration) -> Result<UartEvent, UartError> {
+        if self.event_queue.is_null() {
+            return Err(UartError::Generic);
+        }
+        let mut event = idf::uart_event_t::default();
+        let received = unsafe { idf::xQueueGenericReceive(self.event_queue, &mut event as *mut idf::uart_event_t as *mut c_void, wait_ticks.to_ticks(), 0) };
+        if received == 0 {
+            return Err(UartError::FreeRtosError(FreeRtosError::QueueReceiveTimeout));
+        }
+        Ok(event.into())
+    }
+}
+
+impl Drop for Uart {
+    fn drop(&mut self) {
+        unsafe {
+            idf::uart_driver_delete(self.port());
+        }
+    }
+}
+
+impl serial::Read<u8> for Uart {
+    type Error = UartError;
+
+    fn read(&mut self) -> nb::Result<u8, UartError> {
+        let mut buffer = [0u8; 1];
+        match Uart::read(self, &mut buffer, Duration::zero()) {
+            Ok(1) => Ok(buffer[0]),
+            Ok(_) => Err(nb::Error::WouldBlock),
+            Err(err) => Err(nb::Error::Other(err)),
+        }
+    }
+}
+
+impl serial::Write<u8> for Uart {
+    type Error = UartError;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), UartError> {
+        match self.try_write(&[word]) {
+            Ok(0) => Err(nb::Error::WouldBlock),
+            Ok(_) => Ok(()),
+            Err(err) => Err(nb::Error::Other(err)),
+        }
+    }
+    fn flush(&mut self) -> nb::Result<(), UartError> {
+        match self.wait_tx_done(Duration::zero()) {
+            Ok(_) => Ok(()),
+            Err(UartError::IdfError(err)) if err.code() == idf::ESP_ERR_TIMEOUT => Err(nb::Error::WouldBlock),
+            Err(err) => Err(nb::Error::Other(err)),
+        }
+    }
+}
+
+impl blocking::serial::Write<u8> for Uart {
+    type Error = UartError;
+
+    fn bwrite_all(&mut self, buffer: &[u8]) -> Result<(), UartError> {
+        let mut offset = 0;
+        while offset < buffer.len() {
+            offset += Uart::write(self, &buffer[offset..])?;
+        }
+        Ok(())
+    }
+    fn bflush(&mut self) -> Result<(), UartError> {
+        self.wait_tx_done(Duration::infinite())
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        blocking::serial::Write::bwrite_all(self, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}